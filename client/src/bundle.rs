@@ -0,0 +1,59 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::report::SCHEMA_VERSION;
+
+/// Everything needed to re-run a benchmark: the targets, the seeds and all options, as
+/// written by `--print-config` and read by `--seed-from-file`.
+#[derive(Serialize, Deserialize)]
+pub struct Bundle<C> {
+    /// Schema version of the client that wrote the bundle, a client only reads its own.
+    pub schema_version: u32,
+    /// Version of the client that wrote the bundle, for the humans reading it.
+    pub client_version: String,
+    pub config: C,
+}
+
+impl<C> Bundle<C> {
+    pub fn new(config: C) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            client_version: env!("CARGO_PKG_VERSION").to_string(),
+            config,
+        }
+    }
+
+    pub fn into_config(self) -> Result<C> {
+        if self.schema_version != SCHEMA_VERSION {
+            bail!(
+                "Bundle was written by client {} with schema version {}, this client reads {}",
+                self.client_version,
+                self.schema_version,
+                SCHEMA_VERSION
+            );
+        }
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Config;
+
+    #[test]
+    fn round_trip() {
+        let mut config = Config::new(false, vec!["127.0.0.1:7".to_string()], 50);
+        config
+            .set_poisson(200.0, 42)
+            .set_rate_step(10.0)
+            .set_interval(std::time::Duration::from_millis(5));
+        let bundle = config.to_bundle().unwrap();
+
+        let read = Config::from_bundle(&bundle).unwrap();
+        assert_eq!(read.to_json().unwrap(), config.to_json().unwrap());
+        assert!(read.live_rate().is_some());
+
+        let stale = bundle.replacen("\"schema_version\": ", "\"schema_version\": 1", 1);
+        assert!(Config::from_bundle(&stale).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::report::TargetSummary;
+
+/// The part of a saved report `--compare` looks at.
+#[derive(Debug, Deserialize)]
+struct SavedReport {
+    targets: Vec<TargetSummary>,
+}
+
+/// Regressions `--compare` accepts before failing a target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    /// Absolute increase of the loss, as a fraction.
+    pub loss: f64,
+    /// Increase of the mean and p99 RTT, as a fraction of the baseline.
+    pub rtt: f64,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Self {
+            loss: 0.01,
+            rtt: 0.1,
+        }
+    }
+}
+
+/// Change of a target between the baseline and the candidate report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetDelta {
+    pub target: String,
+    /// Loss in the baseline and the candidate, `None` if the target is missing in one of them.
+    pub loss: Option<(f64, f64)>,
+    pub mean_rtt: (Option<Duration>, Option<Duration>),
+    pub p99_rtt: (Option<Duration>, Option<Duration>),
+    pub passed: bool,
+}
+
+impl TargetDelta {
+    fn new(
+        target: &str,
+        baseline: Option<&TargetSummary>,
+        candidate: Option<&TargetSummary>,
+        tolerance: Tolerance,
+    ) -> Self {
+        let (baseline, candidate) = match (baseline, candidate) {
+            (Some(baseline), Some(candidate)) => (baseline, candidate),
+            _ => {
+                return Self {
+                    target: target.to_string(),
+                    loss: None,
+                    mean_rtt: (
+                        baseline.and_then(|s| s.mean_rtt),
+                        candidate.and_then(|s| s.mean_rtt),
+                    ),
+                    p99_rtt: (
+                        baseline.and_then(|s| s.p99_rtt),
+                        candidate.and_then(|s| s.p99_rtt),
+                    ),
+                    passed: false,
+                }
+            }
+        };
+
+        let loss = (loss(baseline), loss(candidate));
+        let mean_rtt = (baseline.mean_rtt, candidate.mean_rtt);
+        let p99_rtt = (baseline.p99_rtt, candidate.p99_rtt);
+        let rtt_passed = |(old, new): (Option<Duration>, Option<Duration>)| match (old, new) {
+            (Some(old), Some(new)) => {
+                new.as_secs_f64() <= old.as_secs_f64() * (1.0 + tolerance.rtt)
+            }
+            // nothing came back before, so anything is an improvement
+            (None, _) => true,
+            (Some(_), None) => false,
+        };
+
+        Self {
+            target: target.to_string(),
+            loss: Some(loss),
+            mean_rtt,
+            p99_rtt,
+            passed: loss.1 - loss.0 <= tolerance.loss
+                && rtt_passed(mean_rtt)
+                && rtt_passed(p99_rtt),
+        }
+    }
+}
+
+impl fmt::Display for TargetDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verdict = if self.passed { "ok" } else { "FAIL" };
+        let (old, new) = match self.loss {
+            Some(loss) => loss,
+            None => return write!(f, "{}: only in one report {}", self.target, verdict),
+        };
+        write!(
+            f,
+            "{}: loss {:.2}% -> {:.2}% ({:+.2}), mean rtt {}, p99 rtt {} {}",
+            self.target,
+            old * 100.0,
+            new * 100.0,
+            (new - old) * 100.0,
+            Shift(self.mean_rtt),
+            Shift(self.p99_rtt),
+            verdict
+        )
+    }
+}
+
+/// Formats an RTT change as `old -> new (+percent)`.
+struct Shift((Option<Duration>, Option<Duration>));
+
+impl fmt::Display for Shift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            (Some(old), Some(new)) => write!(
+                f,
+                "{:?} -> {:?} ({:+.1}%)",
+                old,
+                new,
+                (new.as_secs_f64() / old.as_secs_f64() - 1.0) * 100.0
+            ),
+            (old, new) => write!(f, "{:?} -> {:?}", old, new),
+        }
+    }
+}
+
+fn loss(summary: &TargetSummary) -> f64 {
+    let sent = summary.succeeded + summary.failed;
+    if sent == 0 {
+        return 0.0;
+    }
+    summary.failed as f64 / sent as f64
+}
+
+/// Compare two reports as written by the client, matching the targets by address. Targets
+/// that appear in only one of them fail.
+pub fn compare(baseline: &str, candidate: &str, tolerance: Tolerance) -> Result<Vec<TargetDelta>> {
+    let baseline: SavedReport =
+        serde_json::from_str(baseline).context("Failed to parse the baseline report")?;
+    let candidate: SavedReport =
+        serde_json::from_str(candidate).context("Failed to parse the candidate report")?;
+
+    let mut ret = Vec::new();
+    for summary in &baseline.targets {
+        let other = candidate
+            .targets
+            .iter()
+            .find(|s| s.target == summary.target);
+        ret.push(TargetDelta::new(
+            &summary.target,
+            Some(summary),
+            other,
+            tolerance,
+        ));
+    }
+    for summary in &candidate.targets {
+        if !baseline.targets.iter().any(|s| s.target == summary.target) {
+            ret.push(TargetDelta::new(
+                &summary.target,
+                None,
+                Some(summary),
+                tolerance,
+            ));
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{compare, Tolerance};
+    use crate::results::{JsonResultState, JsonResults};
+    use crate::Report;
+
+    fn report(target: &'static str, failed: usize, rtt: u64) -> String {
+        let results = (0..10)
+            .map(|sequence| JsonResults {
+                identifier: 0,
+                sequence,
+                target: target.to_string(),
+                local: None,
+                remote: None,
+                family: None,
+                ecn: None,
+                tos: None,
+                cpu: None,
+                received_size: None,
+                truncated: false,
+                corrupt: false,
+                warmup: false,
+                timed_out: None,
+                error: None,
+                sent_at: None,
+                sent_wall_ns: None,
+                send_latency: None,
+                lateness: None,
+                icmp_error: None,
+                one_way: None,
+                state: if (sequence as usize) < failed {
+                    JsonResultState::Failed
+                } else {
+                    JsonResultState::Succeded(Duration::from_millis(rtt))
+                },
+            })
+            .collect();
+        serde_json::to_string(&Report::new(results)).unwrap()
+    }
+
+    #[test]
+    fn deltas() {
+        let baseline = report("a:7", 0, 10);
+        let tolerance = Tolerance::default();
+
+        let same = compare(&baseline, &report("a:7", 0, 10), tolerance).unwrap();
+        assert_eq!(same.len(), 1);
+        assert!(same[0].passed);
+        assert_eq!(same[0].loss, Some((0.0, 0.0)));
+
+        let slower = compare(&baseline, &report("a:7", 0, 12), tolerance).unwrap();
+        assert!(!slower[0].passed);
+        assert_eq!(
+            slower[0].p99_rtt,
+            (
+                Some(Duration::from_millis(10)),
+                Some(Duration::from_millis(12))
+            )
+        );
+
+        let lossy = compare(&baseline, &report("a:7", 1, 10), tolerance).unwrap();
+        assert!(!lossy[0].passed);
+        assert!(
+            compare(
+                &baseline,
+                &report("a:7", 1, 10),
+                Tolerance {
+                    loss: 0.1,
+                    rtt: 0.1
+                }
+            )
+            .unwrap()[0]
+                .passed
+        );
+
+        let moved = compare(&baseline, &report("b:7", 0, 10), tolerance).unwrap();
+        assert_eq!(moved.len(), 2);
+        assert!(moved.iter().all(|d| !d.passed && d.loss.is_none()));
+    }
+}
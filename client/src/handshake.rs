@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_std::net::UdpSocket;
+use packet::{
+    read_params, EchoParams, Packet, UdpEchoBuilder, UdpEchoPacket, NEXT_LEVEL_PARAMS, PARAMS_LEN,
+};
+
+/// Identifier of the parameter queries, next to the one of the preload packets.
+const QUERY_IDENTIFIER: u64 = u64::MAX - 1;
+
+/// How long `--query-server` waits for the answer of a target.
+pub const QUERY_WAIT: Duration = Duration::from_secs(1);
+
+/// Ask the server at `target` for its echo modes. Returns `None` if it doesn't answer within
+/// `wait` or only echoes the query, as servers that don't know it do.
+pub async fn query(socket: &UdpSocket, target: &str, wait: Duration) -> Result<Option<EchoParams>> {
+    let mut buf = vec![0u8; UdpEchoPacket::minimum_packet_size() + PARAMS_LEN];
+    let len = UdpEchoBuilder::new()
+        .identifier(QUERY_IDENTIFIER)
+        .next_level(NEXT_LEVEL_PARAMS)
+        .payload(&[0; PARAMS_LEN])
+        .build_into(&mut buf)
+        .expect("buffer fits the query");
+    socket
+        .send_to(&buf[..len], target)
+        .await
+        .with_context(|| format!("Failed to send parameter query to {}", target))?;
+
+    let answer = async {
+        let mut recv = vec![0u8; 65536];
+        loop {
+            if let Ok(size) = socket.recv(&mut recv).await {
+                match UdpEchoPacket::new(&recv[..size]) {
+                    Some(echo) if echo.get_identifier() == QUERY_IDENTIFIER => {
+                        return read_params(echo.payload());
+                    }
+                    _ => (),
+                }
+            }
+        }
+    };
+    Ok(async_std::future::timeout(wait, answer)
+        .await
+        .ok()
+        .flatten())
+}
@@ -1,32 +1,208 @@
+mod bundle;
+mod clock;
+mod compare;
+mod handshake;
+mod load;
+mod mtu;
+mod pacing;
+mod preload;
+mod replay;
+mod report;
 mod results;
+mod search;
+mod socket;
+mod streaming;
+mod sweep;
+mod trace;
 
-use std::sync::atomic::AtomicBool;
+pub use crate::bundle::Bundle;
+pub use crate::clock::{Clock, MockClock, SystemClock};
+pub use crate::compare::{compare, TargetDelta, Tolerance};
+pub use crate::load::{LoadParams, LoadResult, LoadStats, ProbeStats, TargetUnderLoad};
+pub use crate::mtu::{MtuResult, Reassembly};
+pub use crate::pacing::{LiveRate, RateCap, RateChange};
+pub use crate::replay::{load_replay, parse_replay, ReplayPacket};
+pub use crate::report::{
+    AbortReason, Goodput, OnTimeout, ReplaySummary, Report, ReverseProbes, ServerParams,
+    TargetSummary, LATE_AFTER, SCHEMA_VERSION,
+};
+pub use crate::results::{
+    AddressFamily, IcmpError, JsonResultState, JsonResults, OnResult, OneWayDelay, TimeoutPhase,
+    TryError,
+};
+pub use crate::search::{RatePhase, RateSearch, RateSearchResult};
+pub use crate::sweep::{parse_sizes, SizePoint, SizeSweepResult, DEFAULT_SWEEP};
+pub use crate::trace::{Hop, TraceResult};
+pub use packet::list_interfaces;
 
-use crate::results::{JsonResultState, JsonResults, Results};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::pacing::Pacer;
+use crate::results::{RecvInfo, Results};
+use crate::search::Search;
 use anyhow::{bail, Context, Result};
-use async_std::io;
 use async_std::net::{
-    Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream,
-    ToSocketAddrs, UdpSocket,
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream, ToSocketAddrs,
+    UdpSocket,
 };
 use async_std::prelude::*;
-use async_std::stream::IntoStream;
-use async_std::sync::Mutex;
 use log::*;
-use packet::{MutableUdpEchoPacket, UdpEcho, UdpEchoPacket};
-use std::collections::HashMap;
+use packet::{
+    read_timestamps, write_reverse_request, EchoParams, MutableUdpEchoCompactPacket, Packet,
+    UdpEchoBuilder, UdpEchoCompact, UdpEchoCompactPacket, UdpEchoPacket, NEXT_LEVEL_REVERSE,
+    NEXT_LEVEL_REVERSE_PROBE, NEXT_LEVEL_TIMESTAMPS, NEXT_LEVEL_TOS, REVERSE_REQUEST_LEN,
+    TIMESTAMPS_LEN,
+};
+use serde::{Deserialize, Serialize};
 use std::fs::OpenOptions;
-use std::process::id;
+use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 
+/// Size of a datagram in the default packet format.
+const ECHO_SIZE: usize = 18;
+
+/// Default UDP receive buffer, large enough for any datagram.
+const MAX_RECV_BUFFER: usize = 65536;
+
+/// File descriptors kept free for stdio, the async runtime and the like.
+const RESERVED_FDS: u64 = 32;
+
+/// Most frames `--tcp-pipeline` writes before reading, so a window always fits the socket
+/// buffers and the server never blocks on echoes the client doesn't read yet.
+const MAX_TCP_PIPELINE: usize = 4096;
+
+/// Fraction of the requested rate below which the pacing is reported as falling short.
+const SLOW_PACING: f64 = 0.95;
+
+/// Packets per second all targets together may send unless told otherwise, high enough to
+/// stay out of the way of deliberate runs but keep a runaway one from starving the host.
+pub const DEFAULT_MAX_TOTAL_RATE: f64 = 1_000_000.0;
+
+/// How often a run looks for rate changes of `--signal-rate`.
+const RATE_POLL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// How long the load of `--under-load` runs before the probes start.
+const LOAD_RAMP: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// How long a sequence may wait for its response before it counts towards `--abort-after`.
+const ABORT_REPLY_WINDOW: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How often a sender held back by `--abort-after` checks whether its sequences were answered.
+const ABORT_POLL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// How often the workers of a run look for the exit flag or the expired timeout.
+const STOP_POLL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// How often a run with `--max-runtime-memory` estimates the memory its results need.
+const MEMORY_POLL: std::time::Duration = std::time::Duration::from_millis(100);
+
+#[derive(Serialize, Deserialize)]
 pub struct Config {
     addresses: Vec<String>,
     tcp: bool,
     tries: usize,
+    bytes: Option<usize>,
+    weights: Option<Vec<usize>>,
     timeout: Option<usize>,
+    connect_timeout: Option<std::time::Duration>,
+    read_timeout: Option<std::time::Duration>,
     output: Option<String>,
+    output_dir: Option<String>,
+    report_to: Option<String>,
+    timestamps_output: Option<String>,
+    max_memory: Option<usize>,
+    mtu_probe: Option<usize>,
+    fragmentation: bool,
+    trace: Option<u8>,
+    abort_after: Option<usize>,
+    interval: Option<std::time::Duration>,
+    poisson: Option<(f64, u64)>,
+    bind_address: Option<IpAddr>,
+    source_addresses: Option<Vec<IpAddr>>,
+    compact: bool,
+    pin: Option<Vec<usize>>,
+    record_cpu: bool,
+    send_latency: bool,
+    recverr: bool,
+    separate_recv: bool,
+    server_timestamps: bool,
+    preload: Option<(usize, std::time::Duration)>,
+    tcp_nodelay: bool,
+    fail_fast_corruption: bool,
+    recv_buffer: Option<usize>,
+    recv_concurrency: usize,
+    source_pool: Option<usize>,
+    no_dns: bool,
+    strict_timeout: bool,
+    max_rtt: Option<std::time::Duration>,
+    strict_order: bool,
+    query_server: bool,
+    ecn: Option<u8>,
+    tos_verify: Option<u8>,
+    embed_config: bool,
+    json_compact: bool,
+    repeat_until_loss: Option<f64>,
+    max_iterations: Option<usize>,
+    rate_search: Option<RateSearch>,
+    load: Option<LoadParams>,
+    size_sweep: Option<Vec<usize>>,
+    /// Datagram size of the plain packet format, set per step by `--probe-size-sweep`.
+    #[serde(skip)]
+    sweep_size: Option<usize>,
+    duration: Option<std::time::Duration>,
+    replay: Option<Vec<ReplayPacket>>,
+    rate_step: Option<f64>,
+    busy_poll: Option<std::time::Duration>,
+    tcp_pipeline: Option<usize>,
+    two_way: bool,
+    rlimit_bump: bool,
+    warmup: usize,
+    exclude_warmup: bool,
+    ndjson: bool,
+    flush_interval: Option<std::time::Duration>,
+    tags: std::collections::BTreeMap<String, String>,
+    #[serde(skip, default = "default_namespace")]
     namespace: String,
-    exit: AtomicBool,
+    #[serde(skip)]
+    exit: Arc<AtomicBool>,
+    #[serde(skip)]
+    on_result: Option<OnResult>,
+    #[serde(skip)]
+    live_rate: Option<Arc<LiveRate>>,
+    drain_remaining: bool,
+    /// Set once the timeout expired with `--drain-remaining-budget`, so the senders stop pacing.
+    #[serde(skip)]
+    deadline_passed: AtomicBool,
+    /// Set once the timeout expired without `--drain-remaining-budget`, stopping the run like the
+    /// exit flag does, but for this run only.
+    #[serde(skip)]
+    timed_out: AtomicBool,
+    /// Workers still in their send loop, see [`Sending`].
+    #[serde(skip)]
+    sending: AtomicUsize,
+    max_total_rate: Option<f64>,
+    #[serde(skip)]
+    rate_cap: Option<Arc<RateCap>>,
+    /// Reverse probes requested from and received by every target with `--two-way`, kept up to
+    /// date as they arrive so a timeout doesn't lose them.
+    #[serde(skip)]
+    reverse: Arc<std::sync::Mutex<std::collections::BTreeMap<String, (u64, u64)>>>,
+    /// Why targets stopped sending early, see `--abort-after`.
+    #[serde(skip)]
+    aborted: Arc<std::sync::Mutex<std::collections::BTreeMap<String, AbortReason>>>,
+    /// Summaries of the targets `--max-runtime-memory` switched to summary-only results.
+    #[serde(skip)]
+    summaries: Arc<std::sync::Mutex<Vec<TargetSummary>>>,
+    /// Echo modes answered to `--query-server` by every target, `None` if it didn't answer.
+    #[serde(skip)]
+    server_params: Arc<std::sync::Mutex<std::collections::BTreeMap<String, Option<EchoParams>>>>,
+    /// First corrupt echo seen with `--fail-fast-corruption`, as target and sequence.
+    #[serde(skip)]
+    corrupted: Arc<std::sync::Mutex<Option<(String, u64)>>>,
+}
+
+fn default_namespace() -> String {
+    module_path!().to_string()
 }
 
 impl Config {
@@ -35,154 +211,3154 @@ impl Config {
             tcp,
             addresses,
             tries,
+            bytes: None,
+            weights: None,
             timeout: None,
+            connect_timeout: None,
+            read_timeout: None,
             output: None,
-            namespace: module_path!().to_string(),
-            exit: AtomicBool::new(false),
+            output_dir: None,
+            report_to: None,
+            timestamps_output: None,
+            max_memory: None,
+            mtu_probe: None,
+            fragmentation: false,
+            trace: None,
+            abort_after: None,
+            interval: None,
+            poisson: None,
+            bind_address: None,
+            source_addresses: None,
+            compact: false,
+            pin: None,
+            record_cpu: false,
+            send_latency: false,
+            recverr: false,
+            separate_recv: false,
+            server_timestamps: false,
+            preload: None,
+            tcp_nodelay: true,
+            fail_fast_corruption: false,
+            recv_buffer: None,
+            recv_concurrency: 1,
+            source_pool: None,
+            no_dns: false,
+            strict_timeout: false,
+            max_rtt: None,
+            strict_order: false,
+            query_server: false,
+            ecn: None,
+            tos_verify: None,
+            embed_config: false,
+            json_compact: false,
+            repeat_until_loss: None,
+            max_iterations: None,
+            rate_search: None,
+            load: None,
+            size_sweep: None,
+            sweep_size: None,
+            duration: None,
+            rate_step: None,
+            busy_poll: None,
+            tcp_pipeline: None,
+            replay: None,
+            two_way: false,
+            rlimit_bump: true,
+            warmup: 0,
+            exclude_warmup: false,
+            ndjson: false,
+            flush_interval: None,
+            tags: std::collections::BTreeMap::new(),
+            namespace: default_namespace(),
+            exit: Arc::new(AtomicBool::new(false)),
+            reverse: Arc::new(std::sync::Mutex::new(std::collections::BTreeMap::new())),
+            aborted: Arc::new(std::sync::Mutex::new(std::collections::BTreeMap::new())),
+            summaries: Arc::new(std::sync::Mutex::new(Vec::new())),
+            server_params: Arc::new(std::sync::Mutex::new(std::collections::BTreeMap::new())),
+            corrupted: Arc::new(std::sync::Mutex::new(None)),
+            on_result: None,
+            live_rate: None,
+            drain_remaining: false,
+            deadline_passed: AtomicBool::new(false),
+            timed_out: AtomicBool::new(false),
+            sending: AtomicUsize::new(0),
+            max_total_rate: Some(DEFAULT_MAX_TOTAL_RATE),
+            rate_cap: Some(Arc::new(
+                RateCap::new(DEFAULT_MAX_TOTAL_RATE).expect("the default rate is positive"),
+            )),
         }
     }
 
+    /// Call `hook` with every result as soon as it is final: when its echo arrives, its TCP try
+    /// times out, or the run stops or ends without an echo for it.
+    ///
+    /// The hook runs synchronously on whichever thread or task settled the result, including
+    /// the pinned threads of [`Config::set_pin`], while the results are locked. It must return
+    /// quickly and must not block; hand the result to a channel to do more with it.
+    pub fn set_on_result<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&JsonResults) + Send + Sync + 'static,
+    {
+        self.on_result = Some(OnResult(Arc::new(hook)));
+        self
+    }
+
+    /// Print every result to stdout as a line of json as soon as it is final. The report then
+    /// only goes to `--output` or `--report-to`, if given.
+    pub fn set_ndjson(&mut self, ndjson: bool) -> &mut Self {
+        self.ndjson = ndjson;
+        self
+    }
+
+    /// Flag that stops the run when set. Every target stops sending before its next packet, waits
+    /// for the echoes in flight and reports the rest as not sent.
+    pub fn exit_flag(&self) -> Arc<AtomicBool> {
+        self.exit.clone()
+    }
+
+    /// Distribute the total budget of `tries` per address proportionally to `weights`.
+    pub fn set_weights(&mut self, weights: Vec<usize>) -> &mut Self {
+        self.weights = Some(weights);
+        self
+    }
+
+    /// Stop the run after `timeout` seconds the way the [`Config::exit_flag`] does.
     pub fn set_timeout(&mut self, timeout: usize) -> &mut Self {
         self.timeout = Some(timeout);
         self
     }
 
+    /// When the timeout expires, send what is left of every target's budget without pacing and
+    /// wait for those echoes, instead of stopping right away and reporting the rest as not sent.
+    /// The drain may take as long as the timeout once more, then the workers still sending are
+    /// dropped.
+    pub fn set_drain_remaining(&mut self, drain: bool) -> &mut Self {
+        self.drain_remaining = drain;
+        self
+    }
+
+    /// Give up a TCP try if the connection is not established within `timeout`.
+    pub fn set_connect_timeout(&mut self, timeout: std::time::Duration) -> &mut Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up a TCP try if the echo does not arrive within `timeout` after sending.
+    pub fn set_read_timeout(&mut self, timeout: std::time::Duration) -> &mut Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail the run if the p99 RTT of any target exceeds `max_rtt`.
+    pub fn set_max_rtt(&mut self, max_rtt: std::time::Duration) -> &mut Self {
+        self.max_rtt = Some(max_rtt);
+        self
+    }
+
+    /// Fail the run if any echo arrived out of order.
+    pub fn set_strict_order(&mut self, strict: bool) -> &mut Self {
+        self.strict_order = strict;
+        self
+    }
+
+    /// Ask every UDP target for its echo modes before the run and relax the checks they would
+    /// fail: `--strict-order` against servers that reorder or delay echoes and
+    /// `--fail-fast-corruption` against servers that corrupt or resize them. Servers that don't
+    /// answer are taken to echo plainly.
+    pub fn set_query_server(&mut self, query: bool) -> &mut Self {
+        self.query_server = query;
+        self
+    }
+
+    /// Fail the run when the timeout expires instead of reporting the outstanding requests.
+    pub fn set_strict_timeout(&mut self, strict: bool) -> &mut Self {
+        self.strict_timeout = strict;
+        self
+    }
+
     pub fn set_output(&mut self, output: String) -> &mut Self {
         self.output = Some(output);
         self
     }
 
+    /// Additionally write the results of every target to its own file in `dir`.
+    pub fn set_output_dir(&mut self, dir: String) -> &mut Self {
+        self.output_dir = Some(dir);
+        self
+    }
+
+    /// Send the report over a stream connection to `address` instead of writing a file, either
+    /// `HOST:PORT` for TCP or `unix:PATH` for a unix socket. Every report gets its own
+    /// connection, closed once the json is written.
+    pub fn set_report_to(&mut self, address: String) -> &mut Self {
+        self.report_to = Some(address);
+        self
+    }
+
+    /// Write only the wall clock send and receive time of every packet to `path` instead of the
+    /// report, to correlate the run with a packet capture.
+    ///
+    /// The file is CSV with the header `target,seq,sent_ns,recv_ns` and a row per sent packet,
+    /// ordered by target and sequence. Both times are nanoseconds since the UNIX epoch on the
+    /// client's wall clock; `recv_ns` is empty for packets without an echo.
+    pub fn set_timestamps_only(&mut self, path: String) -> &mut Self {
+        self.timestamps_output = Some(path);
+        self
+    }
+
+    /// Keep only a summary of the largest targets once their per-sample results need more than
+    /// `bytes` of memory. The estimate is checked while the run goes, the switched targets
+    /// drop their samples and fold every further result into the summary, so they are missing
+    /// from the results, `--output-dir` and `--flush-interval`, and their percentiles are
+    /// approximate.
+    pub fn set_max_memory(&mut self, bytes: usize) -> &mut Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
     pub fn set_namespace(&mut self, namespace: String) -> &mut Self {
         self.namespace = namespace;
         self
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        let mut results = Results::new();
+    /// Instead of benchmarking, search the path MTU to each target, starting at `ceiling` bytes.
+    pub fn set_mtu_probe(&mut self, ceiling: usize) -> &mut Self {
+        self.mtu_probe = Some(ceiling);
+        self
+    }
 
-        results.prime(&self.addresses, self.tries).await;
+    /// Also sweep sizes around common MTUs without the DF bit during `--mtu-probe`, to find the
+    /// largest packet that survives fragmentation and reassembly.
+    pub fn set_fragmentation(&mut self, fragmentation: bool) -> &mut Self {
+        self.fragmentation = fragmentation;
+        self
+    }
 
-        let results = Arc::new(results);
+    /// Trace the path to every target with TTLs up to `max_ttl` instead of benchmarking.
+    pub fn set_trace(&mut self, max_ttl: u8) -> &mut Self {
+        self.trace = Some(max_ttl);
+        self
+    }
 
-        let mut workers = Vec::new();
-        for address in &self.addresses {
-            let tcp = self.tcp;
-            let identifier = results
-                .targets
-                .get(address.as_str())
-                .context("Failed to find target identifier")?;
+    /// Stop sending to a target after `count` consecutive sequences failed. A target never has
+    /// more than `count` unanswered sequences in flight, so the breaker trips before an unpaced
+    /// sender has spent its budget.
+    pub fn set_abort_after(&mut self, count: usize) -> &mut Self {
+        self.abort_after = Some(count);
+        self
+    }
 
-            if tcp {
-                // TODO
-                bail!("TCP not yet implemented");
-            } else {
-                workers.push(Self::run_udp_target(
-                    address,
-                    self.tries,
-                    *identifier,
-                    results.clone(),
-                    self.namespace.as_str(),
-                ));
-                trace!(target: self.namespace.as_str(), "created job for {}", address);
-            }
+    /// Send with exponentially distributed gaps at a mean of `rate` packets per second to every
+    /// target, seeded with `seed`.
+    pub fn set_poisson(&mut self, rate: f64, seed: u64) -> &mut Self {
+        self.poisson = Some((rate, seed));
+        self
+    }
+
+    /// Pacing of the sends to the target with `identifier`.
+    fn pacer(&self, identifier: u64, tries: usize) -> Pacer {
+        if let Some(trace) = &self.replay {
+            return Pacer::replay(trace.iter().take(tries).map(|packet| packet.at).collect());
+        }
+        if let Some(duration) = self.duration {
+            return Pacer::schedule(tries, duration);
+        }
+        if let Some(live) = &self.live_rate {
+            return Pacer::Live(live.clone());
+        }
+        match (self.interval, self.poisson) {
+            // different seeds keep the targets from sending in lockstep
+            (_, Some((rate, seed))) => Pacer::poisson(rate, seed.wrapping_add(identifier)),
+            (Some(interval), None) => Pacer::Fixed(interval),
+            (None, None) => Pacer::None,
         }
+    }
 
-        let future = futures::future::try_join_all(workers);
-        //let (future, abortHandle) = futures::future::abortable(future);
+    /// Spin instead of sleeping for the last `threshold` before every paced send, trading CPU
+    /// for an accurate rate on coarse timers.
+    pub fn set_busy_poll_threshold(&mut self, threshold: std::time::Duration) -> &mut Self {
+        self.busy_poll = Some(threshold);
+        self
+    }
 
-        if let Err(e) = if let Some(timeout) = self.timeout {
-            let timeouter = async {
-                async_std::task::sleep(std::time::Duration::from_secs(timeout as u64)).await;
-                bail!("Time exceeded")
-            };
+    /// Packets per second the pacing asks for to every target, if it is fixed.
+    fn requested_rate(&self) -> Option<f64> {
+        if self.live_rate.is_some() {
+            return None;
+        }
+        if let Some(duration) = self.duration {
+            return Some(self.tries as f64 / duration.as_secs_f64());
+        }
+        match (self.interval, self.poisson) {
+            (_, Some((rate, _))) => Some(rate),
+            (Some(interval), None) if !interval.is_zero() => Some(1.0 / interval.as_secs_f64()),
+            _ => None,
+        }
+    }
 
-            future.race(timeouter).await
-        } else {
-            future.await
-        } {
-            warn!(target: self.namespace.as_str(), "Failed to run client: {:?}", e);
+    /// Spread the packets of every target evenly over `duration`, recording how late each one
+    /// went out.
+    pub fn set_duration(&mut self, duration: std::time::Duration) -> &mut Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    /// Send every target the packets of `trace` at their times and sizes instead of pacing them.
+    /// Only the first `tries` packets of a longer trace are sent, and how late each one went out
+    /// is recorded.
+    pub fn set_replay(&mut self, trace: Vec<ReplayPacket>) -> &mut Self {
+        self.replay = Some(trace);
+        self
+    }
+
+    /// Start at the rate of `--interval` and let [`LiveRate::adjust`] change it by `step`
+    /// packets per second while running.
+    pub fn set_rate_step(&mut self, step: f64) -> &mut Self {
+        self.rate_step = Some(step);
+        self.live_rate = Some(Arc::new(LiveRate::new(step)));
+        self
+    }
+
+    /// The rate changed by `--signal-rate`, if enabled.
+    pub fn live_rate(&self) -> Option<Arc<LiveRate>> {
+        self.live_rate.clone()
+    }
+
+    /// Limit the packets per second all targets together send to `rate`, or lift the limit
+    /// with `None`. Defaults to [`DEFAULT_MAX_TOTAL_RATE`], fails unless the rate is finite and
+    /// positive.
+    pub fn set_max_total_rate(&mut self, rate: Option<f64>) -> Result<&mut Self> {
+        self.rate_cap = rate.map(RateCap::new).transpose()?.map(Arc::new);
+        self.max_total_rate = rate;
+        Ok(self)
+    }
+
+    /// Raise the soft limit of open files up to the hard limit if the run needs more, on by
+    /// default.
+    pub fn set_rlimit_bump(&mut self, bump: bool) -> &mut Self {
+        self.rlimit_bump = bump;
+        self
+    }
+
+    /// Flag the first `count` sequences of every target as warmup, keeping them out of the
+    /// summary.
+    pub fn set_warmup(&mut self, count: usize) -> &mut Self {
+        self.warmup = count;
+        self
+    }
+
+    /// Leave the warmup sequences out of the written and the `--ndjson` results as well, instead
+    /// of flagging them.
+    pub fn set_exclude_warmup(&mut self, exclude: bool) -> &mut Self {
+        self.exclude_warmup = exclude;
+        self
+    }
+
+    /// Wait `interval` between two sends to the same target.
+    pub fn set_interval(&mut self, interval: std::time::Duration) -> &mut Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Send from `address` instead of letting the routing table pick the source.
+    pub fn set_bind_address(&mut self, address: IpAddr) -> &mut Self {
+        self.bind_address = Some(address);
+        self
+    }
+
+    /// Send to every target from one of `addresses`, taking turns in target order.
+    pub fn set_source_addresses(&mut self, addresses: Vec<IpAddr>) -> &mut Self {
+        self.source_addresses = Some(addresses);
+        self
+    }
+
+    /// Source address of the target at `index`: its turn of `--source-pool`, or
+    /// `--bind-address`.
+    fn source_address(&self, index: usize) -> Option<IpAddr> {
+        match &self.source_addresses {
+            Some(pool) if !pool.is_empty() => Some(pool[index % pool.len()]),
+            _ => self.bind_address,
         }
+    }
 
-        // TODO: error handling
-        let results = Arc::try_unwrap(results).unwrap();
-        let results = results.finish().await;
+    /// Use the compact packet format with 32 bit identifier and sequence.
+    pub fn set_compact(&mut self, compact: bool) -> &mut Self {
+        self.compact = compact;
+        self
+    }
 
-        let num_failed = JsonResults::count_failed(&results);
-        info!(target: self.namespace.as_str(), "{} requests failed", num_failed);
+    /// Run every target on its own thread, pinned round-robin to `cpus`.
+    ///
+    /// This takes the targets out of the async-std thread pool, so its work-stealing can't move
+    /// them between CPUs. The sender and receiver of a target share the thread and the CPU.
+    pub fn set_pin(&mut self, cpus: Vec<usize>) -> &mut Self {
+        self.pin = Some(cpus);
+        self
+    }
 
-        if let Some(output) = &self.output {
-            let mut file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(output)
-                .context("Failed to open output file")?;
-            serde_json::to_writer_pretty(&mut file, &results).context("Failed to write json")?;
+    /// Record which CPU received each echo, to analyze the spread over NIC queues.
+    pub fn set_record_cpu(&mut self, record: bool) -> &mut Self {
+        self.record_cpu = record;
+        self
+    }
+
+    /// Time every UDP send call, to tell a saturated client from a slow network.
+    pub fn set_send_latency(&mut self, record: bool) -> &mut Self {
+        self.send_latency = record;
+        self
+    }
+
+    /// Read ICMP errors from the socket error queue and report them per sample.
+    pub fn set_recverr(&mut self, recverr: bool) -> &mut Self {
+        self.recverr = recverr;
+        self
+    }
+
+    /// Receive the echoes on a socket of their own instead of the one sending.
+    ///
+    /// The receive socket binds the port of the sending socket with `SO_REUSEPORT` and is
+    /// connected to the target, which makes the kernel prefer it for the replies. Replies from
+    /// any other address than the resolved target still go to the sending socket, where nobody
+    /// reads them, so this does not work with servers answering from a different address.
+    pub fn set_separate_recv(&mut self, separate: bool) -> &mut Self {
+        self.separate_recv = separate;
+        self
+    }
+
+    /// Ask the server to stamp its receive and send time into the echo, to split the RTT into
+    /// forward delay, server time and return delay. The split assumes synchronized clocks.
+    pub fn set_server_timestamps(&mut self, stamps: bool) -> &mut Self {
+        self.server_timestamps = stamps;
+        self
+    }
+
+    /// Before the run, send `count` unrecorded packets to every target and wait up to `wait`
+    /// for an echo, so the first timed packets don't pay for neighbor resolution.
+    pub fn set_preload(&mut self, count: usize, wait: std::time::Duration) -> &mut Self {
+        self.preload = Some((count, wait));
+        self
+    }
+
+    /// Set `TCP_NODELAY` on the TCP connections, on by default so Nagle's algorithm doesn't hold
+    /// back the small echo frames.
+    pub fn set_tcp_nodelay(&mut self, nodelay: bool) -> &mut Self {
+        self.tcp_nodelay = nodelay;
+        self
+    }
+
+    /// Stop the whole run at the first echo whose payload differs from the one sent, and fail
+    /// it after writing the partial report.
+    pub fn set_fail_fast_corruption(&mut self, fail_fast: bool) -> &mut Self {
+        self.fail_fast_corruption = fail_fast;
+        self
+    }
+
+    /// Receive UDP echoes into a buffer of `size` bytes per receiver instead of one that fits any
+    /// datagram. Echoes that fill it are counted as truncated.
+    pub fn set_recv_buffer(&mut self, size: usize) -> &mut Self {
+        self.recv_buffer = Some(size);
+        self
+    }
+
+    /// Keep `count` receives outstanding on every target's socket.
+    pub fn set_recv_concurrency(&mut self, count: usize) -> &mut Self {
+        self.recv_concurrency = count;
+        self
+    }
+
+    /// Rotate the packets to every target over `pool` sockets with their own source port, so
+    /// they take different paths through flow-hashing load balancers.
+    pub fn set_source_randomize(&mut self, pool: usize) -> &mut Self {
+        self.source_pool = Some(pool);
+        self
+    }
+
+    /// Only accept literal `IP:PORT` targets, so nothing needs to be resolved.
+    pub fn set_no_dns(&mut self, no_dns: bool) -> &mut Self {
+        self.no_dns = no_dns;
+        self
+    }
+
+    /// Mark packets with the ECN `codepoint` and ask the server to reflect what it received.
+    pub fn set_ecn(&mut self, codepoint: u8) -> &mut Self {
+        self.ecn = Some(codepoint);
+        self
+    }
+
+    /// Mark packets with the whole `tos` byte (DSCP and ECN) and report per target whether the
+    /// server saw it unchanged.
+    pub fn set_tos_verify(&mut self, tos: u8) -> &mut Self {
+        self.tos_verify = Some(tos);
+        self
+    }
+
+    /// Include the configuration in the written report.
+    pub fn set_embed_config(&mut self, embed: bool) -> &mut Self {
+        self.embed_config = embed;
+        self
+    }
+
+    /// Send enough packets to every target to transfer `bytes`, instead of `tries` packets.
+    pub fn set_bytes(&mut self, bytes: usize) -> &mut Self {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    /// Echo all tries of a target over one TCP connection, writing `depth` frames at a time
+    /// before reading their echoes.
+    pub fn set_tcp_pipeline(&mut self, depth: usize) -> &mut Self {
+        self.tcp_pipeline = Some(depth);
+        self
+    }
+
+    /// Ask the server of every UDP target to probe the client back as often and as fast as the
+    /// client probes it, and reflect those probes. The probes that arrive give the loss of the
+    /// return path alone, the server logs the round trips of their reflections.
+    pub fn set_two_way(&mut self, two_way: bool) -> &mut Self {
+        self.two_way = two_way;
+        self
+    }
+
+    /// Size of every datagram sent, as given by the packet format.
+    fn datagram_size(&self) -> usize {
+        if self.compact {
+            UdpEchoCompactPacket::minimum_packet_size()
+        } else if self.server_timestamps {
+            UdpEchoPacket::minimum_packet_size() + TIMESTAMPS_LEN
         } else {
-            println!(
-                "{}",
-                serde_json::to_string_pretty(&results).context("Failed to create json")?
-            );
+            self.sweep_size.unwrap_or(ECHO_SIZE)
         }
+    }
 
-        Ok(())
+    /// Packets to send per target, derived from `bytes` if set.
+    fn tries(&self) -> Result<usize> {
+        if let Some(trace) = &self.replay {
+            return Ok(self.tries.min(trace.len()));
+        }
+        match self.bytes {
+            Some(bytes) => Ok(bytes.div_ceil(self.datagram_size())),
+            None => Ok(self.tries),
+        }
     }
 
-    async fn run_udp_target(
-        target: &str,
-        tries: usize,
-        identifier: u64,
-        results: Arc<Results<'_>>,
-        namespace: &str,
-    ) -> Result<()> {
-        let address = [
-            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
-            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
-        ];
+    /// Write the output json without pretty printing.
+    pub fn set_json_compact(&mut self, compact: bool) -> &mut Self {
+        self.json_compact = compact;
+        self
+    }
 
-        let socket = Arc::new(UdpSocket::bind(address.as_ref()).await?);
+    /// Repeat the benchmark until the loss of a run exceeds `threshold` (a fraction).
+    pub fn set_repeat_until_failure(&mut self, threshold: f64) -> &mut Self {
+        self.repeat_until_loss = Some(threshold);
+        self
+    }
 
-        let mut counter = tries;
-        let read_half = socket.clone();
-        let write_results = results.clone();
-        let receiver = async move {
-            loop {
-                let mut buf = [0u8; 1500];
-                read_half.recv(&mut buf).await;
-                trace!(target: namespace, "got packet");
+    /// Give up repeating after `iterations` runs.
+    pub fn set_max_iterations(&mut self, iterations: usize) -> &mut Self {
+        self.max_iterations = Some(iterations);
+        self
+    }
 
-                let udp = UdpEchoPacket::new(&buf).unwrap();
-                if identifier != udp.get_identifier() {
-                    warn!(target: namespace, "invalid identifier in response");
-                    continue;
-                }
+    /// Search for the highest rate that stays within a loss target instead of running once.
+    pub fn set_rate_search(&mut self, search: RateSearch) -> &mut Self {
+        self.rate_search = Some(search);
+        self
+    }
 
-                let seq = udp.get_sequence();
-                if let Err(e) = write_results.recv_packet(identifier, seq).await {
-                    info!(target: namespace, "failed to store result: {:?}", e);
-                }
-                counter -= 1;
-                if counter == 0 {
-                    break;
-                }
-            }
-        };
+    /// Run the probes once for every datagram size in `sizes` instead of once, reporting the RTT
+    /// per size.
+    pub fn set_size_sweep(&mut self, sizes: Vec<usize>) -> &mut Self {
+        self.size_sweep = Some(sizes);
+        self
+    }
 
-        let work = async move {
-            for x in 0..tries {
-                let payload = UdpEcho::new(identifier, x as u64);
-                let mut buf = [0u8; 18];
-                let mut echo = MutableUdpEchoPacket::new(&mut buf).unwrap();
-                echo.populate(&payload);
+    /// Run the probes twice, idle and next to a load stream to every target, instead of once.
+    pub fn set_load(&mut self, load: LoadParams) -> &mut Self {
+        self.load = Some(load);
+        self
+    }
 
-                socket.send_to(&buf, target).await;
-                results.start_packet(identifier, x as u64).await;
-                trace!(target: namespace, "send packet {}:{}", identifier, x);
+    /// Rewrite the output file with the results settled so far every `interval` during the run.
+    pub fn set_flush_interval(&mut self, interval: std::time::Duration) -> &mut Self {
+        self.flush_interval = Some(interval);
+        self
+    }
+
+    /// Attach `value` under `key` to the report, failing if the key is already taken.
+    pub fn add_tag(&mut self, key: String, value: String) -> Result<&mut Self> {
+        if self.tags.contains_key(&key) {
+            bail!("Tag '{}' is given more than once", key);
+        }
+        self.tags.insert(key, value);
+        Ok(self)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("Failed to create json")
+    }
+
+    /// The configuration as a [`Bundle`] that [`Config::from_bundle`] turns back into an
+    /// identical run.
+    pub fn to_bundle(&self) -> Result<String> {
+        serde_json::to_string_pretty(&Bundle::new(self)).context("Failed to create json")
+    }
+
+    /// Read back a bundle written by [`Config::to_bundle`], failing if it was written for a
+    /// different schema version.
+    pub fn from_bundle(json: &str) -> Result<Self> {
+        let bundle: Bundle<Self> = serde_json::from_str(json).context("Failed to parse bundle")?;
+        let mut config = bundle.into_config()?;
+        if let Some(step) = config.rate_step {
+            config.set_rate_step(step);
+        }
+        config.set_max_total_rate(config.max_total_rate)?;
+        Ok(config)
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        self.check_output()?;
+        self.check_targets()?;
+        self.check_open_files()?;
+
+        if self.ndjson
+            && (self.mtu_probe.is_some()
+                || self.trace.is_some()
+                || self.repeat_until_loss.is_some()
+                || self.rate_search.is_some()
+                || self.load.is_some()
+                || self.size_sweep.is_some())
+        {
+            bail!("--ndjson only streams the results of a single run");
+        }
+
+        if self.timestamps_output.is_some() {
+            if self.mtu_probe.is_some()
+                || self.trace.is_some()
+                || self.repeat_until_loss.is_some()
+                || self.rate_search.is_some()
+                || self.load.is_some()
+                || self.size_sweep.is_some()
+            {
+                bail!("--output-timestamps-only only records a single run");
             }
-        };
+            if self.output.is_some()
+                || self.output_dir.is_some()
+                || self.report_to.is_some()
+                || self.ndjson
+            {
+                bail!("--output-timestamps-only replaces the report, it can't be written as well");
+            }
+        }
 
-        work.join(receiver).await;
+        if self.max_memory.is_some()
+            && (self.mtu_probe.is_some()
+                || self.trace.is_some()
+                || self.repeat_until_loss.is_some()
+                || self.rate_search.is_some()
+                || self.load.is_some()
+                || self.size_sweep.is_some()
+                || self.timestamps_output.is_some()
+                || self.replay.is_some())
+        {
+            // the other modes read every sample of their runs
+            bail!("--max-runtime-memory only applies to the report of a single run");
+        }
 
-        Ok(())
+        if let Some(ceiling) = self.mtu_probe {
+            let results = self.run_mtu_probe(ceiling).await?;
+            return self.write_output(&results);
+        }
+
+        if let Some(max_ttl) = self.trace {
+            let results = self.run_trace(max_ttl).await?;
+            return self.write_output(&results);
+        }
+
+        if let Some(threshold) = self.repeat_until_loss {
+            return self.run_until_failure(threshold).await;
+        }
+
+        if let Some(search) = self.rate_search.clone() {
+            let result = self.run_rate_search(&search).await?;
+            return self.write_output(&result);
+        }
+
+        if let Some(load) = self.load.clone() {
+            let result = self.run_under_load(&load).await?;
+            return self.write_output(&result);
+        }
+
+        if let Some(sizes) = self.size_sweep.clone() {
+            let result = self.run_size_sweep(&sizes).await?;
+            return self.write_output(&result);
+        }
+
+        let start = std::time::Instant::now();
+        let results = self.run_collect().await?;
+        let duration = start.elapsed();
+        if let Some(path) = &self.timestamps_output {
+            return self.write_timestamps(path, &results);
+        }
+        if let Some(dir) = &self.output_dir {
+            self.write_output_dir(dir, &results)?;
+        }
+        let mut report = self.report(results);
+        if self.bytes.is_some() || self.tcp_pipeline.is_some() {
+            let summarized: usize = report
+                .targets
+                .iter()
+                .filter(|s| s.summary_only)
+                .map(|s| s.succeeded)
+                .sum();
+            report.goodput = Some(Goodput::new(
+                (JsonResults::count_succeeded(&report.results) + summarized) * self.datagram_size(),
+                duration,
+            ));
+        }
+        if let Some(tos) = self.tos_verify {
+            report.check_tos(tos);
+        }
+        for reverse in &report.reverse {
+            if reverse.received == 0 {
+                warn!(
+                    target: self.namespace.as_str(),
+                    "{}: no reverse probes arrived, is the server running with --reverse-probes?",
+                    reverse.target
+                );
+            }
+        }
+        if let Some(requested) = report.requested_rate {
+            for summary in &report.targets {
+                match summary.send_rate {
+                    Some(rate) if rate < requested * SLOW_PACING => warn!(
+                        target: self.namespace.as_str(),
+                        "{}: sent {:.1} of the requested {:.1} packets per second{}",
+                        summary.target,
+                        rate,
+                        requested,
+                        if self.busy_poll.is_none() {
+                            ", try --busy-poll-threshold"
+                        } else {
+                            ""
+                        }
+                    ),
+                    _ => (),
+                }
+            }
+        }
+        let breaches = match self.max_rtt {
+            Some(limit) => report
+                .check_max_rtt(limit)
+                .iter()
+                .map(|s| format!("{} by {:?}", s.target, s.rtt_breach.unwrap_or_default()))
+                .collect(),
+            None => Vec::new(),
+        };
+        let reordered: Vec<String> = report
+            .targets
+            .iter()
+            .filter(|s| {
+                self.strict_order && !s.out_of_order.is_empty() && self.expects_order(&s.target)
+            })
+            .map(|s| {
+                format!(
+                    "{}: {} out of order ({:?})",
+                    s.target,
+                    s.out_of_order.len(),
+                    s.out_of_order
+                )
+            })
+            .collect();
+        if self.embed_config {
+            report.config = Some(self);
+        }
+        self.write_output(&report)?;
+
+        if let Some((target, sequence)) = self.corrupted.lock().unwrap().take() {
+            bail!("Corrupt echo from {} at sequence {}", target, sequence);
+        }
+        if !breaches.is_empty() {
+            bail!("p99 RTT exceeded the limit: {}", breaches.join(", "));
+        }
+        if !reordered.is_empty() {
+            bail!("Echoes arrived out of order: {}", reordered.join(", "));
+        }
+        Ok(())
+    }
+
+    fn report<'a>(&self, results: Vec<JsonResults>) -> Report<'a> {
+        let mut report = Report::new(results);
+        report
+            .targets
+            .extend(self.summaries.lock().unwrap().iter().cloned());
+        report.targets.sort_by_key(|s| s.identifier);
+        report.tags = self.tags.clone();
+        if self.tcp {
+            report.tcp_nodelay = Some(self.tcp_nodelay);
+        }
+        if let Some(live) = &self.live_rate {
+            report.rate_timeline = live.timeline();
+        }
+        if self.exclude_warmup {
+            report.results.retain(|r| !r.warmup);
+        }
+        report.requested_rate = self.requested_rate();
+        report.on_timeout = self.timeout.map(|_| {
+            if self.drain_remaining {
+                OnTimeout::Drain
+            } else {
+                OnTimeout::Stop
+            }
+        });
+        report.reverse = self
+            .reverse
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(target, &(requested, received))| {
+                ReverseProbes::new(target.clone(), requested, received)
+            })
+            .collect();
+        let aborted = self.aborted.lock().unwrap();
+        for summary in &mut report.targets {
+            summary.aborted = aborted.get(&summary.target).copied();
+        }
+        drop(aborted);
+        report.replay = self.replay.as_ref().map(|trace| {
+            ReplaySummary::new(trace.len(), self.tries.min(trace.len()), &report.results)
+        });
+        report.server_params = self
+            .server_params
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(target, &params)| ServerParams::new(target.clone(), params))
+            .collect();
+        report
+    }
+
+    /// Echo modes `target` answered to `--query-server`, the plain echo otherwise.
+    fn echo_params(&self, target: &str) -> EchoParams {
+        self.server_params
+            .lock()
+            .unwrap()
+            .get(target)
+            .copied()
+            .flatten()
+            .unwrap_or_default()
+    }
+
+    /// Whether echoes of `target` should come back in order, see [`Config::set_query_server`].
+    fn expects_order(&self, target: &str) -> bool {
+        let params = self.echo_params(target);
+        !params.reorders && !params.delays
+    }
+
+    /// Whether echoes of `target` should come back unchanged, see [`Config::set_query_server`].
+    fn expects_intact(&self, target: &str) -> bool {
+        let params = self.echo_params(target);
+        params.corrupt_rate == 0.0 && !params.resizes
+    }
+
+    /// Repeat the benchmark until a run loses more than `threshold` of its packets, and write
+    /// the report of that run.
+    async fn run_until_failure(&self, threshold: f64) -> Result<()> {
+        let namespace = self.namespace.as_str();
+        let mut iteration = 0;
+        loop {
+            iteration += 1;
+            let results = self.run_collect().await?;
+            let loss = JsonResults::loss(&results);
+            info!(
+                target: namespace,
+                "iteration {}: {:.2}% loss",
+                iteration,
+                loss * 100.0
+            );
+
+            let mut report = self.report(results);
+            let breached = match self.max_rtt {
+                Some(limit) => !report.check_max_rtt(limit).is_empty(),
+                None => false,
+            };
+
+            if loss > threshold || breached {
+                if self.embed_config {
+                    report.config = Some(self);
+                }
+                self.write_output(&report)?;
+                if breached {
+                    bail!("Iteration {} exceeded the p99 RTT limit", iteration);
+                }
+                bail!(
+                    "Iteration {} lost {:.2}% of packets, exceeding {:.2}%",
+                    iteration,
+                    loss * 100.0,
+                    threshold * 100.0
+                );
+            }
+
+            if self.max_iterations == Some(iteration) {
+                info!(
+                    target: namespace,
+                    "no failure after {} iterations", iteration
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    /// Run short phases at changing rates to find the highest rate whose loss stays within
+    /// `params.loss_target`.
+    pub async fn run_rate_search(&mut self, params: &RateSearch) -> Result<RateSearchResult> {
+        if self.bytes.is_some() || self.poisson.is_some() || self.duration.is_some() {
+            bail!("The rate search sets rate and count itself, it conflicts with --bytes, --poisson and --duration");
+        }
+        if !(params.step.is_finite() && params.step > 0.0) {
+            bail!("Rate step must be positive, got {}", params.step);
+        }
+
+        let (tries, interval) = (self.tries, self.interval);
+        let mut search = Search::new(params);
+        let mut phases = Vec::new();
+        for _ in 0..params.max_phases {
+            let rate = search.rate();
+            self.tries = ((rate * params.phase.as_secs_f64()).ceil() as usize).max(1);
+            self.interval = Some(std::time::Duration::from_secs_f64(1.0 / rate));
+
+            let results = self.run_collect().await?;
+            let phase = RatePhase {
+                rate,
+                sent: JsonResults::count_succeeded(&results) + JsonResults::count_failed(&results),
+                loss: JsonResults::loss(&results),
+                mean_rtt: JsonResults::mean_rtt(&results),
+                p99_rtt: JsonResults::rtt_percentile(&results, 99.0),
+            };
+            info!(
+                target: self.namespace.as_str(),
+                "phase {}: {:.1} packets per second, {:.2}% loss",
+                phases.len() + 1,
+                rate,
+                phase.loss * 100.0
+            );
+            let loss = phase.loss;
+            phases.push(phase);
+
+            if !search.record(loss) || self.exit.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+        self.tries = tries;
+        self.interval = interval;
+
+        Ok(RateSearchResult {
+            loss_target: params.loss_target,
+            max_rate: search.max_rate(),
+            phases,
+        })
+    }
+
+    /// Run the probes once per datagram size in `sizes`, reporting the RTT at every size.
+    pub async fn run_size_sweep(&mut self, sizes: &[usize]) -> Result<SizeSweepResult> {
+        if self.tcp || self.compact || self.server_timestamps || self.tos_verify.is_some() {
+            bail!("--probe-size-sweep needs the plain UDP packet format");
+        }
+        if self.ecn.is_some() || self.bytes.is_some() {
+            bail!("--probe-size-sweep conflicts with --ecn and --bytes");
+        }
+        if let Some(&size) = sizes.iter().find(|&&size| size < ECHO_SIZE) {
+            bail!(
+                "Sweep size {} is smaller than a {} byte echo",
+                size,
+                ECHO_SIZE
+            );
+        }
+
+        let mut curve = Vec::new();
+        for &size in sizes {
+            self.sweep_size = Some(size);
+            let results = self.run_collect().await;
+            self.sweep_size = None;
+            let results = results?;
+
+            let point = SizePoint {
+                size,
+                sent: JsonResults::count_succeeded(&results) + JsonResults::count_failed(&results),
+                loss: JsonResults::loss(&results),
+                mean_rtt: JsonResults::mean_rtt(&results),
+                p50_rtt: JsonResults::rtt_percentile(&results, 50.0),
+                p99_rtt: JsonResults::rtt_percentile(&results, 99.0),
+            };
+            info!(
+                target: self.namespace.as_str(),
+                "{} bytes: mean rtt {:?}, p99 rtt {:?}",
+                size,
+                point.mean_rtt,
+                point.p99_rtt
+            );
+            curve.push(point);
+
+            if self.exit.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+
+        Ok(SizeSweepResult { curve })
+    }
+
+    /// Measure the probe latency to every target idle, then again while a load stream of
+    /// `params` runs next to the probes.
+    pub async fn run_under_load(&self, params: &LoadParams) -> Result<LoadResult> {
+        params.check()?;
+        if self.tcp {
+            bail!("--under-load only supports UDP");
+        }
+        if self.rate_search.is_some() || self.repeat_until_loss.is_some() {
+            bail!("--under-load conflicts with --count-per-second and --repeat-until-failure");
+        }
+        let namespace = self.namespace.as_str();
+
+        let idle = self.run_collect().await?;
+
+        let mut sockets = Vec::new();
+        for index in 0..self.addresses.len() {
+            sockets.push(self.bind_udp(index).await?);
+        }
+        let stop = AtomicBool::new(false);
+        let loads = self
+            .addresses
+            .iter()
+            .zip(&sockets)
+            .map(|(address, socket)| load::load(socket, address, params, &stop));
+        let probes = async {
+            // let the queues along the path fill up before measuring
+            async_std::task::sleep(LOAD_RAMP).await;
+            let results = self.run_collect().await;
+            stop.store(true, Ordering::Relaxed);
+            results
+        };
+        let (loaded, loads) = probes.join(futures::future::try_join_all(loads)).await;
+        let loaded = loaded?;
+
+        let mut targets = Vec::new();
+        for (address, load) in self.addresses.iter().zip(loads?) {
+            let idle = probe_stats(&idle, address);
+            let loaded = probe_stats(&loaded, address);
+            info!(
+                target: namespace,
+                "{}: mean rtt {:?} idle, {:?} under load, {:.1} Mbit/s echoed",
+                address,
+                idle.mean_rtt,
+                loaded.mean_rtt,
+                load.throughput
+            );
+            targets.push(TargetUnderLoad {
+                target: address.clone(),
+                idle,
+                loaded,
+                load,
+            });
+        }
+
+        Ok(LoadResult {
+            load: params.clone(),
+            targets,
+        })
+    }
+
+    /// Reject a run without targets and, with `--no-dns`, every target that is not a literal
+    /// socket address.
+    fn check_targets(&self) -> Result<()> {
+        if self.addresses.is_empty() {
+            bail!("No targets given, pass at least one address[*weight] argument");
+        }
+        if !self.no_dns {
+            return Ok(());
+        }
+
+        let rejected: Vec<&str> = self
+            .addresses
+            .iter()
+            .filter(|address| address.parse::<SocketAddr>().is_err())
+            .map(|address| address.as_str())
+            .collect();
+        if !rejected.is_empty() {
+            bail!(
+                "Targets are not literal IP:PORT addresses: {}",
+                rejected.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// File descriptors the run holds open at most: the sockets of every target, the output
+    /// and some headroom for stdio and the runtime.
+    fn required_fds(&self) -> u64 {
+        let sockets = if self.tcp {
+            1
+        } else {
+            let pool = self.source_pool.unwrap_or(1).max(1) as u64;
+            pool * if self.separate_recv { 2 } else { 1 }
+        };
+        let load = self.load.is_some() as u64;
+        let files = self.output.is_some() as u64
+            + self.output_dir.is_some() as u64
+            + self.report_to.is_some() as u64
+            + self.timestamps_output.is_some() as u64;
+        self.addresses.len() as u64 * (sockets + load) + files + RESERVED_FDS
+    }
+
+    /// Make sure the run won't run out of file descriptors halfway, raising the soft limit if
+    /// allowed.
+    fn check_open_files(&self) -> Result<()> {
+        let needed = self.required_fds();
+        let (soft, hard) = socket::nofile_limit().context("Failed to read the open file limit")?;
+        if needed <= soft {
+            return Ok(());
+        }
+        if self.rlimit_bump && needed <= hard {
+            socket::set_nofile_limit(needed, hard)
+                .context("Failed to raise the open file limit")?;
+            info!(
+                target: self.namespace.as_str(),
+                "raised the open file limit from {} to {}", soft, needed
+            );
+            return Ok(());
+        }
+        bail!(
+            "The run needs about {} open files, but the limit is {} (hard limit {}), raise it with `ulimit -n {}`",
+            needed,
+            soft,
+            hard,
+            needed
+        );
+    }
+
+    /// Make sure the output file can be written before spending time on the benchmark, without
+    /// creating anything a run that fails its validation would leave behind.
+    fn check_output(&self) -> Result<()> {
+        if let Some(output) = &self.output {
+            check_writable(std::path::Path::new(output))
+                .with_context(|| format!("Output file {} is not writable", output))?;
+        }
+
+        if let Some(path) = &self.timestamps_output {
+            check_writable(std::path::Path::new(path))
+                .with_context(|| format!("Timestamps file {} is not writable", path))?;
+        }
+
+        if let Some(dir) = &self.output_dir {
+            // the directory is created along with the files, see `write_output_dir`
+            let path = std::path::Path::new(dir);
+            let existing = path
+                .ancestors()
+                .find(|path| path.exists())
+                .unwrap_or_else(|| std::path::Path::new("."));
+            check_directory(existing)
+                .with_context(|| format!("Failed to create output directory {}", dir))?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the results of every target to `dir/<target>.json`.
+    fn write_output_dir(&self, dir: &str, results: &[JsonResults]) -> Result<()> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create output directory {}", dir))?;
+        for address in &self.addresses {
+            let own: Vec<&JsonResults> = results
+                .iter()
+                .filter(|r| &r.target == address && !(self.exclude_warmup && r.warmup))
+                .collect();
+            let path =
+                std::path::Path::new(dir).join(format!("{}.json", sanitize_filename(address)));
+            let file = std::fs::File::create(&path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            if self.json_compact {
+                serde_json::to_writer(file, &own)
+            } else {
+                serde_json::to_writer_pretty(file, &own)
+            }
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the timestamps of every sent packet to `path`, see [`Config::set_timestamps_only`].
+    fn write_timestamps(&self, path: &str, results: &[JsonResults]) -> Result<()> {
+        let mut sent: Vec<&JsonResults> = results
+            .iter()
+            .filter(|r| r.sent_wall_ns.is_some() && !(self.exclude_warmup && r.warmup))
+            .collect();
+        sent.sort_unstable_by_key(|r| (r.identifier, r.sequence));
+
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create timestamps file {}", path))?;
+        write_timestamps_csv(std::io::BufWriter::new(file), &sent)
+            .with_context(|| format!("Failed to write timestamps file {}", path))
+    }
+
+    fn write_output<T: serde::Serialize>(&self, results: &T) -> Result<()> {
+        if let Some(address) = &self.report_to {
+            let json = self.output_json(results)?;
+            if let Err(e) = send_report(address, &json) {
+                error!(
+                    target: self.namespace.as_str(),
+                    "failed to send report to {}: {:#}, printing results to stdout", address, e
+                );
+                println!("{}", json);
+                return Err(e).context("Results were printed to stdout instead");
+            }
+        } else if let Some(output) = &self.output {
+            let written = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(output)
+                .context("Failed to open output file")
+                .and_then(|mut file| {
+                    if self.json_compact {
+                        serde_json::to_writer(&mut file, results)
+                    } else {
+                        serde_json::to_writer_pretty(&mut file, results)
+                    }
+                    .context("Failed to write json")
+                });
+
+            if let Err(e) = written {
+                // don't lose the results of a possibly long run
+                error!(
+                    target: self.namespace.as_str(),
+                    "failed to write {}: {:#}, printing results to stdout", output, e
+                );
+                println!("{}", self.output_json(results)?);
+                return Err(e).context("Results were printed to stdout instead");
+            }
+        } else if !self.ndjson {
+            println!("{}", self.output_json(results)?);
+        }
+
+        Ok(())
+    }
+
+    fn output_json<T: serde::Serialize>(&self, results: &T) -> Result<String> {
+        if self.json_compact {
+            serde_json::to_string(results)
+        } else {
+            serde_json::to_string_pretty(results)
+        }
+        .context("Failed to create json")
+    }
+
+    /// Search the path MTU to every target, reporting the largest IP packet that got echoed.
+    pub async fn run_mtu_probe(&self, ceiling: usize) -> Result<Vec<MtuResult<'_>>> {
+        let probes = self.addresses.iter().zip(0..).map(|(address, identifier)| {
+            mtu::probe(
+                address,
+                identifier,
+                ceiling,
+                self.fragmentation,
+                self.namespace.as_str(),
+            )
+        });
+
+        let results = futures::future::try_join_all(probes).await?;
+        for result in &results {
+            info!(target: self.namespace.as_str(), "{}: mtu {:?}", result.target, result.mtu);
+            if let Some(reassembly) = &result.reassembly {
+                info!(
+                    target: self.namespace.as_str(),
+                    "{}: largest fragmented {:?}",
+                    result.target,
+                    reassembly.largest
+                );
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Trace the path to every target, one hop per TTL.
+    pub async fn run_trace(&self, max_ttl: u8) -> Result<Vec<TraceResult<'_>>> {
+        let traces = self.addresses.iter().zip(0..).map(|(address, identifier)| {
+            trace::trace(address, identifier, max_ttl, self.namespace.as_str())
+        });
+
+        let results = futures::future::try_join_all(traces).await?;
+        for result in &results {
+            info!(
+                target: self.namespace.as_str(),
+                "{}: {} hops, reached: {}",
+                result.target,
+                result.hops.len(),
+                result.reached
+            );
+        }
+
+        Ok(results)
+    }
+
+    /// Run the benchmark and return the results instead of writing them out.
+    pub async fn run_collect(&self) -> Result<Vec<JsonResults>> {
+        self.check_targets()?;
+        let per_target = self.tries()?;
+        let tries = match &self.weights {
+            Some(weights) => {
+                if weights.len() != self.addresses.len() {
+                    bail!(
+                        "Got {} weights for {} targets",
+                        weights.len(),
+                        self.addresses.len()
+                    );
+                }
+                let total = per_target
+                    .checked_mul(self.addresses.len())
+                    .context("Total number of tries is too large")?;
+                distribute(total, weights)?
+            }
+            None => vec![per_target; self.addresses.len()],
+        };
+
+        if let Some(pool) = &self.source_addresses {
+            if self.tcp {
+                bail!("--source-pool only applies to UDP");
+            }
+            if self.bind_address.is_some() {
+                bail!("--source-pool and --bind-address are mutually exclusive");
+            }
+            if pool.is_empty() {
+                bail!("--source-pool needs at least one address");
+            }
+            for &source in pool {
+                if source.is_unspecified() || source.is_multicast() {
+                    bail!("Source {} can't be sent from", source);
+                }
+                std::net::UdpSocket::bind(SocketAddr::new(source, 0))
+                    .with_context(|| format!("Source {} is not an address of this host", source))?;
+            }
+        }
+
+        for (index, address) in self.addresses.iter().enumerate() {
+            let source = match self.source_address(index) {
+                Some(source) => source,
+                None => continue,
+            };
+            let resolved: Vec<SocketAddr> = address
+                .to_socket_addrs()
+                .await
+                .with_context(|| format!("Failed to resolve '{}'", address))?
+                .collect();
+            if !resolved.iter().any(|a| a.is_ipv6() == source.is_ipv6()) {
+                bail!(
+                    "'{}' has no address of the same family as source address {}",
+                    address,
+                    source
+                );
+            }
+        }
+
+        if !self.tcp && (self.connect_timeout.is_some() || self.read_timeout.is_some()) {
+            bail!("Connect and read timeouts only apply to TCP");
+        }
+        if self.tcp && self.recverr {
+            bail!("--recverr only applies to UDP");
+        }
+        if self.tcp && self.separate_recv {
+            bail!("--separate-recv-socket only applies to UDP");
+        }
+        if self.server_timestamps {
+            if self.tcp || self.compact {
+                bail!("--server-timestamp needs the default UDP packet format");
+            }
+            if self.ecn.is_some() || self.tos_verify.is_some() {
+                bail!("--server-timestamp can't be combined with TOS reflection");
+            }
+        }
+        if self.fail_fast_corruption
+            && (self.compact
+                || self.server_timestamps
+                || self.ecn.is_some()
+                || self.tos_verify.is_some())
+        {
+            bail!("--fail-fast-corruption needs the default packet format without reflection");
+        }
+        if let Some(size) = self.recv_buffer {
+            if self.tcp {
+                bail!("--recv-buffer only applies to UDP");
+            }
+            if size < self.datagram_size() {
+                bail!(
+                    "Receive buffer of {} bytes can't hold the {} byte echo",
+                    size,
+                    self.datagram_size()
+                );
+            }
+        }
+        if self.tcp && self.source_pool.is_some() {
+            bail!("--source-randomize conflicts with TCP, whose connections fix the source port");
+        }
+        if self.drain_remaining {
+            if self.timeout.is_none() {
+                bail!("--drain-remaining-budget needs a timeout to act on");
+            }
+            if self.pin.is_some() {
+                bail!("--drain-remaining-budget doesn't work with --pin");
+            }
+        }
+        if self.two_way {
+            if self.tcp || self.compact {
+                bail!("--two-way needs the default UDP packet format");
+            }
+            if self.interval.is_none_or(|interval| interval.is_zero()) {
+                bail!("--two-way probes back at the rate of --interval, which must be positive");
+            }
+        }
+        if let Some(depth) = self.tcp_pipeline {
+            if !self.tcp {
+                bail!("--tcp-pipeline only applies to TCP");
+            }
+            if depth == 0 || depth > MAX_TCP_PIPELINE {
+                bail!(
+                    "--tcp-pipeline takes 1 to {} frames, got {}",
+                    MAX_TCP_PIPELINE,
+                    depth
+                );
+            }
+        }
+        if self.tcp
+            && (self.compact
+                || self.ecn.is_some()
+                || self.tos_verify.is_some()
+                || self.record_cpu
+                || self.send_latency)
+        {
+            bail!(
+                "Compact packets, ECN, CPU and send latency recording are only supported over UDP"
+            );
+        }
+
+        if self.report_to.is_some() && self.output.is_some() {
+            bail!("--report-to conflicts with --output");
+        }
+
+        if self.flush_interval.is_some() {
+            if self.output.is_none() && self.report_to.is_none() {
+                bail!("--flush-interval needs an output file or --report-to");
+            }
+            if self.pin.is_some() {
+                bail!("--flush-interval is not supported with pinned workers");
+            }
+        }
+
+        if let Some(duration) = self.duration {
+            if self.interval.is_some() || self.poisson.is_some() {
+                bail!(
+                    "--duration sets the pacing itself, it conflicts with --interval and --poisson"
+                );
+            }
+            if duration.is_zero() {
+                bail!("--duration must be positive");
+            }
+        }
+
+        if let Some(trace) = &self.replay {
+            if self.interval.is_some()
+                || self.poisson.is_some()
+                || self.duration.is_some()
+                || self.live_rate.is_some()
+            {
+                bail!("--replay sets the pacing itself, it conflicts with --interval, --poisson, --duration and --signal-rate");
+            }
+            if self.bytes.is_some() || self.weights.is_some() || self.tcp_pipeline.is_some() {
+                bail!("--replay sends the trace to every target, it conflicts with --bytes, weights and --tcp-pipeline");
+            }
+            if trace.iter().any(|packet| packet.size.is_some())
+                && (self.tcp
+                    || self.compact
+                    || self.server_timestamps
+                    || self.ecn.is_some()
+                    || self.tos_verify.is_some()
+                    || self.sweep_size.is_some())
+            {
+                bail!("Packet sizes of --replay need the plain UDP packet format");
+            }
+            if trace.len() > self.tries {
+                info!(
+                    target: self.namespace.as_str(),
+                    "replaying the first {} of {} packets of the trace",
+                    self.tries,
+                    trace.len()
+                );
+            }
+        }
+
+        if let (Some(step), Some(live)) = (self.rate_step, &self.live_rate) {
+            let interval = match self.interval {
+                Some(interval) if !interval.is_zero() => interval,
+                _ => {
+                    bail!("--signal-rate starts at the rate of --interval, which must be positive")
+                }
+            };
+            if self.poisson.is_some() || self.duration.is_some() {
+                bail!("--signal-rate conflicts with --poisson and --duration");
+            }
+            if self.pin.is_some() {
+                bail!("--signal-rate is not supported with pinned workers");
+            }
+            if !(step.is_finite() && step > 0.0) {
+                bail!("Rate step must be positive, got {}", step);
+            }
+            live.start(1.0 / interval.as_secs_f64());
+        }
+
+        if let Some((rate, _)) = self.poisson {
+            if self.interval.is_some() {
+                bail!("--poisson and --interval are mutually exclusive");
+            }
+            if !(rate.is_finite() && rate > 0.0) {
+                bail!("Poisson rate must be positive, got {}", rate);
+            }
+        }
+
+        if self.ecn.is_some() && self.tos_verify.is_some() {
+            bail!("--ecn and --tos-verify are mutually exclusive, --tos-verify sets ECN as well");
+        }
+        if self.compact && (self.ecn.is_some() || self.tos_verify.is_some()) {
+            bail!("TOS reflection is not supported with the compact packet format");
+        }
+
+        if self.compact {
+            if self.addresses.len() as u64 > u32::MAX as u64 + 1 {
+                bail!("Too many targets for compact identifiers");
+            }
+            if tries.iter().any(|&t| t as u64 > u32::MAX as u64 + 1) {
+                bail!("Too many tries for compact sequence numbers");
+            }
+        }
+
+        if self.warmup > 0 && tries.iter().any(|&t| t > 0 && t <= self.warmup) {
+            bail!(
+                "--warmup of {} sequences leaves nothing to measure for some targets",
+                self.warmup
+            );
+        }
+
+        if self.query_server && self.tcp {
+            bail!("--query-server only applies to UDP");
+        }
+        if self.query_server {
+            self.run_query_server().await;
+        }
+        if let Some((count, wait)) = self.preload {
+            self.run_preload(count, wait).await;
+        }
+
+        self.reverse.lock().unwrap().clear();
+        self.aborted.lock().unwrap().clear();
+        self.summaries.lock().unwrap().clear();
+        if let Some(cap) = &self.rate_cap {
+            cap.restart();
+        }
+        self.deadline_passed.store(false, Ordering::Relaxed);
+        self.timed_out.store(false, Ordering::Relaxed);
+        let mut results = Results::new();
+        if self.ndjson {
+            let hook = self.on_result.clone();
+            let namespace = self.namespace.clone();
+            let exclude_warmup = self.exclude_warmup;
+            results.set_on_result(OnResult(Arc::new(move |result| {
+                // the same results the report would leave out
+                if !(exclude_warmup && result.warmup) {
+                    print_ndjson(&namespace, result);
+                }
+                if let Some(hook) = &hook {
+                    (hook.0)(result);
+                }
+            })));
+        } else if let Some(hook) = &self.on_result {
+            results.set_on_result(hook.clone());
+        }
+        results.set_warmup(self.warmup as u64);
+
+        results.prime(&self.addresses, &tries);
+
+        let results = Arc::new(results);
+
+        let mut workers: Vec<futures::future::BoxFuture<Result<()>>> = Vec::new();
+        for (address, &tries) in self.addresses.iter().zip(&tries) {
+            let tcp = self.tcp;
+            if tries == 0 {
+                continue;
+            }
+            let identifier = results
+                .targets
+                .get(address.as_str())
+                .context("Failed to find target identifier")?;
+
+            if tcp {
+                workers.push(Box::pin(self.run_tcp_target(
+                    address,
+                    tries,
+                    *identifier,
+                    results.clone(),
+                )));
+            } else {
+                workers.push(Box::pin(self.run_udp_target(
+                    address,
+                    tries,
+                    *identifier,
+                    results.clone(),
+                )));
+            }
+            trace!(target: self.namespace.as_str(), "created job for {}", address);
+        }
+
+        let timeout = self
+            .timeout
+            .map(|t| std::time::Duration::from_secs(t as u64));
+
+        // `None` if the timeout fired before all workers finished
+        let finished = if self.drain_remaining {
+            async { futures::future::try_join_all(workers).await.map(Some) }
+                .race(self.drain_deadline(timeout))
+                .race(self.flush(&results))
+                .race(self.watch_rate())
+                .race(self.guard_memory(&results))
+                .await
+                .map(|finished| finished.map(|_| ()))
+        } else {
+            // the workers stop on their own once the timeout expired and are joined either way
+            let stopped = if let Some(cpus) = &self.pin {
+                let workers = workers
+                    .into_iter()
+                    .map(|worker| {
+                        // every thread keeps an eye on the memory until its worker is done
+                        worker
+                            .race(self.expire(timeout))
+                            .race(self.guard_memory(&results))
+                    })
+                    .collect();
+                run_pinned(workers, cpus)
+            } else {
+                async { futures::future::try_join_all(workers).await.map(|_| ()) }
+                    .race(self.expire(timeout))
+                    .race(self.flush(&results))
+                    .race(self.watch_rate())
+                    .race(self.guard_memory(&results))
+                    .await
+            };
+            stopped.map(|()| (!self.timed_out.load(Ordering::Relaxed)).then_some(()))
+        }
+        .context("Failed to run client")?;
+
+        if finished.is_none() {
+            if self.strict_timeout {
+                bail!("Time exceeded");
+            }
+            info!(
+                target: self.namespace.as_str(),
+                "Time exceeded, reporting outstanding requests as failed"
+            );
+        }
+
+        // the workers are joined or dropped by now, so there are no other references left
+        let results =
+            Arc::try_unwrap(results).map_err(|_| anyhow::anyhow!("Results are still in use"))?;
+        if let Some(limit) = self.max_memory {
+            // a run shorter than the poll interval wasn't checked yet
+            self.limit_memory(&results, limit).await;
+        }
+        let (results, summaries) = results.finish().await;
+
+        if let Some(cap) = &self.rate_cap {
+            if cap.throttled() > 0 {
+                info!(
+                    target: self.namespace.as_str(),
+                    "{} sends waited for the limit of {} packets per second",
+                    cap.throttled(),
+                    cap.rate()
+                );
+            }
+        }
+
+        let num_failed =
+            JsonResults::count_failed(&results) + summaries.iter().map(|s| s.failed).sum::<usize>();
+        info!(target: self.namespace.as_str(), "{} requests failed", num_failed);
+        *self.summaries.lock().unwrap() = summaries;
+
+        Ok(results)
+    }
+
+    /// Stop the run once `timeout` expires, see [`Config::stopped`]. Never finishes.
+    async fn expire<T>(&self, timeout: Option<std::time::Duration>) -> T {
+        if let Some(timeout) = timeout {
+            async_std::task::sleep(timeout).await;
+            info!(
+                target: self.namespace.as_str(),
+                "Time exceeded, stopping the run"
+            );
+            self.timed_out.store(true, Ordering::Relaxed);
+        }
+        futures::future::pending().await
+    }
+
+    /// Whether the run stops, by the exit flag or the expired timeout.
+    fn stopping(&self) -> bool {
+        self.exit.load(Ordering::Relaxed) || self.timed_out.load(Ordering::Relaxed)
+    }
+
+    /// Resolve as soon as the run stops, see [`Config::stopping`].
+    async fn until_stopping(&self) {
+        while !self.stopping() {
+            async_std::task::sleep(STOP_POLL).await;
+        }
+    }
+
+    /// Resolve once the run stops and the responses in flight had [`ABORT_REPLY_WINDOW`] to
+    /// arrive. Workers race what they wait for against it, so none outlives the stop.
+    async fn stopped(&self) {
+        self.until_stopping().await;
+        async_std::task::sleep(ABORT_REPLY_WINDOW).await;
+    }
+
+    /// Expire the timeout of `--drain-remaining-budget`: let the senders go through the rest of
+    /// their budget unpaced, then give the last echoes the same time an abort would. Senders
+    /// still busy after the timeout passed once more, say on a TCP read without a timeout, are
+    /// given up on.
+    async fn drain_deadline<T>(&self, timeout: Option<std::time::Duration>) -> Result<Option<T>> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return futures::future::pending().await,
+        };
+        async_std::task::sleep(timeout).await;
+        info!(
+            target: self.namespace.as_str(),
+            "Time exceeded, sending the remaining budget before draining"
+        );
+        self.deadline_passed.store(true, Ordering::Relaxed);
+        let drained = async {
+            while self.sending.load(Ordering::Relaxed) > 0 {
+                async_std::task::sleep(RATE_POLL).await;
+            }
+            true
+        };
+        let limit = async {
+            async_std::task::sleep(timeout).await;
+            false
+        };
+        if drained.race(limit).await {
+            async_std::task::sleep(ABORT_REPLY_WINDOW).await;
+        } else {
+            warn!(
+                target: self.namespace.as_str(),
+                "the remaining budget didn't drain within {:?}, reporting the rest as failed",
+                timeout
+            );
+        }
+        Ok(None)
+    }
+
+    /// Wait for the next send slot of `pacer`, or not at all once a drained timeout expired.
+    /// A stop ends the wait early, the sender finds out right after.
+    async fn pace(&self, pacer: &mut Pacer) -> Option<std::time::Duration> {
+        if self.deadline_passed.load(Ordering::Relaxed) {
+            return None;
+        }
+        pacer
+            .wait(self.busy_poll)
+            .race(async {
+                self.until_stopping().await;
+                None
+            })
+            .await
+    }
+
+    /// Wait until `packets` more sends fit the limit of all targets together, see
+    /// [`Config::set_max_total_rate`].
+    async fn throttle(&self, packets: usize) {
+        let cap = match &self.rate_cap {
+            Some(cap) => cap,
+            None => return,
+        };
+        if let Some((wait, first)) = cap.reserve(packets) {
+            if first {
+                warn!(
+                    target: self.namespace.as_str(),
+                    "sending is throttled to {} packets per second across all targets, raise --max-tries-per-second-adaptive or pass --no-limit",
+                    cap.rate()
+                );
+            }
+            async_std::task::sleep(wait).await;
+        }
+    }
+
+    /// Ask every target for its echo modes, see [`Config::set_query_server`].
+    async fn run_query_server(&self) {
+        let namespace = self.namespace.as_str();
+        let queries = self
+            .addresses
+            .iter()
+            .enumerate()
+            .map(|(index, address)| async move {
+                let answer = match self.bind_udp(index).await {
+                    Ok(socket) => handshake::query(&socket, address, handshake::QUERY_WAIT).await,
+                    Err(e) => Err(e),
+                };
+                let params = match answer {
+                    Ok(Some(params)) => {
+                        info!(target: namespace, "{}: server echoes with {:?}", address, params);
+                        Some(params)
+                    }
+                    Ok(None) => {
+                        info!(target: namespace, "{}: no echo modes, assuming a plain echo", address);
+                        None
+                    }
+                    Err(e) => {
+                        warn!(target: namespace, "{}: parameter query failed: {:#}", address, e);
+                        None
+                    }
+                };
+                (address.clone(), params)
+            });
+        let answers = futures::future::join_all(queries).await;
+        *self.server_params.lock().unwrap() = answers.into_iter().collect();
+        for address in &self.addresses {
+            if self.strict_order && !self.expects_order(address) {
+                info!(target: namespace, "{}: server reorders, --strict-order ignores it", address);
+            }
+            if self.fail_fast_corruption && !self.expects_intact(address) {
+                info!(
+                    target: namespace,
+                    "{}: server alters echoes, --fail-fast-corruption ignores it", address
+                );
+            }
+        }
+    }
+
+    /// Warm the path to every target, see [`Config::set_preload`]. Targets that don't answer are
+    /// only logged, the run finds out about them soon enough.
+    async fn run_preload(&self, count: usize, wait: std::time::Duration) {
+        let namespace = self.namespace.as_str();
+        let preloads = self
+            .addresses
+            .iter()
+            .enumerate()
+            .map(|(index, address)| async move {
+                let answered = if self.tcp {
+                    // the handshake does all the warming a TCP try needs
+                    phase_timeout(TcpStream::connect(address.as_str()), Some(wait))
+                        .await
+                        .map_or(Ok(false), |stream| stream.map(|_| true))
+                        .map_err(anyhow::Error::from)
+                } else {
+                    match self.bind_udp(index).await {
+                        Ok(socket) => preload::preload(&socket, address, count, wait).await,
+                        Err(e) => Err(e),
+                    }
+                };
+                match answered {
+                    Ok(true) => debug!(target: namespace, "{}: preloaded", address),
+                    Ok(false) => warn!(target: namespace, "{}: no answer to preload", address),
+                    Err(e) => warn!(target: namespace, "{}: preload failed: {:#}", address, e),
+                }
+            });
+        futures::future::join_all(preloads).await;
+    }
+
+    /// With `--flush-interval`, periodically replace the output file with a report of the
+    /// results settled so far, or send it to `--report-to`. Never finishes.
+    async fn flush<T>(&self, results: &Results<'_>) -> T {
+        if let Some(interval) = self.flush_interval {
+            loop {
+                async_std::task::sleep(interval).await;
+                *self.summaries.lock().unwrap() = results.summaries().await;
+                let report = self.report(results.snapshot().await);
+                let flushed = match (&self.report_to, &self.output) {
+                    (Some(address), _) => self
+                        .output_json(&report)
+                        .and_then(|json| send_report(address, &json)),
+                    (None, Some(output)) => self.write_atomic(output, &report),
+                    (None, None) => break,
+                };
+                if let Err(e) = flushed {
+                    warn!(target: self.namespace.as_str(), "failed to flush results: {:#}", e);
+                }
+            }
+        }
+        futures::future::pending().await
+    }
+
+    /// With `--max-runtime-memory`, periodically switch the largest targets to summary-only
+    /// results while their samples need more than the limit. Never finishes.
+    async fn guard_memory<T>(&self, results: &Results<'_>) -> T {
+        if let Some(limit) = self.max_memory {
+            loop {
+                async_std::task::sleep(MEMORY_POLL).await;
+                self.limit_memory(results, limit).await;
+            }
+        }
+        futures::future::pending().await
+    }
+
+    async fn limit_memory(&self, results: &Results<'_>, limit: usize) {
+        let (estimate, switched) = results.limit_memory(limit).await;
+        if !switched.is_empty() {
+            warn!(
+                target: self.namespace.as_str(),
+                "results need about {} bytes, more than the limit of {}, keeping only the summary of {}",
+                estimate,
+                limit,
+                switched.join(", ")
+            );
+        }
+    }
+
+    /// With `--signal-rate`, log every rate change and add it to the timeline. Never finishes.
+    async fn watch_rate<T>(&self) -> T {
+        if let Some(live) = &self.live_rate {
+            let start = std::time::Instant::now();
+            loop {
+                async_std::task::sleep(RATE_POLL).await;
+                if let Some(rate) = live.take_change() {
+                    info!(
+                        target: self.namespace.as_str(),
+                        "rate changed to {:.1} packets per second", rate
+                    );
+                    live.record(RateChange {
+                        at: start.elapsed(),
+                        rate,
+                    });
+                }
+            }
+        }
+        futures::future::pending().await
+    }
+
+    /// Write `results` to a temporary file next to `output` and move it over, so a crash
+    /// leaves either the previous or the new file.
+    fn write_atomic<T: serde::Serialize>(&self, output: &str, results: &T) -> Result<()> {
+        let partial = format!("{}.partial", output);
+        let mut file = std::fs::File::create(&partial)
+            .with_context(|| format!("Failed to create {}", partial))?;
+        if self.json_compact {
+            serde_json::to_writer(&mut file, results)
+        } else {
+            serde_json::to_writer_pretty(&mut file, results)
+        }
+        .context("Failed to write json")?;
+        file.sync_data().context("Failed to sync")?;
+        std::fs::rename(&partial, output)
+            .with_context(|| format!("Failed to move {} to {}", partial, output))
+    }
+
+    /// Echo every try over its own TCP connection, so connection setup is part of each try.
+    async fn run_tcp_target(
+        &self,
+        target: &str,
+        tries: usize,
+        identifier: u64,
+        results: Arc<Results<'_>>,
+    ) -> Result<()> {
+        let _sending = Sending::new(&self.sending);
+        if let Some(depth) = self.tcp_pipeline {
+            return self
+                .run_tcp_pipeline(target, tries, identifier, results, depth)
+                .await;
+        }
+        let namespace = self.namespace.as_str();
+        let mut pacer = self.pacer(identifier, tries);
+        let stopped = self.stopped();
+        futures::pin_mut!(stopped);
+
+        for x in 0..tries {
+            let lateness = if x != 0 {
+                self.pace(&mut pacer).await
+            } else {
+                None
+            };
+            self.throttle(1).await;
+            if self.stopping() {
+                info!(target: namespace, "{}: stopped, {} sequences not sent", target, tries - x);
+                results.abort(identifier, x as u64).await?;
+                break;
+            }
+
+            let connect = phase_timeout(TcpStream::connect(target), self.connect_timeout);
+            let stream = match unless_stopped(connect, &mut stopped).await {
+                None => {
+                    info!(target: namespace, "{}: stopped, {} sequences not sent", target, tries - x);
+                    results.abort(identifier, x as u64).await?;
+                    break;
+                }
+                Some(Some(Ok(stream))) => stream,
+                Some(Some(Err(e))) => {
+                    warn!(target: namespace, "failed to connect to {}: {}", target, e);
+                    results
+                        .failed(identifier, x as u64, TimeoutPhase::Connect, e.to_string())
+                        .await?;
+                    continue;
+                }
+                Some(None) => {
+                    debug!(target: namespace, "{}: connect {} timed out", target, x);
+                    results
+                        .timed_out(identifier, x as u64, TimeoutPhase::Connect)
+                        .await?;
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_nodelay(self.tcp_nodelay) {
+                warn!(target: namespace, "failed to set TCP_NODELAY: {}", e);
+            }
+            if x == 0 {
+                results.set_local(identifier, stream.local_addr()?).await?;
+            }
+
+            let mut buf = [0u8; ECHO_SIZE];
+            UdpEchoBuilder::new()
+                .identifier(identifier)
+                .sequence(x as u64)
+                .payload(&[0])
+                .build_into(&mut buf)
+                .expect("ECHO_SIZE fits the packet");
+
+            let mut stream = stream;
+            results
+                .start_packet(identifier, x as u64, stream.peer_addr()?)
+                .await?;
+            if let Some(lateness) = lateness {
+                results.set_lateness(identifier, x as u64, lateness).await?;
+            }
+            if let Err(e) = stream.write_all(&buf).await {
+                warn!(target: namespace, "failed to send packet: {}", e);
+                results
+                    .failed(identifier, x as u64, TimeoutPhase::Write, e.to_string())
+                    .await?;
+                continue;
+            }
+
+            let mut recv = [0u8; ECHO_SIZE];
+            let read = phase_timeout(stream.read_exact(&mut recv), self.read_timeout);
+            match unless_stopped(read, &mut stopped).await {
+                None => {
+                    // the echo missed the window of the stop, so the try failed
+                    info!(
+                        target: namespace,
+                        "{}: stopped, {} sequences not sent",
+                        target,
+                        tries - x - 1
+                    );
+                    results.abort(identifier, x as u64 + 1).await?;
+                    break;
+                }
+                Some(Some(Ok(()))) => {
+                    let echo = UdpEchoPacket::new(&recv).context("response too short")?;
+                    if echo.get_identifier() != identifier || echo.get_sequence() != x as u64 {
+                        warn!(target: namespace, "unexpected echo from {}", target);
+                        continue;
+                    }
+                    let corrupt = recv != buf;
+                    if self.fail_fast_corruption && corrupt {
+                        self.fail_corrupted(target, x as u64);
+                        continue;
+                    }
+                    let info = RecvInfo {
+                        corrupt,
+                        ..RecvInfo::default()
+                    };
+                    results.recv_packet(identifier, x as u64, info).await?;
+                }
+                Some(Some(Err(e))) => {
+                    warn!(target: namespace, "failed to receive packet: {}", e);
+                    results
+                        .failed(identifier, x as u64, TimeoutPhase::Read, e.to_string())
+                        .await?;
+                }
+                Some(None) => {
+                    debug!(target: namespace, "{}: read {} timed out", target, x);
+                    results
+                        .timed_out(identifier, x as u64, TimeoutPhase::Read)
+                        .await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Echo all tries over a single TCP connection, writing `depth` frames before reading
+    /// their echoes. The RTT of a frame includes the wait behind the frames ahead of it.
+    async fn run_tcp_pipeline(
+        &self,
+        target: &str,
+        tries: usize,
+        identifier: u64,
+        results: Arc<Results<'_>>,
+        depth: usize,
+    ) -> Result<()> {
+        let namespace = self.namespace.as_str();
+        // the pacing applies to whole windows
+        let mut pacer = self.pacer(identifier, tries.div_ceil(depth));
+        let stopped = self.stopped();
+        futures::pin_mut!(stopped);
+
+        let connect = phase_timeout(TcpStream::connect(target), self.connect_timeout);
+        let mut stream = match unless_stopped(connect, &mut stopped).await {
+            None => {
+                info!(target: namespace, "{}: stopped, {} sequences not sent", target, tries);
+                return Ok(());
+            }
+            Some(Some(Ok(stream))) => stream,
+            Some(Some(Err(e))) => {
+                warn!(target: namespace, "failed to connect to {}: {}", target, e);
+                let error = e.to_string();
+                for x in 0..tries {
+                    results
+                        .failed(identifier, x as u64, TimeoutPhase::Connect, error.clone())
+                        .await?;
+                }
+                return Ok(());
+            }
+            Some(None) => {
+                debug!(target: namespace, "{}: connect timed out", target);
+                for x in 0..tries {
+                    results
+                        .timed_out(identifier, x as u64, TimeoutPhase::Connect)
+                        .await?;
+                }
+                return Ok(());
+            }
+        };
+        if let Err(e) = stream.set_nodelay(self.tcp_nodelay) {
+            warn!(target: namespace, "failed to set TCP_NODELAY: {}", e);
+        }
+        results.set_local(identifier, stream.local_addr()?).await?;
+        let remote = stream.peer_addr()?;
+
+        let mut buf = vec![0u8; ECHO_SIZE * depth];
+        for (window, first) in (0..tries).step_by(depth).enumerate() {
+            let lateness = if window != 0 {
+                self.pace(&mut pacer).await
+            } else {
+                None
+            };
+            self.throttle(depth.min(tries - first)).await;
+            if self.stopping() {
+                info!(target: namespace, "{}: stopped, {} sequences not sent", target, tries - first);
+                results.abort(identifier, first as u64).await?;
+                break;
+            }
+
+            let count = depth.min(tries - first);
+            for (i, frame) in buf.chunks_exact_mut(ECHO_SIZE).take(count).enumerate() {
+                let seq = (first + i) as u64;
+                UdpEchoBuilder::new()
+                    .identifier(identifier)
+                    .sequence(seq)
+                    .payload(&[0])
+                    .build_into(frame)
+                    .expect("ECHO_SIZE fits the packet");
+                results.start_packet(identifier, seq, remote).await?;
+                if let Some(lateness) = lateness {
+                    results.set_lateness(identifier, seq, lateness).await?;
+                }
+            }
+            if let Err(e) = stream.write_all(&buf[..count * ECHO_SIZE]).await {
+                warn!(target: namespace, "failed to send packets: {}", e);
+                let error = e.to_string();
+                for seq in first..first + count {
+                    results
+                        .failed(identifier, seq as u64, TimeoutPhase::Write, error.clone())
+                        .await?;
+                }
+                results.abort(identifier, (first + count) as u64).await?;
+                return Ok(());
+            }
+
+            for (i, sent) in buf.chunks_exact(ECHO_SIZE).take(count).enumerate() {
+                let seq = (first + i) as u64;
+                let mut recv = [0u8; ECHO_SIZE];
+                let read = phase_timeout(stream.read_exact(&mut recv), self.read_timeout);
+                match unless_stopped(read, &mut stopped).await {
+                    None => {
+                        // the echoes missed the window of the stop, so their tries failed
+                        let left = tries - first - count;
+                        info!(target: namespace, "{}: stopped, {} sequences not sent", target, left);
+                        results.abort(identifier, (first + count) as u64).await?;
+                        return Ok(());
+                    }
+                    Some(Some(Ok(()))) => {
+                        let echo = UdpEchoPacket::new(&recv).context("response too short")?;
+                        // the stream keeps the order, anything else means it lost its framing
+                        if echo.get_identifier() != identifier || echo.get_sequence() != seq {
+                            warn!(target: namespace, "unexpected echo from {}, closing", target);
+                            return Ok(());
+                        }
+                        let corrupt = recv != sent;
+                        if self.fail_fast_corruption && corrupt {
+                            self.fail_corrupted(target, seq);
+                            return Ok(());
+                        }
+                        let info = RecvInfo {
+                            corrupt,
+                            ..RecvInfo::default()
+                        };
+                        results.recv_packet(identifier, seq, info).await?;
+                    }
+                    Some(Some(Err(e))) => {
+                        // the connection is gone, and with it the rest of the window
+                        warn!(target: namespace, "failed to receive packet: {}", e);
+                        let error = e.to_string();
+                        for seq in seq..(first + count) as u64 {
+                            results
+                                .failed(identifier, seq, TimeoutPhase::Read, error.clone())
+                                .await?;
+                        }
+                        results.abort(identifier, (first + count) as u64).await?;
+                        return Ok(());
+                    }
+                    Some(None) => {
+                        // the echoes still in flight would shift the framing of the next window
+                        debug!(target: namespace, "{}: read {} timed out", target, seq);
+                        for seq in seq..(first + count) as u64 {
+                            results
+                                .timed_out(identifier, seq, TimeoutPhase::Read)
+                                .await?;
+                        }
+                        results.abort(identifier, (first + count) as u64).await?;
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stop every target for `--fail-fast-corruption`, remembering the first offender.
+    fn fail_corrupted(&self, target: &str, sequence: u64) {
+        let mut corrupted = self.corrupted.lock().unwrap();
+        if corrupted.is_none() {
+            error!(
+                target: self.namespace.as_str(),
+                "{}: corrupt echo for sequence {}, stopping",
+                target,
+                sequence
+            );
+            *corrupted = Some((target.to_string(), sequence));
+        }
+        self.exit.store(true, Ordering::Relaxed);
+    }
+
+    /// Record every queued ICMP error that belongs to `identifier`, returning how many there were.
+    async fn drain_icmp_errors(
+        &self,
+        socket: &UdpSocket,
+        buf: &mut [u8],
+        identifier: u64,
+        results: &Results<'_>,
+    ) -> usize {
+        let mut count = 0;
+        while let Ok((size, error)) = socket::recv_err(socket.as_raw_fd(), buf) {
+            let (kind, code) = match error.icmp {
+                Some(icmp) => icmp,
+                None => continue,
+            };
+            let sent = if self.compact {
+                UdpEchoCompactPacket::new(&buf[..size])
+                    .map(|udp| (udp.get_identifier() as u64, udp.get_sequence() as u64))
+            } else {
+                UdpEchoPacket::new(&buf[..size])
+                    .map(|udp| (udp.get_identifier(), udp.get_sequence()))
+            };
+            if let Some((id, seq)) = sent {
+                if id != identifier {
+                    continue;
+                }
+                let error = IcmpError::new(error.ipv6, kind, code);
+                if let Err(e) = results.set_icmp_error(identifier, seq, error).await {
+                    info!(target: self.namespace.as_str(), "failed to store result: {:?}", e);
+                }
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Bind a UDP socket for the target at `index` to its source address, or the unspecified
+    /// address.
+    async fn bind_udp(&self, index: usize) -> Result<UdpSocket> {
+        if let Some(source) = self.source_address(index) {
+            self.bind_udp_at(SocketAddr::new(source, 0))
+                .await
+                .with_context(|| format!("Failed to bind to {}", source))
+        } else {
+            let address = [
+                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+                SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
+            ];
+
+            // a host without IPv4 still gets a socket, if only an IPv6 one
+            match self.bind_udp_at(address[0]).await {
+                Ok(socket) => Ok(socket),
+                Err(v4) => match self.bind_udp_at(address[1]).await {
+                    Ok(socket) => {
+                        warn!(
+                            target: self.namespace.as_str(),
+                            "failed to bind {}: {:#}, using {}", address[0], v4, address[1]
+                        );
+                        Ok(socket)
+                    }
+                    Err(v6) => Err(v6).with_context(|| {
+                        format!(
+                            "Failed to bind {} ({:#}) and {}",
+                            address[0], v4, address[1]
+                        )
+                    }),
+                },
+            }
+        }
+    }
+
+    /// Bind `address`, shareable with the receive socket for `--separate-recv-socket`.
+    async fn bind_udp_at(&self, address: SocketAddr) -> Result<UdpSocket> {
+        if self.separate_recv {
+            Ok(UdpSocket::from(socket::bind_reuseport(address)?))
+        } else {
+            Ok(UdpSocket::bind(address).await?)
+        }
+    }
+
+    async fn run_udp_target(
+        &self,
+        target: &str,
+        tries: usize,
+        identifier: u64,
+        results: Arc<Results<'_>>,
+    ) -> Result<()> {
+        let namespace = self.namespace.as_str();
+        let abort_after = self.abort_after;
+        let mut pacer = self.pacer(identifier, tries);
+        let compact = self.compact;
+        // --ecn only sets the two low bits of the byte --tos-verify sets as a whole
+        let tos = self.tos_verify.or(self.ecn);
+        let record_cpu = self.record_cpu;
+        let send_latency = self.send_latency;
+        let server_timestamps = self.server_timestamps;
+        let mut recverr = self.recverr;
+        let recv_concurrency = self.recv_concurrency;
+        let fail_fast_corruption = self.fail_fast_corruption && self.expects_intact(target);
+        let recv_buffer = self.recv_buffer.unwrap_or(MAX_RECV_BUFFER);
+        // the zeroes a plain echo carries, grown to the size of `--probe-size-sweep`
+        let header = UdpEchoPacket::minimum_packet_size();
+        let replay = self.replay.as_deref().unwrap_or_default();
+        let datagram_size = replay
+            .iter()
+            .filter_map(|packet| packet.size)
+            .fold(self.datagram_size(), usize::max);
+        let padding = vec![0u8; datagram_size.max(ECHO_SIZE) - header];
+        let plain = self.datagram_size().max(ECHO_SIZE) - header;
+        let mut buf = vec![0u8; datagram_size.max(ECHO_SIZE + TIMESTAMPS_LEN)];
+
+        let mut sockets = Vec::new();
+        for _ in 0..self.source_pool.unwrap_or(1).max(1) {
+            // identifiers count the targets from zero
+            let socket = self.bind_udp(identifier as usize).await?;
+            if let Some(tos) = tos {
+                socket::set_tos(socket.as_raw_fd(), socket.local_addr()?.is_ipv6(), tos)
+                    .context("Failed to set TOS")?;
+            }
+            if recverr {
+                if let Err(e) =
+                    socket::enable_recv_err(socket.as_raw_fd(), socket.local_addr()?.is_ipv6())
+                {
+                    warn!(target: namespace, "can't read icmp errors, ignoring them: {}", e);
+                    recverr = false;
+                }
+            }
+            sockets.push(Arc::new(socket));
+        }
+        let locals = sockets
+            .iter()
+            .map(|socket| socket.local_addr())
+            .collect::<std::io::Result<Vec<SocketAddr>>>()?;
+        // resolved once, so every packet goes to the same address and the results can say which
+        let remote = resolve(target, locals[0].is_ipv6()).await?;
+        debug!(
+            target: namespace,
+            "{}: sending from {:?} to {}", target, locals, remote
+        );
+        if locals.len() == 1 {
+            results.set_local(identifier, locals[0]).await?;
+        }
+
+        let recv_sockets = if self.separate_recv {
+            let mut recv_sockets = Vec::new();
+            for &local in &locals {
+                let socket = UdpSocket::from(
+                    socket::bind_reuseport(local)
+                        .with_context(|| format!("Failed to bind receive socket to {}", local))?,
+                );
+                socket
+                    .connect(remote)
+                    .await
+                    .context("Failed to connect receive socket")?;
+                // icmp errors are matched to the connected socket as well
+                if recverr {
+                    socket::enable_recv_err(socket.as_raw_fd(), local.is_ipv6())
+                        .context("Failed to enable icmp errors on the receive socket")?;
+                }
+                recv_sockets.push(Arc::new(socket));
+            }
+            recv_sockets
+        } else {
+            sockets.clone()
+        };
+
+        let two_way = self.two_way;
+        let remaining = Arc::new(AtomicUsize::new(tries));
+        // the reverse probes of the server may trail the echoes, see `reflect_until`
+        let probes = Arc::new(AtomicUsize::new(if two_way { tries } else { 0 }));
+        let receivers: Vec<_> = recv_sockets
+            .iter()
+            .flat_map(|socket| std::iter::repeat_n(socket, recv_concurrency.max(1)))
+            .map(|socket| {
+                let read_half = socket.clone();
+                let write_results = results.clone();
+                let remaining = remaining.clone();
+                let probes = probes.clone();
+                let mut record_cpu = record_cpu;
+                Box::pin(async move {
+                    // the server may answer with more than was sent, see `--response-size`
+                    let mut buf = vec![0u8; recv_buffer];
+                    // once every echo is settled, the reverse probes still missing get a window
+                    // as long as an abort gives the echoes in flight, lost ones never come
+                    let mut reflect_until: Option<std::time::Instant> = None;
+                    let stopped = self.stopped();
+                    futures::pin_mut!(stopped);
+                    loop {
+                        let received = unless_stopped(read_half.recv(&mut buf), &mut stopped);
+                        let received = match reflect_until {
+                            Some(until) => {
+                                let left =
+                                    until.saturating_duration_since(std::time::Instant::now());
+                                match async_std::future::timeout(left, received).await {
+                                    Ok(received) => received,
+                                    Err(_) => break,
+                                }
+                            }
+                            None => received.await,
+                        };
+                        let size = match received {
+                            // the run stopped and the echoes in flight had their window
+                            None => break,
+                            Some(Ok(size)) => size,
+                            Some(Err(e)) if recverr => {
+                                debug!(target: namespace, "socket error: {}", e);
+                                // no echo follows an icmp error, so it settles the sequence
+                                let errors = self
+                                    .drain_icmp_errors(
+                                        &read_half,
+                                        &mut buf,
+                                        identifier,
+                                        &write_results,
+                                    )
+                                    .await;
+                                if errors > 0 && count_down(&remaining, errors) <= errors {
+                                    if probes.load(Ordering::Relaxed) == 0 {
+                                        break;
+                                    }
+                                    reflect_until =
+                                        Some(std::time::Instant::now() + ABORT_REPLY_WINDOW);
+                                }
+                                continue;
+                            }
+                            Some(Err(e)) => {
+                                warn!(target: namespace, "failed to receive packet: {}", e);
+                                continue;
+                            }
+                        };
+                        trace!(target: namespace, "got packet");
+
+                        if two_way {
+                            match UdpEchoPacket::new(&buf[..size]) {
+                                Some(probe)
+                                    if probe.get_next_level() == NEXT_LEVEL_REVERSE_PROBE =>
+                                {
+                                    if probe.get_identifier() != identifier {
+                                        continue;
+                                    }
+                                    if let Err(e) = read_half.send_to(&buf[..size], remote).await {
+                                        debug!(target: namespace, "failed to reflect probe: {}", e);
+                                    }
+                                    if let Some((_, received)) =
+                                        self.reverse.lock().unwrap().get_mut(target)
+                                    {
+                                        *received += 1;
+                                    }
+                                    if probes.fetch_sub(1, Ordering::Relaxed) == 1
+                                        && remaining.load(Ordering::Relaxed) == 0
+                                    {
+                                        break;
+                                    }
+                                    continue;
+                                }
+                                _ => (),
+                            }
+                        }
+
+                        let parsed = if compact {
+                            UdpEchoCompactPacket::new(&buf[..size]).map(|udp| {
+                                (
+                                    udp.get_identifier() as u64,
+                                    udp.get_sequence() as u64,
+                                    RecvInfo::default(),
+                                    true,
+                                )
+                            })
+                        } else {
+                            UdpEchoPacket::new(&buf[..size]).map(|udp| {
+                                let mut info = RecvInfo::default();
+                                // a plain echo carries back the zeroed payload, padded with
+                                // zeros by --response-size
+                                let intact = udp.get_next_level() != 0
+                                    || udp.payload().iter().all(|&byte| byte == 0);
+                                match udp.get_next_level() {
+                                    NEXT_LEVEL_TOS => {
+                                        info.tos = udp.payload().first().copied();
+                                        info.ecn = info.tos.map(|tos| tos & 0b11);
+                                    }
+                                    NEXT_LEVEL_TIMESTAMPS => {
+                                        info.server_timestamps = read_timestamps(udp.payload());
+                                    }
+                                    _ => (),
+                                }
+                                (udp.get_identifier(), udp.get_sequence(), info, intact)
+                            })
+                        };
+                        let (id, seq, mut info, intact) = match parsed {
+                            Some(parsed) => parsed,
+                            None => {
+                                warn!(target: namespace, "response too short");
+                                continue;
+                            }
+                        };
+                        info.size = Some(size);
+                        // recv cuts off what doesn't fit, so a full buffer may be a longer echo
+                        info.truncated = size == buf.len();
+                        if info.truncated {
+                            debug!(target: namespace, "echo {} filled the receive buffer", seq);
+                        }
+                        if record_cpu {
+                            match socket::incoming_cpu(read_half.as_raw_fd()) {
+                                Ok(cpu) => info.cpu = cpu,
+                                Err(e) => {
+                                    warn!(target: namespace, "can't record receiving cpu: {}", e);
+                                    record_cpu = false;
+                                }
+                            }
+                        }
+                        if identifier != id {
+                            warn!(target: namespace, "invalid identifier in response");
+                            continue;
+                        }
+                        info.corrupt = !intact;
+                        if fail_fast_corruption && !intact {
+                            self.fail_corrupted(target, seq);
+                            continue;
+                        }
+
+                        let changed = match write_results.recv_packet(identifier, seq, info).await {
+                            Ok(changed) => changed,
+                            Err(e) => {
+                                info!(target: namespace, "failed to store result: {:?}", e);
+                                false
+                            }
+                        };
+                        // the first receiver to see the last response ends all of them, duplicates
+                        // don't count
+                        if changed && count_down(&remaining, 1) == 1 {
+                            if probes.load(Ordering::Relaxed) == 0 {
+                                break;
+                            }
+                            reflect_until = Some(std::time::Instant::now() + ABORT_REPLY_WINDOW);
+                        }
+                    }
+                })
+            })
+            .collect();
+        let receiver = async move {
+            futures::future::select_all(receivers).await;
+        };
+
+        let (abort_tx, abort_rx) = futures::channel::oneshot::channel::<()>();
+        let receiver = receiver.race(async move {
+            // a dropped sender means the work finished normally, keep receiving
+            if abort_rx.await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        });
+
+        if two_way {
+            let interval = self.interval.unwrap_or_default();
+            let mut payload = [0u8; REVERSE_REQUEST_LEN];
+            write_reverse_request(&mut payload, tries as u64, interval.as_micros() as u64);
+            let mut request = [0u8; ECHO_SIZE - 1 + REVERSE_REQUEST_LEN];
+            UdpEchoBuilder::new()
+                .identifier(identifier)
+                .next_level(NEXT_LEVEL_REVERSE)
+                .payload(&payload)
+                .build_into(&mut request)
+                .expect("buffer fits the request");
+            self.reverse
+                .lock()
+                .unwrap()
+                .insert(target.to_string(), (tries as u64, 0));
+            sockets[0]
+                .send_to(&request, remote)
+                .await
+                .with_context(|| format!("Failed to ask {} for reverse probes", target))?;
+        }
+
+        let work = async move {
+            let _sending = Sending::new(&self.sending);
+            for x in 0..tries {
+                let lateness = if x > 0 {
+                    self.pace(&mut pacer).await
+                } else {
+                    None
+                };
+                self.throttle(1).await;
+
+                // the receivers stop on their own once the echoes in flight had their window
+                if self.stopping() {
+                    info!(
+                        target: namespace,
+                        "{}: stopped, {} sequences not sent",
+                        target,
+                        tries - x
+                    );
+                    if let Err(e) = results.abort(identifier, x as u64).await {
+                        info!(target: namespace, "failed to store result: {:?}", e);
+                    }
+                    return;
+                }
+
+                if let Some(limit) = abort_after {
+                    // hold back while the last `limit` sequences are unanswered but not overdue,
+                    // an unpaced sender would otherwise be done before any of them fails
+                    let failures = loop {
+                        let (failures, waiting) = results
+                            .consecutive_failures(identifier, x as u64, ABORT_REPLY_WINDOW, limit)
+                            .await
+                            .unwrap_or((0, 0));
+                        if failures >= limit || failures + waiting < limit {
+                            break failures;
+                        }
+                        async_std::task::sleep(ABORT_POLL).await;
+                    };
+                    if failures >= limit {
+                        warn!(
+                            target: namespace,
+                            "{}: aborting after {} consecutive failures, {} sequences not sent",
+                            target,
+                            failures,
+                            tries - x
+                        );
+                        if let Err(e) = results.abort(identifier, x as u64).await {
+                            info!(target: namespace, "failed to store result: {:?}", e);
+                        }
+                        self.aborted.lock().unwrap().insert(
+                            target.to_string(),
+                            AbortReason::ConsecutiveFailures {
+                                failures,
+                                sequence: x as u64,
+                            },
+                        );
+                        let _ = abort_tx.send(());
+                        return;
+                    }
+                }
+
+                let buf = if compact {
+                    let payload = UdpEchoCompact::new(identifier as u32, x as u32);
+                    let mut echo = MutableUdpEchoCompactPacket::new(&mut buf).unwrap();
+                    echo.populate(&payload);
+                    &buf[..UdpEchoCompactPacket::minimum_packet_size()]
+                } else {
+                    let (next_level, payload): (u8, &[u8]) = if server_timestamps {
+                        (NEXT_LEVEL_TIMESTAMPS, &[0; TIMESTAMPS_LEN])
+                    } else if tos.is_some() {
+                        (NEXT_LEVEL_TOS, &[0])
+                    } else {
+                        // the sizes of a trace are checked to fit the plain format
+                        let size = replay.get(x).and_then(|packet| packet.size);
+                        (0, &padding[..size.map_or(plain, |size| size - header)])
+                    };
+                    let len = UdpEchoBuilder::new()
+                        .identifier(identifier)
+                        .sequence(x as u64)
+                        .next_level(next_level)
+                        .payload(payload)
+                        .build_into(&mut buf)
+                        .expect("buffer fits every packet variant");
+                    &buf[..len]
+                };
+
+                let source = x % sockets.len();
+                let before = std::time::Instant::now();
+                if let Err(e) = sockets[source].send_to(buf, remote).await {
+                    warn!(target: namespace, "failed to send packet: {}", e);
+                }
+                let latency = before.elapsed();
+                if let Err(e) = results.start_packet(identifier, x as u64, remote).await {
+                    info!(target: namespace, "failed to store result: {:?}", e);
+                }
+                if let Some(lateness) = lateness {
+                    if let Err(e) = results.set_lateness(identifier, x as u64, lateness).await {
+                        info!(target: namespace, "failed to store result: {:?}", e);
+                    }
+                }
+                if send_latency {
+                    if let Err(e) = results
+                        .set_send_latency(identifier, x as u64, latency)
+                        .await
+                    {
+                        info!(target: namespace, "failed to store result: {:?}", e);
+                    }
+                }
+                if sockets.len() > 1 {
+                    if let Err(e) = results
+                        .set_sample_local(identifier, x as u64, locals[source])
+                        .await
+                    {
+                        info!(target: namespace, "failed to store result: {:?}", e);
+                    }
+                }
+                trace!(target: namespace, "send packet {}:{}", identifier, x);
+            }
+        };
+
+        work.join(receiver).await;
+
+        Ok(())
+    }
+}
+
+/// Take `settled` off the sequences still `remaining` for the receivers, stopping at zero since
+/// a sequence may draw more than one ICMP error. Returns the count before.
+fn count_down(remaining: &AtomicUsize, settled: usize) -> usize {
+    remaining
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+            Some(left.saturating_sub(settled))
+        })
+        .unwrap_or_else(|left| left)
+}
+
+/// Counts a worker as sending from creation until dropped, however its send loop ends, so
+/// `--drain-remaining-budget` knows when the last packet is out.
+struct Sending<'a>(&'a AtomicUsize);
+
+impl<'a> Sending<'a> {
+    fn new(sending: &'a AtomicUsize) -> Self {
+        sending.fetch_add(1, Ordering::Relaxed);
+        Self(sending)
+    }
+}
+
+impl Drop for Sending<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Write the CSV of [`Config::set_timestamps_only`] for `results` to `out`.
+fn write_timestamps_csv<W: std::io::Write>(
+    mut out: W,
+    results: &[&JsonResults],
+) -> std::io::Result<()> {
+    writeln!(out, "target,seq,sent_ns,recv_ns")?;
+    for result in results {
+        let sent_ns = result.sent_wall_ns.unwrap_or_default();
+        write!(out, "{},{},{},", result.target, result.sequence, sent_ns)?;
+        match result.state {
+            JsonResultState::Succeded(rtt) => writeln!(out, "{}", sent_ns + rtt.as_nanos() as u64)?,
+            _ => writeln!(out)?,
+        }
+    }
+    out.flush()
+}
+
+/// Write `result` to stdout as a single line of json, for `--ndjson`.
+fn print_ndjson(namespace: &str, result: &JsonResults) {
+    use std::io::Write;
+
+    let mut stdout = std::io::stdout().lock();
+    let written = serde_json::to_writer(&mut stdout, result)
+        .map_err(std::io::Error::from)
+        .and_then(|_| stdout.write_all(b"\n"))
+        .and_then(|_| stdout.flush());
+    if let Err(e) = written {
+        debug!(target: namespace, "failed to print result: {}", e);
+    }
+}
+
+/// Latency of the probes to `target` among `results`.
+fn probe_stats(results: &[JsonResults], target: &str) -> ProbeStats {
+    let own: Vec<JsonResults> = results
+        .iter()
+        .filter(|r| r.target == target)
+        .cloned()
+        .collect();
+    ProbeStats {
+        sent: JsonResults::count_succeeded(&own) + JsonResults::count_failed(&own),
+        loss: JsonResults::loss(&own),
+        mean_rtt: JsonResults::mean_rtt(&own),
+        p99_rtt: JsonResults::rtt_percentile(&own, 99.0),
+    }
+}
+
+/// Split a target of the form `ADDRESS*WEIGHT` into address and weight, defaulting to weight 1.
+pub fn parse_target(target: &str) -> Result<(String, usize)> {
+    match target.rsplit_once('*') {
+        Some((address, weight)) => {
+            let weight = weight
+                .parse()
+                .with_context(|| format!("Failed to parse weight of '{}'", target))?;
+            if weight == 0 {
+                bail!("Weight of '{}' must be positive", target);
+            }
+            Ok((address.to_string(), weight))
+        }
+        None => Ok((target.to_string(), 1)),
+    }
+}
+
+/// Await a single step of a try that returns its own error, giving up with `None` after
+/// `timeout`.
+async fn phase_timeout<F, T>(future: F, timeout: Option<std::time::Duration>) -> Option<T>
+where
+    F: Future<Output = T>,
+{
+    match timeout {
+        Some(timeout) => async_std::future::timeout(timeout, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Await `future`, giving up with `None` once `stopped` resolved, see [`Config::stopped`].
+async fn unless_stopped<F, S>(future: F, stopped: &mut S) -> Option<F::Output>
+where
+    F: Future,
+    S: Future<Output = ()> + Unpin,
+{
+    async { Some(future.await) }
+        .race(async {
+            stopped.await;
+            None
+        })
+        .await
+}
+
+/// Drive each worker on a dedicated thread pinned to one of `cpus`, blocking until all are done.
+fn run_pinned<F>(workers: Vec<F>, cpus: &[usize]) -> Result<()>
+where
+    F: Future<Output = Result<()>> + Send,
+{
+    if cpus.is_empty() {
+        bail!("No CPUs to pin to");
+    }
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = workers
+            .into_iter()
+            .zip(cpus.iter().cycle())
+            .map(|(worker, &cpu)| {
+                scope.spawn(move || {
+                    let mut set = nix::sched::CpuSet::new();
+                    set.set(cpu)
+                        .with_context(|| format!("Invalid CPU {}", cpu))?;
+                    nix::sched::sched_setaffinity(nix::unistd::Pid::from_raw(0), &set)
+                        .with_context(|| format!("Failed to pin to CPU {}", cpu))?;
+
+                    async_std::task::block_on(worker)
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            match handle.join() {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => bail!("Worker thread panicked"),
+            }
+        }
+        Ok(())
+    })
+}
+
+/// How long `--report-to` waits for the controller to accept the connection.
+const REPORT_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Write `json` to a fresh stream connection to `address`, see [`Config::set_report_to`].
+fn send_report(address: &str, json: &str) -> Result<()> {
+    use std::io::Write;
+
+    if let Some(path) = address.strip_prefix("unix:") {
+        let mut stream = std::os::unix::net::UnixStream::connect(path)
+            .with_context(|| format!("Failed to connect to {}", path))?;
+        stream
+            .write_all(json.as_bytes())
+            .context("Failed to send report")?;
+        return Ok(());
+    }
+
+    let mut last = None;
+    for addr in std::net::ToSocketAddrs::to_socket_addrs(address)
+        .with_context(|| format!("Failed to resolve {}", address))?
+    {
+        match std::net::TcpStream::connect_timeout(&addr, REPORT_CONNECT_TIMEOUT) {
+            Ok(mut stream) => {
+                return stream
+                    .write_all(json.as_bytes())
+                    .context("Failed to send report");
+            }
+            Err(e) => last = Some(e),
+        }
+    }
+    match last {
+        Some(e) => Err(e).with_context(|| format!("Failed to connect to {}", address)),
+        None => bail!("{} resolved to no address", address),
+    }
+}
+
+/// Resolve `target` to the first of its addresses in the family of the sending socket, or the
+/// first one at all if it has none in that family.
+async fn resolve(target: &str, ipv6: bool) -> Result<SocketAddr> {
+    let addresses: Vec<SocketAddr> = target
+        .to_socket_addrs()
+        .await
+        .with_context(|| format!("Failed to resolve '{}'", target))?
+        .collect();
+    addresses
+        .iter()
+        .find(|address| address.is_ipv6() == ipv6)
+        .or_else(|| addresses.first())
+        .copied()
+        .with_context(|| format!("'{}' resolved to no address", target))
+}
+
+/// Check that `path` can be written without creating or truncating it: an existing file has to
+/// be writable, otherwise the directory it would be created in.
+fn check_writable(path: &std::path::Path) -> std::io::Result<()> {
+    if path.exists() {
+        return OpenOptions::new().write(true).open(path).map(drop);
+    }
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => check_directory(parent),
+        _ => check_directory(std::path::Path::new(".")),
+    }
+}
+
+/// Check that files can be created in the directory `path`.
+fn check_directory(path: &std::path::Path) -> std::io::Result<()> {
+    nix::unistd::access(path, nix::unistd::AccessFlags::W_OK)?;
+    if !path.is_dir() {
+        return Err(std::io::Error::from_raw_os_error(libc::ENOTDIR));
+    }
+    Ok(())
+}
+
+/// Turn a target address into a file name, replacing brackets, colons and anything else
+/// that is not alphanumeric, `.` or `-` with `_`.
+fn sanitize_filename(address: &str) -> String {
+    address
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Parse a byte count with an optional decimal `k`, `M` or `G` suffix.
+pub fn parse_size(size: &str) -> Result<usize> {
+    let (number, factor) = match size.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&size[..i], 1_000),
+        Some((i, 'M')) => (&size[..i], 1_000_000),
+        Some((i, 'G')) => (&size[..i], 1_000_000_000),
+        _ => (size, 1),
+    };
+    let number: usize = number
+        .parse()
+        .with_context(|| format!("Invalid size '{}'", size))?;
+    number
+        .checked_mul(factor)
+        .with_context(|| format!("Size '{}' is too large", size))
+}
+
+/// Parse an ECN codepoint name (`ect0`, `ect1` or `ce`).
+pub fn parse_ecn(name: &str) -> Result<u8> {
+    match name.to_lowercase().as_str() {
+        "ect0" | "ect(0)" => Ok(0b10),
+        "ect1" | "ect(1)" => Ok(0b01),
+        "ce" => Ok(0b11),
+        _ => bail!("Unknown ECN codepoint '{}'", name),
+    }
+}
+
+/// Longest key or value accepted by [`parse_tag`].
+const MAX_TAG_LENGTH: usize = 1024;
+
+/// Split a `KEY=VALUE` tag.
+pub fn parse_tag(tag: &str) -> Result<(String, String)> {
+    let (key, value) = tag
+        .split_once('=')
+        .with_context(|| format!("Tag '{}' is not of the form KEY=VALUE", tag))?;
+    if key.is_empty() {
+        bail!("Tag '{}' has an empty key", tag);
+    }
+    if key.len() > MAX_TAG_LENGTH || value.len() > MAX_TAG_LENGTH {
+        bail!("Tag key and value are limited to {} bytes", MAX_TAG_LENGTH);
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parse a TOS byte, decimal or hexadecimal with a `0x` prefix.
+pub fn parse_tos(tos: &str) -> Result<u8> {
+    match tos.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => tos.parse(),
+    }
+    .with_context(|| format!("Invalid TOS byte '{}'", tos))
+}
+
+/// Split `total` proportionally to `weights`, handing out rounding leftovers by largest remainder.
+fn distribute(total: usize, weights: &[usize]) -> Result<Vec<usize>> {
+    // the products of large budgets and weights don't fit a usize
+    let sum: u128 = weights.iter().map(|&w| w as u128).sum();
+    if sum == 0 {
+        bail!("Weights must be positive");
+    }
+
+    // each share is at most `total`, so it fits a usize again
+    let mut ret: Vec<usize> = weights
+        .iter()
+        .map(|&w| (total as u128 * w as u128 / sum) as usize)
+        .collect();
+    let mut remainders: Vec<(u128, usize)> = weights
+        .iter()
+        .enumerate()
+        .map(|(i, &w)| (total as u128 * w as u128 % sum, i))
+        .collect();
+    remainders.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+    let missing = total - ret.iter().sum::<usize>();
+    for &(_, i) in remainders.iter().take(missing) {
+        ret[i] += 1;
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::{
+        count_down, distribute, parse_size, parse_tag, parse_target, parse_tos, sanitize_filename,
+        Config, MAX_TAG_LENGTH, RESERVED_FDS,
+    };
+
+    #[test]
+    fn weighted_targets() {
+        assert_eq!(
+            parse_target("1.2.3.4:7").unwrap(),
+            ("1.2.3.4:7".to_string(), 1)
+        );
+        assert_eq!(
+            parse_target("[::1]:7*3").unwrap(),
+            ("[::1]:7".to_string(), 3)
+        );
+        assert!(parse_target("1.2.3.4:7*0").is_err());
+        assert!(parse_target("1.2.3.4:7*x").is_err());
+
+        assert_eq!(distribute(30, &[1, 1, 1]).unwrap(), vec![10, 10, 10]);
+        assert_eq!(distribute(20, &[1, 3]).unwrap(), vec![5, 15]);
+        assert_eq!(distribute(10, &[1, 1, 1]).unwrap(), vec![4, 3, 3]);
+        assert_eq!(
+            distribute(10, &[2, 1, 1]).unwrap().iter().sum::<usize>(),
+            10
+        );
+        let huge = distribute(usize::MAX, &[usize::MAX, usize::MAX]).unwrap();
+        assert_eq!(huge, vec![usize::MAX / 2 + 1, usize::MAX / 2]);
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1500").unwrap(), 1500);
+        assert_eq!(parse_size("2k").unwrap(), 2_000);
+        assert_eq!(parse_size("10M").unwrap(), 10_000_000);
+        assert_eq!(parse_size("1G").unwrap(), 1_000_000_000);
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.5M").is_err());
+    }
+
+    #[test]
+    fn tags() {
+        assert_eq!(
+            parse_tag("scenario=a=b").unwrap(),
+            ("scenario".to_string(), "a=b".to_string())
+        );
+        assert_eq!(
+            parse_tag("empty=").unwrap(),
+            ("empty".to_string(), String::new())
+        );
+        assert!(parse_tag("=value").is_err());
+        assert!(parse_tag("novalue").is_err());
+        assert!(parse_tag(&format!("long={}", "x".repeat(MAX_TAG_LENGTH + 1))).is_err());
+    }
+
+    #[test]
+    fn tos() {
+        assert_eq!(parse_tos("184").unwrap(), 0xb8);
+        assert_eq!(parse_tos("0xb8").unwrap(), 184);
+        assert!(parse_tos("256").is_err());
+        assert!(parse_tos("0xg").is_err());
+    }
+
+    #[test]
+    fn filenames() {
+        assert_eq!(sanitize_filename("1.2.3.4:7"), "1.2.3.4_7");
+        assert_eq!(sanitize_filename("[fe80::1%eth0]:7"), "_fe80__1_eth0__7");
+        assert_eq!(sanitize_filename("../host:7"), ".._host_7");
+    }
+
+    #[test]
+    fn open_files() {
+        let targets = (0..100).map(|i| format!("10.0.0.{}:7", i)).collect();
+        let mut config = Config::new(false, targets, 1);
+        assert_eq!(config.required_fds(), 100 + RESERVED_FDS);
+
+        config.set_source_randomize(4).set_separate_recv(true);
+        config.set_output("out.json".to_string());
+        assert_eq!(config.required_fds(), 800 + 1 + RESERVED_FDS);
+
+        let tcp = Config::new(true, vec!["a:7".to_string(), "b:7".to_string()], 1);
+        assert_eq!(tcp.required_fds(), 2 + RESERVED_FDS);
+    }
+
+    #[test]
+    fn remaining_saturates() {
+        let remaining = AtomicUsize::new(3);
+        assert_eq!(count_down(&remaining, 2), 3);
+        // more errors than sequences left
+        assert_eq!(count_down(&remaining, 4), 1);
+        assert_eq!(count_down(&remaining, 1), 0);
+        assert_eq!(remaining.into_inner(), 0);
     }
 }
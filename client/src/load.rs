@@ -0,0 +1,151 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use async_std::net::UdpSocket;
+use async_std::prelude::*;
+use packet::{UdpEchoBuilder, UdpEchoPacket};
+use serde::{Deserialize, Serialize};
+
+/// Identifier of the load packets, next to the one of the preload packets.
+const LOAD_IDENTIFIER: u64 = u64::MAX - 1;
+
+/// Largest UDP payload of an IPv4 datagram.
+const MAX_LOAD_SIZE: usize = 65507;
+
+/// How far the sender may fall behind its schedule before it stops catching up, so a stall
+/// doesn't turn into a burst.
+const MAX_BEHIND: Duration = Duration::from_millis(10);
+
+/// How long the load keeps receiving after the last send.
+const DRAIN: Duration = Duration::from_millis(200);
+
+/// Parameters of `--under-load`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LoadParams {
+    /// Megabits per second of UDP payload sent to every target.
+    pub megabits: f64,
+    /// UDP payload bytes of a load packet.
+    pub size: usize,
+}
+
+impl LoadParams {
+    pub fn check(&self) -> Result<()> {
+        if !(self.megabits.is_finite() && self.megabits > 0.0) {
+            bail!("Load rate must be positive, got {}", self.megabits);
+        }
+        let min = UdpEchoPacket::minimum_packet_size();
+        if self.size < min || self.size > MAX_LOAD_SIZE {
+            bail!(
+                "Load packets must be between {} and {} bytes, got {}",
+                min,
+                MAX_LOAD_SIZE,
+                self.size
+            );
+        }
+        Ok(())
+    }
+}
+
+/// What the load stream to a single target sent and got back.
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct LoadStats {
+    pub sent: u64,
+    pub received: u64,
+    pub sent_bytes: u64,
+    pub received_bytes: u64,
+    /// Megabits per second that came back, over the time the load was sending.
+    pub throughput: f64,
+}
+
+/// Latency of the probe stream during one phase.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ProbeStats {
+    pub sent: usize,
+    pub loss: f64,
+    pub mean_rtt: Option<Duration>,
+    pub p99_rtt: Option<Duration>,
+}
+
+/// Probe latency to a target without and with the load running.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TargetUnderLoad {
+    pub target: String,
+    pub idle: ProbeStats,
+    pub loaded: ProbeStats,
+    pub load: LoadStats,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LoadResult {
+    pub load: LoadParams,
+    pub targets: Vec<TargetUnderLoad>,
+}
+
+/// Send echoes of `params.size` bytes to `target` at `params.megabits` until `stop` is set,
+/// counting what comes back.
+pub async fn load(
+    socket: &UdpSocket,
+    target: &str,
+    params: &LoadParams,
+    stop: &AtomicBool,
+) -> Result<LoadStats> {
+    let mut buf = vec![0u8; params.size];
+    let payload = vec![0u8; params.size - UdpEchoPacket::minimum_packet_size()];
+    let period = Duration::from_secs_f64(params.size as f64 * 8.0 / (params.megabits * 1e6));
+
+    let received = AtomicU64::new(0);
+    let received_bytes = AtomicU64::new(0);
+    let receiving = async {
+        let mut recv = vec![0u8; MAX_LOAD_SIZE];
+        loop {
+            if let Ok(size) = socket.recv(&mut recv).await {
+                if let Some(echo) = UdpEchoPacket::new(&recv[..size]) {
+                    if echo.get_identifier() == LOAD_IDENTIFIER {
+                        received.fetch_add(1, Ordering::Relaxed);
+                        received_bytes.fetch_add(size as u64, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+    };
+
+    let start = Instant::now();
+    let sending = async {
+        let mut stats = LoadStats::default();
+        let mut next = start;
+        while !stop.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            if next > now {
+                async_std::task::sleep(next - now).await;
+            } else if now - next > MAX_BEHIND {
+                next = now - MAX_BEHIND;
+            }
+            next += period;
+
+            let len = UdpEchoBuilder::new()
+                .identifier(LOAD_IDENTIFIER)
+                .sequence(stats.sent)
+                .payload(&payload)
+                .build_into(&mut buf)
+                .expect("buffer fits the packet");
+            socket
+                .send_to(&buf[..len], target)
+                .await
+                .with_context(|| format!("Failed to send load to {}", target))?;
+            stats.sent += 1;
+            stats.sent_bytes += len as u64;
+        }
+        let elapsed = start.elapsed();
+        async_std::task::sleep(DRAIN).await;
+        Ok::<_, anyhow::Error>((stats, elapsed))
+    };
+
+    let (mut stats, elapsed) = sending.race(receiving).await?;
+    stats.received = received.load(Ordering::Relaxed);
+    stats.received_bytes = received_bytes.load(Ordering::Relaxed);
+    if !elapsed.is_zero() {
+        stats.throughput = stats.received_bytes as f64 * 8.0 / elapsed.as_secs_f64() / 1e6;
+    }
+    Ok(stats)
+}
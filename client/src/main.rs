@@ -1,11 +1,6 @@
 use anyhow::{bail, Context, Result};
-use async_std::io;
-use async_std::net::{TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
-use async_std::prelude::*;
-use async_std::stream::IntoStream;
 use client::Config;
 use getopts::Options;
-use log::*;
 
 #[async_std::main]
 async fn main() {
@@ -24,9 +19,387 @@ async fn main_err() -> Result<()> {
     options.optflagopt("c", "count", "numbers of packages per address", "count");
     options.optflagopt("T", "timeout", "number of seconds until timeout", "seconds");
     options.optflagopt("o", "output", "file to write results into", "FILE");
-    // TODO: delay betwen requests
+    options.optflag("", "compact", "use 32 bit identifier and sequence numbers");
+    options.optflagopt(
+        "b",
+        "bind-address",
+        "source address to send from",
+        "ADDRESS",
+    );
+    options.optflagopt(
+        "",
+        "abort-after",
+        "stop sending to a target after N consecutive failures",
+        "N",
+    );
+    options.optflagopt(
+        "",
+        "mtu-probe",
+        "search the path MTU to each target instead of benchmarking (default ceiling 1500)",
+        "CEILING",
+    );
+    options.optflagopt(
+        "i",
+        "interval",
+        "milliseconds to wait between requests to a target",
+        "MS",
+    );
+    options.optflag(
+        "",
+        "print-config",
+        "print the resolved configuration as a bundle for --seed-from-file and exit",
+    );
+    options.optopt(
+        "",
+        "seed-from-file",
+        "re-run the benchmark of a bundle written by --print-config, taking its targets and options",
+        "PATH",
+    );
+    options.optflag(
+        "",
+        "embed-config",
+        "include the resolved configuration in the report",
+    );
+    options.optflagopt(
+        "",
+        "ecn",
+        "mark packets with ECT0 or ECT1 and count CE marks seen by the server",
+        "CODEPOINT",
+    );
+    options.optflagopt(
+        "",
+        "tos-verify",
+        "mark packets with the TOS byte and report whether the server saw it unchanged",
+        "TOS",
+    );
+    options.optflag("", "strict-timeout", "treat an expired timeout as an error");
+    options.optflag(
+        "",
+        "drain-remaining-budget",
+        "when the timeout expires, send the rest of every target's packets unpaced and wait for their echoes instead of stopping",
+    );
+    options.optflagopt(
+        "",
+        "pin",
+        "run each target on its own thread, pinned round-robin to the comma separated CPUs",
+        "CPUS",
+    );
+    options.optflag("", "record-cpu", "record the CPU that received each echo");
+    options.optflag(
+        "",
+        "recverr",
+        "record icmp errors such as port unreachable per sample instead of waiting for a timeout",
+    );
+    options.optflag(
+        "",
+        "separate-recv-socket",
+        "receive echoes on a dedicated socket sharing the port of the sending one",
+    );
+    options.optflag(
+        "",
+        "server-timestamp",
+        "split the RTT into forward, server and return time, assuming synchronized clocks",
+    );
+    options.optflag(
+        "",
+        "send-latency",
+        "record how long each send call takes, reported apart from the RTT",
+    );
+    options.optflag(
+        "",
+        "json-compact",
+        "write the output json without pretty printing",
+    );
+    options.optflagopt(
+        "",
+        "bytes",
+        "send enough packets to transfer SIZE bytes (k, M and G suffixes) to every target, overriding -c",
+        "SIZE",
+    );
+    options.optflagopt(
+        "",
+        "repeat-until-failure",
+        "repeat the benchmark until a run loses more than PERCENT of its packets (default 0)",
+        "PERCENT",
+    );
+    options.optflagopt(
+        "",
+        "max-iterations",
+        "stop --repeat-until-failure after N runs",
+        "N",
+    );
+    options.optflagopt(
+        "",
+        "output-dir",
+        "additionally write the results of every target to DIR/<target>.json",
+        "DIR",
+    );
+    options.optflagopt(
+        "",
+        "connect-timeout",
+        "give up a tcp try if connecting takes longer than MS milliseconds",
+        "MS",
+    );
+    options.optflagopt(
+        "",
+        "read-timeout",
+        "give up a tcp try if the echo takes longer than MS milliseconds",
+        "MS",
+    );
+    options.optflagopt(
+        "",
+        "poisson",
+        "send with exponentially distributed gaps at a mean of RATE packets per second",
+        "RATE",
+    );
+    options.optflagopt(
+        "",
+        "poisson-seed",
+        "seed for the --poisson gaps (default 1)",
+        "SEED",
+    );
+    options.optflagopt(
+        "",
+        "max-runtime-memory",
+        "keep only the summary of the largest targets once their results need more than SIZE bytes during the run",
+        "SIZE",
+    );
+    options.optflagopt(
+        "",
+        "trace",
+        "trace the path to each target with TTLs up to MAX instead of benchmarking (default 30)",
+        "MAX",
+    );
+    options.optflagopt(
+        "",
+        "max-rtt",
+        "fail if the p99 RTT of any target exceeds MS milliseconds",
+        "MS",
+    );
+    options.optflagopt(
+        "",
+        "recv-concurrency",
+        "keep N receives outstanding on every target's socket (default 1)",
+        "N",
+    );
+    options.optflagopt(
+        "",
+        "source-randomize",
+        "rotate the packets to every target over POOL source ports (default 16)",
+        "POOL",
+    );
+    options.optflag(
+        "",
+        "no-dns",
+        "only accept literal IP:PORT targets, never resolve names",
+    );
+    options.optflag(
+        "",
+        "strict-order",
+        "exit with an error if any echo arrived out of order",
+    );
+    options.optflag(
+        "",
+        "query-server",
+        "ask the servers for their echo modes first and don't fail checks they break on purpose",
+    );
+    options.optflagopt(
+        "",
+        "count-per-second",
+        "search the highest rate losing at most PERCENT of the packets (default 1)",
+        "PERCENT",
+    );
+    options.optflagopt(
+        "",
+        "rate-step",
+        "packets per second added per --count-per-second phase (default 100)",
+        "PPS",
+    );
+    options.optflagopt(
+        "",
+        "phase-duration",
+        "milliseconds every --count-per-second phase sends (default 1000)",
+        "MS",
+    );
+    options.optflagopt(
+        "",
+        "max-phases",
+        "stop --count-per-second after N phases (default 20)",
+        "N",
+    );
+    options.optflagopt(
+        "",
+        "flush-interval",
+        "rewrite the output file with the results so far every SECS seconds (default only at the end)",
+        "SECS",
+    );
+    options.optmulti(
+        "",
+        "tag",
+        "add KEY=VALUE metadata to the report, repeatable",
+        "KEY=VALUE",
+    );
+    options.optflagopt(
+        "",
+        "preload",
+        "send COUNT unrecorded packets per target before the run to warm the path (default 3)",
+        "COUNT",
+    );
+    options.optflagopt(
+        "",
+        "preload-wait",
+        "milliseconds to wait for a --preload echo (default 1000)",
+        "MS",
+    );
+    options.optflagopt(
+        "",
+        "no-delay",
+        "set TCP_NODELAY on tcp connections, on or off (default on)",
+        "on|off",
+    );
+    options.optflag(
+        "",
+        "compare",
+        "compare two saved reports, given as BASELINE CANDIDATE instead of addresses",
+    );
+    options.optflagopt(
+        "",
+        "loss-tolerance",
+        "loss increase in percentage points --compare accepts (default 1)",
+        "PERCENT",
+    );
+    options.optflagopt(
+        "",
+        "rtt-tolerance",
+        "mean and p99 RTT increase in percent --compare accepts (default 10)",
+        "PERCENT",
+    );
+    options.optflag(
+        "",
+        "fail-fast-corruption",
+        "stop the whole run at the first echo with a corrupt payload",
+    );
+    options.optflagopt(
+        "",
+        "recv-buffer",
+        "receive udp echoes into a buffer of SIZE bytes, counting echoes that fill it as truncated (default 65536)",
+        "SIZE",
+    );
+    options.optflag(
+        "",
+        "fragmentation",
+        "with --mtu-probe, also find the largest packet that round-trips fragmented",
+    );
+    options.optflagopt(
+        "",
+        "duration",
+        "spread the packets of every target evenly over SECS seconds, reporting how late they went out",
+        "SECS",
+    );
+    options.optflagopt(
+        "",
+        "replay",
+        "send the packets of a trace at its times, one line of SECS and optionally SIZE per packet",
+        "FILE",
+    );
+    options.optflagopt(
+        "",
+        "report-to",
+        "send the report over a TCP (HOST:PORT) or unix (unix:PATH) connection instead of writing a file",
+        "ADDR",
+    );
+    options.optflagopt(
+        "",
+        "under-load",
+        "measure the RTT idle and again next to a load stream of MBPS to every target",
+        "MBPS",
+    );
+    options.optflagopt(
+        "",
+        "load-size",
+        "UDP payload bytes of a load packet (default 1200)",
+        "BYTES",
+    );
+    options.optflagopt(
+        "",
+        "signal-rate",
+        "step the --interval rate up on SIGUSR1 and down on SIGUSR2 by STEP packets per second",
+        "STEP",
+    );
+    options.optflag(
+        "",
+        "no-rlimit-bump",
+        "fail instead of raising the open file limit when a run needs more",
+    );
+    options.optflagopt(
+        "",
+        "warmup",
+        "flag the first COUNT sequences of every target as warmup, leaving them out of the summary",
+        "COUNT",
+    );
+    options.optflag(
+        "",
+        "exclude-warmup-from-output",
+        "leave the --warmup sequences out of the results instead of flagging them",
+    );
+    options.optflag(
+        "",
+        "ndjson",
+        "print every result to stdout as a line of json once it is final, instead of the report",
+    );
+    options.optflagopt(
+        "",
+        "max-tries-per-second-adaptive",
+        "limit the packets per second sent to all targets together, waiting when over it (default 1000000)",
+        "PPS",
+    );
+    options.optflag(
+        "",
+        "no-limit",
+        "lift the --max-tries-per-second-adaptive limit",
+    );
+    options.optflagopt(
+        "",
+        "output-timestamps-only",
+        "write only the send and receive time of every packet to FILE as CSV (target,seq,sent_ns,recv_ns in ns since the UNIX epoch), instead of the report",
+        "FILE",
+    );
+    options.optflagopt(
+        "",
+        "probe-size-sweep",
+        "run the probes once per datagram size, given as a list (64,512) or a range (100-1400:100), and report the RTT per size",
+        "SIZES",
+    );
+    options.optflagopt(
+        "",
+        "busy-poll-threshold",
+        "spin instead of sleeping for the last USECS microseconds before every paced send",
+        "USECS",
+    );
+    options.optflagopt(
+        "",
+        "tcp-pipeline",
+        "echo all packets of a target over one tcp connection, writing N at a time before reading the echoes, and report the throughput",
+        "N",
+    );
+    options.optflagopt(
+        "",
+        "source-pool",
+        "send to the targets from these local addresses in turn, instead of a single --bind-address",
+        "ADDR,...",
+    );
+    options.optflag(
+        "",
+        "two-way",
+        "have the server probe back at the --interval rate and report the loss of the return path, needs a server with --reverse-probes",
+    );
     // TODO: paralel?
 
+    options.optflag(
+        "",
+        "list-interfaces",
+        "List network interfaces and their addresses",
+    );
     options.optflag("V", "version", "Show version info");
     options.optflag("h", "help", "Show this help message");
 
@@ -35,7 +408,7 @@ async fn main_err() -> Result<()> {
         .context("Failed to parse cli arguments")?;
 
     if matches.opt_present("h") {
-        let brief = format!("Usage: {} [options] addresses", args[0]);
+        let brief = format!("Usage: {} [options] addresses[*weight]", args[0]);
         print!("{}", options.usage(&brief));
         return Ok(());
     }
@@ -43,27 +416,61 @@ async fn main_err() -> Result<()> {
     pretty_env_logger::init();
 
     if matches.opt_present("V") {
-        // TODO: base function/macro?
-        eprintln!("{}: Version {}", args[0], env!("CARGO_PKG_VERSION"));
-        eprintln!(
-            "(C) {}",
-            env!("CARGO_PKG_AUTHORS")
-                .split(":")
-                .collect::<Vec<&str>>()
-                .join("\n(C) ")
-        );
+        version::print_version!(&args[0]);
+        return Ok(());
+    }
+
+    if matches.opt_present("list-interfaces") {
+        for (name, addresses) in client::list_interfaces().context("Failed to query interfaces")? {
+            let addresses: Vec<String> = addresses.iter().map(|a| a.to_string()).collect();
+            println!("{}: {}", name, addresses.join(", "));
+        }
         return Ok(());
     }
 
+    if matches.opt_present("compare") {
+        return compare(&matches);
+    }
+
+    if matches.opt_present("seed-from-file") {
+        let path = seed_path(&args[1..])?;
+        let json = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read bundle '{}'", path))?;
+        let config = Config::from_bundle(&json)
+            .with_context(|| format!("Failed to load bundle '{}'", path))?;
+        return run(config).await;
+    }
+
+    let mut addresses = Vec::new();
+    let mut weights = Vec::new();
+    for target in &matches.free {
+        let (address, weight) = client::parse_target(target)?;
+        addresses.push(address);
+        weights.push(weight);
+    }
+
+    let replay = match matches.opt_str("replay") {
+        Some(path) => Some(client::load_replay(&path)?),
+        None => None,
+    };
+
+    // a replay without a count sends the whole trace
     let mut config = Config::new(
         matches.opt_present("t"),
-        matches.free.clone(),
+        addresses,
         matches
             .opt_str("c")
-            .map(|p| p.parse().ok())
-            .flatten()
+            .and_then(|p| p.parse().ok())
+            .or_else(|| replay.as_ref().map(Vec::len))
             .unwrap_or(10),
     );
+    if let Some(trace) = replay {
+        config.set_replay(trace);
+    }
+
+    if weights.iter().any(|&w| w != 1) {
+        config.set_weights(weights);
+    }
 
     match matches.opt_str("T").map(|v| v.parse()) {
         Some(Ok(timeout)) => {
@@ -75,11 +482,498 @@ async fn main_err() -> Result<()> {
         None => (),
     }
 
+    if let Some(cpus) = matches.opt_str("pin") {
+        let cpus = cpus
+            .split(',')
+            .map(|cpu| cpu.trim().parse())
+            .collect::<Result<Vec<usize>, _>>()
+            .context("Failed to parse CPU list")?;
+        config.set_pin(cpus);
+    }
+
+    config.set_record_cpu(matches.opt_present("record-cpu"));
+    config.set_rlimit_bump(!matches.opt_present("no-rlimit-bump"));
+    config.set_exclude_warmup(matches.opt_present("exclude-warmup-from-output"));
+    config.set_ndjson(matches.opt_present("ndjson"));
+    config.set_two_way(matches.opt_present("two-way"));
+    config.set_fail_fast_corruption(matches.opt_present("fail-fast-corruption"));
+    config.set_send_latency(matches.opt_present("send-latency"));
+    config.set_recverr(matches.opt_present("recverr"));
+    config.set_separate_recv(matches.opt_present("separate-recv-socket"));
+    config.set_server_timestamps(matches.opt_present("server-timestamp"));
+    config.set_no_dns(matches.opt_present("no-dns"));
+    config.set_strict_order(matches.opt_present("strict-order"));
+    config.set_query_server(matches.opt_present("query-server"));
+    config.set_strict_timeout(matches.opt_present("strict-timeout"));
+    config.set_drain_remaining(matches.opt_present("drain-remaining-budget"));
+
     if let Some(output) = matches.opt_str("o") {
         config.set_output(output);
     }
 
+    if let Some(address) = matches.opt_str("report-to") {
+        config.set_report_to(address);
+    }
+
+    if let Some(dir) = matches.opt_str("output-dir") {
+        config.set_output_dir(dir);
+    }
+
+    match matches
+        .opt_str("max-tries-per-second-adaptive")
+        .map(|v| v.parse::<f64>())
+    {
+        Some(Ok(_)) if matches.opt_present("no-limit") => {
+            bail!("--max-tries-per-second-adaptive and --no-limit are mutually exclusive")
+        }
+        Some(Ok(rate)) => {
+            config
+                .set_max_total_rate(Some(rate))
+                .context("Invalid --max-tries-per-second-adaptive")?;
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse max tries per second"),
+        None => (),
+    }
+    if matches.opt_present("no-limit") {
+        config.set_max_total_rate(None)?;
+    }
+
+    if let Some(path) = matches.opt_str("output-timestamps-only") {
+        config.set_timestamps_only(path);
+    }
+
+    match matches.opt_str("i").map(|v| v.parse()) {
+        Some(Ok(interval)) => {
+            config.set_interval(std::time::Duration::from_millis(interval));
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse interval"),
+        None => (),
+    }
+
+    match matches.opt_str("poisson").map(|v| v.parse()) {
+        Some(Ok(rate)) => {
+            let seed = match matches.opt_str("poisson-seed").map(|v| v.parse()) {
+                Some(Ok(seed)) => seed,
+                Some(Err(e)) => return Err(e).context("Failed to parse poisson seed"),
+                None => 1,
+            };
+            config.set_poisson(rate, seed);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse poisson rate"),
+        None => (),
+    }
+
+    config.set_compact(matches.opt_present("compact"));
+
+    if let Some(pool) = matches.opt_str("source-pool") {
+        let pool = pool
+            .split(',')
+            .map(|address| address.trim().parse())
+            .collect::<Result<Vec<std::net::IpAddr>, _>>()
+            .context("Failed to parse source pool")?;
+        config.set_source_addresses(pool);
+    }
+
+    match matches.opt_str("b").map(|v| v.parse()) {
+        Some(Ok(address)) => {
+            config.set_bind_address(address);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse bind address"),
+        None => (),
+    }
+
+    match matches.opt_str("recv-concurrency").map(|v| v.parse()) {
+        Some(Ok(count)) => {
+            config.set_recv_concurrency(count);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse receive concurrency"),
+        None => (),
+    }
+
+    if matches.opt_present("source-randomize") {
+        match matches.opt_str("source-randomize").map(|v| v.parse()) {
+            Some(Ok(pool)) => {
+                config.set_source_randomize(pool);
+            }
+            Some(Err(e)) => return Err(e).context("Failed to parse source port pool"),
+            None => {
+                config.set_source_randomize(16);
+            }
+        }
+    }
+
+    match matches.opt_str("max-rtt").map(|v| v.parse()) {
+        Some(Ok(max_rtt)) => {
+            config.set_max_rtt(std::time::Duration::from_millis(max_rtt));
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse max rtt"),
+        None => (),
+    }
+
+    match matches.opt_str("connect-timeout").map(|v| v.parse()) {
+        Some(Ok(timeout)) => {
+            config.set_connect_timeout(std::time::Duration::from_millis(timeout));
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse connect timeout"),
+        None => (),
+    }
+
+    match matches.opt_str("read-timeout").map(|v| v.parse()) {
+        Some(Ok(timeout)) => {
+            config.set_read_timeout(std::time::Duration::from_millis(timeout));
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse read timeout"),
+        None => (),
+    }
+
+    match matches.opt_str("bytes").map(|v| client::parse_size(&v)) {
+        Some(Ok(bytes)) => {
+            config.set_bytes(bytes);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse bytes"),
+        None => (),
+    }
+
+    match matches
+        .opt_str("max-runtime-memory")
+        .map(|v| client::parse_size(&v))
+    {
+        Some(Ok(limit)) => {
+            config.set_max_memory(limit);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse memory limit"),
+        None => (),
+    }
+
+    match matches.opt_str("abort-after").map(|v| v.parse()) {
+        Some(Ok(count)) => {
+            config.set_abort_after(count);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse abort-after"),
+        None => (),
+    }
+
+    if matches.opt_present("mtu-probe") {
+        match matches.opt_str("mtu-probe").map(|v| v.parse()) {
+            Some(Ok(ceiling)) => {
+                config.set_mtu_probe(ceiling);
+            }
+            Some(Err(e)) => return Err(e).context("Failed to parse mtu probe ceiling"),
+            None => {
+                config.set_mtu_probe(1500);
+            }
+        }
+    }
+
+    if matches.opt_present("fragmentation") {
+        if !matches.opt_present("mtu-probe") {
+            bail!("--fragmentation needs --mtu-probe");
+        }
+        config.set_fragmentation(true);
+    }
+
+    if matches.opt_present("trace") {
+        match matches.opt_str("trace").map(|v| v.parse()) {
+            Some(Ok(max_ttl)) => {
+                config.set_trace(max_ttl);
+            }
+            Some(Err(e)) => return Err(e).context("Failed to parse maximum TTL"),
+            None => {
+                config.set_trace(30);
+            }
+        }
+    }
+
+    if let Some(ecn) = matches.opt_str("ecn") {
+        config.set_ecn(client::parse_ecn(&ecn)?);
+    }
+    if let Some(tos) = matches.opt_str("tos-verify") {
+        config.set_tos_verify(client::parse_tos(&tos)?);
+    }
+
+    config.set_embed_config(matches.opt_present("embed-config"));
+    config.set_json_compact(matches.opt_present("json-compact"));
+
+    if matches.opt_present("repeat-until-failure") {
+        match matches
+            .opt_str("repeat-until-failure")
+            .map(|v| v.parse::<f64>())
+        {
+            Some(Ok(percent)) => {
+                config.set_repeat_until_failure(percent / 100.0);
+            }
+            Some(Err(e)) => return Err(e).context("Failed to parse loss threshold"),
+            None => {
+                config.set_repeat_until_failure(0.0);
+            }
+        }
+    }
+
+    if matches.opt_present("count-per-second") {
+        let loss_target = match matches
+            .opt_str("count-per-second")
+            .map(|v| v.parse::<f64>())
+        {
+            Some(Ok(percent)) => percent / 100.0,
+            Some(Err(e)) => return Err(e).context("Failed to parse loss target"),
+            None => 0.01,
+        };
+        let step = match matches.opt_str("rate-step").map(|v| v.parse()) {
+            Some(Ok(step)) => step,
+            Some(Err(e)) => return Err(e).context("Failed to parse rate step"),
+            None => 100.0,
+        };
+        let phase = match matches.opt_str("phase-duration").map(|v| v.parse()) {
+            Some(Ok(ms)) => std::time::Duration::from_millis(ms),
+            Some(Err(e)) => return Err(e).context("Failed to parse phase duration"),
+            None => std::time::Duration::from_secs(1),
+        };
+        let max_phases = match matches.opt_str("max-phases").map(|v| v.parse()) {
+            Some(Ok(phases)) => phases,
+            Some(Err(e)) => return Err(e).context("Failed to parse max phases"),
+            None => 20,
+        };
+        config.set_rate_search(client::RateSearch {
+            loss_target,
+            step,
+            phase,
+            max_phases,
+        });
+    }
+
+    if matches.opt_present("probe-size-sweep") {
+        let sizes = match matches.opt_str("probe-size-sweep") {
+            Some(sizes) => client::parse_sizes(&sizes).context("Failed to parse sweep sizes")?,
+            None => client::DEFAULT_SWEEP.to_vec(),
+        };
+        config.set_size_sweep(sizes);
+    }
+
+    match matches.opt_str("warmup").map(|v| v.parse()) {
+        Some(Ok(count)) => {
+            config.set_warmup(count);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse warmup count"),
+        None => (),
+    }
+
+    match matches.opt_str("under-load").map(|v| v.parse()) {
+        Some(Ok(megabits)) => {
+            let size = match matches.opt_str("load-size").map(|v| client::parse_size(&v)) {
+                Some(Ok(size)) => size,
+                Some(Err(e)) => return Err(e).context("Failed to parse load size"),
+                None => 1200,
+            };
+            config.set_load(client::LoadParams { megabits, size });
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse load rate"),
+        None => (),
+    }
+
+    if matches.opt_present("preload") {
+        let count = match matches.opt_str("preload").map(|v| v.parse()) {
+            Some(Ok(count)) => count,
+            Some(Err(e)) => return Err(e).context("Failed to parse preload count"),
+            None => 3,
+        };
+        let wait = match matches.opt_str("preload-wait").map(|v| v.parse()) {
+            Some(Ok(ms)) => std::time::Duration::from_millis(ms),
+            Some(Err(e)) => return Err(e).context("Failed to parse preload wait"),
+            None => std::time::Duration::from_secs(1),
+        };
+        config.set_preload(count, wait);
+    }
+
+    match matches
+        .opt_str("recv-buffer")
+        .map(|v| client::parse_size(&v))
+    {
+        Some(Ok(size)) => {
+            config.set_recv_buffer(size);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse receive buffer size"),
+        None => (),
+    }
+
+    match matches.opt_str("no-delay").as_deref() {
+        Some("on") | None => (),
+        Some("off") => {
+            config.set_tcp_nodelay(false);
+        }
+        Some(other) => bail!("--no-delay takes on or off, got '{}'", other),
+    }
+
+    for tag in matches.opt_strs("tag") {
+        let (key, value) = client::parse_tag(&tag)?;
+        config.add_tag(key, value)?;
+    }
+
+    match matches.opt_str("duration").map(|v| v.parse()) {
+        Some(Ok(secs)) => {
+            config.set_duration(
+                std::time::Duration::try_from_secs_f64(secs).context("Failed to parse duration")?,
+            );
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse duration"),
+        None => (),
+    }
+
+    match matches.opt_str("tcp-pipeline").map(|v| v.parse()) {
+        Some(Ok(depth)) => {
+            config.set_tcp_pipeline(depth);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse pipeline depth"),
+        None => (),
+    }
+
+    match matches.opt_str("busy-poll-threshold").map(|v| v.parse()) {
+        Some(Ok(usecs)) => {
+            config.set_busy_poll_threshold(std::time::Duration::from_micros(usecs));
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse busy poll threshold"),
+        None => (),
+    }
+
+    match matches.opt_str("flush-interval").map(|v| v.parse()) {
+        Some(Ok(secs)) => {
+            config.set_flush_interval(std::time::Duration::from_secs(secs));
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse flush interval"),
+        None => (),
+    }
+
+    match matches.opt_str("max-iterations").map(|v| v.parse()) {
+        Some(Ok(iterations)) => {
+            config.set_max_iterations(iterations);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse max iterations"),
+        None => (),
+    }
+
+    match matches.opt_str("signal-rate").map(|v| v.parse()) {
+        Some(Ok(step)) => {
+            config.set_rate_step(step);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse rate step"),
+        None => (),
+    }
+
+    if matches.opt_present("print-config") {
+        println!("{}", config.to_bundle()?);
+        return Ok(());
+    }
+
+    run(config).await
+}
+
+/// The bundle `--seed-from-file` names. A bundle is the whole benchmark, so the arguments are
+/// parsed again with no other option known and any other option or a target fails.
+fn seed_path(args: &[String]) -> Result<String> {
+    let mut options = Options::new();
+    options.optopt("", "seed-from-file", "", "PATH");
+    let matches = match options.parse(args) {
+        Ok(matches) if matches.free.is_empty() => matches,
+        _ => bail!("--seed-from-file takes no other options or targets"),
+    };
+    matches
+        .opt_str("seed-from-file")
+        .context("--seed-from-file needs the PATH of a bundle")
+}
+
+async fn run(mut config: Config) -> Result<()> {
+    if let Some(live) = config.live_rate() {
+        install_rate_signals(live)?;
+    }
+    install_exit_signal(config.exit_flag())?;
+
     config.run().await?;
 
     Ok(())
 }
+
+/// Rate changed by the handler of `--signal-rate`.
+static LIVE_RATE: std::sync::OnceLock<std::sync::Arc<client::LiveRate>> =
+    std::sync::OnceLock::new();
+
+extern "C" fn rate_signal(signal: libc::c_int) {
+    if let Some(live) = LIVE_RATE.get() {
+        live.adjust(signal == libc::SIGUSR1);
+    }
+}
+
+/// Step `live` up on SIGUSR1 and down on SIGUSR2.
+fn install_rate_signals(live: std::sync::Arc<client::LiveRate>) -> Result<()> {
+    if LIVE_RATE.set(live).is_err() {
+        bail!("Rate signals are already installed");
+    }
+    for signal in [libc::SIGUSR1, libc::SIGUSR2] {
+        let handler = rate_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to install signal handler");
+        }
+    }
+    Ok(())
+}
+
+/// Exit flag of the run, set by the handler of SIGINT.
+static EXIT: std::sync::OnceLock<std::sync::Arc<std::sync::atomic::AtomicBool>> =
+    std::sync::OnceLock::new();
+
+extern "C" fn exit_signal(_: libc::c_int) {
+    if let Some(exit) = EXIT.get() {
+        // a second interrupt doesn't wait for the run to wind down
+        if exit.swap(true, std::sync::atomic::Ordering::Relaxed) {
+            unsafe { libc::_exit(130) };
+        }
+    }
+}
+
+/// Stop the run on SIGINT, so the results so far are still reported.
+fn install_exit_signal(exit: std::sync::Arc<std::sync::atomic::AtomicBool>) -> Result<()> {
+    if EXIT.set(exit).is_err() {
+        bail!("Exit signal is already installed");
+    }
+    let handler = exit_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGINT, handler) } == libc::SIG_ERR {
+        return Err(std::io::Error::last_os_error()).context("Failed to install signal handler");
+    }
+    Ok(())
+}
+
+fn compare(matches: &getopts::Matches) -> Result<()> {
+    let (baseline, candidate) = match matches.free.as_slice() {
+        [baseline, candidate] => (baseline, candidate),
+        _ => bail!("--compare takes exactly two reports, BASELINE CANDIDATE"),
+    };
+
+    let mut tolerance = client::Tolerance::default();
+    match matches.opt_str("loss-tolerance").map(|v| v.parse::<f64>()) {
+        Some(Ok(percent)) => tolerance.loss = percent / 100.0,
+        Some(Err(e)) => return Err(e).context("Failed to parse loss tolerance"),
+        None => (),
+    }
+    match matches.opt_str("rtt-tolerance").map(|v| v.parse::<f64>()) {
+        Some(Ok(percent)) => tolerance.rtt = percent / 100.0,
+        Some(Err(e)) => return Err(e).context("Failed to parse RTT tolerance"),
+        None => (),
+    }
+
+    let read = |path: &String| {
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read report {}", path))
+    };
+    let deltas = client::compare(&read(baseline)?, &read(candidate)?, tolerance)?;
+    for delta in &deltas {
+        println!("{}", delta);
+    }
+
+    let failed: Vec<&str> = deltas
+        .iter()
+        .filter(|d| !d.passed)
+        .map(|d| d.target.as_str())
+        .collect();
+    if !failed.is_empty() {
+        bail!("Regression against the baseline: {}", failed.join(", "));
+    }
+    Ok(())
+}
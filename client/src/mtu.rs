@@ -0,0 +1,203 @@
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use log::*;
+use packet::{UdpEchoBuilder, UdpEchoPacket};
+use serde::Serialize;
+
+use crate::socket;
+
+const UDP_HEADER: usize = 8;
+const PROBE_TRIES: usize = 3;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// IP packet sizes the fragmentation sweep tries, around common MTUs and jumbo frames.
+const SWEEP_SIZES: &[usize] = &[
+    576, 1280, 1400, 1480, 1492, 1500, 1501, 2000, 4000, 8000, 9000, 9001, 16000, 32000, 65535,
+];
+
+#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
+pub struct MtuResult<'a> {
+    pub target: &'a str,
+    /// Largest IP packet size that round-tripped without fragmentation.
+    pub mtu: Option<usize>,
+    /// Sizes that round-tripped fragmented, with `--fragmentation`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reassembly: Option<Reassembly>,
+}
+
+/// Outcome of sending datagrams without the DF bit, which routers and the sender may fragment.
+#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
+pub struct Reassembly {
+    /// Largest swept IP packet size whose echo came back complete.
+    pub largest: Option<usize>,
+    /// Every swept size and whether it round-tripped.
+    pub sizes: Vec<(usize, bool)>,
+}
+
+fn ip_header(addr: &SocketAddr) -> usize {
+    match addr {
+        SocketAddr::V4(_) => 20,
+        SocketAddr::V6(_) => 40,
+    }
+}
+
+async fn connect(addr: SocketAddr) -> Result<UdpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0").await,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0").await,
+    }
+    .context("Failed to bind socket")?;
+    socket.connect(addr).await.context("Failed to connect")?;
+    Ok(socket)
+}
+
+/// Binary-search the path MTU to `target`, starting from `ceiling` bytes. With `fragmentation`,
+/// also sweep sizes without the DF bit to find what survives fragmentation and reassembly.
+pub async fn probe<'a>(
+    target: &'a str,
+    identifier: u64,
+    ceiling: usize,
+    fragmentation: bool,
+    namespace: &str,
+) -> Result<MtuResult<'a>> {
+    let addr = target
+        .to_socket_addrs()
+        .await
+        .context("Failed to resolve target")?
+        .next()
+        .context("Target did not resolve to any address")?;
+
+    let socket = connect(addr).await?;
+    socket::set_dont_fragment(socket.as_raw_fd(), addr.is_ipv6())
+        .context("Failed to set DF bit")?;
+
+    let overhead = ip_header(&addr) + UDP_HEADER;
+    let minimum = overhead + UdpEchoPacket::minimum_packet_size();
+
+    // invariant: `low` is known to work (or is below the minimum), everything above `high` fails
+    let mut low = minimum - 1;
+    let mut high = ceiling;
+    let mut sequence = 0;
+    while low < high {
+        let size = (low + high).div_ceil(2);
+        let mut ok = false;
+        for _ in 0..PROBE_TRIES {
+            sequence += 1;
+            match probe_size(&socket, identifier, sequence, size - overhead).await {
+                Ok(true) => {
+                    ok = true;
+                    break;
+                }
+                Ok(false) => (),
+                Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => {
+                    trace!(target: namespace, "{}: {} bytes exceed local MTU", target, size);
+                    break;
+                }
+                Err(e) => warn!(target: namespace, "{}: failed to probe: {}", target, e),
+            }
+        }
+
+        debug!(target: namespace, "{}: probe {} bytes: {}", target, size, ok);
+        if ok {
+            low = size;
+        } else {
+            high = size - 1;
+        }
+    }
+
+    let reassembly = if fragmentation {
+        Some(sweep(target, addr, identifier, sequence, namespace).await?)
+    } else {
+        None
+    };
+
+    Ok(MtuResult {
+        target,
+        mtu: if low >= minimum { Some(low) } else { None },
+        reassembly,
+    })
+}
+
+/// Send every size of [`SWEEP_SIZES`] without the DF bit, continuing after `sequence`.
+async fn sweep(
+    target: &str,
+    addr: SocketAddr,
+    identifier: u64,
+    mut sequence: u64,
+    namespace: &str,
+) -> Result<Reassembly> {
+    let socket = connect(addr).await?;
+    socket::allow_fragment(socket.as_raw_fd(), addr.is_ipv6()).context("Failed to clear DF bit")?;
+
+    let overhead = ip_header(&addr) + UDP_HEADER;
+    let mut sizes = Vec::new();
+    for &size in SWEEP_SIZES {
+        let mut ok = false;
+        for _ in 0..PROBE_TRIES {
+            sequence += 1;
+            match probe_size(&socket, identifier, sequence, size - overhead).await {
+                Ok(true) => {
+                    ok = true;
+                    break;
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    warn!(target: namespace, "{}: failed to send {} bytes: {}", target, size, e);
+                    break;
+                }
+            }
+        }
+        debug!(target: namespace, "{}: fragmented {} bytes: {}", target, size, ok);
+        sizes.push((size, ok));
+    }
+
+    Ok(Reassembly {
+        largest: sizes
+            .iter()
+            .filter(|(_, ok)| *ok)
+            .map(|&(size, _)| size)
+            .max(),
+        sizes,
+    })
+}
+
+/// Send a single datagram of `len` bytes and wait for its complete echo.
+async fn probe_size(
+    socket: &UdpSocket,
+    identifier: u64,
+    sequence: u64,
+    len: usize,
+) -> std::io::Result<bool> {
+    let padding = vec![0u8; len - UdpEchoPacket::minimum_packet_size()];
+    let mut buf = vec![0u8; len];
+    UdpEchoBuilder::new()
+        .identifier(identifier)
+        .sequence(sequence)
+        .payload(&padding)
+        .build_into(&mut buf)
+        .expect("buffer is sized for the packet");
+
+    socket.send(&buf).await?;
+
+    let mut recv = vec![0u8; len + 1];
+    let deadline = async_std::future::timeout(PROBE_TIMEOUT, async {
+        loop {
+            let size = socket.recv(&mut recv).await?;
+            let echo = match UdpEchoPacket::new(&recv[..size]) {
+                Some(echo) => echo,
+                None => continue,
+            };
+            if echo.get_identifier() == identifier && echo.get_sequence() == sequence {
+                return Ok::<bool, std::io::Error>(size == len);
+            }
+        }
+    });
+
+    match deadline.await {
+        Ok(res) => res,
+        Err(_) => Ok(false),
+    }
+}
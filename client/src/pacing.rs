@@ -0,0 +1,425 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// Lowest rate `--signal-rate` steps down to, in packets per second.
+const MIN_LIVE_RATE: f64 = 1.0;
+
+/// Sends the `--max-tries-per-second-adaptive` bucket lets through at once, as time at its rate.
+const CAP_BURST: Duration = Duration::from_millis(10);
+
+/// Decides how long to wait between two sends to the same target.
+#[derive(Debug, Clone)]
+pub enum Pacer {
+    /// Send as fast as possible.
+    None,
+    /// Constant bit rate, `--interval`.
+    Fixed(Duration),
+    /// Exponentially distributed gaps with a mean rate of `rate` packets per second, `--poisson`.
+    Poisson { rate: f64, state: u64 },
+    /// Send slot `n` at `start + n * period`, `--duration`. Slots missed aren't skipped, their
+    /// packets go out late.
+    Schedule {
+        start: Instant,
+        period: Duration,
+        slot: u32,
+    },
+    /// Send packet `n` at `start + offsets[n]`, `--replay`. Like a schedule, missed send times
+    /// aren't skipped.
+    Replay {
+        start: Instant,
+        offsets: Vec<Duration>,
+        slot: usize,
+    },
+    /// Constant bit rate that changes while running, `--signal-rate`.
+    Live(Arc<LiveRate>),
+}
+
+impl Pacer {
+    pub fn poisson(rate: f64, seed: u64) -> Self {
+        Pacer::Poisson {
+            rate,
+            // xorshift gets stuck on zero
+            state: seed.max(1),
+        }
+    }
+
+    /// Spread `tries` packets evenly over `duration`, starting now.
+    pub fn schedule(tries: usize, duration: Duration) -> Self {
+        Pacer::Schedule {
+            start: Instant::now(),
+            period: duration / tries.max(1) as u32,
+            slot: 0,
+        }
+    }
+
+    /// Send at `offsets` after now, the first of them being the first send.
+    pub fn replay(offsets: Vec<Duration>) -> Self {
+        Pacer::Replay {
+            start: Instant::now(),
+            offsets,
+            slot: 0,
+        }
+    }
+
+    /// Delay before the next send.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        match self {
+            Pacer::None => None,
+            Pacer::Schedule {
+                start,
+                period,
+                slot,
+            } => {
+                *slot += 1;
+                Some((*start + *period * *slot).saturating_duration_since(Instant::now()))
+            }
+            Pacer::Replay {
+                start,
+                offsets,
+                slot,
+            } => {
+                *slot += 1;
+                let at = offsets.get(*slot)?;
+                Some((*start + *at).saturating_duration_since(Instant::now()))
+            }
+            Pacer::Fixed(interval) => Some(*interval),
+            Pacer::Live(live) => Some(Duration::from_secs_f64(1.0 / live.rate())),
+            Pacer::Poisson { rate, state } => {
+                // uniform in (0, 1], so the logarithm stays finite
+                let uniform = ((xorshift(state) >> 11) + 1) as f64 / (1u64 << 53) as f64;
+                Some(Duration::from_secs_f64(-uniform.ln() / *rate))
+            }
+        }
+    }
+
+    /// Wait for the next send slot. Returns how far a `--duration` schedule or a `--replay` trace
+    /// is behind that slot.
+    ///
+    /// With `busy_poll`, the last `busy_poll` of every wait spins on the clock instead of
+    /// sleeping, so coarse timers can't stretch short gaps.
+    pub async fn wait(&mut self, busy_poll: Option<Duration>) -> Option<Duration> {
+        if let Some(delay) = self.next_delay() {
+            match busy_poll {
+                Some(threshold) => {
+                    let deadline = Instant::now() + delay;
+                    if delay > threshold {
+                        async_std::task::sleep(delay - threshold).await;
+                    }
+                    // yield rather than spin in place, the other tasks of the executor thread
+                    // still have to run
+                    while Instant::now() < deadline {
+                        async_std::task::yield_now().await;
+                    }
+                }
+                None => async_std::task::sleep(delay).await,
+            }
+        }
+        match self {
+            Pacer::Schedule {
+                start,
+                period,
+                slot,
+            } => Some(Instant::now().saturating_duration_since(*start + *period * *slot)),
+            Pacer::Replay {
+                start,
+                offsets,
+                slot,
+            } => offsets
+                .get(*slot)
+                .map(|at| Instant::now().saturating_duration_since(*start + *at)),
+            _ => None,
+        }
+    }
+}
+
+/// A rate change of `--signal-rate`, `at` is the time since the run started.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct RateChange {
+    pub at: Duration,
+    /// Packets per second sent to every target.
+    pub rate: f64,
+}
+
+/// Send rate shared by the pacers of all targets, stepped up and down from a signal handler.
+#[derive(Debug)]
+pub struct LiveRate {
+    step: f64,
+    /// Packets per second, as the bits of an `f64` so the signal handler can update it.
+    rate: AtomicU64,
+    /// Set by [`LiveRate::adjust`] until the change is picked up by [`LiveRate::take_change`].
+    changed: AtomicBool,
+    timeline: Mutex<Vec<RateChange>>,
+}
+
+impl LiveRate {
+    pub fn new(step: f64) -> Self {
+        Self {
+            step,
+            rate: AtomicU64::new(MIN_LIVE_RATE.to_bits()),
+            changed: AtomicBool::new(false),
+            timeline: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        f64::from_bits(self.rate.load(Ordering::Relaxed))
+    }
+
+    /// Set the rate a run starts with and restart the timeline.
+    pub fn start(&self, rate: f64) {
+        self.rate.store(rate.to_bits(), Ordering::Relaxed);
+        self.changed.store(false, Ordering::Relaxed);
+        *self.timeline.lock().unwrap() = vec![RateChange {
+            at: Duration::ZERO,
+            rate,
+        }];
+    }
+
+    /// Step the rate up or down. Only touches atomics, so it is safe to call from a signal
+    /// handler.
+    pub fn adjust(&self, up: bool) {
+        let step = if up { self.step } else { -self.step };
+        let _ = self
+            .rate
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + step).max(MIN_LIVE_RATE).to_bits())
+            });
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// The current rate if it changed since the last call.
+    pub fn take_change(&self) -> Option<f64> {
+        if self.changed.swap(false, Ordering::Relaxed) {
+            Some(self.rate())
+        } else {
+            None
+        }
+    }
+
+    pub fn record(&self, change: RateChange) {
+        self.timeline.lock().unwrap().push(change);
+    }
+
+    pub fn timeline(&self) -> Vec<RateChange> {
+        self.timeline.lock().unwrap().clone()
+    }
+}
+
+/// Packets per second all targets together may send, `--max-tries-per-second-adaptive`.
+///
+/// A token bucket shared by the workers of every target. A send that finds it empty takes its
+/// tokens on credit and waits until they are paid off, so concurrent senders queue up behind
+/// each other instead of all waking at once.
+#[derive(Debug)]
+pub struct RateCap {
+    rate: f64,
+    burst: f64,
+    /// Tokens available at the given time, negative while sends wait for theirs.
+    state: Mutex<(f64, Instant)>,
+    /// Reservations that had to wait since the last restart.
+    throttled: AtomicU64,
+}
+
+impl RateCap {
+    /// A bucket letting `rate` sends through per second, failing unless the rate is finite and
+    /// positive.
+    pub fn new(rate: f64) -> Result<Self> {
+        if !(rate.is_finite() && rate > 0.0) {
+            bail!("The total rate must be positive, got {}", rate);
+        }
+        let burst = (rate * CAP_BURST.as_secs_f64()).max(1.0);
+        Ok(Self {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+            throttled: AtomicU64::new(0),
+        })
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Refill the bucket and reset the count of throttled sends for a new run.
+    pub fn restart(&self) {
+        *self.state.lock().unwrap() = (self.burst, Instant::now());
+        self.throttled.store(0, Ordering::Relaxed);
+    }
+
+    /// Take the tokens of `packets` sends, returning how long to wait before sending them and
+    /// whether this is the first wait since the restart.
+    pub fn reserve(&self, packets: usize) -> Option<(Duration, bool)> {
+        self.reserve_at(packets, Instant::now())
+    }
+
+    fn reserve_at(&self, packets: usize, now: Instant) -> Option<(Duration, bool)> {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        *tokens = (*tokens + now.saturating_duration_since(*last).as_secs_f64() * self.rate)
+            .min(self.burst);
+        *last = now.max(*last);
+
+        let packets = packets as f64;
+        let wait = if *tokens >= packets {
+            None
+        } else {
+            Some(Duration::from_secs_f64((packets - *tokens) / self.rate))
+        };
+        *tokens -= packets;
+        wait.map(|wait| (wait, self.throttled.fetch_add(1, Ordering::Relaxed) == 0))
+    }
+
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+}
+
+/// xorshift64*
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::{LiveRate, Pacer, RateCap};
+
+    #[test]
+    fn poisson_mean() {
+        let mut pacer = Pacer::poisson(1000.0, 7);
+        let delays: Vec<f64> = (0..20_000)
+            .map(|_| pacer.next_delay().unwrap().as_secs_f64())
+            .collect();
+
+        let mean = delays.iter().sum::<f64>() / delays.len() as f64;
+        assert!((mean - 0.001).abs() < 0.0001, "mean {}", mean);
+
+        // the exponential distribution has a coefficient of variation of 1
+        let var = delays.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / delays.len() as f64;
+        let cv = var.sqrt() / mean;
+        assert!((cv - 1.0).abs() < 0.05, "cv {}", cv);
+
+        let mut again = Pacer::poisson(1000.0, 7);
+        assert_eq!(again.next_delay().unwrap().as_secs_f64(), delays[0]);
+    }
+
+    #[test]
+    fn schedule() {
+        let mut pacer = Pacer::schedule(4, Duration::from_millis(400));
+        let first = pacer.next_delay().unwrap();
+        assert!(first <= Duration::from_millis(100) && first > Duration::from_millis(90));
+
+        // a send that took too long leaves later slots closer, not shifted
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(pacer.next_delay(), Some(Duration::ZERO));
+        let third = pacer.next_delay().unwrap();
+        assert!(third <= Duration::from_millis(50), "{:?}", third);
+
+        let lateness = async_std::task::block_on(pacer.wait(None)).unwrap();
+        assert!(lateness < Duration::from_millis(50), "{:?}", lateness);
+    }
+
+    #[test]
+    fn replay() {
+        let offsets = vec![Duration::ZERO, Duration::ZERO, Duration::from_millis(100)];
+        let mut pacer = Pacer::replay(offsets);
+        assert_eq!(pacer.next_delay(), Some(Duration::ZERO));
+        let third = pacer.next_delay().unwrap();
+        assert!(third <= Duration::from_millis(100) && third > Duration::from_millis(90));
+        // nothing to wait for past the end of the trace
+        assert_eq!(pacer.next_delay(), None);
+    }
+
+    #[test]
+    fn busy_poll() {
+        let gap = Duration::from_micros(200);
+        let mut pacer = Pacer::Fixed(gap);
+        let done = AtomicBool::new(false);
+        let mut polls = 0;
+        let waits = async {
+            for _ in 0..100 {
+                let start = Instant::now();
+                pacer.wait(Some(Duration::from_millis(1))).await;
+                assert!(start.elapsed() >= gap, "{:?}", start.elapsed());
+            }
+            done.store(true, Ordering::Relaxed);
+        };
+        // a task sharing the thread keeps running while the pacer spins
+        let other = async {
+            while !done.load(Ordering::Relaxed) {
+                polls += 1;
+                async_std::task::yield_now().await;
+            }
+        };
+        async_std::task::block_on(futures::future::join(waits, other));
+        assert!(polls > 0);
+    }
+
+    #[test]
+    fn live_rate() {
+        let live = std::sync::Arc::new(LiveRate::new(50.0));
+        live.start(100.0);
+        let mut pacer = Pacer::Live(live.clone());
+        assert_eq!(pacer.next_delay(), Some(Duration::from_millis(10)));
+        assert_eq!(live.take_change(), None);
+
+        live.adjust(true);
+        assert_eq!(
+            pacer.next_delay(),
+            Some(Duration::from_secs_f64(1.0 / 150.0))
+        );
+        assert_eq!(live.take_change(), Some(150.0));
+        assert_eq!(live.take_change(), None);
+
+        // never stops sending
+        for _ in 0..5 {
+            live.adjust(false);
+        }
+        assert_eq!(live.take_change(), Some(1.0));
+        assert_eq!(live.timeline().len(), 1);
+    }
+
+    #[test]
+    fn rate_cap() {
+        let cap = RateCap::new(1000.0).unwrap();
+        cap.restart();
+        let now = Instant::now();
+
+        // a burst of 10ms worth of sends passes right away
+        for _ in 0..10 {
+            assert_eq!(cap.reserve_at(1, now), None);
+        }
+        // the rest queue up behind each other, only the first of them counts as news
+        let (wait, first) = cap.reserve_at(1, now).unwrap();
+        assert!(first);
+        assert!((wait.as_secs_f64() - 0.001).abs() < 1e-6, "{:?}", wait);
+        let (wait, first) = cap.reserve_at(2, now).unwrap();
+        assert!(!first);
+        assert!((wait.as_secs_f64() - 0.003).abs() < 1e-6, "{:?}", wait);
+        assert_eq!(cap.throttled(), 2);
+
+        // once the debt is paid off, the bucket refills up to the burst
+        assert_eq!(cap.reserve_at(1, now + Duration::from_millis(4)), None);
+        assert_eq!(cap.reserve_at(9, now + Duration::from_millis(100)), None);
+        assert!(cap
+            .reserve_at(2, now + Duration::from_millis(100))
+            .is_some());
+
+        cap.restart();
+        assert_eq!(cap.throttled(), 0);
+
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(RateCap::new(rate).is_err(), "{}", rate);
+        }
+    }
+}
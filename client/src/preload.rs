@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_std::net::UdpSocket;
+use packet::{UdpEchoBuilder, UdpEchoPacket};
+
+/// Identifier of the preload packets, no target of a run gets it.
+const PRELOAD_IDENTIFIER: u64 = u64::MAX;
+
+/// Send `count` throwaway packets to `target` and wait up to `wait` for the first echo, so
+/// neighbor caches and routes are populated before the timed run. Returns whether an echo came
+/// back in time.
+pub async fn preload(
+    socket: &UdpSocket,
+    target: &str,
+    count: usize,
+    wait: Duration,
+) -> Result<bool> {
+    let mut buf = [0u8; 18];
+    for sequence in 0..count {
+        let len = UdpEchoBuilder::new()
+            .identifier(PRELOAD_IDENTIFIER)
+            .sequence(sequence as u64)
+            .payload(&[0])
+            .build_into(&mut buf)
+            .expect("buffer fits the packet");
+        socket
+            .send_to(&buf[..len], target)
+            .await
+            .with_context(|| format!("Failed to send preload packet to {}", target))?;
+    }
+
+    let answer = async {
+        let mut recv = vec![0u8; 65536];
+        loop {
+            if let Ok(size) = socket.recv(&mut recv).await {
+                if let Some(echo) = UdpEchoPacket::new(&recv[..size]) {
+                    if echo.get_identifier() == PRELOAD_IDENTIFIER {
+                        return;
+                    }
+                }
+            }
+        }
+    };
+    Ok(async_std::future::timeout(wait, answer).await.is_ok())
+}
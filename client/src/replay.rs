@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use packet::UdpEchoPacket;
+use serde::{Deserialize, Serialize};
+
+use crate::sweep::MAX_SWEEP_SIZE;
+
+/// A packet of a `--replay` trace.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct ReplayPacket {
+    /// Send time relative to the first packet of the trace.
+    pub at: Duration,
+    /// UDP payload bytes, header included, the size of the packet format if not given.
+    pub size: Option<usize>,
+}
+
+/// Read a `--replay` trace from `path`, see [`parse_replay`].
+pub fn load_replay(path: &str) -> Result<Vec<ReplayPacket>> {
+    let trace = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read replay trace '{}'", path))?;
+    parse_replay(&trace).with_context(|| format!("Invalid replay trace '{}'", path))
+}
+
+/// Parse a trace of one packet per line: its send time in seconds and optionally its size in
+/// bytes, separated by whitespace. Empty lines and lines starting with `#` are skipped. Send
+/// times must not decrease and are taken relative to the first one.
+pub fn parse_replay(trace: &str) -> Result<Vec<ReplayPacket>> {
+    // the plain format carries at least one byte of payload
+    let min = UdpEchoPacket::minimum_packet_size() + 1;
+    let mut ret: Vec<(f64, Option<usize>)> = Vec::new();
+    for (index, line) in trace.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let at = fields.next().expect("line is not empty");
+        let at: f64 = at
+            .parse()
+            .with_context(|| format!("Line {}: invalid send time '{}'", number, at))?;
+        if !at.is_finite() || at < 0.0 {
+            bail!("Line {}: negative or infinite send time {}", number, at);
+        }
+        if let Some(&(previous, _)) = ret.last() {
+            if at < previous {
+                bail!(
+                    "Line {}: send time {} is before the previous {}",
+                    number,
+                    at,
+                    previous
+                );
+            }
+        }
+        let size = match fields.next() {
+            Some(size) => {
+                let size: usize = size
+                    .parse()
+                    .with_context(|| format!("Line {}: invalid size '{}'", number, size))?;
+                if size < min || size > MAX_SWEEP_SIZE {
+                    bail!(
+                        "Line {}: size {} is outside of {} to {} bytes",
+                        number,
+                        size,
+                        min,
+                        MAX_SWEEP_SIZE
+                    );
+                }
+                Some(size)
+            }
+            None => None,
+        };
+        if let Some(extra) = fields.next() {
+            bail!("Line {}: unexpected '{}' after the size", number, extra);
+        }
+        ret.push((at, size));
+    }
+
+    let first = match ret.first() {
+        Some(&(first, _)) => first,
+        None => bail!("Trace has no packets"),
+    };
+    Ok(ret
+        .into_iter()
+        .map(|(at, size)| ReplayPacket {
+            at: Duration::from_secs_f64(at - first),
+            size,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse_replay, ReplayPacket};
+
+    #[test]
+    fn replay() {
+        let trace = parse_replay("# captured\n10.5 100\n\n10.5\n  11.25\t1400  \n").unwrap();
+        assert_eq!(
+            trace,
+            vec![
+                ReplayPacket {
+                    at: Duration::ZERO,
+                    size: Some(100),
+                },
+                ReplayPacket {
+                    at: Duration::ZERO,
+                    size: None,
+                },
+                ReplayPacket {
+                    at: Duration::from_millis(750),
+                    size: Some(1400),
+                },
+            ]
+        );
+
+        let error = |trace| format!("{:#}", parse_replay(trace).unwrap_err());
+        assert_eq!(
+            error("0\nx"),
+            "Line 2: invalid send time 'x': invalid float literal"
+        );
+        assert!(error("1\n0.5").starts_with("Line 2: send time 0.5 is before"));
+        assert!(error("0 10").starts_with("Line 1: size 10 is outside"));
+        assert!(error("0 1.5").starts_with("Line 1: invalid size"));
+        assert!(error("0 100 x").starts_with("Line 1: unexpected 'x'"));
+        assert_eq!(error("-1"), "Line 1: negative or infinite send time -1");
+        assert_eq!(error("# nothing\n"), "Trace has no packets");
+    }
+}
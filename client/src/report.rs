@@ -0,0 +1,406 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+use packet::EchoParams;
+use serde::{Deserialize, Serialize};
+
+use crate::pacing::RateChange;
+use crate::results::{JsonResultState, JsonResults, OneWayDelay};
+use crate::Config;
+
+/// Version of the report layout, bump whenever the serialized shape changes.
+pub const SCHEMA_VERSION: u32 = 32;
+
+/// Lateness a `--duration` schedule tolerates before counting a packet as late.
+pub const LATE_AFTER: Duration = Duration::from_millis(1);
+
+/// Top-level object written by the client.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Report<'a> {
+    pub schema_version: u32,
+    /// RFC 3339 timestamp of when the report was created.
+    pub generated_at: String,
+    /// Free-form metadata given with `--tag`.
+    pub tags: BTreeMap<String, String>,
+    /// Whether `TCP_NODELAY` was set, for TCP runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp_nodelay: Option<bool>,
+    /// Only written, a report read back never carries the config.
+    #[serde(skip_serializing_if = "Option::is_none", skip_deserializing)]
+    pub config: Option<&'a Config>,
+    /// Achieved goodput, when a byte count or `--tcp-pipeline` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub goodput: Option<Goodput>,
+    /// Rates set with `--signal-rate` and when, starting with the initial one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rate_timeline: Vec<RateChange>,
+    /// Packets per second the pacing asked for to every target, to hold against the achieved
+    /// `send_rate` of the summaries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requested_rate: Option<f64>,
+    /// What the run did about the sends left when its timeout expired, if it had one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_timeout: Option<OnTimeout>,
+    /// Probes the server sent back with `--two-way`, per target.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reverse: Vec<ReverseProbes>,
+    /// Echo modes the servers answered with `--query-server`, per target.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub server_params: Vec<ServerParams>,
+    /// How closely the sends followed the trace of `--replay`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay: Option<ReplaySummary>,
+    pub targets: Vec<TargetSummary>,
+    pub results: Vec<JsonResults>,
+}
+
+/// Sends left when the timeout of a run expires, see `--drain-remaining-budget`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum OnTimeout {
+    /// Stop right away, the rest is not sent.
+    Stop,
+    /// Send the rest without pacing, then wait for their echoes.
+    Drain,
+}
+
+/// Probes the server sent to a target's socket with `--two-way`. They only cross the path from
+/// the server to the client, the server logs the round trips of their reflections.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReverseProbes {
+    pub target: String,
+    pub requested: u64,
+    pub received: u64,
+    /// Loss of the path from the server to the client alone.
+    pub loss: f64,
+}
+
+impl ReverseProbes {
+    pub fn new(target: String, requested: u64, received: u64) -> Self {
+        Self {
+            target,
+            requested,
+            received,
+            loss: if requested > 0 {
+                requested.saturating_sub(received) as f64 / requested as f64
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// Echo modes of a target's server, as answered to `--query-server`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ServerParams {
+    pub target: String,
+    /// Whether the server answered, plain echo servers don't and are taken to echo as is.
+    pub answered: bool,
+    pub reorders: bool,
+    pub aggregates: bool,
+    pub delays: bool,
+    pub drops: bool,
+    pub resizes: bool,
+    pub corrupt_rate: f64,
+}
+
+impl ServerParams {
+    pub fn new(target: String, params: Option<EchoParams>) -> Self {
+        let answered = params.is_some();
+        let params = params.unwrap_or_default();
+        Self {
+            target,
+            answered,
+            reorders: params.reorders,
+            aggregates: params.aggregates,
+            delays: params.delays,
+            drops: params.drops,
+            resizes: params.resizes,
+            corrupt_rate: params.corrupt_rate,
+        }
+    }
+}
+
+/// Adherence of the sends to the trace of `--replay`, over all targets.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReplaySummary {
+    /// Packets in the trace.
+    pub trace_packets: usize,
+    /// Packets replayed to every target, a trace longer than the tries is cut short.
+    pub replayed: usize,
+    /// Mean time the sends went out behind their time in the trace.
+    pub mean_lateness: Option<Duration>,
+    pub max_lateness: Option<Duration>,
+    /// Sends more than [`LATE_AFTER`] behind their time in the trace.
+    pub late: usize,
+}
+
+impl ReplaySummary {
+    pub fn new(trace_packets: usize, replayed: usize, results: &[JsonResults]) -> Self {
+        let lateness: Vec<Duration> = results.iter().filter_map(|r| r.lateness).collect();
+        Self {
+            trace_packets,
+            replayed,
+            mean_lateness: (!lateness.is_empty())
+                .then(|| lateness.iter().sum::<Duration>() / lateness.len() as u32),
+            max_lateness: lateness.iter().max().copied(),
+            late: lateness.iter().filter(|&&l| l > LATE_AFTER).count(),
+        }
+    }
+}
+
+/// Aggregated view of the results of a single target.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TargetSummary {
+    pub identifier: u64,
+    pub target: String,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub not_sent: usize,
+    pub mean_rtt: Option<Duration>,
+    pub p99_rtt: Option<Duration>,
+    pub max_rtt: Option<Duration>,
+    /// How far the p99 RTT exceeds `--max-rtt`, if it does.
+    pub rtt_breach: Option<Duration>,
+    /// Mean split of the RTT with `--server-timestamp`, see [`OneWayDelay`] for the clock caveats.
+    pub mean_one_way: Option<OneWayDelay>,
+    /// Sequences that arrived after a higher sequence, in arrival order.
+    pub out_of_order: Vec<u64>,
+    /// Echoes the server received with the CE codepoint set.
+    pub ce_marked: usize,
+    /// Echoes that filled the whole receive buffer, see `--recv-buffer`.
+    #[serde(default)]
+    pub truncated: usize,
+    /// Echoes whose payload differs from the one sent.
+    #[serde(default)]
+    pub corrupted: usize,
+    /// Number of echoes received per CPU, if recorded.
+    pub cpus: BTreeMap<u32, usize>,
+    /// Number of ICMP errors received per kind, with `--recverr`.
+    pub icmp_errors: BTreeMap<String, usize>,
+    /// Fraction of the reflected echoes whose TOS byte arrived unchanged, with `--tos-verify`.
+    pub tos_preserved: Option<f64>,
+    /// Number of echoes per TOS byte the server saw instead of the one sent.
+    pub tos_remapped: BTreeMap<u8, usize>,
+    /// The per-sample results of this target were dropped by `--max-runtime-memory`, its
+    /// percentiles are within 1% of the exact ones.
+    pub summary_only: bool,
+    /// Achieved packets per second.
+    pub send_rate: Option<f64>,
+    /// Coefficient of variation of the gaps between sends, 0 for constant pacing.
+    pub send_interval_cv: Option<f64>,
+    /// Median duration of the send call, with `--send-latency`.
+    pub p50_send_latency: Option<Duration>,
+    /// 99th percentile duration of the send call, with `--send-latency`.
+    pub p99_send_latency: Option<Duration>,
+    /// Longest send call, with `--send-latency`.
+    pub max_send_latency: Option<Duration>,
+    /// Furthest a packet was sent behind its `--duration` slot.
+    pub max_lateness: Option<Duration>,
+    /// Packets sent more than [`LATE_AFTER`] behind their slot.
+    #[serde(default)]
+    pub late: usize,
+    /// Sequences flagged by `--warmup`, not part of any other figure of the summary.
+    #[serde(default)]
+    pub warmup: usize,
+    /// Why the target stopped sending before its budget was spent, if it did.
+    #[serde(default)]
+    pub aborted: Option<AbortReason>,
+}
+
+/// Why a target stopped sending early.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AbortReason {
+    /// `--abort-after` saw `failures` unanswered sequences in a row before sending `sequence`.
+    ConsecutiveFailures { failures: usize, sequence: u64 },
+}
+
+/// Bytes echoed back over the whole run.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Goodput {
+    pub bytes: usize,
+    pub duration: Duration,
+    pub bytes_per_second: f64,
+}
+
+impl Goodput {
+    pub fn new(bytes: usize, duration: Duration) -> Self {
+        let secs = duration.as_secs_f64();
+        Self {
+            bytes,
+            duration,
+            bytes_per_second: if secs > 0.0 { bytes as f64 / secs } else { 0.0 },
+        }
+    }
+}
+
+impl TargetSummary {
+    /// A summary of `target` before any of its results were counted.
+    pub(crate) fn empty(identifier: u64, target: String) -> Self {
+        Self {
+            identifier,
+            target,
+            succeeded: 0,
+            failed: 0,
+            not_sent: 0,
+            mean_rtt: None,
+            p99_rtt: None,
+            max_rtt: None,
+            rtt_breach: None,
+            mean_one_way: None,
+            out_of_order: Vec::new(),
+            ce_marked: 0,
+            truncated: 0,
+            corrupted: 0,
+            cpus: BTreeMap::new(),
+            icmp_errors: BTreeMap::new(),
+            tos_preserved: None,
+            tos_remapped: BTreeMap::new(),
+            summary_only: false,
+            send_rate: None,
+            send_interval_cv: None,
+            p50_send_latency: None,
+            p99_send_latency: None,
+            max_send_latency: None,
+            max_lateness: None,
+            late: 0,
+            warmup: 0,
+            aborted: None,
+        }
+    }
+
+    /// Count `result` towards the figures that don't need the other results of the target.
+    pub(crate) fn count(&mut self, result: &JsonResults) {
+        if result.warmup {
+            self.warmup += 1;
+            return;
+        }
+
+        match result.state {
+            JsonResultState::Succeded(_) => self.succeeded += 1,
+            JsonResultState::Failed => self.failed += 1,
+            JsonResultState::NotSent => self.not_sent += 1,
+        }
+        if result.ecn == Some(0b11) {
+            self.ce_marked += 1;
+        }
+        if result.truncated {
+            self.truncated += 1;
+        }
+        if result.corrupt {
+            self.corrupted += 1;
+        }
+        if let Some(lateness) = result.lateness {
+            self.max_lateness = self.max_lateness.max(Some(lateness));
+            if lateness > LATE_AFTER {
+                self.late += 1;
+            }
+        }
+        if let Some(cpu) = result.cpu {
+            *self.cpus.entry(cpu).or_insert(0) += 1;
+        }
+        if let Some(error) = result.icmp_error {
+            *self.icmp_errors.entry(error.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    /// Summarize `results` per target, ordered by identifier.
+    pub fn from_results(results: &[JsonResults]) -> Vec<Self> {
+        let mut ret: Vec<Self> = Vec::new();
+        for result in results {
+            let summary = match ret.iter_mut().find(|s| s.identifier == result.identifier) {
+                Some(summary) => summary,
+                None => {
+                    ret.push(Self::empty(result.identifier, result.target.clone()));
+                    ret.last_mut().unwrap()
+                }
+            };
+            summary.count(result);
+        }
+
+        for summary in &mut ret {
+            let own: Vec<JsonResults> = results
+                .iter()
+                .filter(|r| r.identifier == summary.identifier && !r.warmup)
+                .cloned()
+                .collect();
+            summary.mean_rtt = JsonResults::mean_rtt(&own);
+            summary.p99_rtt = JsonResults::rtt_percentile(&own, 99.0);
+            summary.max_rtt = JsonResults::rtt_percentile(&own, 100.0);
+            summary.mean_one_way = JsonResults::mean_one_way(&own);
+            summary.out_of_order = JsonResults::out_of_order(&own);
+            summary.p50_send_latency = JsonResults::send_latency_percentile(&own, 50.0);
+            summary.p99_send_latency = JsonResults::send_latency_percentile(&own, 99.0);
+            summary.max_send_latency = JsonResults::send_latency_percentile(&own, 100.0);
+            if let Some((rate, cv)) = JsonResults::send_rate(&own) {
+                summary.send_rate = Some(rate);
+                summary.send_interval_cv = Some(cv);
+            }
+        }
+
+        ret.sort_by_key(|s| s.identifier);
+        ret
+    }
+}
+
+impl<'a> Report<'a> {
+    pub fn new(results: Vec<JsonResults>) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            generated_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            tags: BTreeMap::new(),
+            tcp_nodelay: None,
+            config: None,
+            goodput: None,
+            rate_timeline: Vec::new(),
+            requested_rate: None,
+            on_timeout: None,
+            reverse: Vec::new(),
+            server_params: Vec::new(),
+            replay: None,
+            targets: TargetSummary::from_results(&results),
+            results,
+        }
+    }
+
+    /// Flag the targets whose p99 RTT exceeds `limit`, returning them.
+    pub fn check_max_rtt(&mut self, limit: Duration) -> Vec<&TargetSummary> {
+        for summary in &mut self.targets {
+            summary.rtt_breach = summary
+                .p99_rtt
+                .filter(|&p99| p99 > limit)
+                .map(|p99| p99 - limit);
+        }
+        self.targets
+            .iter()
+            .filter(|s| s.rtt_breach.is_some())
+            .collect()
+    }
+
+    /// Compare the TOS bytes reflected by the server against the `sent` one.
+    pub fn check_tos(&mut self, sent: u8) {
+        for summary in &mut self.targets {
+            let identifier = summary.identifier;
+            let mut reflected = 0;
+            let mut preserved = 0;
+            // summary-only targets come with every TOS byte they saw counted in `tos_remapped`
+            let counted = std::mem::take(&mut summary.tos_remapped);
+            let seen = self
+                .results
+                .iter()
+                .filter(|r| r.identifier == identifier)
+                .filter_map(|r| r.tos.map(|tos| (tos, 1)))
+                .chain(counted);
+            for (tos, count) in seen {
+                reflected += count;
+                if tos == sent {
+                    preserved += count;
+                } else {
+                    *summary.tos_remapped.entry(tos).or_insert(0) += count;
+                }
+            }
+            if reflected > 0 {
+                summary.tos_preserved = Some(preserved as f64 / reflected as f64);
+            }
+        }
+    }
+}
@@ -1,84 +1,651 @@
-use std::collections::HashMap;
-use std::process::id;
-use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{bail, Context, Result};
 use async_std::sync::Mutex;
 use log::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{Clock, SystemClock};
+use crate::report::TargetSummary;
+use crate::streaming::StreamingSummary;
+
+/// Estimated memory held per stored sample while collecting and writing the report: the
+/// in-flight entry, the finished entry and its serialized form.
+const SAMPLE_FOOTPRINT: usize =
+    std::mem::size_of::<ResultsValue>() + std::mem::size_of::<JsonResults>() + 256;
 
 #[derive(Debug)]
 pub struct Results<'a> {
-    pub results: Mutex<HashMap<u64, Vec<ResultsValue<'a>>>>,
+    results: Mutex<HashMap<u64, TargetResults<'a>>>,
     pub targets: HashMap<&'a str, u64>,
+    /// Reference point for the send times in the output.
+    epoch: Instant,
+    /// Wall clock time at `epoch`, to compare send times with server timestamps.
+    epoch_wall: SystemTime,
+    clock: Arc<dyn Clock>,
+    on_result: Option<OnResult>,
+    /// Sequences at the start of every target flagged as warmup.
+    warmup: u64,
+}
+
+/// Results of a single target.
+#[derive(Debug)]
+struct TargetResults<'a> {
+    target: &'a str,
+    tries: u64,
+    /// Address the packets are sent from, see [`Results::set_local`].
+    local: Option<SocketAddr>,
+    samples: Samples<'a>,
+}
+
+#[derive(Debug)]
+enum Samples<'a> {
+    /// Every sequence of the target, created up front by [`Results::prime`].
+    All(Vec<ResultsValue<'a>>),
+    /// Only the sequences that aren't final yet, the final ones are folded into `summary`, see
+    /// [`Results::limit_memory`]. Sequences from `next` on were never touched.
+    Summary {
+        pending: BTreeMap<u64, ResultsValue<'a>>,
+        summary: Box<StreamingSummary>,
+        next: u64,
+    },
+}
+
+impl<'a> TargetResults<'a> {
+    fn new(target: &'a str, tries: u64) -> Self {
+        let values = (0..tries)
+            .map(|x| ResultsValue::new(x, target))
+            .collect::<Vec<_>>();
+        Self {
+            target,
+            tries,
+            local: None,
+            samples: Samples::All(values),
+        }
+    }
+
+    /// A fresh entry for `seq`.
+    fn value(&self, seq: u64) -> ResultsValue<'a> {
+        ResultsValue {
+            local: self.local,
+            ..ResultsValue::new(seq, self.target)
+        }
+    }
+
+    /// The entry of `seq`. A summary-only target creates it along with the ones before it if it
+    /// wasn't touched yet, and returns `None` if `seq` is already folded into the summary,
+    /// updates of it are dropped then.
+    fn entry(&mut self, seq: u64) -> Result<Option<&mut ResultsValue<'a>>> {
+        if seq >= self.tries {
+            bail!("sequence not valid");
+        }
+        let (target, local) = (self.target, self.local);
+        match &mut self.samples {
+            Samples::All(values) => Ok(values.get_mut(seq as usize)),
+            Samples::Summary { pending, next, .. } => {
+                let fresh = |seq| ResultsValue {
+                    local,
+                    ..ResultsValue::new(seq, target)
+                };
+                pending.extend((*next..=seq).map(|seq| (seq, fresh(seq))));
+                *next = (*next).max(seq + 1);
+                Ok(pending.get_mut(&seq))
+            }
+        }
+    }
+
+    /// Number of samples held in memory.
+    fn stored(&self) -> usize {
+        match &self.samples {
+            Samples::All(values) => values.len(),
+            Samples::Summary { pending, .. } => pending.len(),
+        }
+    }
+}
+
+/// Callback invoked with every result once it is final, see [`crate::Config::set_on_result`].
+#[derive(Clone)]
+pub struct OnResult(pub Arc<dyn Fn(&JsonResults) + Send + Sync>);
+
+impl std::fmt::Debug for OnResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OnResult")
+    }
 }
 
 impl<'a> Results<'a> {
     pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    /// Take all timestamps from `clock` instead of the system clock.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
         Self {
             results: Mutex::new(HashMap::new()),
             targets: HashMap::new(),
+            epoch: clock.now(),
+            epoch_wall: SystemTime::now(),
+            clock,
+            on_result: None,
+            warmup: 0,
+        }
+    }
+
+    /// Pass every result to `hook` as soon as it is final.
+    pub fn set_on_result(&mut self, hook: OnResult) {
+        self.on_result = Some(hook);
+    }
+
+    /// Flag the first `count` sequences of every target as warmup.
+    pub fn set_warmup(&mut self, count: u64) {
+        self.warmup = count;
+    }
+
+    /// Hand a result that just became final to the hook, once.
+    fn notify(&self, identifier: u64, result: &mut ResultsValue) {
+        if let Some(hook) = &self.on_result {
+            if !result.reported {
+                result.reported = true;
+                (hook.0)(&self.json(identifier, result));
+            }
         }
     }
 
-    pub async fn prime(&mut self, addresses: &'a Vec<String>, tries: usize) {
-        let mut results = self.results.lock().await;
-        let mut identifier = 0;
+    /// Hand the untouched `sequences` to the hook as not sent, without creating their entries.
+    fn notify_untouched(
+        &self,
+        identifier: u64,
+        target: &TargetResults,
+        sequences: std::ops::Range<u64>,
+    ) {
+        if self.on_result.is_some() {
+            for seq in sequences {
+                let mut res = target.value(seq);
+                res.state = ResultsState::NotSent;
+                self.notify(identifier, &mut res);
+            }
+        }
+    }
 
-        for address in addresses {
-            let mut target = Vec::new();
-            for x in 0..tries {
-                target.push(ResultsValue::new(x as u64, address));
+    /// Hand the result of `seq` that just became final to the hook, and fold it into the
+    /// summary if the target is summary-only.
+    fn settle(&self, identifier: u64, target: &mut TargetResults<'a>, seq: u64) {
+        match &mut target.samples {
+            Samples::All(values) => self.notify(identifier, &mut values[seq as usize]),
+            Samples::Summary {
+                pending, summary, ..
+            } => {
+                if let Some(mut res) = pending.remove(&seq) {
+                    self.notify(identifier, &mut res);
+                    summary.add(&self.json(identifier, &res));
+                }
             }
+        }
+    }
 
-            results.insert(identifier, target);
+    /// Switch `target` to summary-only results, folding the samples that are final already
+    /// into the summary and dropping them along with the untouched ones.
+    fn summarize(&self, identifier: u64, target: &mut TargetResults<'a>) {
+        let mut values = match &mut target.samples {
+            Samples::All(values) => std::mem::take(values),
+            Samples::Summary { .. } => return,
+        };
+        // the preallocated entries no sequence got to yet are created again when needed
+        let next = values
+            .iter()
+            .rposition(|res| *res != target.value(res.sequence))
+            .map_or(0, |last| last + 1);
+        values.truncate(next);
+        let next = next as u64;
+        let mut summary = StreamingSummary::new(identifier, target.target.to_string());
+        let mut pending = BTreeMap::new();
+        let mut settled = Vec::new();
+        for res in values {
+            let json = self.json(identifier, &res);
+            if let (Some(sent), false) = (json.sent_at, json.warmup) {
+                summary.add_send(sent);
+            }
+            match res.state {
+                ResultsState::None | ResultsState::Started(_) => {
+                    pending.insert(res.sequence, res);
+                }
+                _ => settled.push(json),
+            }
+        }
+        // the summary takes the echoes in the order they arrived
+        settled.sort_by_key(|result| match (result.sent_at, &result.state) {
+            (Some(sent), JsonResultState::Succeded(rtt)) => sent + *rtt,
+            _ => Duration::ZERO,
+        });
+        for result in &settled {
+            summary.add(result);
+        }
+        target.samples = Samples::Summary {
+            pending,
+            summary: Box::new(summary),
+            next,
+        };
+    }
+
+    /// Create the entries for `tries[i]` sequences of every target `addresses[i]`.
+    pub fn prime(&mut self, addresses: &'a [String], tries: &[usize]) {
+        // nothing is shared yet, so there is no need to go through the lock
+        let results = self.results.get_mut();
+        for ((identifier, address), &tries) in (0..).zip(addresses).zip(tries) {
+            results.insert(identifier, TargetResults::new(address, tries as u64));
             self.targets.insert(address, identifier);
-            identifier += 1;
         }
     }
 
+    /// Record the echo of a sequence. Returns whether it changed the state of the sequence.
     // TODO: create internal thread, so this is instant/sync
-    pub async fn recv_packet(&self, identifier: u64, seq: u64) -> Result<()> {
-        let now = Instant::now();
+    pub async fn recv_packet(&self, identifier: u64, seq: u64, info: RecvInfo) -> Result<bool> {
+        let now = self.clock.now();
         let mut cache = self.results.lock().await;
-        let mut target = cache.get_mut(&identifier).context("identifier not valid")?;
-        let mut res = target.get_mut(seq as usize).context("sequence not valid")?;
-        res.recieved(seq, now)?;
+        let target = cache.get_mut(&identifier).context("identifier not valid")?;
+        let changed = match target.entry(seq)? {
+            Some(res) => res.recieved(seq, now, info)?,
+            // folded into the summary already
+            None => false,
+        };
+        if changed {
+            self.settle(identifier, target, seq);
+        }
+        Ok(changed)
+    }
+
+    /// Record that a sequence was just sent to `remote`, the address the target resolved to.
+    pub async fn start_packet(&self, idenifier: u64, seq: u64, remote: SocketAddr) -> Result<()> {
+        let now = self.clock.now();
+        let mut cache = self.results.lock().await;
+        let target = cache.get_mut(&idenifier).context("identfifier not valid")?;
+        if let Some(res) = target.entry(seq)? {
+            res.start(seq, now)?;
+            res.remote = Some(canonical(remote));
+            if let Samples::Summary { summary, .. } = &mut target.samples {
+                if seq >= self.warmup {
+                    summary.add_send(now.duration_since(self.epoch));
+                }
+            }
+        }
         Ok(())
     }
 
-    pub async fn start_packet(&self, idenifier: u64, seq: u64) -> Result<()> {
-        let now = Instant::now();
+    /// Count the failed sequences before `upto` since the last success, stopping at `limit`,
+    /// and the ones among them still waiting for a response for less than `window`, which are
+    /// not counted as failed.
+    pub async fn consecutive_failures(
+        &self,
+        identifier: u64,
+        upto: u64,
+        window: Duration,
+        limit: usize,
+    ) -> Result<(usize, usize)> {
+        let now = self.clock.now();
+        let cache = self.results.lock().await;
+        let target = cache.get(&identifier).context("identifier not valid")?;
+
+        let (mut failed, mut waiting) = (0, 0);
+        match &target.samples {
+            Samples::All(values) => {
+                for res in values[..(upto as usize).min(values.len())].iter().rev() {
+                    match res.state {
+                        ResultsState::Succeded(_) => break,
+                        ResultsState::Started(then) if now.duration_since(then) < window => {
+                            waiting += 1
+                        }
+                        ResultsState::Started(_) | ResultsState::Failed => failed += 1,
+                        ResultsState::None | ResultsState::NotSent => (),
+                    }
+                    if failed >= limit {
+                        break;
+                    }
+                }
+            }
+            Samples::Summary {
+                pending,
+                summary,
+                next,
+            } => {
+                // whatever was folded since the last success failed
+                let since = summary.last_success().map_or(0, |seq| seq + 1);
+                for seq in (since..upto.min(*next)).rev() {
+                    match pending.get(&seq).map(|res| res.state) {
+                        Some(ResultsState::Started(then)) if now.duration_since(then) < window => {
+                            waiting += 1
+                        }
+                        Some(ResultsState::Started(_)) | Some(ResultsState::Failed) | None => {
+                            failed += 1
+                        }
+                        Some(_) => (),
+                    }
+                    if failed >= limit {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok((failed, waiting))
+    }
+
+    /// Record the local address the target's packets are sent from.
+    pub async fn set_local(&self, identifier: u64, local: SocketAddr) -> Result<()> {
+        let mut cache = self.results.lock().await;
+        let target = cache.get_mut(&identifier).context("identifier not valid")?;
+        let local = Some(canonical(local));
+        target.local = local;
+        match &mut target.samples {
+            Samples::All(values) => values.iter_mut().for_each(|res| res.local = local),
+            Samples::Summary { pending, .. } => {
+                pending.values_mut().for_each(|res| res.local = local)
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the local address a single sequence was sent from.
+    pub async fn set_sample_local(
+        &self,
+        identifier: u64,
+        seq: u64,
+        local: SocketAddr,
+    ) -> Result<()> {
+        let mut cache = self.results.lock().await;
+        let target = cache.get_mut(&identifier).context("identifier not valid")?;
+        if let Some(res) = target.entry(seq)? {
+            res.local = Some(canonical(local));
+        }
+        Ok(())
+    }
+
+    /// Record how long the send call of a sequence took.
+    pub async fn set_send_latency(
+        &self,
+        identifier: u64,
+        seq: u64,
+        latency: Duration,
+    ) -> Result<()> {
         let mut cache = self.results.lock().await;
-        let mut target = cache.get_mut(&idenifier).context("identfifier not valid")?;
-        let mut res = target.get_mut(seq as usize).context("sequcene not valid")?;
-        res.start(seq, now)?;
+        let target = cache.get_mut(&identifier).context("identifier not valid")?;
+        if let Some(res) = target.entry(seq)? {
+            res.send_latency = Some(latency);
+        }
+        Ok(())
+    }
+
+    /// Record how far behind its `--duration` slot a sequence was sent.
+    pub async fn set_lateness(&self, identifier: u64, seq: u64, lateness: Duration) -> Result<()> {
+        let mut cache = self.results.lock().await;
+        let target = cache.get_mut(&identifier).context("identifier not valid")?;
+        if let Some(res) = target.entry(seq)? {
+            res.lateness = Some(lateness);
+        }
+        Ok(())
+    }
+
+    /// Record the ICMP error a sequence ran into.
+    pub async fn set_icmp_error(&self, identifier: u64, seq: u64, error: IcmpError) -> Result<()> {
+        let mut cache = self.results.lock().await;
+        let target = cache.get_mut(&identifier).context("identifier not valid")?;
+        if let Some(res) = target.entry(seq)? {
+            res.icmp_error = Some(error);
+        }
+        Ok(())
+    }
+
+    /// Mark a sequence as failed because `phase` ran into `error`.
+    pub async fn failed(
+        &self,
+        identifier: u64,
+        seq: u64,
+        phase: TimeoutPhase,
+        error: String,
+    ) -> Result<()> {
+        let mut cache = self.results.lock().await;
+        let target = cache.get_mut(&identifier).context("identifier not valid")?;
+        if let Some(res) = target.entry(seq)? {
+            res.state = ResultsState::Failed;
+            res.error = Some(TryError {
+                phase,
+                message: error,
+            });
+            self.settle(identifier, target, seq);
+        }
         Ok(())
     }
 
-    pub async fn finish(self) -> Vec<JsonResults<'a>> {
+    /// Mark every sequence from `from` on as not sent.
+    pub async fn abort(&self, identifier: u64, from: u64) -> Result<()> {
+        let mut cache = self.results.lock().await;
+        let target = cache.get_mut(&identifier).context("identifier not valid")?;
+        match &mut target.samples {
+            Samples::All(values) => {
+                for res in values.iter_mut().skip(from as usize) {
+                    res.state = ResultsState::NotSent;
+                    self.notify(identifier, res);
+                }
+            }
+            Samples::Summary {
+                pending,
+                summary,
+                next,
+            } => {
+                for (_, mut res) in pending.split_off(&from) {
+                    res.state = ResultsState::NotSent;
+                    self.notify(identifier, &mut res);
+                    summary.add(&self.json(identifier, &res));
+                }
+                // all of them are accounted for now, not only the ones from `from` on
+                let untouched = std::mem::replace(next, target.tries)..target.tries;
+                summary.add_not_sent(untouched.clone(), self.warmup);
+                self.notify_untouched(identifier, target, untouched);
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark a sequence as failed because `phase` timed out.
+    pub async fn timed_out(&self, identifier: u64, seq: u64, phase: TimeoutPhase) -> Result<()> {
+        let mut cache = self.results.lock().await;
+        let target = cache.get_mut(&identifier).context("identifier not valid")?;
+        if let Some(res) = target.entry(seq)? {
+            res.state = ResultsState::Failed;
+            res.timed_out = Some(phase);
+            self.settle(identifier, target, seq);
+        }
+        Ok(())
+    }
+
+    /// Settle whatever is left as failed or not sent. Returns the per-sample results and the
+    /// summaries of the summary-only targets.
+    pub async fn finish(self) -> (Vec<JsonResults>, Vec<TargetSummary>) {
+        let mut cache = self.results.lock().await;
+        for (&identifier, target) in cache.iter_mut() {
+            match &mut target.samples {
+                Samples::All(values) => {
+                    if self.on_result.is_some() {
+                        for res in values.iter_mut() {
+                            self.notify(identifier, res);
+                        }
+                    }
+                }
+                Samples::Summary {
+                    pending,
+                    summary,
+                    next,
+                } => {
+                    for (_, mut res) in std::mem::take(pending) {
+                        self.notify(identifier, &mut res);
+                        summary.add(&self.json(identifier, &res));
+                    }
+                    let untouched = *next..target.tries;
+                    summary.add_not_sent(untouched.clone(), self.warmup);
+                    self.notify_untouched(identifier, target, untouched);
+                }
+            }
+        }
+        drop(cache);
+        let summaries = self.summaries().await;
+        (self.collect(false).await, summaries)
+    }
+
+    /// Copy out the sequences that are settled so far, leaving the run untouched. Sequences not
+    /// sent yet or still waiting for their echo are left out, and so are summary-only targets.
+    pub async fn snapshot(&self) -> Vec<JsonResults> {
+        self.collect(true).await
+    }
+
+    /// Summaries of the summary-only targets, without the sequences still in flight.
+    pub async fn summaries(&self) -> Vec<TargetSummary> {
+        let cache = self.results.lock().await;
+        cache
+            .values()
+            .filter_map(|target| match &target.samples {
+                Samples::Summary { summary, .. } => Some(summary.summary()),
+                Samples::All(_) => None,
+            })
+            .collect()
+    }
+
+    /// Estimate the memory the stored samples need and switch the targets storing the most to
+    /// summary-only results until the estimate fits `limit`. Returns the estimate before and
+    /// the targets switched.
+    pub async fn limit_memory(&self, limit: usize) -> (usize, Vec<String>) {
+        let mut cache = self.results.lock().await;
+        let estimate = |cache: &HashMap<u64, TargetResults>| {
+            cache.values().map(TargetResults::stored).sum::<usize>() * SAMPLE_FOOTPRINT
+        };
+        let before = estimate(&cache);
+
+        let mut largest: Vec<(usize, u64)> = cache
+            .iter()
+            .filter(|(_, target)| matches!(target.samples, Samples::All(_)))
+            .map(|(&identifier, target)| (target.stored(), identifier))
+            .collect();
+        largest.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut switched = Vec::new();
+        for (_, identifier) in largest {
+            if estimate(&cache) <= limit {
+                break;
+            }
+            let target = cache.get_mut(&identifier).expect("listed above");
+            self.summarize(identifier, target);
+            switched.push(target.target.to_string());
+        }
+        (before, switched)
+    }
+
+    /// Nanoseconds since the UNIX epoch at `instant`, on the wall clock read at the start.
+    fn wall_ns(&self, instant: Instant) -> Option<u64> {
+        let since_epoch = instant.checked_duration_since(self.epoch)?;
+        (self.epoch_wall + since_epoch)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()
+            .map(|wall| wall.as_nanos() as u64)
+    }
+
+    /// Split the RTT of an echo that carries server timestamps.
+    fn one_way(&self, result: &ResultsValue) -> Option<OneWayDelay> {
+        let (received, sent) = result.info.server_timestamps?;
+        let rtt = match result.state {
+            ResultsState::Succeded(rtt) => rtt,
+            _ => return None,
+        };
+        let client_sent = self.wall_ns(result.sent?)? as i64;
+
+        let forward = received as i64 - client_sent;
+        let server = sent as i64 - received as i64;
+        Some(OneWayDelay {
+            forward_ns: forward,
+            server_ns: server,
+            return_ns: rtt.as_nanos() as i64 - forward - server,
+        })
+    }
+
+    async fn collect(&self, settled_only: bool) -> Vec<JsonResults> {
         let results = self.results.lock().await;
         let mut ret = Vec::new();
-        for (identifier, results) in &*results {
-            for result in results {
-                ret.push(JsonResults {
-                    identifier: *identifier,
-                    sequence: result.sequence,
-                    target: result.target,
-                    state: result.state.finish(),
-                });
+        for (&identifier, target) in &*results {
+            let values = match &target.samples {
+                Samples::All(values) => values,
+                Samples::Summary { .. } => continue,
+            };
+            for result in values {
+                if settled_only
+                    && matches!(result.state, ResultsState::None | ResultsState::Started(_))
+                {
+                    continue;
+                }
+                ret.push(self.json(identifier, result));
             }
         }
 
-        return ret;
+        ret
+    }
+    fn json(&self, identifier: u64, result: &ResultsValue) -> JsonResults {
+        JsonResults {
+            identifier,
+            sequence: result.sequence,
+            target: result.target.to_string(),
+            local: result.local,
+            remote: result.remote,
+            family: result.remote.map(AddressFamily::of),
+            ecn: result.info.ecn,
+            tos: result.info.tos,
+            cpu: result.info.cpu,
+            received_size: result.info.size,
+            truncated: result.info.truncated,
+            corrupt: result.info.corrupt,
+            warmup: result.sequence < self.warmup,
+            timed_out: result.timed_out,
+            error: result.error.clone(),
+            sent_at: result.sent.map(|sent| sent.duration_since(self.epoch)),
+            sent_wall_ns: result.sent.and_then(|sent| self.wall_ns(sent)),
+            send_latency: result.send_latency,
+            lateness: result.lateness,
+            icmp_error: result.icmp_error,
+            one_way: self.one_way(result),
+            state: result.state.finish(),
+        }
     }
 }
 
+/// Extra information read from an echo.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct RecvInfo {
+    pub ecn: Option<u8>,
+    /// Whole TOS (or traffic class) byte the server saw.
+    pub tos: Option<u8>,
+    /// Server receive and send time in nanoseconds since the UNIX epoch.
+    pub server_timestamps: Option<(u64, u64)>,
+    pub cpu: Option<u32>,
+    pub size: Option<usize>,
+    /// The echo filled the whole receive buffer, so it was likely cut off.
+    pub truncated: bool,
+    /// The payload of the echo differs from the one sent.
+    pub corrupt: bool,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct ResultsValue<'a> {
     sequence: u64,
     target: &'a str,
+    local: Option<SocketAddr>,
+    remote: Option<SocketAddr>,
+    info: RecvInfo,
+    timed_out: Option<TimeoutPhase>,
+    error: Option<TryError>,
+    sent: Option<Instant>,
+    send_latency: Option<Duration>,
+    lateness: Option<Duration>,
+    icmp_error: Option<IcmpError>,
     state: ResultsState,
+    /// Already passed to the `on_result` hook.
+    reported: bool,
 }
 
 impl<'a> ResultsValue<'a> {
@@ -86,11 +653,23 @@ impl<'a> ResultsValue<'a> {
         Self {
             sequence,
             target,
+            local: None,
+            remote: None,
+            info: RecvInfo::default(),
+            timed_out: None,
+            error: None,
+            sent: None,
+            send_latency: None,
+            lateness: None,
+            icmp_error: None,
             state: ResultsState::None,
+            reported: false,
         }
     }
 
-    pub fn recieved(&mut self, sequence: u64, now: Instant) -> Result<()> {
+    /// Record the echo of `sequence`. Returns whether it changed the state, a duplicate of an
+    /// echo already recorded doesn't.
+    pub fn recieved(&mut self, sequence: u64, now: Instant, info: RecvInfo) -> Result<bool> {
         if self.sequence != sequence {
             bail!("Invalid sequence");
         }
@@ -100,13 +679,21 @@ impl<'a> ResultsValue<'a> {
                 let dur = now.duration_since(then);
                 ResultsState::Succeded(dur)
             }
+            ResultsState::Succeded(_) | ResultsState::Failed => {
+                debug!(
+                    "recv: sequence {} already settled, duplicate dropped",
+                    sequence
+                );
+                return Ok(false);
+            }
             v => {
                 warn!("recv: sequence {} has state {:?}", sequence, v);
                 ResultsState::Failed
             }
         };
+        self.info = info;
 
-        Ok(())
+        Ok(true)
     }
 
     pub fn start(&mut self, sequence: u64, now: Instant) -> Result<()> {
@@ -114,6 +701,7 @@ impl<'a> ResultsValue<'a> {
             bail!("Invalid sequcene");
         }
 
+        self.sent = Some(now);
         self.state = match self.state {
             ResultsState::None => ResultsState::Started(now),
             v => {
@@ -132,35 +720,163 @@ pub enum ResultsState {
     Started(Instant),
     Succeded(Duration),
     Failed,
+    NotSent,
 }
 
 impl ResultsState {
     pub fn finish(self) -> JsonResultState {
         match self {
-            ResultsState::None | ResultsState::Started(_) | ResultsState::Failed => {
-                JsonResultState::Failed
-            }
+            ResultsState::Started(_) | ResultsState::Failed => JsonResultState::Failed,
             ResultsState::Succeded(dur) => JsonResultState::Succeded(dur),
+            ResultsState::None | ResultsState::NotSent => JsonResultState::NotSent,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
+/// Step of a TCP try that ran into its timeout or error.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+pub enum TimeoutPhase {
+    Connect,
+    /// Writing the request, which only fails with an error.
+    Write,
+    Read,
+}
+
+/// Error a TCP try failed with, other than a timeout.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct TryError {
+    pub phase: TimeoutPhase,
+    pub message: String,
+}
+
+/// RTT split into its parts with the timestamps of the server, in signed nanoseconds.
+///
+/// Forward and return delay compare the clocks of client and server, so they are only
+/// meaningful if both are synchronized; any offset moves time from one to the other. Their sum
+/// and the server time are exact either way.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+pub struct OneWayDelay {
+    pub forward_ns: i64,
+    /// Time between the server receiving the request and sending the echo.
+    pub server_ns: i64,
+    pub return_ns: i64,
+}
+
+/// ICMP error received instead of an echo, see `--recverr`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd)]
+pub enum IcmpError {
+    NetUnreachable,
+    HostUnreachable,
+    PortUnreachable,
+    /// The packet is too big for the path and may not be fragmented.
+    FragNeeded,
+    TtlExceeded,
+    Other {
+        kind: u8,
+        code: u8,
+    },
+}
+
+impl IcmpError {
+    /// Classify an ICMP (`ipv6 == false`) or ICMPv6 error.
+    pub fn new(ipv6: bool, kind: u8, code: u8) -> Self {
+        match (ipv6, kind, code) {
+            (false, 3, 0) | (true, 1, 0) => IcmpError::NetUnreachable,
+            (false, 3, 1) | (true, 1, 3) => IcmpError::HostUnreachable,
+            (false, 3, 3) | (true, 1, 4) => IcmpError::PortUnreachable,
+            (false, 3, 4) | (true, 2, _) => IcmpError::FragNeeded,
+            (false, 11, _) | (true, 3, _) => IcmpError::TtlExceeded,
+            _ => IcmpError::Other { kind, code },
+        }
+    }
+}
+
+impl std::fmt::Display for IcmpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IcmpError::Other { kind, code } => write!(f, "Other({}, {})", kind, code),
+            // the unit variants print as they serialize
+            error => write!(f, "{:?}", error),
+        }
+    }
+}
+
+/// IP version a packet was sent with.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn of(address: SocketAddr) -> Self {
+        if address.is_ipv4() {
+            AddressFamily::Ipv4
+        } else {
+            AddressFamily::Ipv6
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub enum JsonResultState {
     Succeded(Duration),
     Failed,
+    NotSent,
 }
 
-#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
-pub struct JsonResults<'a> {
-    identifier: u64,
-    sequence: u64,
-    target: &'a str,
-    state: JsonResultState,
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct JsonResults {
+    pub identifier: u64,
+    pub sequence: u64,
+    pub target: String,
+    pub local: Option<SocketAddr>,
+    /// Address `target` resolved to that the packet was sent to, `None` if it wasn't sent.
+    #[serde(default)]
+    pub remote: Option<SocketAddr>,
+    /// Address family of `remote`.
+    #[serde(default)]
+    pub family: Option<AddressFamily>,
+    /// ECN codepoint the server saw on arrival, if it was asked to reflect it.
+    pub ecn: Option<u8>,
+    /// TOS byte the server saw on arrival, if it was asked to reflect it.
+    pub tos: Option<u8>,
+    /// CPU that received the echo, if recorded.
+    pub cpu: Option<u32>,
+    /// Size of the echo, which differs from the request if the server resized it.
+    pub received_size: Option<usize>,
+    /// The echo filled the receive buffer and was likely cut off, see `--recv-buffer`.
+    pub truncated: bool,
+    /// The payload of the echo differs from the one sent.
+    #[serde(default)]
+    pub corrupt: bool,
+    /// One of the first sequences of `--warmup`, left out of the summary.
+    #[serde(default)]
+    pub warmup: bool,
+    /// TCP phase that timed out, if any.
+    pub timed_out: Option<TimeoutPhase>,
+    /// Error the TCP try failed with, if any.
+    #[serde(default)]
+    pub error: Option<TryError>,
+    /// When the packet was sent, relative to the start of the run.
+    pub sent_at: Option<Duration>,
+    /// When the packet was sent in nanoseconds since the UNIX epoch, only kept for
+    /// `--output-timestamps-only`.
+    #[serde(skip)]
+    pub sent_wall_ns: Option<u64>,
+    /// How long the send call took, with `--send-latency`.
+    pub send_latency: Option<Duration>,
+    /// How far behind its slot the packet was sent, with `--duration`.
+    pub lateness: Option<Duration>,
+    /// ICMP error reported for the packet, with `--recverr`.
+    pub icmp_error: Option<IcmpError>,
+    /// RTT split with the server timestamps, with `--server-timestamp`.
+    pub one_way: Option<OneWayDelay>,
+    pub state: JsonResultState,
 }
 
-impl<'a> JsonResults<'a> {
-    pub fn count_failed(results: &Vec<Self>) -> usize {
+impl JsonResults {
+    pub fn count_failed(results: &[Self]) -> usize {
         let mut ret = 0;
 
         for entry in results {
@@ -168,6 +884,456 @@ impl<'a> JsonResults<'a> {
                 ret += 1;
             }
         }
-        return ret;
+        ret
+    }
+
+    pub fn count_succeeded(results: &[Self]) -> usize {
+        results
+            .iter()
+            .filter(|entry| matches!(entry.state, JsonResultState::Succeded(_)))
+            .count()
+    }
+
+    /// Fraction of the sent packets that got no response, 0 if nothing was sent.
+    pub fn loss(results: &[Self]) -> f64 {
+        let failed = Self::count_failed(results);
+        let sent = failed + Self::count_succeeded(results);
+        if sent == 0 {
+            return 0.0;
+        }
+        failed as f64 / sent as f64
+    }
+
+    /// RTT below which `percentile` percent of the succeeded results lie (nearest rank),
+    /// `None` if none succeeded.
+    pub fn rtt_percentile(results: &[Self], percentile: f64) -> Option<Duration> {
+        let rtts = results
+            .iter()
+            .filter_map(|entry| match entry.state {
+                JsonResultState::Succeded(rtt) => Some(rtt),
+                _ => None,
+            })
+            .collect();
+        nearest_rank(rtts, percentile)
+    }
+
+    /// Send call duration below which `percentile` percent of the measured sends lie
+    /// (nearest rank), `None` if none were measured.
+    pub fn send_latency_percentile(results: &[Self], percentile: f64) -> Option<Duration> {
+        let latencies = results
+            .iter()
+            .filter_map(|entry| entry.send_latency)
+            .collect();
+        nearest_rank(latencies, percentile)
+    }
+
+    /// Mean of every part of the split RTTs, `None` if no echo carried server timestamps.
+    pub fn mean_one_way(results: &[Self]) -> Option<OneWayDelay> {
+        let split: Vec<OneWayDelay> = results.iter().filter_map(|entry| entry.one_way).collect();
+        if split.is_empty() {
+            return None;
+        }
+        let count = split.len() as i64;
+        Some(OneWayDelay {
+            forward_ns: split.iter().map(|s| s.forward_ns).sum::<i64>() / count,
+            server_ns: split.iter().map(|s| s.server_ns).sum::<i64>() / count,
+            return_ns: split.iter().map(|s| s.return_ns).sum::<i64>() / count,
+        })
+    }
+
+    /// Sequences that arrived after a higher sequence of the same target, in arrival order.
+    ///
+    /// Arrival is reconstructed from the send time plus RTT, so `results` must belong to a
+    /// single target.
+    pub fn out_of_order(results: &[Self]) -> Vec<u64> {
+        let mut arrivals: Vec<(Duration, u64)> = results
+            .iter()
+            .filter_map(|entry| match (entry.sent_at, &entry.state) {
+                (Some(sent), JsonResultState::Succeded(rtt)) => Some((sent + *rtt, entry.sequence)),
+                _ => None,
+            })
+            .collect();
+        arrivals.sort_unstable();
+
+        let mut highest = None;
+        let mut ret = Vec::new();
+        for (_, sequence) in arrivals {
+            match highest {
+                Some(highest) if sequence < highest => ret.push(sequence),
+                _ => highest = Some(sequence),
+            }
+        }
+        ret
+    }
+
+    /// Achieved send rate in packets per second and coefficient of variation of the gaps
+    /// between sends, `None` with less than two sends.
+    pub fn send_rate(results: &[Self]) -> Option<(f64, f64)> {
+        let mut sent: Vec<Duration> = results.iter().filter_map(|r| r.sent_at).collect();
+        if sent.len() < 2 {
+            return None;
+        }
+        sent.sort_unstable();
+
+        let gaps: Vec<f64> = sent
+            .windows(2)
+            .map(|w| (w[1] - w[0]).as_secs_f64())
+            .collect();
+        let mean = gaps.iter().sum::<f64>() / gaps.len() as f64;
+        if mean == 0.0 {
+            return None;
+        }
+        let var = gaps.iter().map(|g| (g - mean).powi(2)).sum::<f64>() / gaps.len() as f64;
+
+        Some((1.0 / mean, var.sqrt() / mean))
+    }
+
+    /// Number of results per target.
+    pub fn count_by_target(results: &[Self]) -> BTreeMap<&str, usize> {
+        let mut ret = BTreeMap::new();
+
+        for entry in results {
+            *ret.entry(entry.target.as_str()).or_insert(0) += 1;
+        }
+        ret
+    }
+
+    /// Mean round trip time of the succeeded results, `None` if none succeeded.
+    pub fn mean_rtt(results: &[Self]) -> Option<Duration> {
+        let mut sum = Duration::ZERO;
+        let mut count = 0;
+
+        for entry in results {
+            if let JsonResultState::Succeded(rtt) = entry.state {
+                sum += rtt;
+                count += 1;
+            }
+        }
+
+        if count == 0 {
+            return None;
+        }
+        Some(sum / count)
+    }
+}
+
+/// `addr` with an IPv4-mapped IPv6 address turned into the IPv4 one, so a dual-stack socket
+/// reports the same address as an IPv4 one would.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+fn nearest_rank(mut values: Vec<Duration>, percentile: f64) -> Option<Duration> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+
+    let rank = ((percentile / 100.0) * values.len() as f64).ceil() as usize;
+    Some(values[rank.clamp(1, values.len()) - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{
+        canonical, AddressFamily, IcmpError, JsonResultState, JsonResults, OnResult, OneWayDelay,
+        RecvInfo, Results, ResultsValue, Samples, TimeoutPhase, SAMPLE_FOOTPRINT,
+    };
+    use crate::clock::MockClock;
+    use crate::{Goodput, Report};
+
+    fn result(target: &str, sequence: u64, state: JsonResultState) -> JsonResults {
+        JsonResults {
+            identifier: 0,
+            sequence,
+            target: target.to_string(),
+            local: None,
+            remote: None,
+            family: None,
+            ecn: None,
+            tos: None,
+            cpu: None,
+            received_size: None,
+            truncated: false,
+            corrupt: false,
+            warmup: false,
+            timed_out: None,
+            error: None,
+            sent_at: None,
+            sent_wall_ns: None,
+            send_latency: None,
+            lateness: None,
+            icmp_error: None,
+            one_way: None,
+            state,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut escaped = result("[::1]:7 \"quoted\"", 0, JsonResultState::Failed);
+        escaped.local = Some("[::1]:4000".parse().unwrap());
+        escaped.icmp_error = Some(IcmpError::Other { kind: 5, code: 1 });
+        escaped.timed_out = Some(TimeoutPhase::Read);
+        let mut full = result("a", 1, JsonResultState::Succeded(Duration::new(1, 5)));
+        full.ecn = Some(0b11);
+        full.tos = Some(0x2e);
+        full.cpu = Some(3);
+        full.received_size = Some(1200);
+        full.truncated = true;
+        full.sent_at = Some(Duration::from_micros(1500));
+        full.send_latency = Some(Duration::from_nanos(800));
+        full.lateness = Some(Duration::from_micros(30));
+        full.one_way = Some(OneWayDelay {
+            forward_ns: -20,
+            server_ns: 10,
+            return_ns: 1_000_000_015,
+        });
+
+        let mut report = Report::new(vec![
+            escaped,
+            full,
+            result("b", 0, JsonResultState::NotSent),
+        ]);
+        report.tags.insert("host".to_string(), "lab 1".to_string());
+        report.tcp_nodelay = Some(true);
+        report.goodput = Some(Goodput::new(1200, Duration::from_millis(3)));
+        report.check_tos(0x2e);
+
+        let json = serde_json::to_string(&report).unwrap();
+        let parsed: Report = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.results, report.results);
+        assert_eq!(parsed.targets, report.targets);
+        assert_eq!(parsed.tags, report.tags);
+        assert_eq!(parsed.goodput, report.goodput);
+        assert_eq!(parsed.generated_at, report.generated_at);
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+    }
+
+    #[test]
+    fn aggregation() {
+        let results = vec![
+            result("a", 0, JsonResultState::Succeded(Duration::from_millis(10))),
+            result("a", 1, JsonResultState::Failed),
+            result("a", 2, JsonResultState::Succeded(Duration::from_millis(30))),
+            result("b", 0, JsonResultState::NotSent),
+            result("b", 1, JsonResultState::Failed),
+        ];
+
+        assert_eq!(JsonResults::count_failed(&results), 2);
+        assert_eq!(JsonResults::count_succeeded(&results), 2);
+
+        let by_target = JsonResults::count_by_target(&results);
+        assert_eq!(by_target.get("a"), Some(&3));
+        assert_eq!(by_target.get("b"), Some(&2));
+
+        assert_eq!(
+            JsonResults::mean_rtt(&results),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(JsonResults::mean_rtt(&results[3..]), None);
+        assert_eq!(
+            JsonResults::rtt_percentile(&results, 50.0),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(
+            JsonResults::rtt_percentile(&results, 99.0),
+            Some(Duration::from_millis(30))
+        );
+        assert_eq!(
+            JsonResults::rtt_percentile(&results, 0.0),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(JsonResults::rtt_percentile(&results[3..], 99.0), None);
+        assert_eq!(JsonResults::count_succeeded(&[]), 0);
+
+        assert_eq!(JsonResults::loss(&results), 0.5);
+        assert_eq!(JsonResults::loss(&results[3..4]), 0.0);
+
+        let mut paced = results.clone();
+        for (result, ms) in paced.iter_mut().zip(&[0, 10, 20, 30]) {
+            result.sent_at = Some(Duration::from_millis(*ms));
+        }
+        let (rate, cv) = JsonResults::send_rate(&paced).unwrap();
+        assert!((rate - 100.0).abs() < 1e-6);
+        assert!(cv.abs() < 1e-6);
+        assert_eq!(JsonResults::send_rate(&results), None);
+
+        for (result, us) in paced.iter_mut().zip(&[40, 10, 30, 20]) {
+            result.send_latency = Some(Duration::from_micros(*us));
+        }
+        assert_eq!(
+            JsonResults::send_latency_percentile(&paced, 50.0),
+            Some(Duration::from_micros(20))
+        );
+        assert_eq!(
+            JsonResults::send_latency_percentile(&paced, 100.0),
+            Some(Duration::from_micros(40))
+        );
+        assert_eq!(JsonResults::send_latency_percentile(&results, 50.0), None);
+
+        // sequence 1 arrives at 55ms, after sequence 2 at 50ms
+        paced[1].state = JsonResultState::Succeded(Duration::from_millis(45));
+        assert_eq!(JsonResults::out_of_order(&paced[..3]), vec![1]);
+        assert!(JsonResults::out_of_order(&results).is_empty());
+    }
+
+    #[async_std::test]
+    async fn prime() {
+        let addresses = vec!["a".to_string(), "b".to_string()];
+        let mut results = Results::new();
+        results.prime(&addresses, &[3, 0]);
+
+        assert_eq!(results.targets.get("a"), Some(&0));
+        assert_eq!(results.targets.get("b"), Some(&1));
+
+        let cache = results.results.lock().await;
+        let expected: Vec<ResultsValue> = (0..3).map(|x| ResultsValue::new(x, "a")).collect();
+        match &cache[&0].samples {
+            Samples::All(values) => {
+                assert_eq!(values, &expected);
+                assert_eq!(values.capacity(), 3);
+            }
+            Samples::Summary { .. } => panic!("primed as summary-only"),
+        }
+        assert!(matches!(&cache[&1].samples, Samples::All(values) if values.is_empty()));
+    }
+
+    #[async_std::test]
+    async fn limit_memory() {
+        let addresses = vec!["a".to_string(), "b".to_string()];
+        let clock = Arc::new(MockClock::new());
+        let mut results = Results::with_clock(clock.clone());
+        let notified = Arc::new(AtomicUsize::new(0));
+        let counter = notified.clone();
+        results.set_on_result(OnResult(Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        })));
+        results.prime(&addresses, &[6, 2]);
+        let remote = "192.0.2.1:7".parse().unwrap();
+
+        results.start_packet(0, 0, remote).await.unwrap();
+        results.start_packet(0, 1, remote).await.unwrap();
+        results.start_packet(1, 0, remote).await.unwrap();
+        clock.advance(Duration::from_millis(3));
+        results
+            .recv_packet(0, 0, RecvInfo::default())
+            .await
+            .unwrap();
+
+        // only the larger target has to go to fit the limit
+        let limit = 7 * SAMPLE_FOOTPRINT;
+        let (estimate, switched) = results.limit_memory(limit).await;
+        assert_eq!(estimate, 8 * SAMPLE_FOOTPRINT);
+        assert_eq!(switched, vec!["a".to_string()]);
+        assert!(results.limit_memory(limit).await.1.is_empty());
+
+        results.start_packet(0, 2, remote).await.unwrap();
+        results.start_packet(0, 3, remote).await.unwrap();
+        clock.advance(Duration::from_millis(5));
+        results
+            .recv_packet(0, 3, RecvInfo::default())
+            .await
+            .unwrap();
+        results
+            .recv_packet(0, 1, RecvInfo::default())
+            .await
+            .unwrap();
+        // the final and the untouched sequences are gone, only sequence 2 is still in flight
+        assert_eq!(results.results.lock().await[&0].stored(), 1);
+        let window = Duration::from_millis(1);
+        assert_eq!(
+            results
+                .consecutive_failures(0, 4, window, 10)
+                .await
+                .unwrap(),
+            (0, 0)
+        );
+        results.start_packet(0, 4, remote).await.unwrap();
+        clock.advance(Duration::from_millis(2));
+        assert_eq!(
+            results
+                .consecutive_failures(0, 5, window, 10)
+                .await
+                .unwrap(),
+            (1, 0)
+        );
+        results.abort(0, 5).await.unwrap();
+
+        let (results, summaries) = results.finish().await;
+        assert!(results.iter().all(|r| r.target == "b"));
+        assert_eq!(results.len(), 2);
+        let summary = &summaries[0];
+        assert!(summary.summary_only);
+        assert_eq!(
+            (summary.succeeded, summary.failed, summary.not_sent),
+            (3, 2, 1)
+        );
+        assert_eq!(summary.out_of_order, vec![1]);
+        assert_eq!(summary.max_rtt, Some(Duration::from_millis(8)));
+        assert_eq!(notified.load(Ordering::Relaxed), 8);
+    }
+
+    #[async_std::test]
+    async fn clock() {
+        let addresses = vec!["a".to_string()];
+        let clock = Arc::new(MockClock::new());
+        let mut results = Results::with_clock(clock.clone());
+        results.prime(&addresses, &[3]);
+        let remote = "[::ffff:192.0.2.1]:7".parse().unwrap();
+
+        clock.advance(Duration::from_millis(5));
+        results.start_packet(0, 0, remote).await.unwrap();
+        results.start_packet(0, 1, remote).await.unwrap();
+        clock.advance(Duration::from_millis(7));
+        results
+            .recv_packet(0, 0, RecvInfo::default())
+            .await
+            .unwrap();
+        results.start_packet(0, 2, remote).await.unwrap();
+
+        // sequence 1 has waited 7ms, sequence 2 not at all
+        let window = Duration::from_millis(5);
+        assert_eq!(
+            results
+                .consecutive_failures(0, 3, window, 10)
+                .await
+                .unwrap(),
+            (1, 1)
+        );
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(
+            results
+                .consecutive_failures(0, 3, window, 10)
+                .await
+                .unwrap(),
+            (2, 0)
+        );
+
+        // only the answered sequence is settled
+        assert_eq!(results.snapshot().await.len(), 1);
+
+        let (results, _) = results.finish().await;
+        assert_eq!(
+            results[0].state,
+            JsonResultState::Succeded(Duration::from_millis(7))
+        );
+        assert_eq!(results[0].sent_at, Some(Duration::from_millis(5)));
+        assert_eq!(results[2].sent_at, Some(Duration::from_millis(12)));
+        // the mapped address a dual-stack socket sends to is reported as the IPv4 one
+        assert_eq!(results[0].remote, Some("192.0.2.1:7".parse().unwrap()));
+        assert_eq!(results[0].family, Some(AddressFamily::Ipv4));
+    }
+
+    #[test]
+    fn mapped_addresses() {
+        let mapped = "[::ffff:192.0.2.1]:7".parse().unwrap();
+        assert_eq!(canonical(mapped), "192.0.2.1:7".parse().unwrap());
+        let v6 = "[2001:db8::1]:7".parse().unwrap();
+        assert_eq!(canonical(v6), v6);
     }
 }
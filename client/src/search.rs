@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Below this rate the search gives up on finding a rate that meets the loss target.
+const MIN_RATE: f64 = 1.0;
+
+/// Parameters of `--count-per-second`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RateSearch {
+    /// Highest acceptable loss of a phase, as a fraction.
+    pub loss_target: f64,
+    /// Packets per second added per phase while ramping up, also the first rate tried.
+    pub step: f64,
+    /// How long every phase sends.
+    pub phase: Duration,
+    pub max_phases: usize,
+}
+
+/// Outcome of a single phase of the rate search.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct RatePhase {
+    /// Packets per second sent to every target.
+    pub rate: f64,
+    pub sent: usize,
+    pub loss: f64,
+    pub mean_rtt: Option<Duration>,
+    pub p99_rtt: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct RateSearchResult {
+    pub loss_target: f64,
+    /// Highest rate that met the loss target, `None` if none did.
+    pub max_rate: Option<f64>,
+    pub phases: Vec<RatePhase>,
+}
+
+/// Ramps the rate up by `step` until a phase misses the loss target, then bisects between the
+/// best passing and the lowest failing rate until they are within a quarter step.
+#[derive(Debug)]
+pub struct Search {
+    step: f64,
+    loss_target: f64,
+    rate: f64,
+    passed: Option<f64>,
+    failed: Option<f64>,
+}
+
+impl Search {
+    pub fn new(params: &RateSearch) -> Self {
+        Self {
+            step: params.step,
+            loss_target: params.loss_target,
+            rate: params.step,
+            passed: None,
+            failed: None,
+        }
+    }
+
+    /// Rate of the next phase.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Highest rate that met the loss target so far.
+    pub fn max_rate(&self) -> Option<f64> {
+        self.passed
+    }
+
+    /// Record the loss of a phase at the current rate. Returns `false` once converged.
+    pub fn record(&mut self, loss: f64) -> bool {
+        if loss <= self.loss_target {
+            self.passed = Some(self.rate);
+        } else {
+            self.failed = Some(self.rate);
+        }
+
+        self.rate = match (self.passed, self.failed) {
+            (Some(passed), None) => passed + self.step,
+            (None, Some(failed)) => failed / 2.0,
+            (Some(passed), Some(failed)) => {
+                if failed - passed <= self.step / 4.0 {
+                    return false;
+                }
+                (passed + failed) / 2.0
+            }
+            (None, None) => unreachable!("a phase was just recorded"),
+        };
+        self.rate >= MIN_RATE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{RateSearch, Search};
+
+    #[test]
+    fn converges() {
+        let params = RateSearch {
+            loss_target: 0.01,
+            step: 100.0,
+            phase: Duration::from_secs(1),
+            max_phases: 100,
+        };
+
+        // a path that drops packets above 730 packets per second
+        let mut search = Search::new(&params);
+        let mut phases = 0;
+        while search.record(if search.rate() <= 730.0 { 0.0 } else { 0.2 }) {
+            phases += 1;
+        }
+        let max = search.max_rate().unwrap();
+        assert!(max <= 730.0 && max > 730.0 - 25.0, "found {}", max);
+        assert!(phases < 20);
+
+        // nothing gets through
+        let mut search = Search::new(&params);
+        while search.record(1.0) {}
+        assert_eq!(search.max_rate(), None);
+    }
+}
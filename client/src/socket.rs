@@ -0,0 +1,253 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::{FromRawFd, RawFd};
+
+pub fn setsockopt(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: value is a valid c_int for the duration of the call
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn getsockopt(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: value and len are valid for the duration of the call
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// CPU that processed the most recently received packet of the socket, `None` if the
+/// kernel did not record one.
+pub fn incoming_cpu(fd: RawFd) -> io::Result<Option<u32>> {
+    getsockopt(fd, libc::SOL_SOCKET, libc::SO_INCOMING_CPU).map(|cpu| u32::try_from(cpu).ok())
+}
+
+/// Set the DF bit on outgoing packets, so oversized datagrams are dropped instead of fragmented.
+pub fn set_dont_fragment(fd: RawFd, ipv6: bool) -> io::Result<()> {
+    if ipv6 {
+        setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        )
+    } else {
+        setsockopt(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        )
+    }
+}
+
+/// Clear the DF bit on outgoing packets, so oversized datagrams are fragmented by the sender and
+/// any router on the way.
+pub fn allow_fragment(fd: RawFd, ipv6: bool) -> io::Result<()> {
+    if ipv6 {
+        // IPv6 routers never fragment, only the sender does
+        setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DONT,
+        )
+    } else {
+        setsockopt(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DONT,
+        )
+    }
+}
+
+/// Bind a UDP socket with `SO_REUSEPORT` set, so another such socket can bind the same address.
+pub fn bind_reuseport(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let domain = if addr.is_ipv6() {
+        libc::AF_INET6
+    } else {
+        libc::AF_INET
+    };
+    // SAFETY: plain syscall, the returned fd is checked below
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd is a fresh socket nobody else owns, the UdpSocket closes it on errors below
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+    nix::sys::socket::bind(fd, &nix::sys::socket::SockaddrStorage::from(addr))?;
+
+    Ok(socket)
+}
+
+/// Set the TOS byte (IPv4) or traffic class (IPv6) of outgoing packets.
+pub fn set_tos(fd: RawFd, ipv6: bool, tos: u8) -> io::Result<()> {
+    if ipv6 {
+        setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            tos as libc::c_int,
+        )
+    } else {
+        setsockopt(fd, libc::IPPROTO_IP, libc::IP_TOS, tos as libc::c_int)
+    }
+}
+
+/// Set the TTL (IPv4) or hop limit (IPv6) of outgoing packets.
+pub fn set_ttl(fd: RawFd, ipv6: bool, ttl: u8) -> io::Result<()> {
+    if ipv6 {
+        setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_UNICAST_HOPS,
+            ttl as libc::c_int,
+        )
+    } else {
+        setsockopt(fd, libc::IPPROTO_IP, libc::IP_TTL, ttl as libc::c_int)
+    }
+}
+
+/// Queue ICMP errors for the socket, so they can be read with [`recv_err`].
+pub fn enable_recv_err(fd: RawFd, ipv6: bool) -> io::Result<()> {
+    if ipv6 {
+        setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVERR, 1)
+    } else {
+        setsockopt(fd, libc::IPPROTO_IP, libc::IP_RECVERR, 1)
+    }
+}
+
+/// An ICMP error read from the error queue.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct QueuedError {
+    /// ICMP (or ICMPv6) type and code, `None` for locally generated errors.
+    pub icmp: Option<(u8, u8)>,
+    pub ipv6: bool,
+    /// Address of the host that sent the ICMP error.
+    pub offender: Option<IpAddr>,
+}
+
+/// Read one entry from the error queue into `buf`, which receives the payload of the packet
+/// that caused the error. Returns the payload length and the error.
+pub fn recv_err(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, QueuedError)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control = [0u8; 512];
+
+    // SAFETY: all pointers in msg stay valid for the duration of the call
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+
+    let size = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut error = QueuedError::default();
+    // SAFETY: msg was filled by recvmsg, the CMSG macros stay within msg_controllen and the
+    // kernel places the offender address right after the extended error
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let header = &*cmsg;
+            if matches!(
+                (header.cmsg_level, header.cmsg_type),
+                (libc::IPPROTO_IP, libc::IP_RECVERR) | (libc::IPPROTO_IPV6, libc::IPV6_RECVERR)
+            ) {
+                let err = libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err;
+                let extended = std::ptr::read_unaligned(err);
+                if extended.ee_origin == libc::SO_EE_ORIGIN_ICMP
+                    || extended.ee_origin == libc::SO_EE_ORIGIN_ICMP6
+                {
+                    error.icmp = Some((extended.ee_type, extended.ee_code));
+                    error.ipv6 = extended.ee_origin == libc::SO_EE_ORIGIN_ICMP6;
+                    error.offender = to_ip(err.add(1) as *const libc::sockaddr);
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok((size as usize, error))
+}
+
+/// Soft and hard limit of open file descriptors.
+pub fn nofile_limit() -> io::Result<(libc::rlim_t, libc::rlim_t)> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: limit is valid for the duration of the call
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((limit.rlim_cur, limit.rlim_max))
+}
+
+/// Set the soft limit of open file descriptors, keeping the hard one.
+pub fn set_nofile_limit(soft: libc::rlim_t, hard: libc::rlim_t) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: soft,
+        rlim_max: hard,
+    };
+    // SAFETY: limit is valid for the duration of the call
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// # Safety
+/// `addr` must point to a `sockaddr_in` or `sockaddr_in6`, as given by its family.
+unsafe fn to_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    match std::ptr::read_unaligned(addr).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let v4 = std::ptr::read_unaligned(addr as *const libc::sockaddr_in);
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(v4.sin_addr.s_addr))))
+        }
+        libc::AF_INET6 => {
+            let v6 = std::ptr::read_unaligned(addr as *const libc::sockaddr_in6);
+            // a router reached through a dual-stack socket reports an IPv4-mapped address
+            Some(IpAddr::V6(Ipv6Addr::from(v6.sin6_addr.s6_addr)).to_canonical())
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::report::TargetSummary;
+use crate::results::{JsonResultState, JsonResults};
+
+/// Width of a [`Histogram`] bucket, as the ratio of its upper to its lower bound.
+const BUCKET_GROWTH: f64 = 1.01;
+
+/// Summary of a target that `--max-runtime-memory` switched to summary-only accumulation. Every
+/// result is folded in once it is final and dropped, so the figures that need all samples of
+/// the target are kept as running sums or, for the percentiles, as a [`Histogram`].
+#[derive(Debug, Clone)]
+pub(crate) struct StreamingSummary {
+    summary: TargetSummary,
+    rtt: Histogram,
+    rtt_sum: Duration,
+    send_latency: Histogram,
+    /// Sums of the parts of the split RTTs and how many there were.
+    one_way: (i64, i64, i64, i64),
+    /// Highest sequence that arrived so far, for `out_of_order`.
+    highest: Option<u64>,
+    /// Highest sequence answered so far, warmup included.
+    last_success: Option<u64>,
+    /// Send time of the last send and the count, mean and sum of squared deviations of the gaps
+    /// between sends.
+    last_sent: Option<Duration>,
+    gaps: (u64, f64, f64),
+}
+
+impl StreamingSummary {
+    pub fn new(identifier: u64, target: String) -> Self {
+        let mut summary = TargetSummary::empty(identifier, target);
+        summary.summary_only = true;
+        Self {
+            summary,
+            rtt: Histogram::default(),
+            rtt_sum: Duration::ZERO,
+            send_latency: Histogram::default(),
+            one_way: (0, 0, 0, 0),
+            highest: None,
+            last_success: None,
+            last_sent: None,
+            gaps: (0, 0.0, 0.0),
+        }
+    }
+
+    /// Fold in a final result. Echoes have to come in the order they arrived.
+    pub fn add(&mut self, result: &JsonResults) {
+        if let JsonResultState::Succeded(_) = result.state {
+            self.last_success = self.last_success.max(Some(result.sequence));
+        }
+        self.summary.count(result);
+        if result.warmup {
+            return;
+        }
+
+        if let JsonResultState::Succeded(rtt) = result.state {
+            self.rtt.add(rtt);
+            self.rtt_sum += rtt;
+            match self.highest {
+                Some(highest) if result.sequence < highest => {
+                    self.summary.out_of_order.push(result.sequence)
+                }
+                _ => self.highest = Some(result.sequence),
+            }
+        }
+        if let Some(tos) = result.tos {
+            // split into preserved and remapped by `Report::check_tos`
+            *self.summary.tos_remapped.entry(tos).or_insert(0) += 1;
+        }
+        if let Some(latency) = result.send_latency {
+            self.send_latency.add(latency);
+        }
+        if let Some(split) = result.one_way {
+            self.one_way.0 += split.forward_ns;
+            self.one_way.1 += split.server_ns;
+            self.one_way.2 += split.return_ns;
+            self.one_way.3 += 1;
+        }
+    }
+
+    /// Count the never sent `sequences` as not sent, the ones below `warmup` as warmup.
+    pub fn add_not_sent(&mut self, sequences: Range<u64>, warmup: u64) {
+        let warm = sequences.end.min(warmup).saturating_sub(sequences.start);
+        self.summary.warmup += warm as usize;
+        self.summary.not_sent += (sequences.end - sequences.start - warm) as usize;
+    }
+
+    /// Account for a send at `sent_at`, sends have to come in the order they went out.
+    pub fn add_send(&mut self, sent_at: Duration) {
+        if let Some(last) = self.last_sent {
+            // Welford's online variance
+            let gap = sent_at.saturating_sub(last).as_secs_f64();
+            let (count, mean, m2) = &mut self.gaps;
+            *count += 1;
+            let delta = gap - *mean;
+            *mean += delta / *count as f64;
+            *m2 += delta * (gap - *mean);
+        }
+        self.last_sent = Some(sent_at);
+    }
+
+    /// Highest sequence answered so far, see [`crate::results::Results::consecutive_failures`].
+    pub fn last_success(&self) -> Option<u64> {
+        self.last_success
+    }
+
+    /// The summary of everything folded in so far.
+    pub fn summary(&self) -> TargetSummary {
+        let mut summary = self.summary.clone();
+        if self.rtt.count > 0 {
+            summary.mean_rtt = Some(self.rtt_sum / self.rtt.count as u32);
+            summary.p99_rtt = self.rtt.percentile(99.0);
+            summary.max_rtt = self.rtt.percentile(100.0);
+        }
+        let (forward, server, ret, count) = self.one_way;
+        if count > 0 {
+            summary.mean_one_way = Some(crate::results::OneWayDelay {
+                forward_ns: forward / count,
+                server_ns: server / count,
+                return_ns: ret / count,
+            });
+        }
+        summary.p50_send_latency = self.send_latency.percentile(50.0);
+        summary.p99_send_latency = self.send_latency.percentile(99.0);
+        summary.max_send_latency = self.send_latency.percentile(100.0);
+        let (count, mean, m2) = self.gaps;
+        if count > 0 && mean > 0.0 {
+            summary.send_rate = Some(1.0 / mean);
+            summary.send_interval_cv = Some((m2 / count as f64).sqrt() / mean);
+        }
+        summary
+    }
+}
+
+/// Durations counted in buckets [`BUCKET_GROWTH`] wide, for percentiles within 1% of the exact
+/// ones without keeping every duration.
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    buckets: BTreeMap<u32, u64>,
+    count: u64,
+    max: Duration,
+}
+
+impl Histogram {
+    fn add(&mut self, duration: Duration) {
+        let nanos = duration.as_nanos() as f64;
+        // bucket `b` holds [GROWTH^(b - 1), GROWTH^b), zero gets one of its own
+        let bucket = if nanos < 1.0 {
+            0
+        } else {
+            (nanos.ln() / BUCKET_GROWTH.ln()) as u32 + 1
+        };
+        *self.buckets.entry(bucket).or_insert(0) += 1;
+        self.count += 1;
+        self.max = self.max.max(duration);
+    }
+
+    /// Upper bound of the bucket holding the nearest rank, `None` if nothing was counted.
+    fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((percentile / 100.0) * self.count as f64).ceil() as u64;
+        let rank = rank.clamp(1, self.count);
+        let mut seen = 0;
+        for (&bucket, &count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                let bound = Duration::from_nanos(BUCKET_GROWTH.powi(bucket as i32).ceil() as u64);
+                return Some(if bucket == 0 {
+                    Duration::ZERO
+                } else {
+                    bound.min(self.max)
+                });
+            }
+        }
+        Some(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::StreamingSummary;
+    use crate::report::TargetSummary;
+    use crate::results::{JsonResultState, JsonResults};
+
+    #[test]
+    fn matches_exact_summary() {
+        let results: Vec<JsonResults> = (0..1000u64)
+            .map(|sequence| JsonResults {
+                identifier: 3,
+                sequence,
+                target: "a".to_string(),
+                local: None,
+                remote: None,
+                family: None,
+                ecn: None,
+                tos: None,
+                cpu: None,
+                received_size: None,
+                truncated: false,
+                corrupt: false,
+                warmup: sequence < 5,
+                timed_out: None,
+                error: None,
+                sent_at: (sequence < 990).then(|| Duration::from_millis(sequence * 2)),
+                sent_wall_ns: None,
+                send_latency: (sequence < 990).then(|| Duration::from_micros(sequence % 50)),
+                lateness: None,
+                icmp_error: None,
+                one_way: None,
+                state: match sequence {
+                    990.. => JsonResultState::NotSent,
+                    _ if sequence % 10 == 0 => JsonResultState::Failed,
+                    _ => JsonResultState::Succeded(Duration::from_micros(100 + sequence * 7)),
+                },
+            })
+            .collect();
+
+        let mut streaming = StreamingSummary::new(3, "a".to_string());
+        for sent in results
+            .iter()
+            .filter(|r| !r.warmup)
+            .filter_map(|r| r.sent_at)
+        {
+            streaming.add_send(sent);
+        }
+        for result in &results[..990] {
+            streaming.add(result);
+        }
+        streaming.add_not_sent(990..1000, 5);
+
+        let exact = TargetSummary::from_results(&results).remove(0);
+        let summary = streaming.summary();
+
+        assert!(summary.summary_only);
+        assert_eq!(summary.succeeded, exact.succeeded);
+        assert_eq!(summary.failed, exact.failed);
+        assert_eq!(summary.not_sent, exact.not_sent);
+        assert_eq!(summary.warmup, exact.warmup);
+        assert_eq!(summary.mean_rtt, exact.mean_rtt);
+        assert_eq!(summary.max_rtt, exact.max_rtt);
+        assert_eq!(summary.out_of_order, exact.out_of_order);
+        for (approximate, exact) in [
+            (summary.p99_rtt, exact.p99_rtt),
+            (summary.p50_send_latency, exact.p50_send_latency),
+            (summary.p99_send_latency, exact.p99_send_latency),
+        ] {
+            let (approximate, exact) = (approximate.unwrap(), exact.unwrap());
+            assert!(approximate >= exact);
+            assert!(approximate.as_secs_f64() <= exact.as_secs_f64() * 1.01);
+        }
+        assert_eq!(summary.max_send_latency, exact.max_send_latency);
+        assert!((summary.send_rate.unwrap() - exact.send_rate.unwrap()).abs() < 1e-6);
+        assert!(summary.send_interval_cv.unwrap() < 1e-6);
+        assert_eq!(streaming.last_success(), Some(989));
+    }
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use packet::UdpEchoPacket;
+use serde::Serialize;
+
+/// Datagram sizes `--probe-size-sweep` tries without a list, up to the largest that fits a
+/// 1500 byte Ethernet MTU over IPv4.
+pub const DEFAULT_SWEEP: [usize; 6] = [64, 128, 256, 512, 1024, 1472];
+
+/// Largest UDP payload of an IPv4 datagram.
+pub(crate) const MAX_SWEEP_SIZE: usize = 65507;
+
+/// RTT of the probes at a single datagram size.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SizePoint {
+    /// UDP payload bytes of every probe, header included.
+    pub size: usize,
+    pub sent: usize,
+    pub loss: f64,
+    pub mean_rtt: Option<Duration>,
+    pub p50_rtt: Option<Duration>,
+    pub p99_rtt: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SizeSweepResult {
+    pub curve: Vec<SizePoint>,
+}
+
+/// Parse the sizes of `--probe-size-sweep`, a comma separated list (`64,512,1400`) or a range
+/// with a step (`100-1400:100`).
+pub fn parse_sizes(sizes: &str) -> Result<Vec<usize>> {
+    // the plain format carries at least one byte of payload
+    let min = UdpEchoPacket::minimum_packet_size() + 1;
+    let parse = |size: &str| -> Result<usize> {
+        size.trim()
+            .parse()
+            .with_context(|| format!("Invalid size '{}'", size))
+    };
+
+    let ret = match sizes.split_once('-') {
+        Some((first, rest)) => {
+            let (last, step) = rest.split_once(':').unwrap_or((rest, "1"));
+            let (first, last, step) = (parse(first)?, parse(last)?, parse(step)?);
+            if step == 0 || first > last {
+                bail!("Size range '{}' is empty", sizes);
+            }
+            (first..=last).step_by(step).collect()
+        }
+        None => sizes.split(',').map(parse).collect::<Result<Vec<_>>>()?,
+    };
+
+    if let Some(&size) = ret.iter().find(|&&s| s < min || s > MAX_SWEEP_SIZE) {
+        bail!(
+            "Sweep size {} is outside of {} to {} bytes",
+            size,
+            min,
+            MAX_SWEEP_SIZE
+        );
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_sizes;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_sizes("64, 512,1400").unwrap(), vec![64, 512, 1400]);
+        assert_eq!(
+            parse_sizes("100-400:100").unwrap(),
+            vec![100, 200, 300, 400]
+        );
+        assert_eq!(parse_sizes("20-22").unwrap(), vec![20, 21, 22]);
+        assert!(parse_sizes("10").is_err());
+        assert!(parse_sizes("400-100").is_err());
+        assert!(parse_sizes("100-400:0").is_err());
+        assert!(parse_sizes("70000").is_err());
+        assert!(parse_sizes("x").is_err());
+    }
+}
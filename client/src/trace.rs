@@ -0,0 +1,142 @@
+use std::net::IpAddr;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use async_std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use log::*;
+use packet::{UdpEchoBuilder, UdpEchoPacket};
+use serde::Serialize;
+
+use crate::socket;
+
+const HOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
+pub struct TraceResult<'a> {
+    pub target: &'a str,
+    pub hops: Vec<Hop>,
+    /// Whether the echo server answered within the maximum TTL.
+    pub reached: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
+pub struct Hop {
+    pub ttl: u8,
+    /// Router that reported the expired TTL, or the target itself on the last hop.
+    pub address: Option<IpAddr>,
+    pub rtt: Option<Duration>,
+}
+
+enum Answer {
+    Echo,
+    Icmp(Option<IpAddr>),
+}
+
+/// Send echoes with increasing TTL to `target`, recording who reports each one as expired.
+pub async fn trace<'a>(
+    target: &'a str,
+    identifier: u64,
+    max_ttl: u8,
+    namespace: &str,
+) -> Result<TraceResult<'a>> {
+    let addr = target
+        .to_socket_addrs()
+        .await
+        .context("Failed to resolve target")?
+        .next()
+        .context("Target did not resolve to any address")?;
+
+    let socket = match addr {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0").await,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0").await,
+    }
+    .context("Failed to bind socket")?;
+    socket.connect(addr).await.context("Failed to connect")?;
+    socket::enable_recv_err(socket.as_raw_fd(), addr.is_ipv6())
+        .context("Failed to enable IP_RECVERR, tracing needs ICMP errors on the socket")?;
+
+    let mut hops = Vec::new();
+    for ttl in 1..=max_ttl {
+        socket::set_ttl(socket.as_raw_fd(), addr.is_ipv6(), ttl).context("Failed to set TTL")?;
+
+        let mut buf = [0u8; 18];
+        let len = UdpEchoBuilder::new()
+            .identifier(identifier)
+            .sequence(ttl as u64)
+            .payload(&[0])
+            .build_into(&mut buf)
+            .expect("buffer fits the packet");
+
+        let start = Instant::now();
+        socket.send(&buf[..len]).await.context("Failed to send")?;
+        let answer =
+            async_std::future::timeout(HOP_TIMEOUT, wait_answer(&socket, identifier, ttl as u64))
+                .await;
+        let rtt = start.elapsed();
+
+        match answer {
+            Ok(Answer::Echo) => {
+                debug!(target: namespace, "{}: reached at ttl {}", target, ttl);
+                hops.push(Hop {
+                    ttl,
+                    address: Some(addr.ip()),
+                    rtt: Some(rtt),
+                });
+                return Ok(TraceResult {
+                    target,
+                    hops,
+                    reached: true,
+                });
+            }
+            Ok(Answer::Icmp(address)) => {
+                debug!(target: namespace, "{}: ttl {} from {:?}", target, ttl, address);
+                hops.push(Hop {
+                    ttl,
+                    address,
+                    rtt: Some(rtt),
+                });
+            }
+            Err(_) => {
+                debug!(target: namespace, "{}: ttl {} timed out", target, ttl);
+                hops.push(Hop {
+                    ttl,
+                    address: None,
+                    rtt: None,
+                });
+            }
+        }
+    }
+
+    Ok(TraceResult {
+        target,
+        hops,
+        reached: false,
+    })
+}
+
+/// Wait for the echo of `sequence` or an ICMP error about it.
+async fn wait_answer(socket: &UdpSocket, identifier: u64, sequence: u64) -> Answer {
+    let mut buf = [0u8; 1500];
+    loop {
+        match socket.recv(&mut buf).await {
+            Ok(size) => {
+                if let Some(echo) = UdpEchoPacket::new(&buf[..size]) {
+                    if echo.get_identifier() == identifier && echo.get_sequence() == sequence {
+                        return Answer::Echo;
+                    }
+                }
+            }
+            Err(_) => {
+                // the pending error only signals that the queue has entries, drain them all
+                while let Ok((size, error)) = socket::recv_err(socket.as_raw_fd(), &mut buf) {
+                    if let Some(echo) = UdpEchoPacket::new(&buf[..size]) {
+                        if echo.get_identifier() == identifier && echo.get_sequence() == sequence {
+                            return Answer::Icmp(error.offender);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::task::{self, JoinHandle};
+
+const ATTEMPTS: usize = 10;
+
+/// Start an in-process UDP (or TCP) echo server on a free loopback port.
+///
+/// The port is picked by binding an ephemeral socket and releasing it again, so another process
+/// may grab it in between. In that case the server fails to bind and we retry with a new port.
+pub async fn start_server(tcp: bool) -> (u16, JoinHandle<anyhow::Result<()>>) {
+    let (port, handle, _) = start_server_with(tcp, |_| ()).await;
+    (port, handle)
+}
+
+/// Like [`start_server`], with `configure` applied to the server config before it runs. Also
+/// returns the counters of the server.
+#[allow(dead_code)]
+pub async fn start_server_with<F>(
+    tcp: bool,
+    configure: F,
+) -> (u16, JoinHandle<anyhow::Result<()>>, Arc<server::Stats>)
+where
+    F: Fn(&mut server::Config),
+{
+    for _ in 0..ATTEMPTS {
+        let port = if tcp {
+            std::net::TcpListener::bind("127.0.0.1:0").and_then(|s| s.local_addr())
+        } else {
+            std::net::UdpSocket::bind("127.0.0.1:0").and_then(|s| s.local_addr())
+        }
+        .expect("Failed to find free port")
+        .port();
+
+        let mut config = server::Config::new(vec![port], vec!["127.0.0.1".to_string()], tcp);
+        configure(&mut config);
+        let stats = config.stats().clone();
+        let mut handle = task::spawn(async move { config.run().await });
+
+        // bind errors surface immediately, a running server never finishes
+        if async_std::future::timeout(Duration::from_millis(100), &mut handle)
+            .await
+            .is_err()
+        {
+            return (port, handle, stats);
+        }
+    }
+
+    panic!("Failed to start server after {} attempts", ATTEMPTS);
+}
+
+/// Start a UDP echo server of our own on a free loopback port, for answers the real server never
+/// gives. Every datagram is passed to `reply` in a buffer of 1500 bytes along with its size, and
+/// the first bytes of the buffer are sent back, as many as `reply` returns. Nothing is sent back
+/// if it returns 0.
+#[allow(dead_code)]
+pub async fn start_mock<F>(reply: F) -> (u16, JoinHandle<()>)
+where
+    F: Fn(&mut [u8], usize) -> usize + Send + 'static,
+{
+    start_mock_copies(1, reply).await
+}
+
+/// Like [`start_mock`], sending every answer `copies` times.
+#[allow(dead_code)]
+pub async fn start_mock_copies<F>(copies: usize, reply: F) -> (u16, JoinHandle<()>)
+where
+    F: Fn(&mut [u8], usize) -> usize + Send + 'static,
+{
+    let socket = async_std::net::UdpSocket::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind mock server");
+    let port = socket.local_addr().unwrap().port();
+    let handle = task::spawn(async move {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (size, addr) = socket.recv_from(&mut buf).await.unwrap();
+            let size = reply(&mut buf, size);
+            if size > 0 {
+                for _ in 0..copies {
+                    socket.send_to(&buf[..size], addr).await.unwrap();
+                }
+            }
+        }
+    });
+    (port, handle)
+}
+
+/// Start a TCP server of our own on a free loopback port, for behaviour the real server never
+/// shows. Every accepted connection is passed to `serve` on a thread of its own.
+#[allow(dead_code)]
+pub fn start_tcp_mock<F>(serve: F) -> u16
+where
+    F: Fn(std::net::TcpStream) + Send + Sync + 'static,
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind mock server");
+    let port = listener.local_addr().unwrap().port();
+    let serve = Arc::new(serve);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let serve = serve.clone();
+            std::thread::spawn(move || serve(stream.unwrap()));
+        }
+    });
+    port
+}
+
+/// Close `stream` with a reset instead of the orderly shutdown, so the peer's next write or
+/// read fails.
+#[allow(dead_code)]
+pub fn reset(stream: std::net::TcpStream) {
+    use std::os::unix::io::AsRawFd;
+
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    // SAFETY: the pointer and length describe `linger`, which outlives the call
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    assert_eq!(ret, 0, "Failed to set SO_LINGER");
+}
+
+/// Drop everything `stream` receives with a socket filter, so the peer's segments and keep-alive
+/// probes go unanswered as if the host vanished.
+#[allow(dead_code)]
+pub fn blackhole(stream: &std::net::TcpStream) {
+    use std::os::unix::io::AsRawFd;
+
+    // a single `ret #0`, accepting no bytes of any packet
+    let mut filter = [libc::sock_filter {
+        code: 0x06,
+        jt: 0,
+        jf: 0,
+        k: 0,
+    }];
+    let program = libc::sock_fprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_mut_ptr(),
+    };
+    // SAFETY: the pointer and length describe `program`, whose filter outlives the call
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &program as *const libc::sock_fprog as *const libc::c_void,
+            std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    assert_eq!(ret, 0, "Failed to attach socket filter");
+}
@@ -0,0 +1,1348 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use client::{
+    AbortReason, AddressFamily, Config, IcmpError, JsonResultState, JsonResults, LoadParams,
+    OnTimeout, Report, TimeoutPhase,
+};
+use packet::{MutablePacket, MutableUdpEchoPacket, UdpEchoPacket, NEXT_LEVEL_REVERSE};
+
+#[async_std::test]
+async fn udp_echo_roundtrip() {
+    let (port, server) = common::start_server(false).await;
+
+    let tries = 20;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(results.len(), tries);
+
+    let remote = format!("127.0.0.1:{}", port).parse().unwrap();
+    for result in &results {
+        match result.state {
+            JsonResultState::Succeded(rtt) => assert!(rtt.as_nanos() > 0),
+            ref state => panic!("sequence {} has state {:?}", result.sequence, state),
+        }
+        assert_eq!(result.remote, Some(remote));
+        assert_eq!(result.family, Some(AddressFamily::Ipv4));
+    }
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn tcp_echo_roundtrip() {
+    let (port, server) = common::start_server(true).await;
+
+    let tries = 5;
+    let mut config = Config::new(true, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_connect_timeout(Duration::from_secs(1));
+    config.set_read_timeout(Duration::from_secs(1));
+
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(results.len(), tries);
+
+    let remote = format!("127.0.0.1:{}", port).parse().unwrap();
+    for result in &results {
+        assert_eq!(result.timed_out, None);
+        assert_eq!(result.remote, Some(remote));
+        match result.state {
+            JsonResultState::Succeded(rtt) => assert!(rtt.as_nanos() > 0),
+            ref state => panic!("sequence {} has state {:?}", result.sequence, state),
+        }
+    }
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn tcp_connect_refused() {
+    // nothing listens on a port we just released
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .unwrap()
+        .port();
+
+    let tries = 3;
+    for depth in [None, Some(4)] {
+        let mut config = Config::new(true, vec![format!("127.0.0.1:{}", port)], tries);
+        config.set_timeout(5);
+        config.set_connect_timeout(Duration::from_secs(1));
+        if let Some(depth) = depth {
+            config.set_tcp_pipeline(depth);
+        }
+
+        let results = config.run_collect().await.unwrap();
+        assert_eq!(results.len(), tries);
+        for result in &results {
+            assert_eq!(result.state, JsonResultState::Failed, "depth {:?}", depth);
+            let error = result.error.as_ref().expect("failed try without error");
+            assert_eq!(error.phase, TimeoutPhase::Connect);
+            assert!(!error.message.is_empty());
+        }
+    }
+}
+
+#[async_std::test]
+async fn tcp_reset() {
+    // the connections are reset once connected, so either the write or the read of a try fails
+    let port = common::start_tcp_mock(|stream| {
+        std::thread::sleep(Duration::from_millis(20));
+        common::reset(stream);
+    });
+
+    let tries = 5;
+    let mut config = Config::new(true, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_read_timeout(Duration::from_secs(1));
+
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(results.len(), tries);
+    for result in &results {
+        assert_eq!(result.state, JsonResultState::Failed);
+        let error = result.error.as_ref().expect("failed try without error");
+        assert!(
+            matches!(error.phase, TimeoutPhase::Write | TimeoutPhase::Read),
+            "{:?}",
+            error
+        );
+        assert!(!error.message.is_empty());
+    }
+}
+
+#[async_std::test]
+async fn tcp_keepalive_closures() {
+    let (port, server, stats) = common::start_server_with(true, |config| {
+        config.set_keepalive(1);
+    })
+    .await;
+
+    // the server probes once a second, nine unanswered probes close the connection
+    let stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    common::blackhole(&stream);
+    let closed = async {
+        while stats.keepalive_closures.load(Ordering::Relaxed) == 0 {
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    async_std::future::timeout(Duration::from_secs(30), closed)
+        .await
+        .expect("keepalive closure not counted");
+    assert_eq!(stats.keepalive_closures.load(Ordering::Relaxed), 1);
+
+    drop(stream);
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn tcp_pipeline() {
+    let (port, server) = common::start_server(true).await;
+
+    let tries = 50;
+    let mut config = Config::new(true, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_read_timeout(Duration::from_secs(1));
+    config.set_tcp_pipeline(8);
+
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(results.len(), tries);
+    // every frame travels over the same connection
+    assert!(results.iter().all(|r| r.local == results[0].local));
+    for result in &results {
+        assert!(!result.corrupt);
+        match result.state {
+            JsonResultState::Succeded(rtt) => assert!(rtt.as_nanos() > 0),
+            ref state => panic!("sequence {} has state {:?}", result.sequence, state),
+        }
+    }
+
+    let mut udp = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    udp.set_tcp_pipeline(8);
+    assert!(udp.run_collect().await.is_err());
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn tcp_pipeline_closed() {
+    // the connection is closed after half of the first window is echoed
+    let port = common::start_tcp_mock(|mut stream| {
+        use std::io::{Read, Write};
+
+        let mut window = [0u8; 4 * 18];
+        stream.read_exact(&mut window).unwrap();
+        stream.write_all(&window[..2 * 18]).unwrap();
+    });
+
+    let tries = 8;
+    let mut config = Config::new(true, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_read_timeout(Duration::from_secs(1));
+    config.set_tcp_pipeline(4);
+
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(results.len(), tries);
+    for result in &results {
+        match result.sequence {
+            0..=1 => assert!(matches!(result.state, JsonResultState::Succeded(_))),
+            2..=3 => {
+                assert_eq!(result.state, JsonResultState::Failed);
+                let error = result.error.as_ref().expect("failed try without error");
+                assert_eq!(error.phase, TimeoutPhase::Read);
+            }
+            _ => assert_eq!(result.state, JsonResultState::NotSent),
+        }
+    }
+}
+
+#[async_std::test]
+async fn udp_source_pool() {
+    let (first, first_server) = common::start_server(false).await;
+    let (second, second_server) = common::start_server(false).await;
+    let (third, third_server) = common::start_server(false).await;
+
+    let targets: Vec<String> = [first, second, third]
+        .iter()
+        .map(|port| format!("127.0.0.1:{}", port))
+        .collect();
+    let pool = vec!["127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap()];
+    let mut config = Config::new(false, targets.clone(), 5);
+    config.set_timeout(5);
+    config.set_source_addresses(pool.clone());
+
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(results.len(), 15);
+    for result in &results {
+        assert!(matches!(result.state, JsonResultState::Succeded(_)));
+        let index = targets.iter().position(|t| *t == result.target).unwrap();
+        assert_eq!(result.local.unwrap().ip(), pool[index % pool.len()]);
+    }
+
+    let mut foreign = Config::new(false, targets, 3);
+    foreign.set_source_addresses(vec!["192.0.2.1".parse().unwrap()]);
+    assert!(foreign.run_collect().await.is_err());
+
+    first_server.cancel().await;
+    second_server.cancel().await;
+    third_server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_server_missing_address() {
+    // 2001:db8::/32 is for documentation, so no host has it, just like a host without IPv6
+    // lacks `::`
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .and_then(|s| s.local_addr())
+        .unwrap()
+        .port();
+    let addresses = vec!["2001:db8::1".to_string(), "127.0.0.1".to_string()];
+    let mut config = server::Config::new(vec![port], addresses, false);
+    config.set_v6only(true);
+    let server = async_std::task::spawn(async move { config.run().await });
+    async_std::task::sleep(Duration::from_millis(100)).await;
+
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], 5);
+    config.set_timeout(5);
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(JsonResults::count_succeeded(&results), 5);
+
+    server.cancel().await;
+
+    // without an address left to serve the port the server fails
+    let mut config = server::Config::new(vec![port], vec!["2001:db8::1".to_string()], false);
+    config.set_v6only(true);
+    assert!(config.run().await.is_err());
+}
+
+#[async_std::test]
+async fn udp_max_total_rate() {
+    let (port, server) = common::start_server(false).await;
+    let (other_port, other_server) = common::start_server(false).await;
+
+    // both targets draw from the same budget, so 300 packets at 3000 per second take at least
+    // 90ms once the 10ms burst is used up
+    let targets = vec![
+        format!("127.0.0.1:{}", port),
+        format!("127.0.0.1:{}", other_port),
+    ];
+    let mut config = Config::new(false, targets, 150);
+    config.set_timeout(5);
+    config.set_max_total_rate(Some(3000.0)).unwrap();
+    let start = std::time::Instant::now();
+    let results = config.run_collect().await.unwrap();
+    assert!(
+        start.elapsed() >= Duration::from_millis(90),
+        "{:?}",
+        start.elapsed()
+    );
+    assert_eq!(JsonResults::count_succeeded(&results), 300);
+
+    server.cancel().await;
+    other_server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_drain_remaining_budget() {
+    let (port, server) = common::start_server(false).await;
+
+    // 40 packets 50ms apart need 2s, the timeout stops the pacing after 1s
+    let tries = 40;
+    let run = |drain| {
+        let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+        config.set_timeout(1);
+        config.set_interval(Duration::from_millis(50));
+        config.set_drain_remaining(drain);
+        config
+    };
+
+    let results = run(false).run_collect().await.unwrap();
+    let not_sent = results
+        .iter()
+        .filter(|r| r.state == JsonResultState::NotSent)
+        .count();
+    assert!(not_sent > 0);
+
+    let mut config = run(true);
+    let output = std::env::temp_dir().join(format!("udp-benchmark-drain-{}.json", port));
+    config.set_output(output.to_str().unwrap().to_string());
+    config.run().await.unwrap();
+    let report: Report = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&output);
+    assert_eq!(report.on_timeout, Some(OnTimeout::Drain));
+    assert_eq!(report.targets[0].succeeded, tries);
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_output_created_late() {
+    let (port, server) = common::start_server(false).await;
+
+    let base = std::env::temp_dir().join(format!("udp-benchmark-late-{}", port));
+    let output = base.with_extension("json");
+    let dir = base.join("targets");
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], 3);
+    config.set_timeout(5);
+    config.set_output(output.to_str().unwrap().to_string());
+    config.set_output_dir(dir.to_str().unwrap().to_string());
+
+    // a run that fails its validation leaves nothing behind
+    config.set_tcp_pipeline(2);
+    assert!(config.run().await.is_err());
+    assert!(!output.exists());
+    assert!(!base.exists());
+
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], 3);
+    config.set_timeout(5);
+    config.set_output(output.to_str().unwrap().to_string());
+    config.set_output_dir(dir.to_str().unwrap().to_string());
+    config.run().await.unwrap();
+    let written = dir.join(format!("127.0.0.1_{}.json", port)).exists();
+    let report = std::fs::read_to_string(&output);
+    let _ = std::fs::remove_file(&output);
+    let _ = std::fs::remove_dir_all(&base);
+    assert!(written);
+    let report: Report = serde_json::from_str(&report.unwrap()).unwrap();
+    assert_eq!(report.targets[0].succeeded, 3);
+
+    // the directory of the output file has to exist, it isn't created
+    config.set_output(base.join("report.json").to_str().unwrap().to_string());
+    assert!(config.run().await.is_err());
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn tcp_drain_bounded() {
+    // a listener that never accepts leaves the read of the first try hanging, so the drain never
+    // finishes on its own
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut config = Config::new(true, vec![format!("127.0.0.1:{}", port)], 5);
+    config.set_timeout(1);
+    config.set_drain_remaining(true);
+    let results = async_std::future::timeout(Duration::from_secs(10), config.run_collect())
+        .await
+        .expect("the drain is bounded")
+        .unwrap();
+    assert_eq!(results[0].state, JsonResultState::Failed);
+    drop(listener);
+}
+
+#[async_std::test]
+async fn udp_replay() {
+    let (port, server) = common::start_server(false).await;
+
+    // 20 packets over 190ms, alternating between the plain size and a larger one
+    let trace: String = (0..20)
+        .map(|x| match x % 2 {
+            0 => format!("{}.{:03}\n", 100, x * 10),
+            _ => format!("{}.{:03} 600\n", 100, x * 10),
+        })
+        .collect();
+    let tries = 15;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_replay(client::parse_replay(&trace).unwrap());
+    let output = std::env::temp_dir().join(format!("udp-benchmark-replay-{}.json", port));
+    config.set_output(output.to_str().unwrap().to_string());
+    let start = std::time::Instant::now();
+    config.run().await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(140));
+
+    let report: Report = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&output);
+    assert_eq!(report.results.len(), tries);
+    assert_eq!(report.targets[0].succeeded, tries);
+    let replay = report.replay.unwrap();
+    assert_eq!(replay.trace_packets, 20);
+    assert_eq!(replay.replayed, tries);
+    assert!(replay.max_lateness.is_some());
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_abort_after() {
+    // a socket that never answers, kept open so no ICMP error comes back
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = silent.local_addr().unwrap().port();
+
+    // unpaced, the sender waits for the first sequences to fail instead of sending everything
+    let tries = 1000;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(10);
+    config.set_abort_after(5);
+    let output = std::env::temp_dir().join(format!("udp-benchmark-abort-{}.json", port));
+    config.set_output(output.to_str().unwrap().to_string());
+    let start = std::time::Instant::now();
+    config.run().await.unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    let report: Report = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&output);
+    let summary = &report.targets[0];
+    assert_eq!(summary.failed, 5);
+    assert_eq!(summary.not_sent, tries - 5);
+    assert_eq!(
+        summary.aborted,
+        Some(AbortReason::ConsecutiveFailures {
+            failures: 5,
+            sequence: 5
+        })
+    );
+}
+
+#[async_std::test]
+async fn udp_exit_flag() {
+    let (port, server) = common::start_server(false).await;
+
+    let tries = 1000;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(10);
+    config.set_interval(Duration::from_millis(5));
+
+    let exit = config.exit_flag();
+    async_std::task::spawn(async move {
+        async_std::task::sleep(Duration::from_millis(200)).await;
+        exit.store(true, Ordering::Relaxed);
+    });
+
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(results.len(), tries);
+
+    let succeeded = JsonResults::count_succeeded(&results);
+    let not_sent = results
+        .iter()
+        .filter(|r| r.state == JsonResultState::NotSent)
+        .count();
+    assert!(succeeded > 0);
+    assert!(not_sent > 0);
+    // everything sent before the stop had time to come back
+    assert_eq!(succeeded + not_sent, tries);
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn timeout_stops_workers() {
+    let (port, server) = common::start_server(false).await;
+
+    // 1000 packets 5ms apart need 5s, the timeout stops the run after 1s
+    let tries = 1000;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(1);
+    config.set_interval(Duration::from_millis(5));
+    let output = std::env::temp_dir().join(format!("udp-benchmark-stop-{}.json", port));
+    config.set_output(output.to_str().unwrap().to_string());
+    config.run().await.unwrap();
+
+    let report: Report = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&output);
+    let target = &report.targets[0];
+    assert!(target.succeeded > 0);
+    assert!(target.not_sent > 0);
+    // the echoes in flight at the stop still came back
+    assert_eq!(target.succeeded + target.not_sent, tries);
+    assert_eq!(report.on_timeout, Some(OnTimeout::Stop));
+
+    server.cancel().await;
+
+    // a listener that never accepts leaves the read of the first try hanging, without a read
+    // timeout only the stop ends it
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut config = Config::new(true, vec![format!("127.0.0.1:{}", port)], 5);
+    config.set_timeout(1);
+    let results = async_std::future::timeout(Duration::from_secs(10), config.run_collect())
+        .await
+        .expect("the stop ends the hanging read")
+        .unwrap();
+    assert_eq!(results[0].state, JsonResultState::Failed);
+    assert!(results[1..]
+        .iter()
+        .all(|r| r.state == JsonResultState::NotSent));
+    drop(listener);
+}
+
+#[test]
+fn sigint_stops_run() {
+    let server = async_std::task::block_on(common::start_server(false));
+    let port = server.0;
+
+    let output = std::env::temp_dir().join(format!("udp-benchmark-sigint-{}.json", port));
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["-c1000", "-i5"])
+        .arg(format!("-o{}", output.display()))
+        .arg(format!("127.0.0.1:{}", port))
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    assert!(child.wait().unwrap().success());
+
+    // the run stopped, but still reported what it got so far
+    let report: Report = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&output);
+    let target = &report.targets[0];
+    assert!(target.succeeded > 0);
+    assert!(target.not_sent > 0);
+    assert_eq!(target.succeeded + target.not_sent, 1000);
+
+    async_std::task::block_on(server.1.cancel());
+}
+
+#[async_std::test]
+async fn udp_recverr_port_unreachable() {
+    // nothing listens on a port we just released
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .and_then(|s| s.local_addr())
+        .unwrap()
+        .port();
+
+    let tries = 5;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_interval(Duration::from_millis(5));
+    config.set_recverr(true);
+
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(results.len(), tries);
+    for result in &results {
+        assert_eq!(result.state, JsonResultState::Failed);
+        assert_eq!(result.icmp_error, Some(IcmpError::PortUnreachable));
+    }
+}
+
+#[async_std::test]
+async fn no_targets() {
+    let config = Config::new(false, Vec::new(), 10);
+
+    let err = config.run_collect().await.unwrap_err();
+    assert!(err.to_string().contains("No targets"), "{:#}", err);
+}
+
+#[async_std::test]
+async fn udp_separate_recv_socket() {
+    let (port, server) = common::start_server(false).await;
+
+    let tries = 20;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_source_randomize(2);
+    config.set_separate_recv(true);
+
+    // only the receive sockets are read, so every echo has to reach them
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(JsonResults::count_succeeded(&results), tries);
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_server_timestamps() {
+    let (port, server) = common::start_server(false).await;
+
+    let tries = 10;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_server_timestamps(true);
+
+    let results = config.run_collect().await.unwrap();
+    for result in &results {
+        let rtt = match result.state {
+            JsonResultState::Succeded(rtt) => rtt,
+            ref state => panic!("sequence {} has state {:?}", result.sequence, state),
+        };
+        let split = result.one_way.expect("echo carries timestamps");
+        assert!(split.server_ns >= 0);
+        // the parts always add up, whatever the clock offset
+        assert_eq!(
+            split.forward_ns + split.server_ns + split.return_ns,
+            rtt.as_nanos() as i64
+        );
+    }
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_preload_not_recorded() {
+    let (port, server) = common::start_server(false).await;
+
+    let tries = 5;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_preload(3, Duration::from_secs(1));
+
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(results.len(), tries);
+    assert_eq!(JsonResults::count_succeeded(&results), tries);
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_fail_fast_corruption() {
+    // echoes everything, but flips the payload of sequence 3
+    let (port, mock) = common::start_mock(|buf, size| {
+        let mut echo = MutableUdpEchoPacket::new(&mut buf[..size]).unwrap();
+        if echo.get_sequence() == 3 {
+            echo.payload_mut()[0] ^= 0xff;
+        }
+        size
+    })
+    .await;
+
+    let output = std::env::temp_dir().join(format!("udp-benchmark-corruption-{}.json", port));
+    let tries = 1000;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(10);
+    config.set_interval(Duration::from_millis(5));
+    config.set_output(output.to_str().unwrap().to_string());
+    config.set_fail_fast_corruption(true);
+
+    let error = config.run().await.unwrap_err();
+    assert!(
+        error.to_string().contains("at sequence 3"),
+        "unexpected error: {}",
+        error
+    );
+
+    // the partial report is written before failing
+    let report: client::Report =
+        serde_json::from_str(&std::fs::read_to_string(&output).expect("partial report written"))
+            .unwrap();
+    let _ = std::fs::remove_file(&output);
+    assert_eq!(report.results.len(), tries);
+    assert_eq!(report.results[3].state, JsonResultState::Failed);
+    assert!(report
+        .results
+        .iter()
+        .any(|r| r.state == JsonResultState::NotSent));
+
+    mock.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_server_corruption_counted() {
+    let (port, server, stats) = common::start_server_with(false, |config| {
+        config.set_corrupt(0.2, 7);
+    })
+    .await;
+
+    let tries = 200;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    let results = config.run_collect().await.unwrap();
+    let report = Report::new(results);
+
+    let corrupted = stats.corrupted.load(Ordering::Relaxed) as usize;
+    assert!(corrupted > 0);
+    assert_eq!(report.targets[0].corrupted, corrupted);
+    // the header is left alone, so every echo still matches its request
+    assert_eq!(report.targets[0].succeeded, tries);
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_query_server() {
+    let (port, server, stats) = common::start_server_with(false, |config| {
+        config.set_corrupt(0.2, 7);
+    })
+    .await;
+
+    // the server announces the corruption, so it doesn't stop the run
+    let tries = 200;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_fail_fast_corruption(true);
+    config.set_query_server(true);
+    let output = std::env::temp_dir().join(format!("udp-benchmark-query-{}.json", port));
+    config.set_output(output.to_str().unwrap().to_string());
+    config.run().await.unwrap();
+
+    let report: Report = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&output);
+    assert!(stats.corrupted.load(Ordering::Relaxed) > 0);
+    assert_eq!(report.targets[0].succeeded, tries);
+    assert_eq!(report.server_params.len(), 1);
+    let params = &report.server_params[0];
+    assert!(params.answered);
+    assert_eq!(params.corrupt_rate, 0.2);
+    assert!(!params.reorders);
+    // the query is answered, not echoed
+    assert_eq!(stats.echoed.load(Ordering::Relaxed) as usize, tries);
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_two_way() {
+    let (port, server, _) = common::start_server_with(false, |config| {
+        config.set_reverse_probes(true);
+    })
+    .await;
+
+    let tries = 30;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_interval(Duration::from_millis(2));
+    config.set_two_way(true);
+    let output = std::env::temp_dir().join(format!("udp-benchmark-two-way-{}.json", port));
+    config.set_output(output.to_str().unwrap().to_string());
+    config.run().await.unwrap();
+
+    let report: Report = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&output);
+    assert_eq!(report.targets[0].succeeded, tries);
+    assert_eq!(report.reverse.len(), 1);
+    assert_eq!(report.reverse[0].requested, tries as u64);
+    assert_eq!(report.reverse[0].received, tries as u64);
+    assert_eq!(report.reverse[0].loss, 0.0);
+
+    server.cancel().await;
+
+    // a server that never probes back leaves the run waiting for the probes only briefly
+    let (port, mock) = common::start_mock(|buf, size| {
+        let echo = UdpEchoPacket::new(&buf[..size]).unwrap();
+        if echo.get_next_level() == NEXT_LEVEL_REVERSE {
+            0
+        } else {
+            size
+        }
+    })
+    .await;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(30);
+    config.set_two_way(true);
+    config.set_interval(Duration::from_millis(2));
+    config.set_output(output.to_str().unwrap().to_string());
+    let start = std::time::Instant::now();
+    config.run().await.unwrap();
+    assert!(
+        start.elapsed() < Duration::from_secs(10),
+        "{:?}",
+        start.elapsed()
+    );
+
+    let report: Report = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&output);
+    assert_eq!(report.targets[0].succeeded, tries);
+    assert_eq!(report.reverse[0].received, 0);
+
+    mock.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_timestamps_only() {
+    let (port, server) = common::start_server(false).await;
+
+    let tries = 10;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    let output = std::env::temp_dir().join(format!("udp-benchmark-timestamps-{}.csv", port));
+    config.set_timestamps_only(output.to_str().unwrap().to_string());
+    config.run().await.unwrap();
+
+    let csv = std::fs::read_to_string(&output).unwrap();
+    let _ = std::fs::remove_file(&output);
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some("target,seq,sent_ns,recv_ns"));
+    let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+    assert_eq!(rows.len(), tries);
+    for (seq, row) in rows.iter().enumerate() {
+        assert_eq!(row[0], format!("127.0.0.1:{}", port));
+        assert_eq!(row[1], seq.to_string());
+        let sent: u64 = row[2].parse().unwrap();
+        let recv: u64 = row[3].parse().unwrap();
+        assert!(recv >= sent);
+    }
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_recv_buffer_truncation() {
+    // pads every echo to 100 bytes, like the server's --response-size
+    let (port, mock) = common::start_mock(|_, _| 100).await;
+
+    let tries = 5;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_recv_buffer(50);
+
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(JsonResults::count_succeeded(&results), tries);
+    assert!(results
+        .iter()
+        .all(|r| r.truncated && r.received_size == Some(50)));
+
+    config.set_recv_buffer(10);
+    assert!(config.run_collect().await.is_err());
+
+    mock.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_duplicate_echoes() {
+    let (port, mock) = common::start_mock_copies(2, |_, size| size).await;
+
+    let tries = 20;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_interval(Duration::from_millis(1));
+
+    // the copies neither end the run before the last echo nor fail the answered tries
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(results.len(), tries);
+    assert_eq!(JsonResults::count_succeeded(&results), tries);
+
+    mock.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_on_result_hook() {
+    let (port, server) = common::start_server(false).await;
+
+    let tries = 20;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = seen.clone();
+    config.set_on_result(move |result| sink.lock().unwrap().push(result.clone()));
+
+    let mut results = config.run_collect().await.unwrap();
+    let mut seen = seen.lock().unwrap().clone();
+    // every result is passed exactly once, in the state it ends up with
+    seen.sort_by_key(|r| r.sequence);
+    results.sort_by_key(|r| r.sequence);
+    assert_eq!(seen, results);
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_ndjson_stream() {
+    let (port, server) = common::start_server(false).await;
+
+    let output = async_std::process::Command::new(env!("CARGO_BIN_EXE_client"))
+        .args([
+            "--ndjson",
+            "-c10",
+            "--warmup=3",
+            "--exclude-warmup-from-output",
+            "-T5",
+        ])
+        .arg(format!("127.0.0.1:{}", port))
+        .output()
+        .await
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    // one result per line and nothing else, leaving out the warmup
+    let mut sequences: Vec<u64> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let result: JsonResults = serde_json::from_str(line).unwrap();
+            assert!(!result.warmup);
+            assert!(matches!(result.state, JsonResultState::Succeded(_)));
+            result.sequence
+        })
+        .collect();
+    sequences.sort_unstable();
+    assert_eq!(sequences, (3..10).collect::<Vec<_>>());
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_interval_option() {
+    let (port, server) = common::start_server(false).await;
+
+    let output = std::env::temp_dir().join(format!("udp-benchmark-interval-{}.json", port));
+    let run = async_std::process::Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["-c5", "-i50", "-T5", "-o"])
+        .arg(&output)
+        .arg(format!("127.0.0.1:{}", port))
+        .output()
+        .await
+        .unwrap();
+    assert!(run.status.success(), "{:?}", run);
+
+    let report: Report = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&output);
+    let mut sent: Vec<Duration> = report.results.iter().filter_map(|r| r.sent_at).collect();
+    sent.sort_unstable();
+    assert_eq!(sent.len(), 5);
+    for gap in sent.windows(2).map(|w| w[1] - w[0]) {
+        assert!(gap >= Duration::from_millis(40), "sent {:?} apart", gap);
+    }
+    assert!(sent[4] - sent[0] >= Duration::from_millis(200));
+
+    let rejected = async_std::process::Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["-c5", "-iabc"])
+        .arg(format!("127.0.0.1:{}", port))
+        .output()
+        .await
+        .unwrap();
+    assert!(!rejected.status.success());
+    assert!(String::from_utf8_lossy(&rejected.stderr).contains("Failed to parse interval"));
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_under_load() {
+    let (port, server) = common::start_server(false).await;
+
+    let tries = 20;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_interval(Duration::from_millis(5));
+
+    let params = LoadParams {
+        megabits: 10.0,
+        size: 1000,
+    };
+    let result = config.run_under_load(&params).await.unwrap();
+    assert_eq!(result.targets.len(), 1);
+    let target = &result.targets[0];
+    assert_eq!(target.idle.sent, tries);
+    assert_eq!(target.loaded.sent, tries);
+    assert!(target.loaded.mean_rtt.is_some());
+    // the probe identifiers don't count towards the load, nor the other way around
+    assert!(target.load.sent > tries as u64);
+    assert!(target.load.received > 0 && target.load.received <= target.load.sent);
+    assert_eq!(target.load.received_bytes, target.load.received * 1000);
+
+    let params = LoadParams {
+        megabits: 10.0,
+        size: 10,
+    };
+    assert!(config.run_under_load(&params).await.is_err());
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_warmup_flagged() {
+    let (port, server) = common::start_server(false).await;
+
+    let tries = 20;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_warmup(5);
+
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(results.len(), tries);
+    for result in &results {
+        assert_eq!(result.warmup, result.sequence < 5);
+    }
+
+    let report = Report::new(results);
+    assert_eq!(report.targets[0].warmup, 5);
+    assert_eq!(report.targets[0].succeeded, tries - 5);
+
+    config.set_warmup(tries);
+    assert!(config.run_collect().await.is_err());
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_size_sweep() {
+    let (port, server) = common::start_server(false).await;
+
+    let tries = 10;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+
+    let result = config.run_size_sweep(&[18, 600]).await.unwrap();
+    let sizes: Vec<usize> = result.curve.iter().map(|point| point.size).collect();
+    assert_eq!(sizes, vec![18, 600]);
+    for point in &result.curve {
+        assert_eq!(point.sent, tries);
+        assert!(point.p50_rtt.is_some());
+    }
+
+    assert!(config.run_size_sweep(&[10]).await.is_err());
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_max_memory() {
+    let (port, server) = common::start_server(false).await;
+
+    // paced long enough for the memory to be checked while the run goes
+    let tries = 300;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(10);
+    config.set_interval(Duration::from_millis(1));
+    config.set_warmup(3);
+    config.set_max_memory(1);
+    let seen = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = seen.clone();
+    config.set_on_result(move |_| {
+        counter.fetch_add(1, Ordering::Relaxed);
+    });
+    let output = std::env::temp_dir().join(format!("udp-benchmark-memory-{}.json", port));
+    let dir = std::env::temp_dir().join(format!("udp-benchmark-memory-{}", port));
+    std::fs::create_dir_all(&dir).unwrap();
+    config.set_output(output.to_str().unwrap().to_string());
+    config.set_output_dir(dir.to_str().unwrap().to_string());
+    config.run().await.unwrap();
+
+    let report: Report = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    let written = std::fs::read_to_string(dir.join(format!("127.0.0.1_{}.json", port)));
+    let _ = std::fs::remove_file(&output);
+    let _ = std::fs::remove_dir_all(&dir);
+    assert!(report.results.is_empty());
+    let summary = &report.targets[0];
+    assert!(summary.summary_only);
+    assert_eq!(summary.warmup, 3);
+    assert_eq!(summary.succeeded, tries - 3);
+    assert!(summary.p99_rtt.is_some());
+    assert!(summary.send_rate.is_some());
+    // the hook still sees every result, only the samples are dropped
+    assert_eq!(seen.load(Ordering::Relaxed), tries);
+    let samples: Vec<JsonResults> = serde_json::from_str(&written.unwrap()).unwrap();
+    assert!(samples.is_empty());
+
+    // the other modes need every sample
+    config.set_repeat_until_failure(0.5);
+    assert!(config.run().await.is_err());
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_mtu_probe() {
+    // echoes IP packets of up to 1099 bytes, as if a link further on had that MTU
+    let (port, mock) = common::start_mock(|_, size| if size + 28 <= 1099 { size } else { 0 }).await;
+
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], 1);
+    let results = config.run_mtu_probe(1100).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].mtu, Some(1099));
+    assert!(results[0].reassembly.is_none());
+
+    // the whole report, as --mtu-probe writes it
+    let output = std::env::temp_dir().join(format!("udp-benchmark-mtu-{}.json", port));
+    config.set_mtu_probe(1050);
+    config.set_output(output.to_str().unwrap().to_string());
+    config.run().await.unwrap();
+    let written: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&output);
+    assert_eq!(written[0]["target"], format!("127.0.0.1:{}", port));
+    assert_eq!(written[0]["mtu"], 1050);
+
+    mock.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_ecn_reflected() {
+    let (port, server) = common::start_server(false).await;
+
+    let tries = 10;
+    let run = |codepoint| async move {
+        let output = std::env::temp_dir().join(format!("udp-benchmark-ecn-{}.json", port));
+        let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+        config.set_timeout(5);
+        config.set_ecn(client::parse_ecn(codepoint).unwrap());
+        config.set_output(output.to_str().unwrap().to_string());
+        config.run().await.unwrap();
+        let report = std::fs::read_to_string(&output);
+        let _ = std::fs::remove_file(&output);
+        report.unwrap()
+    };
+
+    // the server sees the codepoint as sent, loopback never marks congestion
+    let report = run("ect0").await;
+    let report: Report = serde_json::from_str(&report).unwrap();
+    assert_eq!(report.targets[0].succeeded, tries);
+    assert!(report.results.iter().all(|r| r.ecn == Some(0b10)));
+    assert_eq!(report.targets[0].ce_marked, 0);
+
+    // a CE mark on arrival is counted
+    let report = run("ce").await;
+    let report: Report = serde_json::from_str(&report).unwrap();
+    assert!(report.results.iter().all(|r| r.ecn == Some(0b11)));
+    assert_eq!(report.targets[0].ce_marked, tries);
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_trace() {
+    let (port, server) = common::start_server(false).await;
+
+    // loopback has no routers in between, the first TTL already reaches the server
+    let target = format!("127.0.0.1:{}", port);
+    let config = Config::new(false, vec![target.clone()], 1);
+    let results = config.run_trace(4).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].target, target);
+    assert!(results[0].reached);
+    assert_eq!(results[0].hops.len(), 1);
+    let hop = &results[0].hops[0];
+    assert_eq!(hop.ttl, 1);
+    assert_eq!(hop.address, Some("127.0.0.1".parse().unwrap()));
+    assert!(hop.rtt.is_some());
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_no_dns() {
+    let (port, server, stats) = common::start_server_with(false, |_| ()).await;
+
+    let tries = 5;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_no_dns(true);
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(JsonResults::count_succeeded(&results), tries);
+
+    // the same server by name would need the resolver, nothing is sent
+    let name = format!("localhost:{}", port);
+    let mut config = Config::new(
+        false,
+        vec![format!("127.0.0.1:{}", port), name.clone()],
+        tries,
+    );
+    config.set_timeout(5);
+    config.set_no_dns(true);
+    let error = config.run_collect().await.unwrap_err().to_string();
+    assert!(error.contains(&name), "{}", error);
+    assert!(!error.contains("127.0.0.1"), "{}", error);
+    assert_eq!(stats.echoed.load(Ordering::Relaxed), tries as u64);
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_strict_order() {
+    let (port, server, _) = common::start_server_with(false, |config| {
+        config.set_reorder(4, 1).unwrap();
+    })
+    .await;
+
+    let tries = 20;
+    let output = std::env::temp_dir().join(format!("udp-benchmark-order-{}.json", port));
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_output(output.to_str().unwrap().to_string());
+
+    // reordering is always reported, it only fails the run on request
+    config.run().await.unwrap();
+    let report: Report = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    assert_eq!(report.targets[0].succeeded, tries);
+    assert!(!report.targets[0].out_of_order.is_empty());
+
+    config.set_strict_order(true);
+    let error = config.run().await.unwrap_err().to_string();
+    let report: Report = serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&output);
+    assert!(error.contains("out of order"), "{}", error);
+    // the report is still written, listing the offending sequences
+    assert!(!report.targets[0].out_of_order.is_empty());
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_flush_interval() {
+    let (port, server) = common::start_server(false).await;
+
+    let tries = 100;
+    let output = std::env::temp_dir().join(format!("udp-benchmark-flush-{}.json", port));
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(10);
+    config.set_interval(Duration::from_millis(10));
+    config.set_flush_interval(Duration::from_millis(100));
+    config.set_output(output.to_str().unwrap().to_string());
+
+    // halfway through the run the file already holds the echoes settled so far
+    let halfway = async {
+        async_std::task::sleep(Duration::from_millis(500)).await;
+        std::fs::read_to_string(&output)
+    };
+    let (run, flushed) = futures::join!(config.run(), halfway);
+    run.unwrap();
+    let report = std::fs::read_to_string(&output);
+    let _ = std::fs::remove_file(&output);
+
+    let flushed: Report = serde_json::from_str(&flushed.unwrap()).unwrap();
+    let succeeded = flushed.targets[0].succeeded;
+    assert!(succeeded > 0 && succeeded < tries, "{}", succeeded);
+    let report: Report = serde_json::from_str(&report.unwrap()).unwrap();
+    assert_eq!(report.targets[0].succeeded, tries);
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_server_mirror() {
+    let collector = async_std::net::UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let mirror = collector.local_addr().unwrap();
+    let (port, server, stats) = common::start_server_with(false, |config| {
+        config.set_mirror(mirror);
+    })
+    .await;
+
+    let tries = 10;
+    let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(JsonResults::count_succeeded(&results), tries);
+
+    // the collector gets a copy of every request, the client is answered as usual
+    let mut sequences = Vec::new();
+    let mut buf = [0u8; 1500];
+    while sequences.len() < tries {
+        let size = async_std::future::timeout(Duration::from_secs(5), collector.recv(&mut buf))
+            .await
+            .expect("mirrored copy missing")
+            .unwrap();
+        let copy = UdpEchoPacket::new(&buf[..size]).unwrap();
+        assert_eq!(copy.get_identifier(), results[0].identifier);
+        sequences.push(copy.get_sequence());
+    }
+    sequences.sort_unstable();
+    assert_eq!(sequences, (0..tries as u64).collect::<Vec<_>>());
+    assert_eq!(stats.mirrored.load(Ordering::Relaxed), tries as u64);
+    assert_eq!(stats.echoed.load(Ordering::Relaxed), tries as u64);
+    assert_eq!(stats.mirror_dropped.load(Ordering::Relaxed), 0);
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn tcp_listen_backlog() {
+    let (port, server, stats) = common::start_server_with(true, |config| {
+        config.set_listen_backlog(4);
+    })
+    .await;
+
+    // every try connects on its own, so the server counts an accept for each
+    let tries = 20;
+    let mut config = Config::new(true, vec![format!("127.0.0.1:{}", port)], tries);
+    config.set_timeout(5);
+    config.set_read_timeout(Duration::from_secs(1));
+    let results = config.run_collect().await.unwrap();
+    assert_eq!(JsonResults::count_succeeded(&results), tries);
+    assert_eq!(stats.accepted.load(Ordering::Relaxed), tries as u64);
+    // the queue is sampled right after an accept, Linux holds at most one more than the backlog
+    let peak = stats.peak_accept_queue.load(Ordering::Relaxed);
+    assert!(peak <= 4 + 1, "{}", peak);
+    assert!(stats.accept_queue.load(Ordering::Relaxed) <= peak);
+
+    server.cancel().await;
+}
+
+#[async_std::test]
+async fn udp_report_to() {
+    use async_std::io::ReadExt;
+
+    let (port, server) = common::start_server(false).await;
+
+    let tries = 5;
+    let run = |address: String| async move {
+        let mut config = Config::new(false, vec![format!("127.0.0.1:{}", port)], tries);
+        config.set_timeout(5);
+        config.set_report_to(address);
+        config.run().await
+    };
+    let check = |json: &str| {
+        let report: Report = serde_json::from_str(json).unwrap();
+        assert_eq!(report.targets[0].succeeded, tries);
+        assert_eq!(report.results.len(), tries);
+    };
+
+    let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let collect = async {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut json = String::new();
+        stream.read_to_string(&mut json).await.unwrap();
+        json
+    };
+    let (sent, json) = futures::join!(run(address.clone()), collect);
+    sent.unwrap();
+    check(&json);
+
+    let path = std::env::temp_dir().join(format!("udp-benchmark-report-{}.sock", port));
+    let _ = std::fs::remove_file(&path);
+    let listener = async_std::os::unix::net::UnixListener::bind(&path)
+        .await
+        .unwrap();
+    let collect = async {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut json = String::new();
+        stream.read_to_string(&mut json).await.unwrap();
+        json
+    };
+    let (sent, json) = futures::join!(run(format!("unix:{}", path.display())), collect);
+    let _ = std::fs::remove_file(&path);
+    sent.unwrap();
+    check(&json);
+
+    // nobody listens anymore, the report goes to stdout and the run fails
+    drop(listener);
+    assert!(run(format!("unix:{}", path.display())).await.is_err());
+
+    server.cancel().await;
+}
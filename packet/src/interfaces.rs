@@ -0,0 +1,35 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Enumerate the network interfaces and their IP addresses, in the order reported by `getifaddrs`.
+/// Shared by the `--list-interfaces` of client and server.
+pub fn list_interfaces() -> io::Result<Vec<(String, Vec<IpAddr>)>> {
+    let mut interfaces: Vec<(String, Vec<IpAddr>)> = Vec::new();
+
+    for ifaddr in nix::ifaddrs::getifaddrs()? {
+        let address = ifaddr.address.and_then(|a| {
+            if let Some(v4) = a.as_sockaddr_in() {
+                Some(IpAddr::V4(Ipv4Addr::from(v4.ip())))
+            } else {
+                a.as_sockaddr_in6().map(|v6| IpAddr::V6(v6.ip()))
+            }
+        });
+
+        let index = match interfaces
+            .iter()
+            .position(|(name, _)| *name == ifaddr.interface_name)
+        {
+            Some(index) => index,
+            None => {
+                interfaces.push((ifaddr.interface_name, Vec::new()));
+                interfaces.len() - 1
+            }
+        };
+
+        if let Some(address) = address {
+            interfaces[index].1.push(address);
+        }
+    }
+
+    Ok(interfaces)
+}
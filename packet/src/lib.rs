@@ -1,5 +1,154 @@
+mod interfaces;
+
 use pnet_macros::packet;
-use pnet_macros_support::types::u64be;
+use pnet_macros_support::types::{u32be, u64be};
+
+pub use crate::interfaces::list_interfaces;
+pub use pnet_macros_support::packet::{MutablePacket, Packet};
+
+/// `next_level` value asking the server to overwrite the first payload byte with the TOS/traffic
+/// class byte it received the packet with.
+pub const NEXT_LEVEL_TOS: u8 = 1;
+
+/// `next_level` value asking the server to write the time it received the packet and the time it
+/// sent the echo into the first [`TIMESTAMPS_LEN`] payload bytes, see [`write_timestamps`].
+pub const NEXT_LEVEL_TIMESTAMPS: u8 = 2;
+
+/// Payload bytes needed for [`NEXT_LEVEL_TIMESTAMPS`].
+pub const TIMESTAMPS_LEN: usize = 16;
+
+/// `next_level` value of a request asking the server to send probes back to the sender, see
+/// [`write_reverse_request`]. The server doesn't echo the request itself.
+pub const NEXT_LEVEL_REVERSE: u8 = 3;
+
+/// `next_level` value of a probe the server sends for a [`NEXT_LEVEL_REVERSE`] request. It
+/// carries the server's send time in nanoseconds since the UNIX epoch as the first 8 payload
+/// bytes, and the client sends it back unchanged.
+pub const NEXT_LEVEL_REVERSE_PROBE: u8 = 4;
+
+/// Payload bytes needed for [`NEXT_LEVEL_REVERSE`].
+pub const REVERSE_REQUEST_LEN: usize = 16;
+
+/// `next_level` value of a query for the echo modes of the server. The server answers with the
+/// same `next_level` and its modes in the first [`PARAMS_LEN`] payload bytes, see
+/// [`write_params`]. A server that doesn't know the query echoes it unchanged, which
+/// [`read_params`] tells apart.
+pub const NEXT_LEVEL_PARAMS: u8 = 5;
+
+/// Payload bytes needed for [`NEXT_LEVEL_PARAMS`].
+pub const PARAMS_LEN: usize = 16;
+
+/// Marks a [`NEXT_LEVEL_PARAMS`] answer, as opposed to the query echoed back as is.
+const PARAMS_MAGIC: [u8; 4] = *b"prms";
+
+/// Echo modes of a server that change what comes back, answered to a [`NEXT_LEVEL_PARAMS`]
+/// query.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EchoParams {
+    /// Echoes may come back in a different order.
+    pub reorders: bool,
+    /// Echoes are held back and sent in batches.
+    pub aggregates: bool,
+    /// Echoes are held back for a random delay.
+    pub delays: bool,
+    /// Some requests are dropped on purpose, by a loss pattern or a bandwidth limit.
+    pub drops: bool,
+    /// Echoes may be larger or smaller than the request.
+    pub resizes: bool,
+    /// Fraction of the echoes with a flipped payload byte.
+    pub corrupt_rate: f64,
+}
+
+const PARAMS_REORDERS: u32 = 1 << 0;
+const PARAMS_AGGREGATES: u32 = 1 << 1;
+const PARAMS_DELAYS: u32 = 1 << 2;
+const PARAMS_DROPS: u32 = 1 << 3;
+const PARAMS_RESIZES: u32 = 1 << 4;
+
+/// Write `params` into `payload` as the answer to a [`NEXT_LEVEL_PARAMS`] query. Returns `false`
+/// if the payload is too short.
+pub fn write_params(payload: &mut [u8], params: &EchoParams) -> bool {
+    if payload.len() < PARAMS_LEN {
+        return false;
+    }
+    let flags = [
+        (params.reorders, PARAMS_REORDERS),
+        (params.aggregates, PARAMS_AGGREGATES),
+        (params.delays, PARAMS_DELAYS),
+        (params.drops, PARAMS_DROPS),
+        (params.resizes, PARAMS_RESIZES),
+    ]
+    .iter()
+    .filter(|(set, _)| *set)
+    .fold(0, |flags, (_, flag)| flags | flag);
+    payload[..4].copy_from_slice(&PARAMS_MAGIC);
+    payload[4..8].copy_from_slice(&flags.to_be_bytes());
+    payload[8..16].copy_from_slice(&params.corrupt_rate.to_bits().to_be_bytes());
+    true
+}
+
+/// Read the modes written by [`write_params`], `None` if `payload` is not such an answer.
+pub fn read_params(payload: &[u8]) -> Option<EchoParams> {
+    if payload.len() < PARAMS_LEN || payload[..4] != PARAMS_MAGIC {
+        return None;
+    }
+    let mut flags = [0u8; 4];
+    let mut corrupt_rate = [0u8; 8];
+    flags.copy_from_slice(&payload[4..8]);
+    corrupt_rate.copy_from_slice(&payload[8..16]);
+    let flags = u32::from_be_bytes(flags);
+    Some(EchoParams {
+        reorders: flags & PARAMS_REORDERS != 0,
+        aggregates: flags & PARAMS_AGGREGATES != 0,
+        delays: flags & PARAMS_DELAYS != 0,
+        drops: flags & PARAMS_DROPS != 0,
+        resizes: flags & PARAMS_RESIZES != 0,
+        corrupt_rate: f64::from_bits(u64::from_be_bytes(corrupt_rate)),
+    })
+}
+
+/// Write the receive and send time, in nanoseconds since the UNIX epoch, as big-endian integers
+/// into `payload`. Returns `false` if the payload is too short.
+pub fn write_timestamps(payload: &mut [u8], received: u64, sent: u64) -> bool {
+    write_pair(payload, received, sent)
+}
+
+/// Read the receive and send time written by [`write_timestamps`].
+pub fn read_timestamps(payload: &[u8]) -> Option<(u64, u64)> {
+    read_pair(payload)
+}
+
+/// Write the number of probes and the microseconds between them a [`NEXT_LEVEL_REVERSE`]
+/// request asks for, as big-endian integers into `payload`. Returns `false` if the payload is
+/// too short.
+pub fn write_reverse_request(payload: &mut [u8], count: u64, interval_us: u64) -> bool {
+    write_pair(payload, count, interval_us)
+}
+
+/// Read the probe count and interval written by [`write_reverse_request`].
+pub fn read_reverse_request(payload: &[u8]) -> Option<(u64, u64)> {
+    read_pair(payload)
+}
+
+fn write_pair(payload: &mut [u8], first: u64, second: u64) -> bool {
+    if payload.len() < 16 {
+        return false;
+    }
+    payload[..8].copy_from_slice(&first.to_be_bytes());
+    payload[8..16].copy_from_slice(&second.to_be_bytes());
+    true
+}
+
+fn read_pair(payload: &[u8]) -> Option<(u64, u64)> {
+    if payload.len() < 16 {
+        return None;
+    }
+    let mut first = [0u8; 8];
+    let mut second = [0u8; 8];
+    first.copy_from_slice(&payload[..8]);
+    second.copy_from_slice(&payload[8..16]);
+    Some((u64::from_be_bytes(first), u64::from_be_bytes(second)))
+}
 
 //#[derive(Packet)]
 #[packet]
@@ -28,9 +177,126 @@ impl UdpEcho {
     }
 }
 
+/// Error returned by [`UdpEchoBuilder::build_into`] if the packet does not fit the buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferTooSmall {
+    pub needed: usize,
+    pub available: usize,
+}
+
+impl std::fmt::Display for BufferTooSmall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "packet needs {} bytes, buffer has {}",
+            self.needed, self.available
+        )
+    }
+}
+
+impl std::error::Error for BufferTooSmall {}
+
+/// Writes a UdpEcho packet straight into a buffer, without going through an owned `UdpEcho`.
+///
+/// ```
+/// # use packet::UdpEchoBuilder;
+/// let mut buf = [0u8; 18];
+/// let len = UdpEchoBuilder::new()
+///     .identifier(1)
+///     .sequence(2)
+///     .payload(&[0])
+///     .build_into(&mut buf)
+///     .unwrap();
+/// assert_eq!(len, 18);
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UdpEchoBuilder<'a> {
+    identifier: u64,
+    sequence: u64,
+    next_level: u8,
+    payload: &'a [u8],
+}
+
+impl<'a> UdpEchoBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn identifier(mut self, identifier: u64) -> Self {
+        self.identifier = identifier;
+        self
+    }
+
+    pub fn sequence(mut self, sequence: u64) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    pub fn next_level(mut self, next_level: u8) -> Self {
+        self.next_level = next_level;
+        self
+    }
+
+    pub fn payload(mut self, payload: &'a [u8]) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Length of the packet on the wire.
+    pub fn packet_len(&self) -> usize {
+        UdpEchoPacket::minimum_packet_size() + self.payload.len()
+    }
+
+    /// Write the packet to the start of `buf`, returning its length.
+    pub fn build_into(&self, buf: &mut [u8]) -> Result<usize, BufferTooSmall> {
+        let len = self.packet_len();
+        if buf.len() < len {
+            return Err(BufferTooSmall {
+                needed: len,
+                available: buf.len(),
+            });
+        }
+
+        let mut echo = MutableUdpEchoPacket::new(&mut buf[..len]).expect("length checked above");
+        echo.set_identifier(self.identifier);
+        echo.set_sequence(self.sequence);
+        echo.set_next_level(self.next_level);
+        echo.set_payload(self.payload);
+
+        Ok(len)
+    }
+}
+
+// Compact variant of UdpEcho, halving the header for tiny payloads
+#[packet]
+pub struct UdpEchoCompact {
+    // Same meaning as in UdpEcho, limited to 32 bits
+    pub identifier: u32be,
+    pub sequence: u32be,
+    pub next_level: u8,
+
+    #[payload]
+    pub payload: Vec<u8>,
+}
+
+impl UdpEchoCompact {
+    pub fn new(identifier: u32, sequence: u32) -> Self {
+        Self {
+            identifier,
+            sequence,
+            next_level: 0,
+            payload: Vec::new(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{MutableUdpEchoPacket, UdpEcho};
+    use crate::{
+        read_params, read_timestamps, write_params, write_timestamps, BufferTooSmall, EchoParams,
+        MutableUdpEchoCompactPacket, MutableUdpEchoPacket, Packet, UdpEcho, UdpEchoBuilder,
+        UdpEchoCompact, UdpEchoCompactPacket, UdpEchoPacket, PARAMS_LEN, TIMESTAMPS_LEN,
+    };
 
     #[test]
     fn accessors() {
@@ -46,4 +312,87 @@ mod tests {
         assert_eq!(mutable.get_sequence(), sequence);
         assert_eq!(mutable.get_next_level(), 0);
     }
+
+    #[test]
+    fn compact_accessors() {
+        let echo = UdpEchoCompact::new(1234, 5678);
+
+        let mut buf = [0u8; 9];
+        let mut mutable = MutableUdpEchoCompactPacket::new(&mut buf).unwrap();
+        mutable.populate(&echo);
+
+        assert_eq!(mutable.get_identifier(), 1234);
+        assert_eq!(mutable.get_sequence(), 5678);
+        assert_eq!(mutable.get_next_level(), 0);
+        assert_eq!(
+            UdpEchoCompactPacket::minimum_packet_size() + 8,
+            UdpEchoPacket::minimum_packet_size()
+        );
+    }
+
+    #[test]
+    fn builder() {
+        let mut buf = [0xffu8; 32];
+        let len = UdpEchoBuilder::new()
+            .identifier(1234)
+            .sequence(5678)
+            .next_level(1)
+            .payload(&[7, 8])
+            .build_into(&mut buf)
+            .unwrap();
+        assert_eq!(len, 19);
+
+        let echo = UdpEchoPacket::new(&buf[..len]).unwrap();
+        assert_eq!(echo.get_identifier(), 1234);
+        assert_eq!(echo.get_sequence(), 5678);
+        assert_eq!(echo.get_next_level(), 1);
+        assert_eq!(echo.payload(), &[7, 8]);
+        assert_eq!(buf[len], 0xff);
+
+        let len = UdpEchoBuilder::new().build_into(&mut buf).unwrap();
+        assert_eq!(len, UdpEchoPacket::minimum_packet_size());
+        assert_eq!(&buf[..len], &[0; 17]);
+    }
+
+    #[test]
+    fn builder_undersized() {
+        let mut buf = [0u8; 17];
+        assert_eq!(
+            UdpEchoBuilder::new().payload(&[0]).build_into(&mut buf),
+            Err(BufferTooSmall {
+                needed: 18,
+                available: 17
+            })
+        );
+        assert!(UdpEchoBuilder::new().build_into(&mut buf[..16]).is_err());
+        assert_eq!(UdpEchoBuilder::new().build_into(&mut buf), Ok(17));
+    }
+
+    #[test]
+    fn timestamps() {
+        let mut payload = [0u8; TIMESTAMPS_LEN + 1];
+        assert!(write_timestamps(&mut payload, 1, u64::MAX - 1));
+        assert_eq!(read_timestamps(&payload), Some((1, u64::MAX - 1)));
+
+        assert!(!write_timestamps(&mut payload[..TIMESTAMPS_LEN - 1], 1, 2));
+        assert_eq!(read_timestamps(&payload[..TIMESTAMPS_LEN - 1]), None);
+    }
+
+    #[test]
+    fn params() {
+        let params = EchoParams {
+            reorders: true,
+            drops: true,
+            corrupt_rate: 0.25,
+            ..EchoParams::default()
+        };
+        let mut payload = [0u8; PARAMS_LEN];
+        // an echoed query is not an answer
+        assert_eq!(read_params(&payload), None);
+        assert!(write_params(&mut payload, &params));
+        assert_eq!(read_params(&payload), Some(params));
+
+        assert!(!write_params(&mut payload[..PARAMS_LEN - 1], &params));
+        assert_eq!(read_params(&payload[..PARAMS_LEN - 1]), None);
+    }
 }
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Holds back the echoes of every peer until it sent a full batch, to model a backend that
+/// processes requests in batches.
+#[derive(Debug)]
+pub struct Aggregate {
+    size: usize,
+    /// Longest a partial batch is held before it is released anyway.
+    timeout: Duration,
+    /// Partial batches with the arrival of their first packet.
+    pending: HashMap<SocketAddr, (Instant, Vec<Vec<u8>>)>,
+}
+
+impl Aggregate {
+    pub fn new(size: usize, timeout: Duration) -> Self {
+        Self {
+            size: size.max(1),
+            timeout,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queue an echo for `addr`, returning its batch once it is full.
+    pub fn push(
+        &mut self,
+        packet: Vec<u8>,
+        addr: SocketAddr,
+        now: Instant,
+    ) -> Option<Vec<Vec<u8>>> {
+        let size = self.size;
+        let (_, batch) = self
+            .pending
+            .entry(addr)
+            .or_insert_with(|| (now, Vec::with_capacity(size)));
+        batch.push(packet);
+        if batch.len() < size {
+            return None;
+        }
+        self.pending.remove(&addr).map(|(_, batch)| batch)
+    }
+
+    /// Time until the oldest partial batch times out, zero if one already did.
+    pub fn next_due(&self, now: Instant) -> Option<Duration> {
+        self.pending
+            .values()
+            .map(|(since, _)| (*since + self.timeout).saturating_duration_since(now))
+            .min()
+    }
+
+    /// Remove and return the partial batches that timed out.
+    pub fn expired(&mut self, now: Instant) -> Vec<(SocketAddr, Vec<Vec<u8>>)> {
+        let timeout = self.timeout;
+        let due: Vec<SocketAddr> = self
+            .pending
+            .iter()
+            .filter(|(_, (since, _))| now.saturating_duration_since(*since) >= timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+        due.into_iter()
+            .filter_map(|addr| self.pending.remove(&addr).map(|(_, batch)| (addr, batch)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Aggregate;
+
+    #[test]
+    fn batches_per_peer() {
+        let a = "127.0.0.1:1".parse().unwrap();
+        let b = "127.0.0.1:2".parse().unwrap();
+        let start = Instant::now();
+        let mut aggregate = Aggregate::new(3, Duration::from_millis(100));
+
+        assert_eq!(aggregate.push(vec![0], a, start), None);
+        assert_eq!(aggregate.push(vec![1], a, start), None);
+        assert_eq!(aggregate.push(vec![9], b, start), None);
+        assert_eq!(
+            aggregate.push(vec![2], a, start),
+            Some(vec![vec![0], vec![1], vec![2]])
+        );
+        assert_eq!(aggregate.next_due(start), Some(Duration::from_millis(100)));
+
+        let later = start + Duration::from_millis(150);
+        assert_eq!(aggregate.push(vec![3], a, later), None);
+        assert_eq!(aggregate.next_due(later), Some(Duration::ZERO));
+        assert_eq!(aggregate.expired(later), vec![(b, vec![vec![9]])]);
+        assert!(!aggregate.is_empty());
+        assert_eq!(aggregate.expired(later), vec![]);
+    }
+}
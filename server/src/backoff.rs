@@ -0,0 +1,100 @@
+use std::io;
+use std::time::Duration;
+
+/// First wait after a resource error.
+const MIN_BACKOFF: Duration = Duration::from_millis(1);
+
+/// How a failed receive or accept is handled.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ErrorClass {
+    /// Retried right away: interrupts, spurious wakeups, and errors about a single peer, like
+    /// an ICMP unreachable reported on the UDP socket or a connection reset before its accept.
+    Transient,
+    /// Retried after a backoff: the host is short of buffers, memory or file descriptors.
+    Resource,
+    /// The socket itself is broken, serving it stops.
+    Fatal,
+}
+
+impl ErrorClass {
+    pub fn of(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => return Self::Transient,
+            _ => (),
+        }
+        match error.raw_os_error() {
+            Some(
+                libc::EINTR
+                | libc::EAGAIN
+                | libc::ECONNREFUSED
+                | libc::ECONNRESET
+                | libc::ECONNABORTED
+                | libc::EHOSTUNREACH
+                | libc::ENETUNREACH
+                | libc::EPROTO,
+            ) => Self::Transient,
+            Some(libc::ENOBUFS | libc::ENOMEM | libc::EMFILE | libc::ENFILE) => Self::Resource,
+            _ => Self::Fatal,
+        }
+    }
+}
+
+/// Wait between retries while resource errors persist, doubling up to a maximum.
+#[derive(Debug)]
+pub struct Backoff {
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(max: Duration) -> Self {
+        Self {
+            max,
+            next: MIN_BACKOFF.min(max),
+        }
+    }
+
+    /// Time to wait before the next retry.
+    pub fn next(&mut self) -> Duration {
+        let wait = self.next;
+        self.next = (self.next * 2).min(self.max);
+        wait
+    }
+
+    /// Start over after a success.
+    pub fn reset(&mut self) {
+        self.next = MIN_BACKOFF.min(self.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+
+    use super::{Backoff, ErrorClass};
+
+    #[test]
+    fn classes() {
+        let class = |errno| ErrorClass::of(&io::Error::from_raw_os_error(errno));
+        assert_eq!(class(libc::EINTR), ErrorClass::Transient);
+        assert_eq!(class(libc::EAGAIN), ErrorClass::Transient);
+        assert_eq!(class(libc::ECONNREFUSED), ErrorClass::Transient);
+        assert_eq!(class(libc::ENOBUFS), ErrorClass::Resource);
+        assert_eq!(class(libc::EMFILE), ErrorClass::Resource);
+        assert_eq!(class(libc::EBADF), ErrorClass::Fatal);
+        assert_eq!(
+            ErrorClass::of(&io::ErrorKind::Interrupted.into()),
+            ErrorClass::Transient
+        );
+    }
+
+    #[test]
+    fn doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(5));
+        let waits: Vec<u64> = (0..5).map(|_| backoff.next().as_millis() as u64).collect();
+        assert_eq!(waits, vec![1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.next(), Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+/// Longest an echo waits for tokens in [`Shaping::Delay`] mode, anything later is dropped so a
+/// sustained overload can't queue up without bound.
+const MAX_WAIT: Duration = Duration::from_secs(1);
+
+/// Least time worth of tokens the bucket holds, so short bursts pass unshaped.
+const BURST: Duration = Duration::from_millis(10);
+
+/// What `--max-bandwidth` does with echoes above the rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shaping {
+    /// Hold them back until enough tokens accumulated.
+    Delay,
+    /// Drop them.
+    Drop,
+}
+
+/// Verdict of the bucket for a single echo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Admit {
+    Now,
+    After(Duration),
+    Drop,
+}
+
+/// Token bucket over the UDP payload bytes of all echoes, shared by every port.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Bytes per second.
+    rate: f64,
+    /// Most tokens the bucket holds.
+    burst: f64,
+    shaping: Shaping,
+    /// Tokens available at the given time, negative while delayed echoes are owed.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// A full bucket for `megabits` per second. The burst covers at least `largest` bytes, so
+    /// every echo can pass eventually.
+    pub fn new(megabits: f64, shaping: Shaping, largest: usize) -> Result<Self> {
+        if !(megabits.is_finite() && megabits > 0.0) {
+            bail!("Bandwidth must be positive, got {}", megabits);
+        }
+        let rate = megabits * 1_000_000.0 / 8.0;
+        let burst = (rate * BURST.as_secs_f64()).max(largest as f64);
+        Ok(Self {
+            rate,
+            burst,
+            shaping,
+            state: Mutex::new((burst, Instant::now())),
+        })
+    }
+
+    pub fn admit(&self, bytes: usize) -> Admit {
+        self.admit_at(bytes, Instant::now())
+    }
+
+    fn admit_at(&self, bytes: usize, now: Instant) -> Admit {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        *tokens = (*tokens + now.saturating_duration_since(*last).as_secs_f64() * self.rate)
+            .min(self.burst);
+        *last = now.max(*last);
+
+        let bytes = bytes as f64;
+        if *tokens >= bytes {
+            *tokens -= bytes;
+            return Admit::Now;
+        }
+        match self.shaping {
+            Shaping::Drop => Admit::Drop,
+            Shaping::Delay => {
+                let wait = Duration::from_secs_f64((bytes - *tokens) / self.rate);
+                if wait > MAX_WAIT {
+                    return Admit::Drop;
+                }
+                *tokens -= bytes;
+                Admit::After(wait)
+            }
+        }
+    }
+}
+
+/// Parse the `--bandwidth-shaping` mode, `delay` or `drop`.
+pub fn parse_shaping(mode: &str) -> Result<Shaping> {
+    match mode {
+        "delay" => Ok(Shaping::Delay),
+        "drop" => Ok(Shaping::Drop),
+        _ => bail!("Unknown shaping mode '{}', expected delay or drop", mode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Admit, Shaping, TokenBucket};
+
+    #[test]
+    fn bucket() {
+        // 8 Mbit/s are 1000 bytes per millisecond, the burst is 10 ms
+        let bucket = TokenBucket::new(8.0, Shaping::Drop, 1000).unwrap();
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(bucket.admit_at(1000, start), Admit::Now);
+        }
+        assert_eq!(bucket.admit_at(1000, start), Admit::Drop);
+        let later = start + Duration::from_millis(1);
+        assert_eq!(bucket.admit_at(1000, later), Admit::Now);
+        assert_eq!(bucket.admit_at(1000, later), Admit::Drop);
+
+        let bucket = TokenBucket::new(8.0, Shaping::Delay, 1000).unwrap();
+        for _ in 0..10 {
+            assert_eq!(bucket.admit_at(1000, start), Admit::Now);
+        }
+        // every further echo waits one more millisecond
+        for ms in 1..=3 {
+            match bucket.admit_at(1000, start) {
+                Admit::After(wait) => {
+                    assert!((wait.as_secs_f64() * 1000.0 - ms as f64).abs() < 1e-6)
+                }
+                admit => panic!("expected a delay, got {:?}", admit),
+            }
+        }
+        // owing more than a second drops
+        assert_eq!(bucket.admit_at(2_000_000, start), Admit::Drop);
+
+        assert!(TokenBucket::new(0.0, Shaping::Drop, 1000).is_err());
+    }
+}
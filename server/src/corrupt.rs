@@ -0,0 +1,89 @@
+use packet::{MutablePacket, MutableUdpEchoPacket};
+
+use crate::reorder::xorshift;
+
+/// Flips a byte in the payload of UDP echoes with a given probability, `--corrupt`.
+#[derive(Debug)]
+pub struct Corrupt {
+    rate: f64,
+    state: u64,
+}
+
+impl Corrupt {
+    /// Corrupt a `rate` fraction of the echoes, using a generator seeded with `seed`.
+    pub fn new(rate: f64, seed: u64) -> Self {
+        Self {
+            rate,
+            // xorshift gets stuck on zero
+            state: seed.max(1),
+        }
+    }
+
+    /// Maybe flip a byte in the payload of the plain echo `packet`, returning whether it did.
+    ///
+    /// The header stays intact so the client still matches the echo to its request. Echoes
+    /// without a payload, or whose payload carries reflected data, are left alone.
+    pub fn apply(&mut self, packet: &mut [u8]) -> bool {
+        let draw = xorshift(&mut self.state);
+        if draw as f64 >= self.rate * u64::MAX as f64 {
+            return false;
+        }
+        let mut echo = match MutableUdpEchoPacket::new(packet) {
+            Some(echo) if echo.get_next_level() == 0 => echo,
+            _ => return false,
+        };
+        let payload = echo.payload_mut();
+        if payload.is_empty() {
+            return false;
+        }
+        let pick = xorshift(&mut self.state);
+        let index = (pick % payload.len() as u64) as usize;
+        // a zero mask would leave the byte as it was
+        payload[index] ^= ((pick >> 56) as u8).max(1);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use packet::{UdpEchoBuilder, UdpEchoPacket};
+
+    use super::Corrupt;
+
+    fn echo(payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; UdpEchoPacket::minimum_packet_size() + payload.len()];
+        UdpEchoBuilder::new()
+            .identifier(1)
+            .sequence(2)
+            .payload(payload)
+            .build_into(&mut buf)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn flips_payload_only() {
+        let header = UdpEchoPacket::minimum_packet_size();
+        let mut corrupt = Corrupt::new(0.5, 3);
+        let mut flipped = 0;
+        for _ in 0..1000 {
+            let sent = echo(&[0; 8]);
+            let mut packet = sent.clone();
+            if corrupt.apply(&mut packet) {
+                flipped += 1;
+                assert_eq!(packet[..header], sent[..header]);
+                assert_ne!(packet, sent);
+            } else {
+                assert_eq!(packet, sent);
+            }
+        }
+        assert!((400..600).contains(&flipped), "{}", flipped);
+
+        let mut never = Corrupt::new(0.0, 3);
+        let mut always = Corrupt::new(1.0, 3);
+        let mut packet = echo(&[0; 8]);
+        assert!(!never.apply(&mut packet));
+        assert!(always.apply(&mut packet));
+        assert!(!always.apply(&mut echo(&[])));
+    }
+}
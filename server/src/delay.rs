@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use crate::reorder::xorshift;
+
+/// Echo delays drawn from a histogram, `--echo-delay-distribution`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DelayDistribution {
+    /// Delay of every bucket with the sum of the weights up to and including it.
+    buckets: Vec<(Duration, u64)>,
+}
+
+impl DelayDistribution {
+    /// Parse lines of `DELAY_MS WEIGHT`, `#` starts a comment.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut buckets = Vec::new();
+        let mut total = 0u64;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (delay, weight) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(delay), Some(weight), None) => (delay, weight),
+                _ => bail!("Line {}: expected 'DELAY_MS WEIGHT'", number + 1),
+            };
+            let delay: f64 = delay
+                .parse()
+                .with_context(|| format!("Line {}: invalid delay '{}'", number + 1, delay))?;
+            if !(delay.is_finite() && delay >= 0.0) {
+                bail!("Line {}: delay must not be negative", number + 1);
+            }
+            let weight: u64 = weight
+                .parse()
+                .with_context(|| format!("Line {}: invalid weight '{}'", number + 1, weight))?;
+            total = total
+                .checked_add(weight)
+                .context("Histogram weights overflow")?;
+            buckets.push((Duration::from_secs_f64(delay / 1000.0), total));
+        }
+        if total == 0 {
+            bail!("Histogram has no weight");
+        }
+
+        Ok(Self { buckets })
+    }
+
+    /// Draw delays with a generator seeded with `seed`.
+    pub fn sampler(&self, seed: u64) -> DelaySampler<'_> {
+        DelaySampler {
+            distribution: self,
+            // xorshift gets stuck on zero
+            state: seed.max(1),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DelaySampler<'a> {
+    distribution: &'a DelayDistribution,
+    state: u64,
+}
+
+impl DelaySampler<'_> {
+    pub fn sample(&mut self) -> Duration {
+        let buckets = &self.distribution.buckets;
+        let total = buckets[buckets.len() - 1].1;
+        let draw = xorshift(&mut self.state) % total;
+        let index = buckets.partition_point(|&(_, cumulative)| cumulative <= draw);
+        buckets[index].0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::DelayDistribution;
+
+    #[test]
+    fn histogram() {
+        let text = "# ms weight\n1 3\n5 0\n10 1 # tail\n";
+        let distribution = DelayDistribution::parse(text).unwrap();
+
+        let mut sampler = distribution.sampler(1);
+        let draws: Vec<Duration> = (0..4000).map(|_| sampler.sample()).collect();
+        let fast = draws
+            .iter()
+            .filter(|&&d| d == Duration::from_millis(1))
+            .count();
+        let slow = draws
+            .iter()
+            .filter(|&&d| d == Duration::from_millis(10))
+            .count();
+        assert_eq!(fast + slow, draws.len());
+        assert!((2800..3200).contains(&fast), "{} fast draws", fast);
+
+        assert!(DelayDistribution::parse("1 0").is_err());
+        assert!(DelayDistribution::parse("1").is_err());
+        assert!(DelayDistribution::parse("-1 1").is_err());
+    }
+}
@@ -1,28 +1,128 @@
-use std::net::SocketAddr;
+mod aggregate;
+mod backoff;
+mod bandwidth;
+mod corrupt;
+mod delay;
+mod loss;
+mod reorder;
+mod reverse;
+mod self_test;
+mod socket;
+mod stats;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
+use async_io::Async;
 use async_std::io;
-use async_std::net::{TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
 use async_std::prelude::*;
-use async_std::stream::IntoStream;
-use getopts::Options;
 use log::*;
+use packet::{
+    read_timestamps, write_params, write_timestamps, EchoParams, MutablePacket,
+    MutableUdpEchoPacket, Packet, UdpEchoPacket, NEXT_LEVEL_PARAMS, NEXT_LEVEL_REVERSE,
+    NEXT_LEVEL_REVERSE_PROBE, NEXT_LEVEL_TIMESTAMPS, NEXT_LEVEL_TOS,
+};
+
+use crate::aggregate::Aggregate;
+use crate::backoff::{Backoff, ErrorClass};
+use crate::bandwidth::Admit;
+pub use crate::bandwidth::{parse_shaping, Shaping, TokenBucket};
+use crate::corrupt::Corrupt;
+pub use crate::delay::DelayDistribution;
+pub use crate::loss::LossPattern;
+use crate::reorder::Reorder;
+use crate::reverse::{Request, Sessions};
+pub use crate::self_test::{check_self_test, Probed, SELF_TEST_PACKETS};
+pub use crate::stats::Stats;
+pub use packet::list_interfaces;
+
+/// Largest UDP payload that fits an IPv4 datagram, caps `--response-size`.
+pub const MAX_RESPONSE: usize = 65507;
+
+/// Size of the echo sent back for a request, `--response-size`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResponseSize {
+    /// Always answer with this many bytes.
+    Fixed(usize),
+    /// Answer with the request size times this factor.
+    Factor(f64),
+}
+
+impl ResponseSize {
+    /// Response length for a request of `size` bytes. Never cuts into the header, so the
+    /// client can still match identifier and sequence.
+    pub fn apply(&self, size: usize) -> usize {
+        let len = match *self {
+            ResponseSize::Fixed(len) => len,
+            ResponseSize::Factor(factor) => (size as f64 * factor).round() as usize,
+        };
+        len.clamp(UdpEchoPacket::minimum_packet_size(), MAX_RESPONSE)
+    }
+}
+
+/// Longest wait between retries of a socket short of resources, by default.
+const DEFAULT_MAX_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Received packets `--mirror` queues before it drops copies instead of waiting for the
+/// collector.
+const MIRROR_QUEUE: usize = 1024;
+
+/// Accept queue of TCP listeners that `--v6only` binds itself, the standard library's default.
+const DEFAULT_BACKLOG: u32 = 128;
+
+/// How long `--reorder` holds an incomplete window before releasing it.
+const REORDER_HOLD: std::time::Duration = std::time::Duration::from_millis(10);
 
 pub struct Config {
-    port: u16,
+    ports: Vec<u16>,
     addresses: Vec<String>,
     tcp: bool,
+    keepalive: Option<u32>,
+    nodelay: bool,
+    listen_backlog: Option<u32>,
+    v6only: bool,
+    stats_interval: Option<u64>,
+    reorder: Option<(usize, u64)>,
+    response_size: Option<ResponseSize>,
+    loss_pattern: Option<LossPattern>,
+    delay: Option<(DelayDistribution, u64)>,
+    mirror: Option<SocketAddr>,
+    bandwidth: Option<TokenBucket>,
+    aggregate: Option<(usize, std::time::Duration)>,
+    corrupt: Option<(f64, u64)>,
+    reverse_probes: bool,
+    max_backoff: std::time::Duration,
     namespace: String,
+    stats: Arc<Stats>,
     exit: AtomicBool,
 }
 
 impl Config {
-    pub fn new(port: u16, addresses: Vec<String>, tcp: bool) -> Self {
+    pub fn new(ports: Vec<u16>, addresses: Vec<String>, tcp: bool) -> Self {
         Self {
-            port,
+            stats: Arc::new(Stats::new(&ports)),
+            ports,
             addresses,
             tcp,
+            keepalive: None,
+            nodelay: true,
+            listen_backlog: None,
+            v6only: false,
+            stats_interval: None,
+            reorder: None,
+            response_size: None,
+            loss_pattern: None,
+            delay: None,
+            mirror: None,
+            bandwidth: None,
+            aggregate: None,
+            corrupt: None,
+            reverse_probes: false,
+            max_backoff: DEFAULT_MAX_BACKOFF,
             namespace: module_path!().to_string(),
             exit: AtomicBool::new(false),
         }
@@ -32,76 +132,726 @@ impl Config {
         self.namespace = namespace;
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        let mut socket_addresses = Vec::new();
-        for address in &self.addresses {
-            info!("Listening on '[{}]:{}'", address, self.port);
-            let socket_addr = (address.as_str(), self.port)
-                .to_socket_addrs()
-                .await
-                .context("Failed to parse soket address")?
-                .collect::<Vec<SocketAddr>>();
-            socket_addresses.push(socket_addr);
+    /// Enable TCP keep-alive on accepted connections, probing after `secs` seconds of idleness.
+    pub fn set_keepalive(&mut self, secs: u32) -> &mut Self {
+        self.keepalive = Some(secs);
+        self
+    }
+
+    /// Set `TCP_NODELAY` on accepted connections, on by default.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> &mut Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Size the accept queue of the TCP listeners to `backlog` connections.
+    pub fn set_listen_backlog(&mut self, backlog: u32) -> &mut Self {
+        self.listen_backlog = Some(backlog);
+        self
+    }
+
+    /// Bind every address on its own socket and keep the IPv6 ones from taking IPv4-mapped
+    /// traffic, instead of serving a port from the first address that binds.
+    pub fn set_v6only(&mut self, v6only: bool) -> &mut Self {
+        self.v6only = v6only;
+        self
+    }
+
+    /// Log the collected stats every `secs` seconds.
+    pub fn set_stats_interval(&mut self, secs: u64) -> &mut Self {
+        self.stats_interval = Some(secs);
+        self
+    }
+
+    /// Echo UDP packets in shuffled windows of `window` packets, seeded with `seed`.
+    pub fn set_reorder(&mut self, window: usize, seed: u64) -> Result<&mut Self> {
+        if self.tcp {
+            bail!("Reordering only applies to UDP");
+        }
+        if window == 0 {
+            bail!("Reorder window must be positive");
         }
+        self.reorder = Some((window, seed));
+        Ok(self)
+    }
+
+    /// Echo the UDP packets of every peer in batches of `size`, releasing a partial batch after
+    /// `timeout`.
+    pub fn set_aggregate(&mut self, size: usize, timeout: std::time::Duration) -> &mut Self {
+        self.aggregate = Some((size, timeout));
+        self
+    }
+
+    /// Pad or truncate the payload of UDP echoes to `size`.
+    pub fn set_response_size(&mut self, size: ResponseSize) -> Result<&mut Self> {
+        if self.tcp {
+            bail!("Response size only applies to UDP");
+        }
+        if size == ResponseSize::Fixed(0) {
+            bail!("Response size must be positive");
+        }
+        self.response_size = Some(size);
+        Ok(self)
+    }
+
+    /// Drop the UDP packets listed in `pattern` instead of echoing them.
+    pub fn set_loss_pattern(&mut self, pattern: LossPattern) -> &mut Self {
+        self.loss_pattern = Some(pattern);
+        self
+    }
 
-        let socket_addresses = socket_addresses.concat();
+    /// Delay every UDP echo by a time drawn from `distribution`, seeded with `seed`.
+    pub fn set_delay_distribution(
+        &mut self,
+        distribution: DelayDistribution,
+        seed: u64,
+    ) -> &mut Self {
+        self.delay = Some((distribution, seed));
+        self
+    }
+
+    /// Forward a copy of every received UDP packet to the collector at `addr`.
+    pub fn set_mirror(&mut self, addr: SocketAddr) -> &mut Self {
+        self.mirror = Some(addr);
+        self
+    }
+
+    /// Pass every UDP echo of all ports through `bucket`.
+    pub fn set_max_bandwidth(&mut self, bucket: TokenBucket) -> &mut Self {
+        self.bandwidth = Some(bucket);
+        self
+    }
+
+    /// Flip a byte in the payload of a `rate` fraction of the UDP echoes, seeded with `seed`.
+    pub fn set_corrupt(&mut self, rate: f64, seed: u64) -> &mut Self {
+        self.corrupt = Some((rate, seed));
+        self
+    }
+
+    /// Answer the reverse probe requests of clients by probing them back over UDP.
+    pub fn set_reverse_probes(&mut self, reverse: bool) -> &mut Self {
+        self.reverse_probes = reverse;
+        self
+    }
+
+    /// Longest wait between retries while receiving or accepting fails for lack of resources.
+    pub fn set_max_backoff(&mut self, max: std::time::Duration) -> &mut Self {
+        self.max_backoff = max;
+        self
+    }
+
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let (workers, _) = self.bind().await?;
 
         let exit_flag = &self.exit;
         let exiter = async move {
             loop {
                 if exit_flag.load(Ordering::Relaxed) {
-                    return true;
+                    return;
                 }
                 async_std::task::sleep(std::time::Duration::from_millis(500)).await;
             }
         };
 
+        let stats = &self.stats;
+        let stats_interval = self.stats_interval;
+        let namespace = self.namespace.as_str();
+        let reporter = async move {
+            if let Some(interval) = stats_interval {
+                loop {
+                    async_std::task::sleep(std::time::Duration::from_secs(interval)).await;
+                    stats.log(namespace);
+                }
+            }
+            futures::future::pending::<()>().await
+        };
+        let exiter = exiter.race(reporter);
+
+        // a fatal error on any socket stops the whole server
+        async { futures::future::try_join_all(workers).await.map(drop) }
+            .race(async {
+                exiter.await;
+                Ok(())
+            })
+            .await?;
+
+        bail!("The loop should not exit")
+    }
+
+    /// Bind every port like [`Config::run`], then send `count` echo requests to every bound
+    /// socket from the same host instead of serving clients, returning what came back.
+    pub async fn self_test(&self, count: usize) -> Result<Vec<Probed>> {
+        let (workers, bound) = self.bind().await?;
+        let probes = async {
+            let mut probed = Vec::new();
+            for &address in &bound {
+                probed.push(self_test::probe(address, self.tcp, count).await?);
+            }
+            Ok(probed)
+        };
+        async {
+            futures::future::try_join_all(workers).await?;
+            bail!("The loop should not exit")
+        }
+        .race(probes)
+        .await
+    }
+
+    /// Open the sockets of every port, returning their workers and bound addresses.
+    async fn bind(
+        &self,
+    ) -> Result<(
+        Vec<futures::future::BoxFuture<'_, Result<()>>>,
+        Vec<SocketAddr>,
+    )> {
+        let mut socket_addresses = Vec::new();
+        for &port in &self.ports {
+            let mut port_addresses = Vec::new();
+            for address in &self.addresses {
+                info!("Listening on '[{}]:{}'", address, port);
+                let socket_addr = (address.as_str(), port)
+                    .to_socket_addrs()
+                    .await
+                    .context("Failed to parse soket address")?
+                    .collect::<Vec<SocketAddr>>();
+                port_addresses.push(socket_addr);
+            }
+            socket_addresses.push((port, port_addresses.concat()));
+        }
+
+        // bind everything first, so a taken port fails the start instead of a single worker
+        let mut workers: Vec<futures::future::BoxFuture<Result<()>>> = Vec::new();
+        let mut bound = Vec::new();
+        let mirror = match self.mirror {
+            Some(collector) => {
+                let unspecified: IpAddr = if collector.is_ipv6() {
+                    Ipv6Addr::UNSPECIFIED.into()
+                } else {
+                    Ipv4Addr::UNSPECIFIED.into()
+                };
+                let socket = async_std::net::UdpSocket::bind((unspecified, 0))
+                    .await
+                    .context("Failed to open mirror socket")?;
+                let (sender, receiver) = async_std::channel::bounded(MIRROR_QUEUE);
+                let mirror = self.mirror(socket, collector, receiver);
+                workers.push(Box::pin(async move {
+                    mirror.await;
+                    Ok(())
+                }));
+                Some(sender)
+            }
+            None => None,
+        };
+        for (port, socket_addresses) in socket_addresses {
+            // without --v6only a port is served from the first address that binds, an
+            // unspecified IPv6 one takes IPv4 as well, so the others would only conflict
+            if !self.v6only {
+                bound.push(
+                    self.bind_port(port, &socket_addresses, &mut workers, &mirror)
+                        .await?,
+                );
+                continue;
+            }
+
+            // an address that doesn't bind, like an IPv6 one on a host without IPv6, only costs
+            // that address as long as another one serves the port
+            let mut failed = Vec::new();
+            let bound_before = bound.len();
+            for address in &socket_addresses {
+                match self
+                    .bind_port(port, std::slice::from_ref(address), &mut workers, &mirror)
+                    .await
+                {
+                    Ok(address) => bound.push(address),
+                    Err(e) if unavailable(&e) => failed.push((address, e)),
+                    Err(e) => return Err(e),
+                }
+            }
+            if bound.len() == bound_before {
+                if let Some((_, e)) = failed.pop() {
+                    return Err(e);
+                }
+            }
+            for (address, e) in failed {
+                warn!(
+                    target: self.namespace.as_str(),
+                    "{:#}, serving port {} without {}", e, port, address
+                );
+            }
+        }
+
+        Ok((workers, bound))
+    }
+
+    /// Open the socket of `port` on the first of `addresses` that binds and add its worker,
+    /// returning the bound address.
+    async fn bind_port<'a>(
+        &'a self,
+        port: u16,
+        addresses: &[SocketAddr],
+        workers: &mut Vec<futures::future::BoxFuture<'a, Result<()>>>,
+        mirror: &Option<async_std::channel::Sender<Vec<u8>>>,
+    ) -> Result<SocketAddr> {
+        let namespace = self.namespace.as_str();
         if self.tcp {
-            let socket = TcpListener::bind(&*socket_addresses)
-                .await
-                .context("Failed to open TCP socket")?;
+            let socket = match (self.listen_backlog, self.v6only) {
+                (Some(backlog), v6only) => self.listen(addresses, backlog, v6only),
+                (None, true) => self.listen(addresses, DEFAULT_BACKLOG, true),
+                (None, false) => bind_first(namespace, addresses, std::net::TcpListener::bind)
+                    .map(TcpListener::from),
+            }
+            .with_context(|| format!("Failed to open TCP socket on port {}", port))?;
+            let bound = socket.local_addr()?;
+            self.log_bound(addresses, bound);
+            workers.push(Box::pin(self.serve_tcp(port, socket)));
+            Ok(bound)
+        } else {
+            let socket = if self.v6only {
+                bind_first(namespace, addresses, |address| {
+                    socket::bind_udp(address, true)
+                })
+            } else {
+                bind_first(namespace, addresses, std::net::UdpSocket::bind)
+            }
+            .with_context(|| format!("Failed to open UDP socket on port {}", port))?;
+            let bound = socket.local_addr()?;
+            self.log_bound(addresses, bound);
+            let ipv6 = bound.is_ipv6();
+            if let Err(e) = socket::enable_recv_tos(socket.as_raw_fd(), ipv6) {
+                warn!(target: namespace, "failed to enable tos reception: {}", e);
+            }
+            let socket = Async::new(socket).context("Failed to register UDP socket")?;
+            workers.push(Box::pin(self.serve_udp(port, socket, mirror.clone())));
+            Ok(bound)
+        }
+    }
 
-            let mut incoming = socket.incoming();
+    /// Bind the first of `addresses` that works, like `TcpListener::bind` with a custom backlog.
+    fn listen(
+        &self,
+        addresses: &[SocketAddr],
+        backlog: u32,
+        v6only: bool,
+    ) -> io::Result<TcpListener> {
+        bind_first(self.namespace.as_str(), addresses, |address| {
+            socket::listen(address, backlog, v6only)
+        })
+        .map(TcpListener::from)
+    }
 
-            let namespace = self.namespace.clone();
-            let worker = async move {
-                loop {
-                    if let Some(Ok(stream)) = incoming.next().await {
-                        let namespace = namespace.clone();
-                        async_std::task::spawn(async move {
-                            if let Err(e) = Self::handle_tcp(stream).await {
-                                error!(target: namespace.as_str(), "failed to copy tcp: {}", e);
-                            }
-                        });
+    /// Log which of `addresses` a port got, and that the others are not bound on their own.
+    fn log_bound(&self, addresses: &[SocketAddr], bound: SocketAddr) {
+        let namespace = self.namespace.as_str();
+        // the addresses before the bound one failed, which `bind_first` already warned about
+        let skipped: Vec<String> = addresses
+            .iter()
+            .skip_while(|&&address| address != bound)
+            .skip(1)
+            .map(|address| address.to_string())
+            .collect();
+        if skipped.is_empty() {
+            debug!(target: namespace, "bound {}", bound);
+        } else if bound.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+            info!(
+                target: namespace,
+                "bound {}, serving IPv4 as mapped addresses instead of {}, use --v6only to bind them apart",
+                bound,
+                skipped.join(", ")
+            );
+        } else {
+            info!(
+                target: namespace,
+                "bound {}, not binding {}",
+                bound,
+                skipped.join(", ")
+            );
+        }
+    }
+
+    async fn serve_tcp(&self, port: u16, socket: TcpListener) -> Result<()> {
+        let fd = socket.as_raw_fd();
+        let mut incoming = socket.incoming();
+        let mut backoff = Backoff::new(self.max_backoff);
+
+        let namespace = self.namespace.as_str();
+        let mut sample_queue = true;
+        loop {
+            let stream = match incoming.next().await {
+                Some(Ok(stream)) => {
+                    backoff.reset();
+                    stream
+                }
+                Some(Err(e)) => {
+                    self.serve_error(e, &mut backoff, port).await?;
+                    continue;
+                }
+                None => bail!("Listener on port {} closed", port),
+            };
+            self.stats.inc_port(port);
+            let queue = if sample_queue {
+                match socket::accept_queue(fd) {
+                    Ok(queue) => Some(queue),
+                    Err(e) => {
+                        warn!(target: namespace, "can't read the accept queue: {}", e);
+                        sample_queue = false;
+                        None
                     }
                 }
+            } else {
+                None
             };
+            self.stats.record_accept(queue);
+            if let Err(e) = stream.set_nodelay(self.nodelay) {
+                warn!(target: namespace, "failed to set TCP_NODELAY: {}", e);
+            }
+            if let Some(secs) = self.keepalive {
+                if let Err(e) = socket::set_keepalive(stream.as_raw_fd(), secs) {
+                    warn!(target: namespace, "failed to set keepalive: {}", e);
+                }
+            }
 
-            worker.race(exiter).await;
-        } else {
-            let socket = UdpSocket::bind(&*socket_addresses)
-                .await
-                .context("Failed to open UDP socket")?;
+            let namespace = self.namespace.clone();
+            let stats = self.stats.clone();
+            async_std::task::spawn(async move {
+                if let Err(e) = Self::handle_tcp(stream).await {
+                    // io::copy wraps the ETIMEDOUT of the socket, only its kind is left
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        Stats::inc(&stats.keepalive_closures);
+                        debug!(target: namespace.as_str(), "keepalive timed out: {}", e);
+                    } else {
+                        error!(target: namespace.as_str(), "failed to copy tcp: {}", e);
+                    }
+                }
+            });
+        }
+    }
 
-            let worker = async move {
-                let mut buf = [0u8; 1500];
+    async fn serve_udp(
+        &self,
+        port: u16,
+        socket: Async<std::net::UdpSocket>,
+        mirror: Option<async_std::channel::Sender<Vec<u8>>>,
+    ) -> Result<()> {
+        let socket = Arc::new(socket);
+        let mut backoff = Backoff::new(self.max_backoff);
+        let mut reorder = self
+            .reorder
+            .map(|(window, seed)| Reorder::new(window, seed));
+        let mut aggregate = self
+            .aggregate
+            .map(|(size, timeout)| Aggregate::new(size, timeout));
+        // every port draws its own sequence
+        let mut delay = self
+            .delay
+            .as_ref()
+            .map(|(distribution, seed)| distribution.sampler(seed ^ port as u64));
+        let mut corrupt = self
+            .corrupt
+            .map(|(rate, seed)| Corrupt::new(rate, seed ^ port as u64));
+        let sessions = Sessions::default();
+        let stats = &*self.stats;
 
-                loop {
-                    if let Ok((size, addr)) = socket.recv_from(&mut buf).await {
-                        debug_assert!(size <= buf.len());
-                        let _ = socket.send_to(&buf[..size], addr).await;
+        // large enough for any UDP datagram
+        let mut buf = vec![0u8; 65536];
 
-                        buf.fill(0);
-                        // SAFETY: buf is valid for size bytes
-                        //unsafe { libc::memset(buf.as_ptr() as *mut libc::c_void, 0, size) };
+        loop {
+            // a busy peer must not hold up the timed out batches of the others
+            if let Some(aggregate) = &mut aggregate {
+                for (addr, batch) in aggregate.expired(std::time::Instant::now()) {
+                    Self::send_batch(&socket, stats, addr, batch, false).await;
+                }
+            }
+
+            let read = socket.read_with(|s| socket::recv_from_tos(s.as_raw_fd(), &mut buf));
+            let received = match (&mut reorder, &aggregate) {
+                (Some(reorder), _) if !reorder.is_empty() => {
+                    match async_std::future::timeout(REORDER_HOLD, read).await {
+                        Ok(received) => received,
+                        Err(_) => {
+                            let flushed = reorder.flush();
+                            Self::send_reordered(&socket, stats, flushed).await;
+                            continue;
+                        }
+                    }
+                }
+                (_, Some(aggregate)) if !aggregate.is_empty() => {
+                    let due = aggregate
+                        .next_due(std::time::Instant::now())
+                        .unwrap_or_default();
+                    match async_std::future::timeout(due, read).await {
+                        Ok(received) => received,
+                        Err(_) => continue,
                     }
                 }
+                _ => read.await,
             };
 
-            worker.race(exiter).await;
+            let (size, addr, tos) = match received {
+                Ok(received) => {
+                    backoff.reset();
+                    received
+                }
+                Err(e) => {
+                    self.serve_error(e, &mut backoff, port).await?;
+                    continue;
+                }
+            };
+            let received_at = unix_nanos();
+            debug_assert!(size <= buf.len());
+            stats.inc_port(port);
+            if let Some(mirror) = &mirror {
+                // the echo never waits for the collector, a full queue loses the copy
+                if mirror.try_send(buf[..size].to_vec()).is_err() {
+                    Stats::inc(&stats.mirror_dropped);
+                }
+            }
+            // requests for and reflections of reverse probes are never echoed
+            if self.handle_reverse(&socket, &sessions, addr, &buf[..size]) {
+                buf[..size].fill(0);
+                continue;
+            }
+            // queries for the echo modes are answered even when the loss pattern would drop them
+            if self.answer_params(&socket, addr, &mut buf[..size]).await {
+                buf[..size].fill(0);
+                continue;
+            }
+            if let Some(index) = self.loss_pattern.as_ref().and_then(|p| p.next()) {
+                Stats::inc(&stats.pattern_dropped);
+                info!(target: self.namespace.as_str(), "dropped packet {}", index);
+                buf[..size].fill(0);
+                continue;
+            }
+            if let (Some(tos), Some(mut echo)) = (tos, MutableUdpEchoPacket::new(&mut buf[..size]))
+            {
+                if echo.get_next_level() == NEXT_LEVEL_TOS {
+                    if let Some(byte) = echo.payload_mut().first_mut() {
+                        *byte = tos;
+                    }
+                }
+            }
+            if let Some(mut echo) = MutableUdpEchoPacket::new(&mut buf[..size]) {
+                if echo.get_next_level() == NEXT_LEVEL_TIMESTAMPS {
+                    write_timestamps(echo.payload_mut(), received_at, 0);
+                }
+            }
+            // everything past the request is zero, so growing the echo pads it with zeros
+            let len = match self.response_size {
+                Some(response) if size >= UdpEchoPacket::minimum_packet_size() => {
+                    response.apply(size)
+                }
+                _ => size,
+            };
+            let shaped = match self.bandwidth.as_ref().map(|bucket| bucket.admit(len)) {
+                Some(Admit::Drop) => {
+                    stats
+                        .bandwidth_dropped
+                        .fetch_add(len as u64, Ordering::Relaxed);
+                    buf[..size.max(len)].fill(0);
+                    continue;
+                }
+                Some(Admit::After(wait)) => Some(wait),
+                Some(Admit::Now) | None => None,
+            };
+            if self.bandwidth.is_some() {
+                stats
+                    .bandwidth_passed
+                    .fetch_add(len as u64, Ordering::Relaxed);
+            }
+            // only echoes that leave the server count, so the client can match the total
+            if let Some(corrupt) = &mut corrupt {
+                if corrupt.apply(&mut buf[..len]) {
+                    Stats::inc(&stats.corrupted);
+                }
+            }
+            match (&mut aggregate, &mut reorder, &mut delay) {
+                (Some(aggregate), _, _) => {
+                    let now = std::time::Instant::now();
+                    if let Some(batch) = aggregate.push(buf[..len].to_vec(), addr, now) {
+                        Self::send_batch(&socket, stats, addr, batch, true).await;
+                    }
+                }
+                (None, Some(reorder), _) => {
+                    if let Some(flushed) = reorder.push(buf[..len].to_vec(), addr) {
+                        Self::send_reordered(&socket, stats, flushed).await;
+                    }
+                }
+                (None, None, delay) if delay.is_some() || shaped.is_some() => {
+                    let sampled = delay.as_mut().map(|delay| delay.sample());
+                    let wait = sampled.unwrap_or_default() + shaped.unwrap_or_default();
+                    let received = std::time::Instant::now();
+                    let mut packet = buf[..len].to_vec();
+                    let socket = socket.clone();
+                    let stats = self.stats.clone();
+                    // a sleeping echo must not hold up the packets behind it
+                    async_std::task::spawn(async move {
+                        async_std::task::sleep(wait).await;
+                        stamp_sent(&mut packet);
+                        if socket.send_to(&packet, addr).await.is_ok() {
+                            Stats::inc(&stats.echoed);
+                        }
+                        if sampled.is_some() {
+                            stats.record_delay(received.elapsed());
+                        }
+                    });
+                }
+                (None, None, _) => {
+                    stamp_sent(&mut buf[..len]);
+                    if socket.send_to(&buf[..len], addr).await.is_ok() {
+                        Stats::inc(&stats.echoed);
+                    }
+                }
+            }
+
+            buf[..size.max(len)].fill(0);
+            // SAFETY: buf is valid for size bytes
+            //unsafe { libc::memset(buf.as_ptr() as *mut libc::c_void, 0, size) };
         }
+    }
 
-        bail!("The loop should not exit")
+    /// Count a failed receive or accept on `port` by its class, waiting out resource errors
+    /// and failing on fatal ones.
+    async fn serve_error(&self, error: io::Error, backoff: &mut Backoff, port: u16) -> Result<()> {
+        let namespace = self.namespace.as_str();
+        match ErrorClass::of(&error) {
+            ErrorClass::Transient => {
+                Stats::inc(&self.stats.transient_errors);
+                debug!(target: namespace, "port {}: {}, retrying", port, error);
+            }
+            ErrorClass::Resource => {
+                Stats::inc(&self.stats.resource_errors);
+                let wait = backoff.next();
+                warn!(target: namespace, "port {}: {}, retrying in {:?}", port, error, wait);
+                async_std::task::sleep(wait).await;
+            }
+            ErrorClass::Fatal => {
+                Stats::inc(&self.stats.fatal_errors);
+                return Err(error).with_context(|| format!("Failed to serve port {}", port));
+            }
+        }
+        Ok(())
+    }
+
+    /// Start the probes of a reverse probe request or count the reflection of a probe, returning
+    /// whether `packet` was one of them.
+    fn handle_reverse(
+        &self,
+        socket: &Arc<Async<std::net::UdpSocket>>,
+        sessions: &Sessions,
+        addr: SocketAddr,
+        packet: &[u8],
+    ) -> bool {
+        let namespace = self.namespace.as_str();
+        let echo = match UdpEchoPacket::new(packet) {
+            Some(echo) => echo,
+            None => return false,
+        };
+        match echo.get_next_level() {
+            NEXT_LEVEL_REVERSE if !self.reverse_probes => {
+                debug!(target: namespace, "ignoring reverse probe request from {}", addr);
+            }
+            NEXT_LEVEL_REVERSE => match Request::parse(&echo) {
+                Some(request) => {
+                    let sessions = sessions.clone();
+                    let socket = socket.clone();
+                    let namespace = namespace.to_string();
+                    async_std::task::spawn(async move {
+                        sessions.probe(&socket, addr, request, &namespace).await;
+                    });
+                }
+                None => debug!(target: namespace, "invalid reverse probe request from {}", addr),
+            },
+            NEXT_LEVEL_REVERSE_PROBE => sessions.reflected(addr, &echo),
+            _ => return false,
+        }
+        true
+    }
+
+    /// The echo modes that change what a client gets back, see [`NEXT_LEVEL_PARAMS`].
+    pub fn echo_params(&self) -> EchoParams {
+        EchoParams {
+            reorders: self.reorder.is_some(),
+            aggregates: self.aggregate.is_some(),
+            delays: self.delay.is_some(),
+            drops: self.loss_pattern.is_some() || self.bandwidth.is_some(),
+            resizes: self.response_size.is_some(),
+            corrupt_rate: self.corrupt.map_or(0.0, |(rate, _)| rate),
+        }
+    }
+
+    /// Answer `packet` if it queries the echo modes, returning whether it did.
+    async fn answer_params(
+        &self,
+        socket: &Async<std::net::UdpSocket>,
+        addr: SocketAddr,
+        packet: &mut [u8],
+    ) -> bool {
+        let mut echo = match MutableUdpEchoPacket::new(packet) {
+            Some(echo) if echo.get_next_level() == NEXT_LEVEL_PARAMS => echo,
+            _ => return false,
+        };
+        let namespace = self.namespace.as_str();
+        if !write_params(echo.payload_mut(), &self.echo_params()) {
+            debug!(target: namespace, "undersized parameter query from {}", addr);
+            return true;
+        }
+        if let Err(e) = socket.send_to(echo.packet(), addr).await {
+            debug!(target: namespace, "failed to answer parameter query from {}: {}", addr, e);
+        }
+        true
+    }
+
+    async fn send_reordered(
+        socket: &Async<std::net::UdpSocket>,
+        stats: &Stats,
+        flushed: reorder::Flushed,
+    ) {
+        stats
+            .reordered
+            .fetch_add(flushed.reordered as u64, Ordering::Relaxed);
+        for (mut packet, addr) in flushed.packets {
+            stamp_sent(&mut packet);
+            if socket.send_to(&packet, addr).await.is_ok() {
+                Stats::inc(&stats.echoed);
+            }
+        }
+    }
+
+    /// Send a batch of `--aggregate` back to back, `full` if it reached the batch size.
+    async fn send_batch(
+        socket: &Async<std::net::UdpSocket>,
+        stats: &Stats,
+        addr: SocketAddr,
+        batch: Vec<Vec<u8>>,
+        full: bool,
+    ) {
+        stats.record_batch(batch.len(), full);
+        for mut packet in batch {
+            stamp_sent(&mut packet);
+            if socket.send_to(&packet, addr).await.is_ok() {
+                Stats::inc(&stats.echoed);
+            }
+        }
+    }
+
+    async fn mirror(
+        &self,
+        socket: async_std::net::UdpSocket,
+        collector: SocketAddr,
+        packets: async_std::channel::Receiver<Vec<u8>>,
+    ) {
+        while let Ok(packet) = packets.recv().await {
+            match socket.send_to(&packet, collector).await {
+                Ok(_) => Stats::inc(&self.stats.mirrored),
+                Err(e) => {
+                    Stats::inc(&self.stats.mirror_dropped);
+                    debug!(target: self.namespace.as_str(), "failed to mirror packet: {}", e);
+                }
+            }
+        }
     }
 
     async fn handle_tcp(stream: TcpStream) -> io::Result<()> {
@@ -113,3 +863,158 @@ impl Config {
         Ok(())
     }
 }
+
+/// Bind the first of `addresses` that `bind` succeeds on, returning the last error otherwise.
+/// Addresses that failed before are logged, so a missing address family is not fatal but not
+/// silent either.
+fn bind_first<T>(
+    namespace: &str,
+    addresses: &[SocketAddr],
+    mut bind: impl FnMut(SocketAddr) -> io::Result<T>,
+) -> io::Result<T> {
+    let mut failed = Vec::new();
+    for &address in addresses {
+        match bind(address) {
+            Ok(socket) => {
+                for (failed, e) in failed {
+                    warn!(target: namespace, "failed to bind {}: {}, using {}", failed, e, address);
+                }
+                return Ok(socket);
+            }
+            Err(e) => failed.push((address, e)),
+        }
+    }
+    Err(failed.pop().map_or_else(
+        || io::Error::new(io::ErrorKind::InvalidInput, "no address to bind"),
+        |(_, e)| e,
+    ))
+}
+
+/// Whether binding failed because the host lacks the address or its family, rather than for
+/// example because the port is taken.
+fn unavailable(error: &anyhow::Error) -> bool {
+    matches!(
+        error
+            .root_cause()
+            .downcast_ref::<io::Error>()
+            .and_then(|e| e.raw_os_error()),
+        Some(libc::EADDRNOTAVAIL | libc::EAFNOSUPPORT | libc::EPROTONOSUPPORT)
+    )
+}
+
+/// Nanoseconds since the UNIX epoch, as written into timestamped echoes.
+fn unix_nanos() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|since| since.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Fill in the send time of an echo that asked for timestamps.
+fn stamp_sent(packet: &mut [u8]) {
+    if let Some(mut echo) = MutableUdpEchoPacket::new(packet) {
+        if echo.get_next_level() == NEXT_LEVEL_TIMESTAMPS {
+            if let Some((received, _)) = read_timestamps(echo.payload()) {
+                write_timestamps(echo.payload_mut(), received, unix_nanos());
+            }
+        }
+    }
+}
+
+/// Parse a response size, either a byte count (`1400`) or a factor of the request (`x2.5`).
+pub fn parse_response_size(size: &str) -> Result<ResponseSize> {
+    let response = match size.strip_prefix('x') {
+        Some(factor) => {
+            let factor: f64 = factor
+                .parse()
+                .with_context(|| format!("Invalid factor '{}'", factor))?;
+            if !(factor.is_finite() && factor > 0.0) {
+                bail!("Response factor must be positive, got {}", factor);
+            }
+            ResponseSize::Factor(factor)
+        }
+        None => ResponseSize::Fixed(
+            size.parse()
+                .with_context(|| format!("Invalid response size '{}'", size))?,
+        ),
+    };
+    if let ResponseSize::Fixed(len) = response {
+        if len > MAX_RESPONSE {
+            bail!(
+                "Response size {} exceeds the maximum of {}",
+                len,
+                MAX_RESPONSE
+            );
+        }
+    }
+
+    Ok(response)
+}
+
+/// Parse a port (`7`) or an inclusive port range (`7000-7010`).
+pub fn parse_ports(ports: &str) -> Result<Vec<u16>> {
+    let (first, last) = match ports.split_once('-') {
+        Some((first, last)) => (first, last),
+        None => (ports, ports),
+    };
+    let first: u16 = first
+        .parse()
+        .with_context(|| format!("Invalid port '{}'", first))?;
+    let last: u16 = last
+        .parse()
+        .with_context(|| format!("Invalid port '{}'", last))?;
+    if first > last {
+        bail!("Port range {} is empty", ports);
+    }
+
+    Ok((first..=last).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_ports, parse_response_size, Config, ResponseSize, MAX_RESPONSE};
+
+    #[test]
+    fn ports() {
+        assert_eq!(parse_ports("7").unwrap(), vec![7]);
+        assert_eq!(
+            parse_ports("7000-7003").unwrap(),
+            vec![7000, 7001, 7002, 7003]
+        );
+        assert!(parse_ports("7003-7000").is_err());
+        assert!(parse_ports("7-x").is_err());
+        assert!(parse_ports("70000").is_err());
+    }
+
+    #[test]
+    fn response_size() {
+        assert_eq!(
+            parse_response_size("100").unwrap(),
+            ResponseSize::Fixed(100)
+        );
+        assert_eq!(
+            parse_response_size("x2").unwrap(),
+            ResponseSize::Factor(2.0)
+        );
+        assert!(parse_response_size("x0").is_err());
+        assert!(parse_response_size("70000").is_err());
+
+        assert_eq!(ResponseSize::Factor(2.0).apply(18), 36);
+        assert_eq!(ResponseSize::Factor(0.1).apply(100), 17);
+        assert_eq!(ResponseSize::Fixed(1).apply(18), 17);
+        assert_eq!(ResponseSize::Factor(1e9).apply(18), MAX_RESPONSE);
+    }
+
+    #[test]
+    fn udp_only_setters() {
+        let mut config = Config::new(vec![7], vec!["::1".to_string()], false);
+        assert!(config.set_reorder(0, 1).is_err());
+        assert!(config.set_reorder(4, 1).is_ok());
+        assert!(config.set_response_size(ResponseSize::Fixed(0)).is_err());
+        assert!(config.set_response_size(ResponseSize::Fixed(100)).is_ok());
+
+        let mut tcp = Config::new(vec![7], vec!["::1".to_string()], true);
+        assert!(tcp.set_reorder(4, 1).is_err());
+        assert!(tcp.set_response_size(ResponseSize::Fixed(100)).is_err());
+    }
+}
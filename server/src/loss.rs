@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{bail, Context, Result};
+
+/// Drops the UDP packets at fixed indices of the receive order, `--loss-pattern`.
+#[derive(Debug)]
+pub struct LossPattern {
+    /// Sorted indices to drop.
+    drops: Vec<u64>,
+    /// Start over after the last index instead of echoing everything.
+    wrap: bool,
+    received: AtomicU64,
+}
+
+impl LossPattern {
+    /// Parse a list of indices separated by whitespace or commas, `#` starts a comment.
+    pub fn parse(text: &str, wrap: bool) -> Result<Self> {
+        let mut drops = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            for index in line.split(|c: char| c.is_whitespace() || c == ',') {
+                if index.is_empty() {
+                    continue;
+                }
+                drops.push(
+                    index
+                        .parse()
+                        .with_context(|| format!("Invalid packet index '{}'", index))?,
+                );
+            }
+        }
+        if drops.is_empty() {
+            bail!("Loss pattern does not list any packet");
+        }
+        drops.sort_unstable();
+        drops.dedup();
+
+        Ok(Self {
+            drops,
+            wrap,
+            received: AtomicU64::new(0),
+        })
+    }
+
+    /// Count a received packet, returning its index if it has to be dropped.
+    pub fn next(&self) -> Option<u64> {
+        let index = self.received.fetch_add(1, Ordering::Relaxed);
+        // the pattern is as long as its last index
+        let period = self.drops[self.drops.len() - 1] + 1;
+        let position = match (self.wrap, index < period) {
+            (_, true) => index,
+            (true, false) => index % period,
+            (false, false) => return None,
+        };
+        self.drops.binary_search(&position).ok().map(|_| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LossPattern;
+
+    fn dropped(pattern: &LossPattern, count: usize) -> Vec<u64> {
+        (0..count).filter_map(|_| pattern.next()).collect()
+    }
+
+    #[test]
+    fn pattern() {
+        let text = "# dropped packets\n3, 1\n1 4 # again\n";
+        assert_eq!(
+            dropped(&LossPattern::parse(text, false).unwrap(), 12),
+            vec![1, 3, 4]
+        );
+        assert_eq!(
+            dropped(&LossPattern::parse(text, true).unwrap(), 12),
+            vec![1, 3, 4, 6, 8, 9, 11]
+        );
+        assert!(LossPattern::parse("# nothing", false).is_err());
+        assert!(LossPattern::parse("1 x", false).is_err());
+    }
+}
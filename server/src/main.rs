@@ -1,12 +1,5 @@
-use std::net::SocketAddr;
-
 use anyhow::{bail, Context, Result};
-use async_std::io;
-use async_std::net::{TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
-use async_std::prelude::*;
-use async_std::stream::IntoStream;
 use getopts::Options;
-use log::*;
 
 use server::Config;
 
@@ -24,10 +17,144 @@ async fn main_err() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
 
     let mut options = Options::new();
-    options.optflagopt("p", "port", "the port to listen at", "PORT"); // required
+    options.optmulti(
+        "p",
+        "port",
+        "the port or port range (FIRST-LAST) to listen at, repeatable",
+        "PORT",
+    ); // required
     options.optflag("t", "tcp", "use tcp");
     options.optmulti("a", "address", "Address to listen att", "ADDRESS");
+    options.optopt(
+        "",
+        "keepalive",
+        "probe idle tcp connections after SECS seconds",
+        "SECS",
+    );
+    options.optopt(
+        "",
+        "no-delay",
+        "set TCP_NODELAY on accepted connections, on or off (default on)",
+        "on|off",
+    );
+    options.optflag(
+        "",
+        "v6only",
+        "bind every address on its own socket, keeping IPv6 sockets from taking IPv4 traffic",
+    );
+    options.optopt(
+        "",
+        "listen-backlog",
+        "queue up to N tcp connections waiting to be accepted",
+        "N",
+    );
+    options.optopt(
+        "",
+        "max-bandwidth",
+        "limit the udp echoes of all ports to MBPS megabits per second",
+        "MBPS",
+    );
+    options.optopt(
+        "",
+        "bandwidth-shaping",
+        "delay or drop echoes above --max-bandwidth (default delay)",
+        "delay|drop",
+    );
+    options.optopt(
+        "",
+        "mirror",
+        "forward a copy of every received udp packet to the collector at ADDR",
+        "ADDR",
+    );
+    options.optopt(
+        "s",
+        "stats-interval",
+        "log stats every SECS seconds",
+        "SECS",
+    );
+    options.optopt(
+        "",
+        "reorder",
+        "echo udp packets shuffled in windows of WINDOW packets",
+        "WINDOW",
+    );
+    options.optopt(
+        "",
+        "response-size",
+        "pad or truncate udp echoes to SIZE bytes, or to FACTOR times the request with xFACTOR",
+        "SIZE",
+    );
+    options.optopt(
+        "",
+        "aggregate",
+        "hold the udp echoes of every peer until it sent N packets, then send them in a burst",
+        "N",
+    );
+    options.optopt(
+        "",
+        "aggregate-timeout",
+        "release a partial --aggregate batch after MS milliseconds (default 100)",
+        "MS",
+    );
+    options.optopt(
+        "",
+        "reorder-seed",
+        "seed for the --reorder shuffle (default 1)",
+        "SEED",
+    );
+    options.optopt(
+        "",
+        "loss-pattern",
+        "drop the udp packets at the indices listed in FILE, counted in receive order",
+        "FILE",
+    );
+    options.optflag(
+        "",
+        "loss-pattern-wrap",
+        "repeat the --loss-pattern instead of echoing everything after its last index",
+    );
+    options.optopt(
+        "",
+        "echo-delay-distribution",
+        "delay udp echoes by times drawn from the 'DELAY_MS WEIGHT' histogram in FILE",
+        "FILE",
+    );
+    options.optopt(
+        "",
+        "delay-seed",
+        "seed for --echo-delay-distribution (default 1)",
+        "SEED",
+    );
+    options.optopt(
+        "",
+        "max-error-backoff",
+        "longest wait in milliseconds before receiving or accepting again after running out of buffers or descriptors (default 100)",
+        "MS",
+    );
+    options.optflag(
+        "",
+        "reverse-probes",
+        "probe clients back over udp when they ask for it, for two-way measurements",
+    );
+    options.optopt(
+        "",
+        "corrupt",
+        "flip a random byte in the payload of a RATE fraction of the udp echoes",
+        "RATE",
+    );
+    options.optopt("", "corrupt-seed", "seed for --corrupt (default 1)", "SEED");
+
+    options.optflag(
+        "",
+        "self-test",
+        "bind like a normal start, check that every socket echoes a few packets sent from this host, then exit",
+    );
 
+    options.optflag(
+        "",
+        "list-interfaces",
+        "List network interfaces and their addresses",
+    );
     options.optflag("V", "version", "Show version info");
     options.optflag("h", "help", "Show this help message");
 
@@ -45,28 +172,32 @@ async fn main_err() -> Result<()> {
     env_logger::init();
 
     if matches.opt_present("V") {
-        // TODO: base function/macro?
-        eprintln!("{}: Version {}", args[0], env!("CARGO_PKG_VERSION"));
-        eprintln!(
-            "(C) {}",
-            env!("CARGO_PKG_AUTHORS")
-                .split(":")
-                .collect::<Vec<&str>>()
-                .join("\n(C) ")
-        );
+        version::print_version!(&args[0]);
         return Ok(());
     }
 
-    let port: u16 = match matches.opt_str("p").map(|p| p.parse()) {
-        Some(Ok(p)) => p,
-        Some(Err(e)) => return Err(e).context("Failed to parse port"),
-        None => bail!("Port not set"),
-    };
+    if matches.opt_present("list-interfaces") {
+        for (name, addresses) in server::list_interfaces().context("Failed to query interfaces")? {
+            let addresses: Vec<String> = addresses.iter().map(|a| a.to_string()).collect();
+            println!("{}: {}", name, addresses.join(", "));
+        }
+        return Ok(());
+    }
+
+    let mut ports = Vec::new();
+    for port in matches.opt_strs("p") {
+        ports.extend(server::parse_ports(&port).context("Failed to parse port")?);
+    }
+    if ports.is_empty() {
+        bail!("Port not set");
+    }
+    ports.sort_unstable();
+    ports.dedup();
 
     //let addresses = match matches.opt_count("")
     let mut addresses = matches.opt_strs("a");
 
-    if addresses.len() == 0 {
+    if addresses.is_empty() {
         addresses.push("::".to_string());
         // ipv4?
         addresses.push("0.0.0.0".to_string());
@@ -74,7 +205,209 @@ async fn main_err() -> Result<()> {
 
     let tcp = matches.opt_present("t");
 
-    let mut config = Config::new(port, addresses, tcp);
+    let mut config = Config::new(ports, addresses, tcp);
+    config.set_v6only(matches.opt_present("v6only"));
+
+    match matches.opt_str("keepalive").map(|v| v.parse()) {
+        Some(Ok(keepalive)) => {
+            config.set_keepalive(keepalive);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse keepalive"),
+        None => (),
+    }
+
+    match matches.opt_str("no-delay").as_deref() {
+        Some("on") | None => (),
+        Some("off") => {
+            config.set_nodelay(false);
+        }
+        Some(other) => bail!("--no-delay takes on or off, got '{}'", other),
+    }
+
+    match matches.opt_str("listen-backlog").map(|v| v.parse()) {
+        Some(Ok(backlog)) => {
+            if !tcp {
+                bail!("--listen-backlog only applies to tcp");
+            }
+            config.set_listen_backlog(backlog);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse listen backlog"),
+        None => (),
+    }
+
+    match matches.opt_str("max-bandwidth").map(|v| v.parse()) {
+        Some(Ok(megabits)) => {
+            if tcp {
+                bail!("--max-bandwidth only applies to udp");
+            }
+            let shaping = match matches.opt_str("bandwidth-shaping") {
+                Some(mode) => server::parse_shaping(&mode)?,
+                None => server::Shaping::Delay,
+            };
+            if shaping == server::Shaping::Delay && matches.opt_present("reorder") {
+                bail!("--reorder can only be combined with --bandwidth-shaping drop");
+            }
+            config.set_max_bandwidth(server::TokenBucket::new(
+                megabits,
+                shaping,
+                server::MAX_RESPONSE,
+            )?);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse max bandwidth"),
+        None => (),
+    }
+
+    match matches.opt_str("mirror").map(|v| v.parse()) {
+        Some(Ok(collector)) => {
+            if tcp {
+                bail!("--mirror only applies to udp");
+            }
+            config.set_mirror(collector);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse mirror address"),
+        None => (),
+    }
+
+    match matches.opt_str("s").map(|v| v.parse()) {
+        Some(Ok(interval)) => {
+            config.set_stats_interval(interval);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse stats interval"),
+        None => (),
+    }
+
+    match matches.opt_str("reorder").map(|v| v.parse()) {
+        Some(Ok(window)) => {
+            let seed = match matches.opt_str("reorder-seed").map(|v| v.parse()) {
+                Some(Ok(seed)) => seed,
+                Some(Err(e)) => return Err(e).context("Failed to parse reorder seed"),
+                None => 1,
+            };
+            config.set_reorder(window, seed)?;
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse reorder window"),
+        None => (),
+    }
+
+    if let Some(size) = matches.opt_str("response-size") {
+        config.set_response_size(
+            server::parse_response_size(&size).context("Failed to parse response size")?,
+        )?;
+    }
+
+    if let Some(path) = matches.opt_str("loss-pattern") {
+        if tcp {
+            bail!("--loss-pattern only applies to udp");
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read loss pattern '{}'", path))?;
+        config.set_loss_pattern(
+            server::LossPattern::parse(&text, matches.opt_present("loss-pattern-wrap"))
+                .context("Failed to parse loss pattern")?,
+        );
+    }
+
+    if let Some(path) = matches.opt_str("echo-delay-distribution") {
+        if tcp {
+            bail!("--echo-delay-distribution only applies to udp");
+        }
+        if matches.opt_present("reorder") {
+            bail!("--echo-delay-distribution and --reorder are mutually exclusive");
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read delay distribution '{}'", path))?;
+        let seed = match matches.opt_str("delay-seed").map(|v| v.parse()) {
+            Some(Ok(seed)) => seed,
+            Some(Err(e)) => return Err(e).context("Failed to parse delay seed"),
+            None => 1,
+        };
+        config.set_delay_distribution(
+            server::DelayDistribution::parse(&text)
+                .context("Failed to parse delay distribution")?,
+            seed,
+        );
+    }
+
+    match matches.opt_str("max-error-backoff").map(|v| v.parse()) {
+        Some(Ok(0)) => bail!("--max-error-backoff must be positive"),
+        Some(Ok(ms)) => {
+            config.set_max_backoff(std::time::Duration::from_millis(ms));
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse max error backoff"),
+        None => (),
+    }
+
+    if matches.opt_present("reverse-probes") {
+        if tcp {
+            bail!("--reverse-probes only applies to udp");
+        }
+        config.set_reverse_probes(true);
+    }
+
+    match matches.opt_str("corrupt").map(|v| v.parse::<f64>()) {
+        Some(Ok(rate)) => {
+            if tcp {
+                bail!("--corrupt only applies to udp");
+            }
+            if !(0.0..=1.0).contains(&rate) {
+                bail!("--corrupt takes a rate between 0 and 1, got {}", rate);
+            }
+            let seed = match matches.opt_str("corrupt-seed").map(|v| v.parse()) {
+                Some(Ok(seed)) => seed,
+                Some(Err(e)) => return Err(e).context("Failed to parse corrupt seed"),
+                None => 1,
+            };
+            config.set_corrupt(rate, seed);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse corrupt rate"),
+        None => (),
+    }
+
+    match matches.opt_str("aggregate").map(|v| v.parse()) {
+        Some(Ok(size)) => {
+            if tcp {
+                bail!("--aggregate only applies to udp");
+            }
+            if size == 0 {
+                bail!("--aggregate needs a batch of at least one packet");
+            }
+            if matches.opt_present("reorder") || matches.opt_present("echo-delay-distribution") {
+                bail!("--aggregate can't be combined with --reorder or --echo-delay-distribution");
+            }
+            if matches.opt_present("max-bandwidth")
+                && matches.opt_str("bandwidth-shaping").as_deref() != Some("drop")
+            {
+                bail!("--aggregate can only be combined with --bandwidth-shaping drop");
+            }
+            let timeout = match matches.opt_str("aggregate-timeout").map(|v| v.parse()) {
+                Some(Ok(ms)) => std::time::Duration::from_millis(ms),
+                Some(Err(e)) => return Err(e).context("Failed to parse aggregate timeout"),
+                None => std::time::Duration::from_millis(100),
+            };
+            config.set_aggregate(size, timeout);
+        }
+        Some(Err(e)) => return Err(e).context("Failed to parse aggregate size"),
+        None => (),
+    }
+
+    if matches.opt_present("self-test") {
+        let probed = config.self_test(server::SELF_TEST_PACKETS).await?;
+        for probed in &probed {
+            println!(
+                "{}: {} of {} echoed{}",
+                probed.address,
+                probed.echoed,
+                probed.sent,
+                probed
+                    .rtt
+                    .map(|rtt| format!(", mean rtt {:?}", rtt))
+                    .unwrap_or_default()
+            );
+        }
+        server::check_self_test(&probed)?;
+        println!("self-test passed");
+        return Ok(());
+    }
 
     config.run().await
 }
@@ -0,0 +1,109 @@
+use std::net::SocketAddr;
+
+/// Holds back echoes and releases them in a shuffled order, to give clients a known amount of
+/// reordering.
+#[derive(Debug)]
+pub struct Reorder {
+    window: usize,
+    state: u64,
+    pending: Vec<(Vec<u8>, SocketAddr)>,
+}
+
+impl Reorder {
+    /// Shuffle every `window` packets, using a generator seeded with `seed`. `window` has to be
+    /// positive, see [`crate::Config::set_reorder`].
+    pub fn new(window: usize, seed: u64) -> Self {
+        Self {
+            window,
+            // xorshift gets stuck on zero
+            state: seed.max(1),
+            pending: Vec::with_capacity(window),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queue a packet, returning the shuffled window once it is full.
+    pub fn push(&mut self, packet: Vec<u8>, addr: SocketAddr) -> Option<Flushed> {
+        self.pending.push((packet, addr));
+        if self.pending.len() < self.window {
+            return None;
+        }
+        Some(self.flush())
+    }
+
+    /// Shuffle and release all queued packets.
+    pub fn flush(&mut self) -> Flushed {
+        let mut order: Vec<usize> = (0..self.pending.len()).collect();
+        for i in (1..order.len()).rev() {
+            let j = (self.next() % (i as u64 + 1)) as usize;
+            order.swap(i, j);
+        }
+
+        let reordered = order.iter().enumerate().filter(|(i, &o)| *i != o).count();
+        let mut pending: Vec<Option<(Vec<u8>, SocketAddr)>> =
+            self.pending.drain(..).map(Some).collect();
+        let packets = order
+            .into_iter()
+            .filter_map(|o| pending[o].take())
+            .collect();
+
+        Flushed { packets, reordered }
+    }
+
+    fn next(&mut self) -> u64 {
+        xorshift(&mut self.state)
+    }
+}
+
+/// xorshift64*, `state` must not be zero.
+pub(crate) fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+/// Packets released by [`Reorder`], in sending order.
+#[derive(Debug)]
+pub struct Flushed {
+    pub packets: Vec<(Vec<u8>, SocketAddr)>,
+    /// Number of packets not sent at their arrival position.
+    pub reordered: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Reorder;
+
+    fn shuffle(window: usize, seed: u64, count: u8) -> Vec<u8> {
+        let addr = "127.0.0.1:7".parse().unwrap();
+        let mut reorder = Reorder::new(window, seed);
+        let mut ret = Vec::new();
+        for x in 0..count {
+            if let Some(flushed) = reorder.push(vec![x], addr) {
+                ret.extend(flushed.packets.into_iter().map(|(p, _)| p[0]));
+            }
+        }
+        ret.extend(reorder.flush().packets.into_iter().map(|(p, _)| p[0]));
+        ret
+    }
+
+    #[test]
+    fn shuffles_within_window() {
+        let out = shuffle(4, 42, 10);
+        assert_eq!(out, shuffle(4, 42, 10));
+        assert_ne!(out, (0..10).collect::<Vec<u8>>());
+
+        let mut sorted = out.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..10).collect::<Vec<u8>>());
+        for (i, x) in out.iter().enumerate() {
+            assert_eq!(i / 4, *x as usize / 4);
+        }
+
+        assert_eq!(shuffle(1, 42, 10), (0..10).collect::<Vec<u8>>());
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_io::Async;
+use log::*;
+use packet::{
+    read_reverse_request, Packet, UdpEchoBuilder, UdpEchoPacket, NEXT_LEVEL_REVERSE_PROBE,
+};
+
+use crate::unix_nanos;
+
+/// Most probes a single request may ask for, so a small request can't make the server send
+/// without end.
+pub const MAX_REVERSE_PROBES: u64 = 100_000;
+
+/// Shortest gap between two probes a request may ask for.
+pub const MIN_REVERSE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How long a session waits for the reflections of its last probes.
+const REFLECT_WAIT: Duration = Duration::from_secs(1);
+
+/// Payload bytes of a probe, the send time.
+const PROBE_PAYLOAD: usize = 8;
+
+/// Probes a client asked for with a `NEXT_LEVEL_REVERSE` request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Request {
+    pub identifier: u64,
+    pub count: u64,
+    pub interval: Duration,
+}
+
+impl Request {
+    /// Read a request, clamping it to [`MAX_REVERSE_PROBES`] and [`MIN_REVERSE_INTERVAL`].
+    pub fn parse(echo: &UdpEchoPacket) -> Option<Self> {
+        let (count, interval_us) = read_reverse_request(echo.payload())?;
+        Some(Self {
+            identifier: echo.get_identifier(),
+            count: count.min(MAX_REVERSE_PROBES),
+            interval: Duration::from_micros(interval_us).max(MIN_REVERSE_INTERVAL),
+        })
+    }
+}
+
+/// Reflections counted for the probes of a single request.
+#[derive(Debug, Default)]
+struct Session {
+    reflected: AtomicU64,
+    /// Sum of the round trip times of the reflected probes, in nanoseconds.
+    rtt_sum: AtomicU64,
+}
+
+/// Client address and identifier of a session.
+type SessionKey = (SocketAddr, u64);
+
+/// Running reverse probe sessions.
+#[derive(Debug, Default, Clone)]
+pub struct Sessions(Arc<Mutex<HashMap<SessionKey, Arc<Session>>>>);
+
+impl Sessions {
+    /// Count a probe the client at `addr` sent back.
+    pub fn reflected(&self, addr: SocketAddr, echo: &UdpEchoPacket) {
+        let session = match self.0.lock().unwrap().get(&(addr, echo.get_identifier())) {
+            Some(session) => session.clone(),
+            None => return,
+        };
+        let mut sent = [0u8; PROBE_PAYLOAD];
+        if let Some(payload) = echo.payload().get(..PROBE_PAYLOAD) {
+            sent.copy_from_slice(payload);
+        }
+        let rtt = unix_nanos().saturating_sub(u64::from_be_bytes(sent));
+        session.reflected.fetch_add(1, Ordering::Relaxed);
+        session.rtt_sum.fetch_add(rtt, Ordering::Relaxed);
+    }
+
+    /// Send the probes of `request` to `addr` and log what came back. A request repeated while
+    /// its probes are still running is ignored.
+    pub async fn probe(
+        &self,
+        socket: &Async<std::net::UdpSocket>,
+        addr: SocketAddr,
+        request: Request,
+        namespace: &str,
+    ) {
+        let key = (addr, request.identifier);
+        let session = {
+            let mut sessions = self.0.lock().unwrap();
+            if sessions.contains_key(&key) {
+                debug!(target: namespace, "reverse probes to {} already running", addr);
+                return;
+            }
+            sessions.entry(key).or_default().clone()
+        };
+        info!(
+            target: namespace,
+            "reverse probes to {}: {} every {:?}", addr, request.count, request.interval
+        );
+
+        let mut buf = [0u8; 32];
+        let start = Instant::now();
+        let mut sent = 0;
+        for sequence in 0..request.count {
+            let due = start + request.interval * sequence as u32;
+            let now = Instant::now();
+            if due > now {
+                async_std::task::sleep(due - now).await;
+            }
+            let len = UdpEchoBuilder::new()
+                .identifier(request.identifier)
+                .sequence(sequence)
+                .next_level(NEXT_LEVEL_REVERSE_PROBE)
+                .payload(&unix_nanos().to_be_bytes())
+                .build_into(&mut buf)
+                .expect("buffer fits a probe");
+            if socket.send_to(&buf[..len], addr).await.is_ok() {
+                sent += 1;
+            }
+        }
+        async_std::task::sleep(REFLECT_WAIT).await;
+        self.0.lock().unwrap().remove(&key);
+
+        let reflected = session.reflected.load(Ordering::Relaxed);
+        let mean_rtt = session
+            .rtt_sum
+            .load(Ordering::Relaxed)
+            .checked_div(reflected)
+            .map(Duration::from_nanos)
+            .unwrap_or_default();
+        info!(
+            target: namespace,
+            "reverse probes to {}: sent={} reflected={} loss={:.2}% mean_rtt={:?}",
+            addr,
+            sent,
+            reflected,
+            if sent > 0 {
+                100.0 * (sent - reflected.min(sent)) as f64 / sent as f64
+            } else {
+                0.0
+            },
+            mean_rtt
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use packet::{write_reverse_request, UdpEchoBuilder, UdpEchoPacket, NEXT_LEVEL_REVERSE};
+
+    use super::{Request, MAX_REVERSE_PROBES, MIN_REVERSE_INTERVAL};
+
+    fn request(count: u64, interval_us: u64) -> Option<Request> {
+        let mut payload = [0u8; 16];
+        write_reverse_request(&mut payload, count, interval_us);
+        let mut buf = [0u8; 33];
+        UdpEchoBuilder::new()
+            .identifier(7)
+            .next_level(NEXT_LEVEL_REVERSE)
+            .payload(&payload)
+            .build_into(&mut buf)
+            .unwrap();
+        Request::parse(&UdpEchoPacket::new(&buf).unwrap())
+    }
+
+    #[test]
+    fn clamped() {
+        assert_eq!(
+            request(10, 5000),
+            Some(Request {
+                identifier: 7,
+                count: 10,
+                interval: Duration::from_millis(5),
+            })
+        );
+        let clamped = request(u64::MAX, 1).unwrap();
+        assert_eq!(clamped.count, MAX_REVERSE_PROBES);
+        assert_eq!(clamped.interval, MIN_REVERSE_INTERVAL);
+
+        let short = [0u8; 20];
+        assert_eq!(Request::parse(&UdpEchoPacket::new(&short).unwrap()), None);
+    }
+}
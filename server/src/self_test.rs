@@ -0,0 +1,172 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::{TcpStream, UdpSocket};
+use packet::{UdpEchoBuilder, UdpEchoPacket};
+
+/// Packets `--self-test` sends to every bound socket.
+pub const SELF_TEST_PACKETS: usize = 5;
+
+/// How long `--self-test` waits for a single echo.
+const ECHO_WAIT: Duration = Duration::from_secs(1);
+
+/// Identifier of the self-test packets, spelling "selftest".
+const IDENTIFIER: u64 = u64::from_be_bytes(*b"selftest");
+
+/// Echoes a socket returned for `--self-test`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Probed {
+    pub address: SocketAddr,
+    pub echoed: usize,
+    pub sent: usize,
+    /// Mean round trip time of the echoes, `None` if none came back.
+    pub rtt: Option<Duration>,
+}
+
+impl Probed {
+    pub fn passed(&self) -> bool {
+        self.echoed == self.sent
+    }
+}
+
+/// Address to reach a socket bound to `bound` from the same host.
+pub fn loopback(bound: SocketAddr) -> SocketAddr {
+    let ip: IpAddr = match bound.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        ip => ip,
+    };
+    SocketAddr::new(ip, bound.port())
+}
+
+/// Send `count` echo requests to the socket bound to `bound` and count the matching echoes.
+pub async fn probe(bound: SocketAddr, tcp: bool, count: usize) -> Result<Probed> {
+    let address = loopback(bound);
+    let mut rtts = Vec::new();
+    for sequence in 0..count as u64 {
+        let mut request = [0u8; 64];
+        let len = UdpEchoBuilder::new()
+            .identifier(IDENTIFIER)
+            .sequence(sequence)
+            .payload(&[0])
+            .build_into(&mut request)
+            .expect("buffer fits the request");
+        let request = &request[..len];
+
+        let start = Instant::now();
+        let echoed = if tcp {
+            probe_tcp(address, request).await
+        } else {
+            probe_udp(address, request).await
+        }
+        .with_context(|| format!("Failed to probe {}", address))?;
+        if echoed {
+            rtts.push(start.elapsed());
+        }
+    }
+    Ok(Probed {
+        address,
+        echoed: rtts.len(),
+        sent: count,
+        rtt: (!rtts.is_empty()).then(|| rtts.iter().sum::<Duration>() / rtts.len() as u32),
+    })
+}
+
+/// Whether `request` came back with its identifier and sequence within [`ECHO_WAIT`].
+async fn probe_udp(address: SocketAddr, request: &[u8]) -> Result<bool> {
+    let unspecified: IpAddr = if address.is_ipv6() {
+        Ipv6Addr::UNSPECIFIED.into()
+    } else {
+        Ipv4Addr::UNSPECIFIED.into()
+    };
+    let socket = UdpSocket::bind((unspecified, 0)).await?;
+    socket.connect(address).await?;
+    socket.send(request).await?;
+
+    // the server may resize the echo, see `--response-size`
+    let mut buf = vec![0u8; 65536];
+    let size = match async_std::future::timeout(ECHO_WAIT, socket.recv(&mut buf)).await {
+        Ok(size) => size?,
+        Err(_) => return Ok(false),
+    };
+    Ok(same_echo(request, &buf[..size]))
+}
+
+/// Whether `request` came back unchanged over its own connection within [`ECHO_WAIT`].
+async fn probe_tcp(address: SocketAddr, request: &[u8]) -> Result<bool> {
+    let echo = async {
+        let mut stream = TcpStream::connect(address).await?;
+        stream.write_all(request).await?;
+        let mut buf = vec![0u8; request.len()];
+        stream.read_exact(&mut buf).await?;
+        Ok::<_, std::io::Error>(buf)
+    };
+    match async_std::future::timeout(ECHO_WAIT, echo).await {
+        Ok(buf) => Ok(buf? == request),
+        Err(_) => Ok(false),
+    }
+}
+
+fn same_echo(request: &[u8], echo: &[u8]) -> bool {
+    match (UdpEchoPacket::new(request), UdpEchoPacket::new(echo)) {
+        (Some(request), Some(echo)) => {
+            echo.get_identifier() == request.get_identifier()
+                && echo.get_sequence() == request.get_sequence()
+        }
+        _ => false,
+    }
+}
+
+/// Fail unless every socket echoed all of its probes.
+pub fn check_self_test(probed: &[Probed]) -> Result<()> {
+    let failed: Vec<String> = probed
+        .iter()
+        .filter(|probed| !probed.passed())
+        .map(|probed| {
+            format!(
+                "{} echoed {} of {}",
+                probed.address, probed.echoed, probed.sent
+            )
+        })
+        .collect();
+    if !failed.is_empty() {
+        bail!("Self-test failed: {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{loopback, SELF_TEST_PACKETS};
+    use crate::Config;
+
+    #[test]
+    fn loopback_of_unspecified() {
+        assert_eq!(
+            loopback("0.0.0.0:7".parse().unwrap()),
+            "127.0.0.1:7".parse().unwrap()
+        );
+        assert_eq!(
+            loopback("[::]:7".parse().unwrap()),
+            "[::1]:7".parse().unwrap()
+        );
+        assert_eq!(
+            loopback("192.0.2.1:7".parse().unwrap()),
+            "192.0.2.1:7".parse().unwrap()
+        );
+    }
+
+    #[async_std::test]
+    async fn echoes() {
+        for tcp in [false, true] {
+            // port 0 binds an ephemeral port, which the probes find through the bound address
+            let config = Config::new(vec![0], vec!["127.0.0.1".to_string()], tcp);
+            let probed = config.self_test(SELF_TEST_PACKETS).await.unwrap();
+            assert_eq!(probed.len(), 1);
+            assert!(probed[0].passed(), "{:?}", probed[0]);
+            assert_eq!(probed[0].echoed, SELF_TEST_PACKETS);
+        }
+    }
+}
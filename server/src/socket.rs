@@ -0,0 +1,195 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+fn setsockopt(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: value is a valid c_int for the duration of the call
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Enable TCP keep-alive on `fd`, probing after `secs` of idleness and every `secs` after that.
+pub fn set_keepalive(fd: RawFd, secs: u32) -> io::Result<()> {
+    let secs = secs as libc::c_int;
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs)?;
+    setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs)?;
+    Ok(())
+}
+
+/// Open a socket of `ty` bound to `addr`. IPv6 sockets get `IPV6_V6ONLY` set to `v6only`, so an
+/// unspecified IPv6 address can leave the IPv4 port to a socket of its own.
+fn bind(addr: SocketAddr, ty: libc::c_int, v6only: bool) -> io::Result<OwnedFd> {
+    let domain = if addr.is_ipv6() {
+        libc::AF_INET6
+    } else {
+        libc::AF_INET
+    };
+    // SAFETY: plain syscall, the returned fd is checked below
+    let fd = unsafe { libc::socket(domain, ty | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd is a fresh socket nobody else owns, dropping the OwnedFd closes it on errors
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    if addr.is_ipv6() {
+        setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            v6only as libc::c_int,
+        )?;
+    }
+    if ty == libc::SOCK_STREAM {
+        // like the standard library's bind
+        setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    }
+    nix::sys::socket::bind(fd, &nix::sys::socket::SockaddrStorage::from(addr))?;
+
+    Ok(socket)
+}
+
+/// Bind a TCP listener to `addr` with room for `backlog` connections waiting to be accepted.
+pub fn listen(addr: SocketAddr, backlog: u32, v6only: bool) -> io::Result<std::net::TcpListener> {
+    let socket = bind(addr, libc::SOCK_STREAM, v6only)?;
+    // SAFETY: plain syscall on the socket owned by socket
+    let ret = unsafe {
+        libc::listen(
+            socket.as_raw_fd(),
+            backlog.min(libc::c_int::MAX as u32) as libc::c_int,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(socket.into())
+}
+
+/// Bind a UDP socket to `addr`, see [`bind`] for `v6only`.
+pub fn bind_udp(addr: SocketAddr, v6only: bool) -> io::Result<std::net::UdpSocket> {
+    Ok(bind(addr, libc::SOCK_DGRAM, v6only)?.into())
+}
+
+/// Number of connections waiting in the accept queue of the listening socket `fd`.
+pub fn accept_queue(fd: RawFd) -> io::Result<u32> {
+    // SAFETY: tcp_info is plain data, all zeroes is valid
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    // SAFETY: info and len are valid for writes of the given length
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // for listeners the kernel reports the accept queue in place of the unacked segments
+    Ok(info.tcpi_unacked)
+}
+
+/// Ask the kernel to attach the received TOS/traffic class byte to every datagram.
+pub fn enable_recv_tos(fd: RawFd, ipv6: bool) -> io::Result<()> {
+    if ipv6 {
+        setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1)?;
+        // IPv4-mapped traffic on dual-stack sockets, fails on v6only sockets
+        let _ = setsockopt(fd, libc::IPPROTO_IP, libc::IP_RECVTOS, 1);
+        Ok(())
+    } else {
+        setsockopt(fd, libc::IPPROTO_IP, libc::IP_RECVTOS, 1)
+    }
+}
+
+fn to_socket_addr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the kernel wrote a sockaddr_in for AF_INET
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the kernel wrote a sockaddr_in6 for AF_INET6
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown address family",
+        )),
+    }
+}
+
+/// Receive a datagram with `recvmsg`, returning the TOS/traffic class byte if the kernel attached one.
+pub fn recv_from_tos(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<u8>)> {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control = [0u8; 64];
+
+    // SAFETY: all pointers in msg stay valid for the duration of the call
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut storage as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+
+    let size = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut tos = None;
+    // SAFETY: msg was filled by recvmsg, the CMSG macros stay within msg_controllen
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let header = &*cmsg;
+            let data = libc::CMSG_DATA(cmsg);
+            match (header.cmsg_level, header.cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_TOS) => tos = Some(*data),
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                    tos = Some(std::ptr::read_unaligned(data as *const libc::c_int) as u8)
+                }
+                _ => (),
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok((size as usize, to_socket_addr(&storage)?, tos))
+}
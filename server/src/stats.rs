@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+/// Counters collected while the server is running.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// TCP connections closed because a keep-alive probe went unanswered.
+    pub keepalive_closures: AtomicU64,
+    /// UDP echoes sent out of their arrival order by `--reorder`.
+    pub reordered: AtomicU64,
+    /// UDP packets dropped by `--loss-pattern`.
+    pub pattern_dropped: AtomicU64,
+    /// TCP connections accepted on all ports.
+    pub accepted: AtomicU64,
+    /// Connections waiting in the accept queue after the latest accept.
+    pub accept_queue: AtomicU64,
+    /// Longest accept queue seen after an accept.
+    pub peak_accept_queue: AtomicU64,
+    /// Accepted count and time of the previous log, for the accept rate.
+    last_log: Mutex<Option<(Instant, u64)>>,
+    /// UDP echo payload bytes let through by `--max-bandwidth`, including delayed ones.
+    pub bandwidth_passed: AtomicU64,
+    /// UDP echo payload bytes dropped by `--max-bandwidth`.
+    pub bandwidth_dropped: AtomicU64,
+    /// UDP echoes whose payload `--corrupt` flipped a byte in.
+    pub corrupted: AtomicU64,
+    /// UDP echoes sent, after loss, delay and reordering.
+    pub echoed: AtomicU64,
+    /// Copies of received UDP packets sent to the `--mirror` collector.
+    pub mirrored: AtomicU64,
+    /// Copies lost to a full mirror queue or a failed send.
+    pub mirror_dropped: AtomicU64,
+    /// Batches of `--aggregate` released because they were full.
+    pub full_batches: AtomicU64,
+    /// Partial batches of `--aggregate` released by the timeout.
+    pub partial_batches: AtomicU64,
+    /// Echoes sent as part of a batch.
+    pub batched: AtomicU64,
+    /// Failed receives and accepts retried right away.
+    pub transient_errors: AtomicU64,
+    /// Failed receives and accepts retried after a backoff, for lack of buffers, memory or
+    /// file descriptors.
+    pub resource_errors: AtomicU64,
+    /// Failed receives and accepts that stopped the server.
+    pub fatal_errors: AtomicU64,
+    /// UDP packets echoed or TCP connections accepted, per listening port.
+    pub ports: BTreeMap<u16, AtomicU64>,
+    /// Achieved delays of the echoes sent by `--echo-delay-distribution`, in whole milliseconds.
+    pub delays: Mutex<BTreeMap<u64, u64>>,
+}
+
+impl Stats {
+    pub fn new(ports: &[u16]) -> Self {
+        Self {
+            ports: ports
+                .iter()
+                .map(|&port| (port, AtomicU64::new(0)))
+                .collect(),
+            last_log: Mutex::new(Some((Instant::now(), 0))),
+            ..Self::default()
+        }
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_port(&self, port: u16) {
+        if let Some(counter) = self.ports.get(&port) {
+            Self::inc(counter);
+        }
+    }
+
+    /// Record an accepted connection and the queue it left behind.
+    pub fn record_accept(&self, queue: Option<u32>) {
+        Self::inc(&self.accepted);
+        if let Some(queue) = queue {
+            self.accept_queue.store(queue as u64, Ordering::Relaxed);
+            self.peak_accept_queue
+                .fetch_max(queue as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_batch(&self, len: usize, full: bool) {
+        Self::inc(if full {
+            &self.full_batches
+        } else {
+            &self.partial_batches
+        });
+        self.batched.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_delay(&self, delay: Duration) {
+        let mut delays = self.delays.lock().unwrap();
+        *delays.entry(delay.as_millis() as u64).or_insert(0) += 1;
+    }
+
+    pub fn log(&self, namespace: &str) {
+        info!(
+            target: namespace,
+            "stats: keepalive_closures={} reordered={} pattern_dropped={} corrupted={} echoed={} mirrored={} mirror_dropped={}",
+            self.keepalive_closures.load(Ordering::Relaxed),
+            self.reordered.load(Ordering::Relaxed),
+            self.pattern_dropped.load(Ordering::Relaxed),
+            self.corrupted.load(Ordering::Relaxed),
+            self.echoed.load(Ordering::Relaxed),
+            self.mirrored.load(Ordering::Relaxed),
+            self.mirror_dropped.load(Ordering::Relaxed)
+        );
+        let passed = self.bandwidth_passed.load(Ordering::Relaxed);
+        let dropped = self.bandwidth_dropped.load(Ordering::Relaxed);
+        if passed > 0 || dropped > 0 {
+            info!(
+                target: namespace,
+                "stats: bandwidth passed={}B dropped={}B",
+                passed,
+                dropped
+            );
+        }
+        let transient = self.transient_errors.load(Ordering::Relaxed);
+        let resource = self.resource_errors.load(Ordering::Relaxed);
+        let fatal = self.fatal_errors.load(Ordering::Relaxed);
+        if transient > 0 || resource > 0 || fatal > 0 {
+            info!(
+                target: namespace,
+                "stats: errors transient={} resource={} fatal={}",
+                transient,
+                resource,
+                fatal
+            );
+        }
+        let full = self.full_batches.load(Ordering::Relaxed);
+        let partial = self.partial_batches.load(Ordering::Relaxed);
+        if full > 0 || partial > 0 {
+            info!(
+                target: namespace,
+                "stats: batches full={} partial={} mean_size={:.1}",
+                full,
+                partial,
+                self.batched.load(Ordering::Relaxed) as f64 / (full + partial) as f64
+            );
+        }
+        let accepted = self.accepted.load(Ordering::Relaxed);
+        let now = Instant::now();
+        let previous = self.last_log.lock().unwrap().replace((now, accepted));
+        if accepted > 0 {
+            let rate = match previous {
+                Some((then, before)) => {
+                    (accepted - before) as f64 / now.duration_since(then).as_secs_f64()
+                }
+                None => 0.0,
+            };
+            info!(
+                target: namespace,
+                "stats: accepted={} accepts_per_sec={:.1} accept_queue={} peak_accept_queue={}",
+                accepted,
+                rate,
+                self.accept_queue.load(Ordering::Relaxed),
+                self.peak_accept_queue.load(Ordering::Relaxed)
+            );
+        }
+        for (port, counter) in &self.ports {
+            info!(
+                target: namespace,
+                "stats: port {}: {}",
+                port,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+        for (ms, count) in &*self.delays.lock().unwrap() {
+            info!(target: namespace, "stats: delay {}ms: {}", ms, count);
+        }
+    }
+}
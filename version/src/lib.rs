@@ -0,0 +1,38 @@
+/// Format the `-V` banner: program name and version, followed by one copyright line per
+/// author. `authors` is colon separated, as in `CARGO_PKG_AUTHORS`.
+pub fn format_version(prog: &str, version: &str, authors: &str) -> String {
+    let mut banner = format!("{}: Version {}", prog, version);
+    for author in authors.split(':') {
+        banner.push_str("\n(C) ");
+        banner.push_str(author);
+    }
+    banner
+}
+
+/// Print the `-V` banner of the calling crate to stderr.
+#[macro_export]
+macro_rules! print_version {
+    ($prog:expr) => {
+        eprintln!(
+            "{}",
+            $crate::format_version($prog, env!("CARGO_PKG_VERSION"), env!("CARGO_PKG_AUTHORS"))
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn banner() {
+        assert_eq!(
+            format_version("client", "0.1.0", "A <a@example.com>:B <b@example.com>"),
+            "client: Version 0.1.0\n(C) A <a@example.com>\n(C) B <b@example.com>"
+        );
+        assert_eq!(
+            format_version("server", "1.2.3", "A"),
+            "server: Version 1.2.3\n(C) A"
+        );
+    }
+}